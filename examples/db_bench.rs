@@ -0,0 +1,92 @@
+//! Measures the throughput of concurrent GETs and SETs on the keyspace, with a
+//! single shard (one global lock) and with the default number of shards.
+//!
+//! Run with `cargo run --release --example db_bench`.
+
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use redis::db::{self, Db, DbValue, Lfu, Value};
+
+const KEYS: u64 = 100_000;
+const DURATION: Duration = Duration::from_secs(1);
+// Out of 10 operations, the number of SETs.
+const SETS: u64 = 2;
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let configs = [("1 shard", Db::with_shards(1, 1)), ("sharded", Db::new(1))];
+    for (_, db) in &configs {
+        populate(db);
+    }
+
+    println!("{:>8} {:>16} {:>16}", "threads", configs[0].0, configs[1].0);
+    let mut threads = 1;
+    loop {
+        let rates = configs
+            .iter()
+            .map(|(_, db)| run(db, threads))
+            .collect::<Vec<_>>();
+        println!(
+            "{:>8} {:>12.0} op/s {:>12.0} op/s",
+            threads, rates[0], rates[1]
+        );
+        if threads >= cores {
+            break;
+        }
+        threads = (threads * 2).min(cores);
+    }
+}
+
+fn populate(db: &Db) {
+    for i in 0..KEYS {
+        let key = format!("key:{i}");
+        db.lock(&key)[0].insert(key, value());
+    }
+}
+
+/// Runs a mix of GETs and SETs on random keys from `threads` threads and
+/// returns the number of operations per second.
+fn run(db: &Db, threads: usize) -> f64 {
+    let lfu = Lfu {
+        log_factor: 10,
+        decay_time: 1,
+    };
+    let start = Instant::now();
+    let ops = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut ops = 0u64;
+                    while start.elapsed() < DURATION {
+                        // Checking the clock is slow, so run batches.
+                        for _ in 0..1000 {
+                            let n = db::random();
+                            let key = format!("key:{}", n % KEYS);
+                            let mut shard = db.lock(&key);
+                            if n / KEYS % 10 < SETS {
+                                shard[0].insert(key, value());
+                            } else {
+                                std::hint::black_box(shard[0].touch(&key, lfu));
+                            }
+                        }
+                        ops += 1000;
+                    }
+                    ops
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .sum::<u64>()
+    });
+    ops as f64 / start.elapsed().as_secs_f64()
+}
+
+fn value() -> DbValue {
+    DbValue::new(Value::String(Bytes::from_static(b"value")))
+}
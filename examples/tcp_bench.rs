@@ -0,0 +1,138 @@
+//! Measures the throughput of GETs and SETs sent over TCP by concurrent
+//! clients, to a server running every connection on the shared runtime and
+//! to one in thread-per-core mode.
+//!
+//! Run with `cargo run --release --example tcp_bench`.
+
+use std::{
+    io::Cursor,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BytesMut};
+use redis::{
+    config::Config,
+    db,
+    frame::{Frame, ParseError},
+    server::{Role, Server},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    runtime::{Builder, Runtime},
+};
+
+const PORTS: [u16; 2] = [7501, 7502];
+const KEYS: u64 = 100_000;
+const DURATION: Duration = Duration::from_secs(2);
+// Commands sent by a client before reading their replies.
+const PIPELINE: usize = 16;
+// Out of 10 commands, the number of SETs.
+const SETS: u64 = 2;
+
+fn main() {
+    let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let modes = [("shared", 0), ("per core", cores)];
+    // Servers run on runtimes of their own, apart from the clients.
+    let _servers = modes
+        .iter()
+        .zip(PORTS)
+        .map(|(&(_, shard_threads), port)| start_server(port, shard_threads, cores))
+        .collect::<Vec<_>>();
+    let clients = Builder::new_multi_thread().enable_all().build().unwrap();
+
+    println!("{:>8} {:>16} {:>16}", "clients", modes[0].0, modes[1].0);
+    for connections in [1, 4, 16, 64, 256] {
+        let rates = PORTS
+            .iter()
+            .map(|&port| clients.block_on(run(port, connections)))
+            .collect::<Vec<_>>();
+        println!(
+            "{:>8} {:>12.0} op/s {:>12.0} op/s",
+            connections, rates[0], rates[1]
+        );
+    }
+}
+
+fn start_server(port: u16, shard_threads: usize, cores: usize) -> Runtime {
+    let dir = std::env::temp_dir().join(format!("tcp-bench-{}-{port}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = Config {
+        port,
+        dir,
+        save: Vec::new(),
+        shard_threads,
+        ..Config::default()
+    };
+    let runtime = Builder::new_multi_thread()
+        .worker_threads(cores)
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.spawn(Server::new(Role::Master, config).start());
+
+    runtime
+}
+
+/// Sends a mix of GETs and SETs on random keys over `connections`
+/// connections, and returns the number of commands per second.
+async fn run(port: u16, connections: usize) -> f64 {
+    let start = Instant::now();
+    let clients = (0..connections)
+        .map(|_| tokio::spawn(client(port, start)))
+        .collect::<Vec<_>>();
+    let mut ops = 0;
+    for client in clients {
+        ops += client.await.unwrap();
+    }
+
+    ops as f64 / start.elapsed().as_secs_f64()
+}
+
+async fn client(port: u16, start: Instant) -> u64 {
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(stream) => break stream,
+            // The server may still be starting.
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    };
+    stream.set_nodelay(true).unwrap();
+    let mut requests = BytesMut::new();
+    let mut replies = BytesMut::new();
+    let mut ops = 0;
+    while start.elapsed() < DURATION {
+        requests.clear();
+        for _ in 0..PIPELINE {
+            let n = db::random();
+            let key = format!("key:{}", n % KEYS);
+            let command = if n / KEYS % 10 < SETS {
+                Frame::bulk_array(["SET", &key, "value"])
+            } else {
+                Frame::bulk_array(["GET", &key])
+            };
+            requests.extend_from_slice(&command.to_bytes());
+        }
+        stream.write_all(&requests).await.unwrap();
+
+        let mut pending = PIPELINE;
+        while pending > 0 {
+            let mut cursor = Cursor::new(&replies[..]);
+            match Frame::parse(&mut cursor) {
+                Ok(_) => {
+                    let len = cursor.position() as usize;
+                    replies.advance(len);
+                    pending -= 1;
+                }
+                Err(ParseError::Incomplete) => {
+                    let n = stream.read_buf(&mut replies).await.unwrap();
+                    assert!(n > 0, "server closed the connection");
+                }
+                Err(ParseError::Other(err)) => panic!("invalid reply: {:#}", err),
+            }
+        }
+        ops += PIPELINE as u64;
+    }
+
+    ops
+}
@@ -0,0 +1,558 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Cursor, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+
+use crate::{
+    command::Command,
+    config::AppendFsync,
+    db::Snapshot,
+    frame::{Frame, ParseError},
+    rdb::{self, Rdb},
+};
+
+/// The append-only file, in the multi-part layout of redis 7: a base file
+/// holding an RDB snapshot, followed by incremental files of commands in RESP
+/// format. A manifest lists the files in use.
+#[derive(Debug)]
+pub struct Aof {
+    dir: PathBuf,
+    filename: String,
+    manifest: Manifest,
+    // The incremental file commands are appended to.
+    file: File,
+    fsync: AppendFsync,
+    // Whether commands were written since the last fsync.
+    fsync_pending: bool,
+    // Database selected by the last SELECT written to the incremental file.
+    selected_db: Option<usize>,
+    // Commands left to write after a failed write.
+    unwritten: Vec<u8>,
+    // Length of the incremental file up to its last complete command.
+    size: u64,
+    write_error: Option<String>,
+    fsync_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub base: Option<AofFile>,
+    pub incrs: Vec<AofFile>,
+    /// Files replaced by a rewrite, still to be deleted.
+    pub history: Vec<AofFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AofFile {
+    pub name: String,
+    pub seq: u64,
+}
+
+/// The contents of the append-only file.
+pub struct Loaded {
+    pub base: Option<Rdb>,
+    pub commands: Vec<Frame>,
+}
+
+/// A rewrite started with [`Aof::start_rewrite`]. Its base file is written
+/// without holding the AOF, while commands go to a new incremental file.
+pub struct Rewrite {
+    base: AofFile,
+    path: PathBuf,
+    // First incremental file written since the rewrite started.
+    first_incr_seq: u64,
+}
+
+impl Aof {
+    /// Creates an append-only file whose base holds the given snapshot.
+    pub fn create(
+        dir: &Path,
+        filename: &str,
+        fsync: AppendFsync,
+        snapshot: &Snapshot,
+    ) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let base = AofFile::base(filename, 1);
+        rdb::save(&dir.join(&base.name), &snapshot.databases())?;
+        let manifest = Manifest {
+            base: Some(base),
+            incrs: Vec::new(),
+            history: Vec::new(),
+        };
+
+        Self::open(dir, filename, manifest, fsync)
+    }
+
+    /// Opens the last incremental file of the manifest for appending, creating
+    /// one if there is none.
+    pub fn open(
+        dir: &Path,
+        filename: &str,
+        mut manifest: Manifest,
+        fsync: AppendFsync,
+    ) -> anyhow::Result<Self> {
+        if manifest.incrs.is_empty() {
+            manifest.incrs.push(AofFile::incr(filename, 1));
+        }
+        let incr = manifest.incrs.last().unwrap();
+        let file = open_for_append(&dir.join(&incr.name))?;
+        let size = file.metadata()?.len();
+        manifest.save(dir, filename)?;
+
+        Ok(Aof {
+            dir: dir.to_owned(),
+            filename: filename.to_owned(),
+            manifest,
+            file,
+            fsync,
+            fsync_pending: false,
+            selected_db: None,
+            unwritten: Vec::new(),
+            size,
+            write_error: None,
+            fsync_error: None,
+        })
+    }
+
+    /// Appends a write command executed against database `db`, as encoded by
+    /// [`encode_command`].
+    pub fn append(&mut self, db: usize, command: &[u8]) -> anyhow::Result<()> {
+        if self.selected_db != Some(db) {
+            self.unwritten
+                .extend_from_slice(&Command::Select(db).to_frame().to_bytes());
+            self.selected_db = Some(db);
+        }
+        self.unwritten.extend_from_slice(command);
+        self.flush()
+    }
+
+    /// Writes the commands left by a failed write, if any. A failed write is
+    /// cut from the file, and its commands kept for the next attempt.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        if self.unwritten.is_empty() {
+            return Ok(());
+        }
+        let result = self
+            .file
+            .write_all(&self.unwritten)
+            .and_then(|()| match self.fsync {
+                AppendFsync::Always => self.file.sync_data(),
+                AppendFsync::Everysec | AppendFsync::No => Ok(()),
+            });
+        if let Err(err) = result {
+            // Leaves the file ending with a complete command.
+            if let Err(err) = self.file.set_len(self.size) {
+                println!("failed to truncate the append only file: {}", err);
+            }
+            self.write_error = Some(err.to_string());
+            return Err(err.into());
+        }
+
+        self.size += self.unwritten.len() as u64;
+        self.unwritten.clear();
+        self.write_error = None;
+        if self.fsync == AppendFsync::Everysec {
+            self.fsync_pending = true;
+        }
+
+        Ok(())
+    }
+
+    /// Returns a handle to the incremental file if it needs an fsync, so that
+    /// it can be synced without holding the AOF.
+    pub fn take_fsync_pending(&mut self) -> Option<File> {
+        if !std::mem::take(&mut self.fsync_pending) {
+            return None;
+        }
+
+        self.file.try_clone().ok()
+    }
+
+    /// Records the result of an fsync of the handle returned by
+    /// [`Self::take_fsync_pending`], to be retried if it failed.
+    pub fn finish_fsync(&mut self, result: io::Result<()>) {
+        match result {
+            Ok(()) => self.fsync_error = None,
+            Err(err) => {
+                self.fsync_error = Some(err.to_string());
+                self.fsync_pending = true;
+            }
+        }
+    }
+
+    /// Returns the error of the last write or fsync, unless they succeeded
+    /// since.
+    pub fn error(&self) -> Option<&str> {
+        self.write_error.as_deref().or(self.fsync_error.as_deref())
+    }
+
+    /// Starts a rewrite: commands go to a new incremental file from now on, so
+    /// that a snapshot taken now and that file together hold the dataset.
+    pub fn start_rewrite(&mut self) -> anyhow::Result<Rewrite> {
+        // The snapshot holds the commands left to write, so they go to the
+        // current file.
+        self.flush()?;
+        self.file.sync_data()?;
+        let last_seq = self.manifest.incrs.last().map(|f| f.seq).unwrap_or(0);
+        let incr = AofFile::incr(&self.filename, last_seq + 1);
+        self.file = open_for_append(&self.dir.join(&incr.name))?;
+        self.size = 0;
+        self.fsync_pending = false;
+        self.fsync_error = None;
+        // The new file outlives the older ones, so it selects its own database.
+        self.selected_db = None;
+        self.manifest.incrs.push(incr);
+        self.manifest.save(&self.dir, &self.filename)?;
+
+        let base_seq = self.manifest.base.as_ref().map(|f| f.seq).unwrap_or(0) + 1;
+        let base = AofFile::base(&self.filename, base_seq);
+        Ok(Rewrite {
+            path: self.dir.join(&base.name),
+            base,
+            first_incr_seq: last_seq + 1,
+        })
+    }
+
+    /// Replaces the base file with the one of a finished rewrite, and deletes
+    /// the files it made obsolete.
+    pub fn finish_rewrite(&mut self, rewrite: Rewrite) -> anyhow::Result<()> {
+        let mut history = Vec::new();
+        history.extend(self.manifest.base.replace(rewrite.base));
+        let (old, new) = std::mem::take(&mut self.manifest.incrs)
+            .into_iter()
+            .partition(|f| f.seq < rewrite.first_incr_seq);
+        self.manifest.incrs = new;
+        history.extend::<Vec<_>>(old);
+        self.manifest.history.extend(history);
+        self.manifest.save(&self.dir, &self.filename)?;
+
+        for file in std::mem::take(&mut self.manifest.history) {
+            if let Err(err) = std::fs::remove_file(self.dir.join(&file.name)) {
+                println!("failed to delete {}: {}", file.name, err);
+            }
+        }
+        self.manifest.save(&self.dir, &self.filename)
+    }
+}
+
+impl Rewrite {
+    pub fn write_base(&self, snapshot: &Snapshot) -> anyhow::Result<()> {
+        rdb::save(&self.path, &snapshot.databases())
+    }
+}
+
+impl AofFile {
+    fn base(filename: &str, seq: u64) -> Self {
+        AofFile {
+            name: format!("{filename}.{seq}.base.rdb"),
+            seq,
+        }
+    }
+
+    fn incr(filename: &str, seq: u64) -> Self {
+        AofFile {
+            name: format!("{filename}.{seq}.incr.aof"),
+            seq,
+        }
+    }
+}
+
+impl Manifest {
+    /// Reads the manifest in `dir`, if any.
+    pub fn load(dir: &Path, filename: &str) -> anyhow::Result<Option<Self>> {
+        let path = dir.join(format!("{filename}.manifest"));
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => Self::parse(&contents)
+                .with_context(|| format!("invalid manifest {}", path.display()))
+                .map(Some),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+        }
+    }
+
+    /// Parses lines in the `file <name> seq <seq> type <b|i|h>` form.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let mut manifest = Manifest::default();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split(' ').collect::<Vec<_>>();
+            let mut name = None;
+            let mut seq = None;
+            let mut kind = None;
+            for pair in fields.chunks(2) {
+                match pair {
+                    ["file", value] => name = Some(value.to_string()),
+                    ["seq", value] => seq = value.parse::<u64>().ok(),
+                    ["type", value] => kind = Some(*value),
+                    // Unknown fields are kept for forward compatibility.
+                    [_, _] => (),
+                    _ => return Err(anyhow!("invalid manifest line: {}", line)),
+                }
+            }
+            let (Some(name), Some(seq), Some(kind)) = (name, seq, kind) else {
+                return Err(anyhow!("invalid manifest line: {}", line));
+            };
+            let file = AofFile { name, seq };
+            match kind {
+                "b" if manifest.base.is_none() => manifest.base = Some(file),
+                "b" => return Err(anyhow!("more than one base file")),
+                "i" => manifest.incrs.push(file),
+                "h" => manifest.history.push(file),
+                _ => return Err(anyhow!("invalid manifest line: {}", line)),
+            }
+        }
+        manifest.incrs.sort_by_key(|f| f.seq);
+
+        Ok(manifest)
+    }
+
+    pub fn encode(&self) -> String {
+        let mut s = String::new();
+        let files = self
+            .base
+            .iter()
+            .map(|f| (f, "b"))
+            .chain(self.history.iter().map(|f| (f, "h")))
+            .chain(self.incrs.iter().map(|f| (f, "i")));
+        for (file, kind) in files {
+            s.push_str(&format!(
+                "file {} seq {} type {}\n",
+                file.name, file.seq, kind
+            ));
+        }
+
+        s
+    }
+
+    fn save(&self, dir: &Path, filename: &str) -> anyhow::Result<()> {
+        let path = dir.join(format!("{filename}.manifest"));
+        let temp_path = dir.join(format!("temp-{filename}.manifest"));
+        let mut file = File::create(&temp_path)
+            .with_context(|| format!("failed to create {}", temp_path.display()))?;
+        file.write_all(self.encode().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, &path)
+            .with_context(|| format!("failed to rename manifest to {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Reads the files of the manifest. A truncated command at the end of the
+/// last file, as left by a crash, is cut off when `load_truncated` is set.
+/// Expired keys of the base are kept when `keep_expired` is set.
+pub fn load(
+    dir: &Path,
+    manifest: &Manifest,
+    load_truncated: bool,
+    keep_expired: bool,
+) -> anyhow::Result<Loaded> {
+    let mut loaded = Loaded {
+        base: None,
+        commands: Vec::new(),
+    };
+    if let Some(base) = &manifest.base {
+        let path = dir.join(&base.name);
+        let contents =
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        if contents.starts_with(b"REDIS") {
+            let rdb = Rdb::parse(&contents, keep_expired)
+                .with_context(|| format!("failed to load {}", path.display()))?;
+            loaded.base = Some(rdb);
+        } else {
+            let (commands, len) = parse_commands(&contents)
+                .with_context(|| format!("failed to load {}", path.display()))?;
+            if len < contents.len() {
+                return Err(anyhow!("truncated base file {}", path.display()));
+            }
+            loaded.commands.extend(commands);
+        }
+    }
+
+    for (i, incr) in manifest.incrs.iter().enumerate() {
+        let path = dir.join(&incr.name);
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            // The file of a rewrite is created before the manifest lists it.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        let (commands, len) = parse_commands(&contents)
+            .with_context(|| format!("failed to load {}", path.display()))?;
+        loaded.commands.extend(commands);
+
+        if len < contents.len() {
+            let last = i + 1 == manifest.incrs.len();
+            if !last || !load_truncated {
+                return Err(anyhow!(
+                    "unexpected end of {} at offset {}",
+                    path.display(),
+                    len
+                ));
+            }
+            println!(
+                "!!! warning: {} is truncated, dropping the last {} bytes",
+                path.display(),
+                contents.len() - len
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|file| file.set_len(len as u64))
+                .with_context(|| format!("failed to truncate {}", path.display()))?;
+        }
+    }
+
+    Ok(loaded)
+}
+
+/// Parses the commands in `contents`, returning them along with the length
+/// of the complete ones. Anything but an incomplete command at the end is an
+/// error.
+pub fn parse_commands(contents: &[u8]) -> anyhow::Result<(Vec<Frame>, usize)> {
+    let mut cursor = Cursor::new(contents);
+    let mut commands = Vec::new();
+    loop {
+        let start = cursor.position() as usize;
+        if start == contents.len() {
+            return Ok((commands, start));
+        }
+        match Frame::parse(&mut cursor) {
+            Ok(frame) => commands.push(frame),
+            Err(ParseError::Incomplete) => return Ok((commands, start)),
+            Err(ParseError::Other(err)) => {
+                return Err(err).with_context(|| format!("bad file format at offset {}", start))
+            }
+        }
+    }
+}
+
+/// Encodes a command for the AOF and the replication stream. Relative expiry
+/// times are made absolute, so that replaying the file or applying the
+/// stream later does not extend them.
+pub fn encode_command(command: &Command) -> Bytes {
+    match command {
+        Command::Set {
+            key,
+            value,
+            px: Some(millis),
+            ..
+        } => Command::Set {
+            key: key.clone(),
+            value: value.clone(),
+            px: None,
+            pxat: Some(rdb::unix_time_ms() + millis),
+        }
+        .to_frame()
+        .to_bytes(),
+        command => command.to_frame().to_bytes(),
+    }
+}
+
+fn open_for_append(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let manifest = Manifest {
+            base: Some(AofFile::base("appendonly.aof", 2)),
+            incrs: vec![
+                AofFile::incr("appendonly.aof", 3),
+                AofFile::incr("appendonly.aof", 4),
+            ],
+            history: vec![AofFile::base("appendonly.aof", 1)],
+        };
+
+        let encoded = manifest.encode();
+
+        assert!(encoded.starts_with("file appendonly.aof.2.base.rdb seq 2 type b\n"));
+        assert_eq!(manifest, Manifest::parse(&encoded).unwrap());
+    }
+
+    #[test]
+    fn test_parse_manifest_errors() {
+        assert!(Manifest::parse("file a seq x type b\n").is_err());
+        assert!(Manifest::parse("file a seq 1 type z\n").is_err());
+        assert!(Manifest::parse("file a seq 1 type b\nfile b seq 2 type b\n").is_err());
+        assert_eq!(Manifest::default(), Manifest::parse("").unwrap());
+    }
+
+    #[test]
+    fn test_parse_truncated_commands() {
+        let mut contents = Frame::bulk_array(["SET", "a", "1"]).to_bytes().to_vec();
+        let complete = contents.len();
+        contents.extend_from_slice(b"*3\r\n$3\r\nSET\r\n$1\r\nb");
+
+        let (commands, len) = parse_commands(&contents).unwrap();
+
+        assert_eq!(1, commands.len());
+        assert_eq!(complete, len);
+        assert!(parse_commands(b"*1\r\n!oops\r\n").is_err());
+    }
+
+    #[test]
+    fn test_encode_command_with_absolute_expiry() {
+        let set = Command::Set {
+            key: "key".to_owned(),
+            value: Bytes::from("value"),
+            px: Some(1000),
+            pxat: None,
+        };
+
+        let (mut commands, _) = parse_commands(&encode_command(&set)).unwrap();
+
+        let Command::Set { px, pxat, .. } = Command::parse(commands.remove(0)).unwrap() else {
+            panic!("expected SET");
+        };
+        assert_eq!(None, px);
+        assert!(pxat.unwrap() > rdb::unix_time_ms());
+    }
+
+    #[test]
+    fn test_retry_failed_write() {
+        let dir = std::env::temp_dir().join(format!("aof-retry-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut aof =
+            Aof::open(&dir, "appendonly.aof", Manifest::default(), AppendFsync::No).unwrap();
+        let path = dir.join("appendonly.aof.1.incr.aof");
+        let set = |key: &str| {
+            encode_command(&Command::Set {
+                key: key.to_owned(),
+                value: Bytes::from("1"),
+                px: None,
+                pxat: None,
+            })
+        };
+
+        // Writes fail on a read only handle.
+        let file = std::mem::replace(&mut aof.file, File::open(&path).unwrap());
+        assert!(aof.append(0, &set("a")).is_err());
+        assert!(aof.error().is_some());
+        aof.file = file;
+        aof.append(0, &set("b")).unwrap();
+
+        assert!(aof.error().is_none());
+        let contents = std::fs::read(&path).unwrap();
+        let (commands, _) = parse_commands(&contents).unwrap();
+        assert_eq!(3, commands.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,323 @@
+use anyhow::anyhow;
+
+use crate::{cluster, frame::Frame};
+
+/// A message of the cluster bus. Nodes exchange them over their bus port, as
+/// arrays of bulk strings starting with a header that describes the sender.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub header: Header,
+    pub payload: Payload,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub sender: String,
+    pub port: u16,
+    pub bus_port: u16,
+    /// The master of the sender, if it is a replica.
+    pub master: Option<String>,
+    /// Config epoch of the sender, or of its master for replicas.
+    pub config_epoch: u64,
+    pub current_epoch: u64,
+    /// Replication offset of the sender.
+    pub offset: u64,
+    /// Slots served by the sender, or by its master for replicas.
+    pub slots: Vec<(u16, u16)>,
+    /// Whether the sender paused its clients for a manual failover.
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+    Ping(Vec<Gossip>),
+    Pong(Vec<Gossip>),
+    /// A ping asking the receiver to add the sender to its nodes.
+    Meet(Vec<Gossip>),
+    /// A node was flagged as failing by a majority of masters.
+    Fail(String),
+    /// The slots of a node, sent to nodes that claim them with an older
+    /// config.
+    Update {
+        id: String,
+        config_epoch: u64,
+        slots: Vec<(u16, u16)>,
+    },
+    /// A replica asks the masters to vote for it. Manual failovers force
+    /// the vote, even though its master did not fail.
+    AuthRequest {
+        force: bool,
+    },
+    AuthAck,
+    /// A replica asks its master to pause its clients for a manual failover.
+    MfStart,
+}
+
+/// What the sender knows about another node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gossip {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+    pub bus_port: u16,
+    pub pfail: bool,
+    pub fail: bool,
+}
+
+impl Message {
+    pub fn to_frame(&self) -> Frame {
+        let header = &self.header;
+        let kind = match self.payload {
+            Payload::Ping(_) => "PING",
+            Payload::Pong(_) => "PONG",
+            Payload::Meet(_) => "MEET",
+            Payload::Fail(_) => "FAIL",
+            Payload::Update { .. } => "UPDATE",
+            Payload::AuthRequest { .. } => "AUTHREQ",
+            Payload::AuthAck => "AUTHACK",
+            Payload::MfStart => "MFSTART",
+        };
+        let mut elements = vec![
+            MAGIC.to_owned(),
+            kind.to_owned(),
+            header.sender.clone(),
+            header.port.to_string(),
+            header.bus_port.to_string(),
+            header.master.clone().unwrap_or_else(|| "-".to_owned()),
+            header.config_epoch.to_string(),
+            header.current_epoch.to_string(),
+            header.offset.to_string(),
+            format_slots(&header.slots),
+            (header.paused as u8).to_string(),
+        ];
+        match &self.payload {
+            Payload::Ping(gossip) | Payload::Pong(gossip) | Payload::Meet(gossip) => {
+                for node in gossip {
+                    let flags = match (node.fail, node.pfail) {
+                        (true, _) => "fail",
+                        (false, true) => "pfail",
+                        (false, false) => "-",
+                    };
+                    elements.extend([
+                        node.id.clone(),
+                        node.host.clone(),
+                        node.port.to_string(),
+                        node.bus_port.to_string(),
+                        flags.to_owned(),
+                    ]);
+                }
+            }
+            Payload::Fail(id) => elements.push(id.clone()),
+            Payload::Update {
+                id,
+                config_epoch,
+                slots,
+            } => elements.extend([id.clone(), config_epoch.to_string(), format_slots(slots)]),
+            Payload::AuthRequest { force } => elements.push((*force as u8).to_string()),
+            Payload::AuthAck | Payload::MfStart => (),
+        }
+
+        Frame::bulk_array(elements)
+    }
+
+    pub fn parse(frame: Frame) -> anyhow::Result<Self> {
+        let Frame::Array(frames) = frame else {
+            return Err(anyhow!("bus message is not an array"));
+        };
+        let elements = frames
+            .into_iter()
+            .map(|frame| match frame {
+                Frame::Bulk(bytes) => Ok(String::from_utf8(bytes.to_vec())?),
+                _ => Err(anyhow!("bus message has a non bulk element")),
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if elements.len() < HEADER_LEN || elements[0] != MAGIC {
+            return Err(anyhow!("invalid bus message header"));
+        }
+
+        let header = Header {
+            sender: elements[2].clone(),
+            port: parse_number(&elements[3])?,
+            bus_port: parse_number(&elements[4])?,
+            master: match &elements[5][..] {
+                "-" => None,
+                id => Some(id.to_owned()),
+            },
+            config_epoch: parse_number(&elements[6])?,
+            current_epoch: parse_number(&elements[7])?,
+            offset: parse_number(&elements[8])?,
+            slots: parse_slots(&elements[9])?,
+            paused: elements[10] == "1",
+        };
+        let args = &elements[HEADER_LEN..];
+        let expect_args = |count: usize| {
+            if args.len() == count {
+                Ok(())
+            } else {
+                Err(anyhow!("invalid {} bus message", elements[1]))
+            }
+        };
+        let payload = match &elements[1][..] {
+            "PING" => Payload::Ping(parse_gossip(args)?),
+            "PONG" => Payload::Pong(parse_gossip(args)?),
+            "MEET" => Payload::Meet(parse_gossip(args)?),
+            "FAIL" => {
+                expect_args(1)?;
+                Payload::Fail(args[0].clone())
+            }
+            "UPDATE" => {
+                expect_args(3)?;
+                Payload::Update {
+                    id: args[0].clone(),
+                    config_epoch: parse_number(&args[1])?,
+                    slots: parse_slots(&args[2])?,
+                }
+            }
+            "AUTHREQ" => {
+                expect_args(1)?;
+                Payload::AuthRequest {
+                    force: args[0] == "1",
+                }
+            }
+            "AUTHACK" => {
+                expect_args(0)?;
+                Payload::AuthAck
+            }
+            "MFSTART" => {
+                expect_args(0)?;
+                Payload::MfStart
+            }
+            kind => return Err(anyhow!("unknown bus message type: {}", kind)),
+        };
+
+        Ok(Message { header, payload })
+    }
+}
+
+fn parse_gossip(args: &[String]) -> anyhow::Result<Vec<Gossip>> {
+    let entries = args.chunks_exact(GOSSIP_LEN);
+    if !entries.remainder().is_empty() {
+        return Err(anyhow!("invalid gossip section"));
+    }
+
+    entries
+        .map(|entry| {
+            Ok(Gossip {
+                id: entry[0].clone(),
+                host: entry[1].clone(),
+                port: parse_number(&entry[2])?,
+                bus_port: parse_number(&entry[3])?,
+                pfail: entry[4] == "pfail",
+                fail: entry[4] == "fail",
+            })
+        })
+        .collect()
+}
+
+fn parse_number<T: std::str::FromStr>(s: &str) -> anyhow::Result<T> {
+    s.parse()
+        .map_err(|_| anyhow!("invalid number in bus message: {}", s))
+}
+
+/// Formats slot ranges as `<start>-<end>,...`, or `-` for none.
+fn format_slots(slots: &[(u16, u16)]) -> String {
+    if slots.is_empty() {
+        return "-".to_owned();
+    }
+
+    slots
+        .iter()
+        .map(|(start, end)| format!("{start}-{end}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn parse_slots(s: &str) -> anyhow::Result<Vec<(u16, u16)>> {
+    if s == "-" {
+        return Ok(Vec::new());
+    }
+
+    s.split(',').map(cluster::parse_slot_range).collect()
+}
+
+const MAGIC: &str = "RCmb";
+// Number of elements of the header, including the magic and message type.
+const HEADER_LEN: usize = 11;
+// Number of elements of a gossip entry.
+const GOSSIP_LEN: usize = 5;
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            sender: "a".repeat(40),
+            port: 7000,
+            bus_port: 17000,
+            master: Some("b".repeat(40)),
+            config_epoch: 3,
+            current_epoch: 5,
+            offset: 1234,
+            slots: vec![(0, 100), (200, 200)],
+            paused: true,
+        }
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let messages = [
+            Payload::Ping(vec![
+                Gossip {
+                    id: "c".repeat(40),
+                    host: "127.0.0.1".to_owned(),
+                    port: 7002,
+                    bus_port: 17002,
+                    pfail: true,
+                    fail: false,
+                },
+                Gossip {
+                    id: "d".repeat(40),
+                    host: "::1".to_owned(),
+                    port: 7003,
+                    bus_port: 17003,
+                    pfail: false,
+                    fail: true,
+                },
+            ]),
+            Payload::Pong(Vec::new()),
+            Payload::Fail("c".repeat(40)),
+            Payload::Update {
+                id: "c".repeat(40),
+                config_epoch: 7,
+                slots: Vec::new(),
+            },
+            Payload::AuthRequest { force: true },
+            Payload::AuthAck,
+            Payload::MfStart,
+        ];
+        for payload in messages {
+            let message = Message {
+                header: header(),
+                payload,
+            };
+            assert_eq!(message, Message::parse(message.to_frame()).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_parse_invalid_message() {
+        assert!(Message::parse(Frame::bulk_array(["PING"])).is_err());
+        let mut frame = Message {
+            header: header(),
+            payload: Payload::AuthAck,
+        }
+        .to_frame();
+        if let Frame::Array(elements) = &mut frame {
+            elements[1] = Frame::Bulk(Bytes::from_static(b"NOPE"));
+        }
+        assert!(Message::parse(frame).is_err());
+    }
+}
@@ -0,0 +1,1911 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Write as _,
+    path::Path,
+};
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+
+use crate::{
+    bus::{Gossip, Header, Message, Payload},
+    db,
+    frame::Frame,
+    replication,
+};
+
+/// Number of hash slots the keys are divided into.
+pub const SLOTS: usize = 16384;
+
+/// The view of the cluster held by a node: the known nodes, and the master
+/// serving each slot. It is persisted to the cluster config file, in the
+/// format of CLUSTER NODES.
+///
+/// Nodes keep it up to date by exchanging messages over the cluster bus.
+/// Handling them and running [`ClusterState::cron`] returns the [`Effects`]
+/// the server has to carry out. Times are Unix times in milliseconds.
+#[derive(Debug, Clone)]
+pub struct ClusterState {
+    // The known nodes, starting with this one.
+    nodes: Vec<Node>,
+    // Index in `nodes` of the master serving each slot.
+    owners: Box<[Option<usize>]>,
+    pub current_epoch: u64,
+    pub last_vote_epoch: u64,
+    /// Milliseconds without a pong after which a node is possibly failing.
+    pub node_timeout: u64,
+    // Recently forgotten nodes, with the time until which gossip about them
+    // is ignored.
+    forgotten: Vec<(String, u64)>,
+    // The election this replica runs to replace its master.
+    election: Option<Election>,
+    manual_failover: Option<ManualFailover>,
+    // The replica this master paused its clients for, with the time until
+    // which they are paused.
+    paused_for: Option<(String, u64)>,
+    // Time the once per second tasks of the cron last ran.
+    last_second_tasks: u64,
+    // Slots of this node moving to another one, with the ID of the target.
+    migrating_to: BTreeMap<u16, String>,
+    // Slots moving to this node, with the ID of their owner.
+    importing_from: BTreeMap<u16, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+    pub bus_port: u16,
+    /// The master of a replica, `None` for masters.
+    pub master: Option<String>,
+    pub config_epoch: u64,
+    /// Time of the oldest ping still awaiting a pong, 0 if none.
+    pub ping_sent: u64,
+    pub pong_received: u64,
+    /// Whether the node did not answer pings for the node timeout.
+    pub pfail: bool,
+    /// Whether a majority of masters agreed that the node failed.
+    pub fail: bool,
+    pub fail_time: u64,
+    /// Whether the node is yet to answer its first ping. Its ID is a
+    /// placeholder until then.
+    pub handshake: bool,
+    /// Replication offset the node last advertised.
+    pub offset: u64,
+    // Whether the node is sent MEET rather than PING, so that it adds this
+    // one to its nodes.
+    meet: bool,
+    created: u64,
+    // Masters that reported the node as failing, with the time of their last
+    // report.
+    fail_reports: Vec<(String, u64)>,
+    // Time this node last voted for a replica of this master.
+    voted_time: u64,
+}
+
+/// What the server has to carry out after the cluster state changed.
+#[derive(Debug, Default)]
+pub struct Effects {
+    /// Messages to send, with the ID of their receiver.
+    pub messages: Vec<(String, Message)>,
+    /// Replicate the given master, or become a master with `None`.
+    pub replicate: Option<Option<(String, u16)>>,
+    /// Time until which client writes are paused, 0 to resume them.
+    pub pause_until: Option<u64>,
+    /// Whether the cluster config file has to be saved.
+    pub save: bool,
+}
+
+/// How CLUSTER FAILOVER replaces the master of a replica.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverMode {
+    /// Pause the master, wait for the replica to catch up, then run an
+    /// election.
+    Default,
+    /// Run an election right away, even if the master is unreachable.
+    Force,
+    /// Take the slots of the master without any election.
+    Takeover,
+}
+
+/// How CLUSTER SETSLOT changes a slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SetSlot {
+    /// The slot moves from the given node to this one.
+    Importing(String),
+    /// The slot moves from this node to the given one.
+    Migrating(String),
+    /// The slot is served by the given node, which ends its migration.
+    Node(String),
+    /// Cancels the migration of the slot.
+    Stable,
+}
+
+#[derive(Debug, Clone)]
+struct Election {
+    // Time the election starts, delayed so that the most up to date replica
+    // goes first.
+    start: u64,
+    // Epoch the votes were requested for, 0 until then.
+    epoch: u64,
+    votes: HashSet<String>,
+}
+
+#[derive(Debug, Clone)]
+struct ManualFailover {
+    deadline: u64,
+    force: bool,
+    // Offset of the master once it paused its clients.
+    master_offset: Option<u64>,
+}
+
+impl ManualFailover {
+    fn ready(&self, offset: u64) -> bool {
+        self.force || self.master_offset.is_some_and(|master| offset >= master)
+    }
+}
+
+impl Node {
+    pub fn new(id: String, host: String, port: u16, bus_port: u16) -> Self {
+        Node {
+            id,
+            host,
+            port,
+            bus_port,
+            master: None,
+            config_epoch: 0,
+            ping_sent: 0,
+            pong_received: 0,
+            pfail: false,
+            fail: false,
+            fail_time: 0,
+            handshake: false,
+            offset: 0,
+            meet: false,
+            created: 0,
+            fail_reports: Vec::new(),
+            voted_time: 0,
+        }
+    }
+}
+
+impl ClusterState {
+    /// A cluster made of this node alone, serving no slot.
+    pub fn new(myself: Node) -> Self {
+        ClusterState {
+            nodes: vec![myself],
+            owners: vec![None; SLOTS].into_boxed_slice(),
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            node_timeout: DEFAULT_NODE_TIMEOUT,
+            forgotten: Vec::new(),
+            election: None,
+            manual_failover: None,
+            paused_for: None,
+            last_second_tasks: 0,
+            migrating_to: BTreeMap::new(),
+            importing_from: BTreeMap::new(),
+        }
+    }
+
+    /// Loads the cluster config file, or starts a new cluster if there is
+    /// none. This node keeps its ID, but takes the given address.
+    pub fn load(path: &Path, myself: Node) -> anyhow::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(ClusterState::new(myself))
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        let mut state = ClusterState::parse(&contents)
+            .with_context(|| format!("invalid cluster config file {}", path.display()))?;
+        let node = &mut state.nodes[0];
+        node.host = myself.host;
+        node.port = myself.port;
+        node.bus_port = myself.bus_port;
+
+        Ok(state)
+    }
+
+    /// Parses a cluster config file. Nodes still in handshake are skipped.
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut nodes = Vec::new();
+        // Slot ranges of each node, in `nodes` order.
+        let mut ranges = Vec::new();
+        let mut current_epoch = 0;
+        let mut last_vote_epoch = 0;
+        let mut found_myself = false;
+        let mut migrating_to = BTreeMap::new();
+        let mut importing_from = BTreeMap::new();
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields[0] == "vars" {
+                for pair in fields[1..].chunks(2) {
+                    let value = pair.get(1).and_then(|v| v.parse().ok());
+                    match (pair[0], value) {
+                        ("currentEpoch", Some(epoch)) => current_epoch = epoch,
+                        ("lastVoteEpoch", Some(epoch)) => last_vote_epoch = epoch,
+                        _ => return Err(anyhow!("invalid vars line: {}", line)),
+                    }
+                }
+                continue;
+            }
+            if fields.len() < 8 {
+                return Err(anyhow!("invalid node line: {}", line));
+            }
+
+            let flags = fields[2].split(',').collect::<Vec<_>>();
+            if flags.contains(&"handshake") {
+                continue;
+            }
+            let (host, port, bus_port) = parse_node_address(fields[1])
+                .ok_or_else(|| anyhow!("invalid node address: {}", fields[1]))?;
+            let mut node = Node::new(fields[0].to_owned(), host, port, bus_port);
+            node.master = match fields[3] {
+                "-" => None,
+                id => Some(id.to_owned()),
+            };
+            node.config_epoch = fields[6]
+                .parse()
+                .map_err(|_| anyhow!("invalid config epoch: {}", fields[6]))?;
+            node.fail = flags.contains(&"fail");
+            let (migrations, node_ranges) = fields[8..]
+                .iter()
+                .partition::<Vec<_>, _>(|range| range.starts_with('['));
+            let node_ranges = node_ranges
+                .into_iter()
+                .map(parse_slot_range)
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            // This node comes first.
+            if flags.contains(&"myself") {
+                if found_myself {
+                    return Err(anyhow!("several nodes flagged myself"));
+                }
+                found_myself = true;
+                // Slots in migration are in brackets.
+                for migration in migrations {
+                    let (slot, direction, id) = parse_migration(migration)
+                        .ok_or_else(|| anyhow!("invalid slot migration: {}", migration))?;
+                    match direction {
+                        "->-" => migrating_to.insert(slot, id.to_owned()),
+                        _ => importing_from.insert(slot, id.to_owned()),
+                    };
+                }
+                nodes.insert(0, node);
+                ranges.insert(0, node_ranges);
+            } else {
+                nodes.push(node);
+                ranges.push(node_ranges);
+            }
+        }
+        if !found_myself {
+            return Err(anyhow!("no node flagged myself"));
+        }
+
+        let mut state = ClusterState::new(nodes.remove(0));
+        state.nodes.extend(nodes);
+        state.current_epoch = current_epoch;
+        state.last_vote_epoch = last_vote_epoch;
+        state.migrating_to = migrating_to;
+        state.importing_from = importing_from;
+        for (index, node_ranges) in ranges.into_iter().enumerate() {
+            for (start, end) in node_ranges {
+                for slot in start..=end {
+                    state.owners[slot as usize] = Some(index);
+                }
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Writes the cluster config file, through a temp file so that `path`
+    /// never holds a partial config.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let temp_path = path.with_extension(format!("tmp-{}", std::process::id()));
+        std::fs::write(&temp_path, self.to_config())
+            .with_context(|| format!("failed to write {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, path)
+            .with_context(|| format!("failed to rename cluster config to {}", path.display()))
+    }
+
+    pub fn to_config(&self) -> String {
+        let mut config = self.describe_nodes();
+        writeln!(
+            config,
+            "vars currentEpoch {} lastVoteEpoch {}",
+            self.current_epoch, self.last_vote_epoch
+        )
+        .unwrap();
+
+        config
+    }
+
+    /// Describes the nodes as CLUSTER NODES does, one node per line.
+    pub fn describe_nodes(&self) -> String {
+        let mut description = String::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            let mut flags = Vec::new();
+            if index == 0 {
+                flags.push("myself");
+            }
+            flags.push(match node.master {
+                Some(_) => "slave",
+                None => "master",
+            });
+            if node.fail {
+                flags.push("fail");
+            } else if node.pfail {
+                flags.push("fail?");
+            }
+            if node.handshake {
+                flags.push("handshake");
+            }
+            let link_state = if node.pfail || node.fail {
+                "disconnected"
+            } else {
+                "connected"
+            };
+            write!(
+                description,
+                "{} {}:{}@{} {} {} {} {} {} {}",
+                node.id,
+                node.host,
+                node.port,
+                node.bus_port,
+                flags.join(","),
+                node.master.as_deref().unwrap_or("-"),
+                node.ping_sent,
+                node.pong_received,
+                node.config_epoch,
+                link_state
+            )
+            .unwrap();
+            for (start, end) in self.slot_ranges(index) {
+                if start == end {
+                    write!(description, " {}", start).unwrap();
+                } else {
+                    write!(description, " {}-{}", start, end).unwrap();
+                }
+            }
+            if index == 0 {
+                for (slot, id) in &self.migrating_to {
+                    write!(description, " [{}->-{}]", slot, id).unwrap();
+                }
+                for (slot, id) in &self.importing_from {
+                    write!(description, " [{}-<-{}]", slot, id).unwrap();
+                }
+            }
+            description.push('\n');
+        }
+
+        description
+    }
+
+    pub fn myself(&self) -> &Node {
+        &self.nodes[0]
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn node(&self, id: &str) -> Option<&Node> {
+        self.index(id).map(|index| &self.nodes[index])
+    }
+
+    /// The master serving a slot, if any.
+    pub fn owner(&self, slot: u16) -> Option<&Node> {
+        self.owners[slot as usize].map(|index| &self.nodes[index])
+    }
+
+    /// Assigns slots to this node, unless one of them is already served.
+    pub fn add_slots(&mut self, slots: &[u16]) -> anyhow::Result<()> {
+        check_unique(slots)?;
+        if let Some(slot) = slots.iter().find(|&&s| self.owners[s as usize].is_some()) {
+            return Err(anyhow!("Slot {} is already busy", slot));
+        }
+        for &slot in slots {
+            self.owners[slot as usize] = Some(0);
+        }
+
+        Ok(())
+    }
+
+    /// Unassigns slots, unless one of them is not served.
+    pub fn del_slots(&mut self, slots: &[u16]) -> anyhow::Result<()> {
+        check_unique(slots)?;
+        if let Some(slot) = slots.iter().find(|&&s| self.owners[s as usize].is_none()) {
+            return Err(anyhow!("Slot {} is already unassigned", slot));
+        }
+        for &slot in slots {
+            self.owners[slot as usize] = None;
+        }
+
+        Ok(())
+    }
+
+    /// The node a slot of this node is migrating to, if any.
+    pub fn migrating_to(&self, slot: u16) -> Option<&Node> {
+        self.migrating_to.get(&slot).and_then(|id| self.node(id))
+    }
+
+    /// The node a slot is imported from, if any.
+    pub fn importing_from(&self, slot: u16) -> Option<&Node> {
+        self.importing_from.get(&slot).and_then(|id| self.node(id))
+    }
+
+    /// Changes the migration state of a slot, or assigns it, for CLUSTER
+    /// SETSLOT. `keys` is the number of keys this node holds in the slot.
+    pub fn set_slot(
+        &mut self,
+        slot: u16,
+        action: SetSlot,
+        keys: usize,
+        offset: u64,
+        now: u64,
+    ) -> anyhow::Result<Effects> {
+        if self.nodes[0].master.is_some() {
+            return Err(anyhow!("Please use SETSLOT only with masters."));
+        }
+        let target = |id: &str| {
+            self.index(id)
+                .filter(|&index| !self.nodes[index].handshake)
+                .ok_or_else(|| anyhow!("I don't know about node {}", id))
+        };
+        let owned = self.owners[slot as usize] == Some(0);
+
+        let mut effects = Effects::default();
+        match action {
+            SetSlot::Migrating(id) => {
+                if !owned {
+                    return Err(anyhow!("I'm not the owner of hash slot {}", slot));
+                }
+                let index = target(&id)?;
+                if self.nodes[index].master.is_some() {
+                    return Err(anyhow!("Target node is not a master"));
+                }
+                self.migrating_to.insert(slot, id);
+            }
+            SetSlot::Importing(id) => {
+                if owned {
+                    return Err(anyhow!("I'm already the owner of hash slot {}", slot));
+                }
+                let index = target(&id)?;
+                if self.nodes[index].master.is_some() {
+                    return Err(anyhow!("Target node is not a master"));
+                }
+                self.importing_from.insert(slot, id);
+            }
+            SetSlot::Stable => {
+                self.migrating_to.remove(&slot);
+                self.importing_from.remove(&slot);
+            }
+            SetSlot::Node(id) => {
+                let index = target(&id)?;
+                if self.nodes[index].master.is_some() {
+                    return Err(anyhow!("Target node is not a master"));
+                }
+                if owned && index != 0 && keys > 0 {
+                    return Err(anyhow!(
+                        "Can't assign hashslot {} to a different node while I still hold keys \
+                         for this hash slot.",
+                        slot
+                    ));
+                }
+                if keys == 0 {
+                    self.migrating_to.remove(&slot);
+                }
+                self.owners[slot as usize] = Some(index);
+                // The import ends with a new config epoch, so that the other
+                // nodes accept the claim of this node over the slot.
+                if index == 0 && self.importing_from.remove(&slot).is_some() {
+                    self.bump_config_epoch();
+                    self.broadcast(Payload::Pong(Vec::new()), offset, now, &mut effects);
+                }
+            }
+        }
+        effects.save = true;
+
+        Ok(effects)
+    }
+
+    /// Takes a new config epoch without an election, unless this node
+    /// already has the greatest one.
+    fn bump_config_epoch(&mut self) {
+        let max_epoch = self.nodes.iter().map(|node| node.config_epoch).max();
+        let myself = &self.nodes[0];
+        if myself.config_epoch == 0 || Some(myself.config_epoch) != max_epoch {
+            self.current_epoch += 1;
+            self.nodes[0].config_epoch = self.current_epoch;
+            println!("took config epoch {}", self.current_epoch);
+        }
+    }
+
+    /// Starts a handshake with the node at the given address, which then
+    /// joins the cluster.
+    pub fn meet(&mut self, host: &str, port: u16, bus_port: u16, now: u64) {
+        self.start_handshake(host, port, bus_port, true, now);
+    }
+
+    /// Removes a node, and ignores gossip about it for a minute, which gives
+    /// the other nodes time to forget it too.
+    pub fn forget(&mut self, id: &str, now: u64) -> anyhow::Result<()> {
+        let index = self
+            .index(id)
+            .ok_or_else(|| anyhow!("Unknown node {}", id))?;
+        if index == 0 {
+            return Err(anyhow!("I tried hard but I can't forget myself..."));
+        }
+        if self.nodes[0].master.as_deref() == Some(id) {
+            return Err(anyhow!("Can't forget my master!"));
+        }
+        self.remove_node(index);
+        self.forgotten.push((id.to_owned(), now + FORGET_TTL));
+
+        Ok(())
+    }
+
+    /// Makes this node a replica of the given master.
+    pub fn replicate(&mut self, id: &str) -> anyhow::Result<Effects> {
+        let index = self
+            .index(id)
+            .filter(|&index| !self.nodes[index].handshake)
+            .ok_or_else(|| anyhow!("Unknown node {}", id))?;
+        if index == 0 {
+            return Err(anyhow!("Can't replicate myself"));
+        }
+        if self.nodes[index].master.is_some() {
+            return Err(anyhow!("I can only replicate a master, not a replica."));
+        }
+        if self.nodes[0].master.is_none() && self.slot_count(0) > 0 {
+            return Err(anyhow!(
+                "To set a master the node must be empty and without assigned slots."
+            ));
+        }
+
+        let mut effects = Effects::default();
+        self.set_master(index, &mut effects);
+
+        Ok(effects)
+    }
+
+    /// Forgets every other node and releases the slots. A hard reset also
+    /// gives this node a new ID and resets the epochs.
+    pub fn reset(&mut self, hard: bool) -> Effects {
+        let mut effects = Effects {
+            save: true,
+            ..Effects::default()
+        };
+        self.nodes.truncate(1);
+        self.owners.fill(None);
+        self.migrating_to.clear();
+        self.importing_from.clear();
+        self.forgotten.clear();
+        self.election = None;
+        self.manual_failover = None;
+        if self.paused_for.take().is_some() {
+            effects.pause_until = Some(0);
+        }
+        let myself = &mut self.nodes[0];
+        if myself.master.take().is_some() {
+            effects.replicate = Some(None);
+        }
+        if hard {
+            myself.id = replication::random_replid();
+            myself.config_epoch = 0;
+            self.current_epoch = 0;
+            self.last_vote_epoch = 0;
+        }
+
+        effects
+    }
+
+    /// Starts replacing the master of this replica.
+    pub fn failover(
+        &mut self,
+        mode: FailoverMode,
+        offset: u64,
+        now: u64,
+    ) -> anyhow::Result<Effects> {
+        let master = self
+            .nodes
+            .get(self.master_index(0).unwrap_or(0))
+            .filter(|master| master.id != self.nodes[0].id)
+            .ok_or_else(|| anyhow!("You should send CLUSTER FAILOVER to a replica"))?;
+        if mode == FailoverMode::Default && master.fail {
+            return Err(anyhow!(
+                "Master is down or failed, please use CLUSTER FAILOVER FORCE"
+            ));
+        }
+        let master_id = master.id.clone();
+
+        let mut effects = Effects::default();
+        self.election = None;
+        match mode {
+            FailoverMode::Takeover => {
+                self.current_epoch += 1;
+                println!(
+                    "taking over the slots of {} for epoch {}",
+                    master_id, self.current_epoch
+                );
+                self.promote(self.current_epoch, offset, now, &mut effects);
+            }
+            FailoverMode::Force | FailoverMode::Default => {
+                let force = mode == FailoverMode::Force;
+                self.manual_failover = Some(ManualFailover {
+                    deadline: now + MANUAL_FAILOVER_TIMEOUT,
+                    force,
+                    master_offset: None,
+                });
+                if !force {
+                    let message = self.message(Payload::MfStart, offset, now);
+                    effects.messages.push((master_id, message));
+                }
+            }
+        }
+
+        Ok(effects)
+    }
+
+    /// Handles a message received on the bus from `host`. `link` is the ID of
+    /// the node the message comes from, if received on a connection this node
+    /// opened to it. Returns the reply to send on the same connection, if any.
+    pub fn handle(
+        &mut self,
+        message: Message,
+        host: &str,
+        link: Option<&str>,
+        offset: u64,
+        now: u64,
+    ) -> (Option<Message>, Effects) {
+        let mut effects = Effects::default();
+        let Message { header, payload } = message;
+        if let (Payload::Pong(_), Some(link)) = (&payload, link) {
+            self.complete_handshake(link, &header, &mut effects);
+        }
+
+        let mut sender = self
+            .index(&header.sender)
+            .filter(|&index| index != 0 && !self.nodes[index].handshake);
+        if sender.is_none()
+            && matches!(payload, Payload::Meet(_))
+            && !self.is_forgotten(&header.sender)
+        {
+            println!("met node {}", header.sender);
+            self.nodes.push(Node::new(
+                header.sender.clone(),
+                host.to_owned(),
+                header.port,
+                header.bus_port,
+            ));
+            sender = Some(self.nodes.len() - 1);
+            effects.save = true;
+        }
+        // Unknown senders get a pong too, which completes their handshake.
+        let reply_to =
+            matches!(payload, Payload::Ping(_) | Payload::Meet(_)).then(|| header.sender.clone());
+        if let Some(index) = sender {
+            self.handle_known(index, header, payload, offset, now, &mut effects);
+        }
+        let reply = reply_to.map(|receiver| {
+            let gossip = self.gossip(&receiver);
+            self.message(Payload::Pong(gossip), offset, now)
+        });
+
+        (reply, effects)
+    }
+
+    fn handle_known(
+        &mut self,
+        index: usize,
+        header: Header,
+        payload: Payload,
+        offset: u64,
+        now: u64,
+        effects: &mut Effects,
+    ) {
+        if header.current_epoch > self.current_epoch {
+            self.current_epoch = header.current_epoch;
+            effects.save = true;
+        }
+        let node = &mut self.nodes[index];
+        node.offset = header.offset;
+        if header.master.is_none() && header.config_epoch > node.config_epoch {
+            node.config_epoch = header.config_epoch;
+            effects.save = true;
+        }
+
+        let is_pong = matches!(payload, Payload::Pong(_));
+        match payload {
+            Payload::Ping(gossip) | Payload::Pong(gossip) | Payload::Meet(gossip) => {
+                if is_pong {
+                    self.record_pong(index, now, effects);
+                }
+                self.update_role(index, &header, effects);
+                if header.master.is_none() {
+                    self.update_slots(index, header.config_epoch, &header.slots, effects);
+                    self.check_stale_slots(index, &header, offset, now, effects);
+                    self.handle_epoch_collision(index, effects);
+                }
+                self.process_gossip(index, &gossip, now);
+            }
+            Payload::Fail(id) => {
+                if let Some(failed) = self.index(&id).filter(|&failed| failed != 0) {
+                    let node = &mut self.nodes[failed];
+                    if !node.fail {
+                        println!("node {} failed, as reported by {}", id, header.sender);
+                        node.fail = true;
+                        node.pfail = false;
+                        node.fail_time = now;
+                        effects.save = true;
+                    }
+                }
+            }
+            Payload::Update {
+                id,
+                config_epoch,
+                slots,
+            } => {
+                if let Some(updated) = self.index(&id) {
+                    if config_epoch > self.nodes[updated].config_epoch {
+                        self.nodes[updated].config_epoch = config_epoch;
+                        self.update_slots(updated, config_epoch, &slots, effects);
+                    }
+                }
+            }
+            Payload::AuthRequest { force } => {
+                self.vote(index, &header, force, offset, now, effects);
+            }
+            Payload::AuthAck => {
+                let voter = self.is_voter(index);
+                if let Some(election) = &mut self.election {
+                    if voter && election.epoch != 0 && header.current_epoch >= election.epoch {
+                        election.votes.insert(header.sender.clone());
+                    }
+                }
+            }
+            Payload::MfStart => {
+                if self.nodes[0].master.is_none()
+                    && header.master.as_deref() == Some(&self.nodes[0].id[..])
+                {
+                    println!("manual failover requested by replica {}", header.sender);
+                    let until = now + MANUAL_FAILOVER_TIMEOUT;
+                    self.paused_for = Some((header.sender.clone(), until));
+                    effects.pause_until = Some(until);
+                    let message = self.message(Payload::Ping(Vec::new()), offset, now);
+                    effects.messages.push((header.sender.clone(), message));
+                }
+            }
+        }
+
+        // A paused master advertises the offset its replica has to reach.
+        if header.paused && self.nodes[0].master.as_deref() == Some(&header.sender[..]) {
+            if let Some(failover) = &mut self.manual_failover {
+                failover.master_offset.get_or_insert(header.offset);
+            }
+        }
+    }
+
+    /// Gives a node in handshake its real ID, once it answered.
+    fn complete_handshake(&mut self, link: &str, header: &Header, effects: &mut Effects) {
+        let Some(index) = self
+            .index(link)
+            .filter(|&index| self.nodes[index].handshake)
+        else {
+            return;
+        };
+        if self.index(&header.sender).is_some() {
+            // Already known under its real ID.
+            self.remove_node(index);
+        } else {
+            let node = &mut self.nodes[index];
+            println!("handshake with node {} completed", header.sender);
+            node.id = header.sender.clone();
+            node.port = header.port;
+            node.bus_port = header.bus_port;
+            node.handshake = false;
+            node.meet = false;
+        }
+        effects.save = true;
+    }
+
+    fn record_pong(&mut self, index: usize, now: u64, effects: &mut Effects) {
+        let serves_slots = self.slot_count(index) > 0;
+        let undo_fail_time = self.node_timeout * 2;
+        let node = &mut self.nodes[index];
+        node.pong_received = now;
+        node.ping_sent = 0;
+        node.pfail = false;
+        // A failed master serving slots stays failed long enough for its
+        // replicas to take over.
+        if node.fail
+            && (node.master.is_some()
+                || !serves_slots
+                || now.saturating_sub(node.fail_time) > undo_fail_time)
+        {
+            println!("node {} is reachable again", node.id);
+            node.fail = false;
+            effects.save = true;
+        }
+    }
+
+    /// Follows the role a node advertises.
+    fn update_role(&mut self, index: usize, header: &Header, effects: &mut Effects) {
+        if self.nodes[index].master == header.master {
+            return;
+        }
+        self.nodes[index].master = header.master.clone();
+        if header.master.is_some() {
+            // Replicas serve no slot.
+            for owner in self.owners.iter_mut() {
+                if *owner == Some(index) {
+                    *owner = None;
+                }
+            }
+        }
+        effects.save = true;
+    }
+
+    /// Assigns to a master the slots it claims, unless they are served under
+    /// a config as recent. When this node, or its master, loses its last slot
+    /// that way, it replicates the new owner.
+    fn update_slots(
+        &mut self,
+        index: usize,
+        config_epoch: u64,
+        slots: &[(u16, u16)],
+        effects: &mut Effects,
+    ) {
+        if index == 0 {
+            return;
+        }
+        let my_master = self.master_index(0);
+        let mut lost = false;
+        for &(start, end) in slots {
+            for slot in start..=end {
+                let owner = self.owners[slot as usize];
+                // Slots being imported are assigned by CLUSTER SETSLOT.
+                if owner == Some(index)
+                    || self.importing_from.contains_key(&slot)
+                    || owner.is_some_and(|owner| self.nodes[owner].config_epoch >= config_epoch)
+                {
+                    continue;
+                }
+                lost |= owner.is_some() && owner == my_master;
+                self.owners[slot as usize] = Some(index);
+                effects.save = true;
+            }
+        }
+
+        if lost && my_master.is_some_and(|master| self.slot_count(master) == 0) {
+            self.set_master(index, effects);
+        }
+    }
+
+    /// Sends an UPDATE to a master claiming slots served under a more recent
+    /// config.
+    fn check_stale_slots(
+        &self,
+        index: usize,
+        header: &Header,
+        offset: u64,
+        now: u64,
+        effects: &mut Effects,
+    ) {
+        let newer_owner = header
+            .slots
+            .iter()
+            .flat_map(|&(start, end)| start..=end)
+            .filter_map(|slot| self.owners[slot as usize])
+            .find(|&owner| owner != index && self.nodes[owner].config_epoch > header.config_epoch);
+        if let Some(owner) = newer_owner {
+            let update = Payload::Update {
+                id: self.nodes[owner].id.clone(),
+                config_epoch: self.nodes[owner].config_epoch,
+                slots: self.slot_ranges(owner),
+            };
+            let message = self.message(update, offset, now);
+            effects.messages.push((header.sender.clone(), message));
+        }
+    }
+
+    /// Two masters sharing a config epoch could both win claims over the same
+    /// slots, so the one with the smaller ID takes a new epoch.
+    fn handle_epoch_collision(&mut self, index: usize, effects: &mut Effects) {
+        let (myself, node) = (&self.nodes[0], &self.nodes[index]);
+        if myself.master.is_some()
+            || node.master.is_some()
+            || myself.config_epoch != node.config_epoch
+            || myself.id > node.id
+        {
+            return;
+        }
+        self.current_epoch += 1;
+        self.nodes[0].config_epoch = self.current_epoch;
+        effects.save = true;
+    }
+
+    fn process_gossip(&mut self, sender: usize, gossip: &[Gossip], now: u64) {
+        let reporter = self.nodes[sender].id.clone();
+        let sender_is_master = self.nodes[sender].master.is_none();
+        for entry in gossip {
+            match self.index(&entry.id) {
+                Some(0) => (),
+                // Only masters report failures.
+                Some(index) if sender_is_master => {
+                    let reports = &mut self.nodes[index].fail_reports;
+                    reports.retain(|(id, _)| *id != reporter);
+                    if entry.pfail || entry.fail {
+                        reports.push((reporter.clone(), now));
+                    }
+                }
+                Some(_) => (),
+                None => {
+                    if !entry.fail && !self.is_forgotten(&entry.id) {
+                        // Both ends already are part of the cluster, so a
+                        // ping is enough.
+                        self.start_handshake(&entry.host, entry.port, entry.bus_port, false, now);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Votes for a replica asking to replace its master, at most once per
+    /// epoch.
+    fn vote(
+        &mut self,
+        index: usize,
+        header: &Header,
+        force: bool,
+        offset: u64,
+        now: u64,
+        effects: &mut Effects,
+    ) {
+        if !self.is_voter(0)
+            || header.current_epoch < self.current_epoch
+            || self.last_vote_epoch == self.current_epoch
+        {
+            return;
+        }
+        let Some(master) = header.master.as_deref().and_then(|id| self.index(id)) else {
+            return;
+        };
+        if !self.nodes[master].fail && !force {
+            return;
+        }
+        // Replicas of a master failing over in turn would otherwise all get
+        // elected.
+        if now.saturating_sub(self.nodes[master].voted_time) < self.node_timeout * 2 {
+            return;
+        }
+        let stale = header
+            .slots
+            .iter()
+            .flat_map(|&(start, end)| start..=end)
+            .filter_map(|slot| self.owners[slot as usize])
+            .any(|owner| self.nodes[owner].config_epoch > header.config_epoch);
+        if stale {
+            return;
+        }
+
+        println!(
+            "voting for replica {} for epoch {}",
+            self.nodes[index].id, self.current_epoch
+        );
+        self.last_vote_epoch = self.current_epoch;
+        self.nodes[master].voted_time = now;
+        effects.save = true;
+        let message = self.message(Payload::AuthAck, offset, now);
+        effects.messages.push((header.sender.clone(), message));
+    }
+
+    /// Runs the periodic tasks: pings, failure detection and failovers. Meant
+    /// to run every 100 milliseconds.
+    pub fn cron(&mut self, offset: u64, now: u64) -> Effects {
+        let mut effects = Effects::default();
+        self.forgotten.retain(|(_, until)| *until > now);
+
+        let handshake_timeout = self.node_timeout.max(1000);
+        let expired = (1..self.nodes.len()).rev().filter(|&index| {
+            let node = &self.nodes[index];
+            node.handshake && now.saturating_sub(node.created) > handshake_timeout
+        });
+        for index in expired.collect::<Vec<_>>() {
+            self.remove_node(index);
+        }
+
+        self.send_pings(offset, now, &mut effects);
+        self.detect_failures(offset, now, &mut effects);
+        self.handle_failover(offset, now, &mut effects);
+
+        if self
+            .manual_failover
+            .as_ref()
+            .is_some_and(|failover| now > failover.deadline)
+        {
+            println!("manual failover timed out");
+            self.manual_failover = None;
+            self.election = None;
+        }
+        match self.paused_for.clone() {
+            Some((_, until)) if until <= now => {
+                self.paused_for = None;
+                effects.pause_until = Some(0);
+            }
+            // The replica failing over waits for the offset of its paused
+            // master.
+            Some((replica, _)) => {
+                let message = self.message(Payload::Ping(Vec::new()), offset, now);
+                effects.messages.push((replica, message));
+            }
+            None => (),
+        }
+
+        effects
+    }
+
+    fn send_pings(&mut self, offset: u64, now: u64, effects: &mut Effects) {
+        // Every second, pings still unanswered are sent again.
+        let every_second = now.saturating_sub(self.last_second_tasks) >= 1000;
+        if every_second {
+            self.last_second_tasks = now;
+        }
+        let mut targets = Vec::new();
+        for (index, node) in self.nodes.iter().enumerate().skip(1) {
+            let due = if node.ping_sent == 0 {
+                node.handshake || now.saturating_sub(node.pong_received) > self.node_timeout / 2
+            } else {
+                every_second
+            };
+            if due {
+                targets.push(index);
+            }
+        }
+        // Also ping the node with the oldest pong among a few random ones.
+        if every_second && self.nodes.len() > 1 {
+            let oldest = (0..5)
+                .map(|_| 1 + db::random() as usize % (self.nodes.len() - 1))
+                .filter(|&index| self.nodes[index].ping_sent == 0)
+                .min_by_key(|&index| self.nodes[index].pong_received);
+            targets.extend(oldest);
+            targets.sort_unstable();
+            targets.dedup();
+        }
+
+        for index in targets {
+            let gossip = self.gossip(&self.nodes[index].id);
+            let payload = if self.nodes[index].meet {
+                Payload::Meet(gossip)
+            } else {
+                Payload::Ping(gossip)
+            };
+            let message = self.message(payload, offset, now);
+            let node = &mut self.nodes[index];
+            if node.ping_sent == 0 {
+                node.ping_sent = now;
+            }
+            effects.messages.push((node.id.clone(), message));
+        }
+    }
+
+    /// Flags the nodes not answering pings as possibly failing, and as failed
+    /// once a majority of masters agree.
+    fn detect_failures(&mut self, offset: u64, now: u64, effects: &mut Effects) {
+        let node_timeout = self.node_timeout;
+        let voters = (0..self.nodes.len())
+            .filter(|&index| self.is_voter(index))
+            .map(|index| self.nodes[index].id.clone())
+            .collect::<HashSet<_>>();
+        let quorum = voters.len() / 2 + 1;
+        let my_report = self.is_voter(0) as usize;
+
+        let mut failed = Vec::new();
+        for node in self.nodes.iter_mut().skip(1) {
+            if node.handshake {
+                continue;
+            }
+            if !node.pfail
+                && !node.fail
+                && node.ping_sent != 0
+                && now.saturating_sub(node.ping_sent) > node_timeout
+            {
+                println!("node {} is possibly failing", node.id);
+                node.pfail = true;
+            }
+            node.fail_reports.retain(|(reporter, time)| {
+                now.saturating_sub(*time) <= node_timeout * 2 && voters.contains(reporter)
+            });
+            if node.pfail && node.fail_reports.len() + my_report >= quorum {
+                println!("node {} failed", node.id);
+                node.pfail = false;
+                node.fail = true;
+                node.fail_time = now;
+                failed.push(node.id.clone());
+            }
+        }
+
+        for id in failed {
+            effects.save = true;
+            self.broadcast(Payload::Fail(id), offset, now, effects);
+        }
+    }
+
+    /// Replaces the master of this replica once it failed, or once a manual
+    /// failover is ready, if a majority of masters vote for it.
+    fn handle_failover(&mut self, offset: u64, now: u64, effects: &mut Effects) {
+        let Some(master) = self.master_index(0).filter(|&master| master != 0) else {
+            self.election = None;
+            return;
+        };
+        let manual = self
+            .manual_failover
+            .as_ref()
+            .map(|failover| failover.ready(offset));
+        if !(self.nodes[master].fail || manual == Some(true)) || self.slot_count(master) == 0 {
+            self.election = None;
+            return;
+        }
+
+        if self.election.is_none() {
+            let start = if manual.is_some() {
+                now
+            } else {
+                let rank = self
+                    .replicas(&self.nodes[master])
+                    .filter(|replica| replica.offset > offset)
+                    .count() as u64;
+                now + 500 + db::random() % 500 + rank * 1000
+            };
+            self.election = Some(Election {
+                start,
+                epoch: 0,
+                votes: HashSet::new(),
+            });
+        }
+        let election = self.election.as_mut().unwrap();
+        if now < election.start {
+            return;
+        }
+        if now - election.start > (self.node_timeout * 2).max(2000) {
+            // Retried with a new epoch.
+            self.election = None;
+            return;
+        }
+        if election.epoch == 0 {
+            self.current_epoch += 1;
+            election.epoch = self.current_epoch;
+            println!(
+                "starting failover election for epoch {}",
+                self.current_epoch
+            );
+            effects.save = true;
+            let force = manual.is_some();
+            self.broadcast(Payload::AuthRequest { force }, offset, now, effects);
+            return;
+        }
+
+        let (epoch, votes) = (election.epoch, election.votes.len());
+        if votes >= self.quorum() {
+            println!("failover election won for epoch {}", epoch);
+            self.promote(epoch, offset, now, effects);
+        }
+    }
+
+    /// Turns this replica into a master serving the slots of its master.
+    fn promote(&mut self, epoch: u64, offset: u64, now: u64, effects: &mut Effects) {
+        if let Some(master) = self.master_index(0) {
+            for owner in self.owners.iter_mut() {
+                if *owner == Some(master) {
+                    *owner = Some(0);
+                }
+            }
+        }
+        let myself = &mut self.nodes[0];
+        myself.master = None;
+        myself.config_epoch = myself.config_epoch.max(epoch);
+        self.election = None;
+        self.manual_failover = None;
+        effects.replicate = Some(None);
+        effects.save = true;
+        // The other nodes learn about the new config right away.
+        self.broadcast(Payload::Pong(Vec::new()), offset, now, effects);
+    }
+
+    /// Makes this node a replica of a master, giving up its slots.
+    fn set_master(&mut self, master: usize, effects: &mut Effects) {
+        let node = &self.nodes[master];
+        println!("replicating master {}", node.id);
+        effects.replicate = Some(Some((node.host.clone(), node.port)));
+        self.nodes[0].master = Some(node.id.clone());
+        for owner in self.owners.iter_mut() {
+            if *owner == Some(0) {
+                *owner = None;
+            }
+        }
+        self.migrating_to.clear();
+        self.importing_from.clear();
+        self.election = None;
+        self.manual_failover = None;
+        if self.paused_for.take().is_some() {
+            effects.pause_until = Some(0);
+        }
+        effects.save = true;
+    }
+
+    fn start_handshake(&mut self, host: &str, port: u16, bus_port: u16, meet: bool, now: u64) {
+        if self
+            .nodes
+            .iter()
+            .any(|node| node.handshake && node.host == host && node.port == port)
+        {
+            return;
+        }
+        let mut node = Node::new(
+            replication::random_replid(),
+            host.to_owned(),
+            port,
+            bus_port,
+        );
+        node.handshake = true;
+        node.meet = meet;
+        node.created = now;
+        self.nodes.push(node);
+    }
+
+    fn broadcast(&self, payload: Payload, offset: u64, now: u64, effects: &mut Effects) {
+        let message = self.message(payload, offset, now);
+        for node in self.nodes.iter().skip(1).filter(|node| !node.handshake) {
+            effects.messages.push((node.id.clone(), message.clone()));
+        }
+    }
+
+    /// A message from this node, with the header describing it.
+    fn message(&self, payload: Payload, offset: u64, now: u64) -> Message {
+        let myself = &self.nodes[0];
+        // Replicas advertise the config of their master.
+        let master = self.master_index(0);
+        let header = Header {
+            sender: myself.id.clone(),
+            port: myself.port,
+            bus_port: myself.bus_port,
+            master: myself.master.clone(),
+            config_epoch: master.map_or(0, |master| self.nodes[master].config_epoch),
+            current_epoch: self.current_epoch,
+            offset,
+            slots: master.map_or_else(Vec::new, |master| self.slot_ranges(master)),
+            paused: self
+                .paused_for
+                .as_ref()
+                .is_some_and(|(_, until)| *until > now),
+        };
+
+        Message { header, payload }
+    }
+
+    /// Gossip about the failing nodes, and a few random others.
+    fn gossip(&self, receiver: &str) -> Vec<Gossip> {
+        let candidates = self
+            .nodes
+            .iter()
+            .skip(1)
+            .filter(|node| !node.handshake && node.id != receiver)
+            .collect::<Vec<_>>();
+        let mut picked = candidates
+            .iter()
+            .filter(|node| node.pfail || node.fail)
+            .copied()
+            .collect::<Vec<_>>();
+        for _ in 0..GOSSIP_COUNT.min(candidates.len()) {
+            let node = candidates[db::random() as usize % candidates.len()];
+            if !picked.iter().any(|picked| picked.id == node.id) {
+                picked.push(node);
+            }
+        }
+
+        picked
+            .into_iter()
+            .map(|node| Gossip {
+                id: node.id.clone(),
+                host: node.host.clone(),
+                port: node.port,
+                bus_port: node.bus_port,
+                pfail: node.pfail,
+                fail: node.fail,
+            })
+            .collect()
+    }
+
+    fn index(&self, id: &str) -> Option<usize> {
+        self.nodes.iter().position(|node| node.id == id)
+    }
+
+    /// The index of the master of a node, or of the node itself for masters.
+    fn master_index(&self, index: usize) -> Option<usize> {
+        match &self.nodes[index].master {
+            Some(id) => self.index(id),
+            None => Some(index),
+        }
+    }
+
+    fn remove_node(&mut self, index: usize) {
+        let node = self.nodes.remove(index);
+        for owner in self.owners.iter_mut() {
+            *owner = match *owner {
+                Some(i) if i == index => None,
+                Some(i) if i > index => Some(i - 1),
+                owner => owner,
+            };
+        }
+        for other in &mut self.nodes {
+            other.fail_reports.retain(|(id, _)| *id != node.id);
+        }
+        self.migrating_to.retain(|_, id| *id != node.id);
+        self.importing_from.retain(|_, id| *id != node.id);
+    }
+
+    fn is_forgotten(&self, id: &str) -> bool {
+        self.forgotten.iter().any(|(forgotten, _)| forgotten == id)
+    }
+
+    /// Whether a node is a master serving slots, which takes part in failure
+    /// detection and elections.
+    fn is_voter(&self, index: usize) -> bool {
+        self.nodes[index].master.is_none() && self.owners.contains(&Some(index))
+    }
+
+    /// The number of votes of a majority of masters serving slots.
+    fn quorum(&self) -> usize {
+        let voters = (0..self.nodes.len())
+            .filter(|&index| self.is_voter(index))
+            .count();
+        voters / 2 + 1
+    }
+
+    fn slot_count(&self, index: usize) -> usize {
+        self.owners
+            .iter()
+            .filter(|&&owner| owner == Some(index))
+            .count()
+    }
+
+    /// The ranges of consecutive slots served by a node, in order.
+    fn slot_ranges(&self, index: usize) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for slot in 0..SLOTS as u16 {
+            if self.owners[slot as usize] != Some(index) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+
+        ranges
+    }
+
+    pub fn slots_assigned(&self) -> usize {
+        self.owners.iter().filter(|owner| owner.is_some()).count()
+    }
+
+    /// Whether every slot is served by a master that did not fail.
+    pub fn is_ok(&self) -> bool {
+        self.owners
+            .iter()
+            .all(|owner| owner.is_some_and(|owner| !self.nodes[owner].fail))
+    }
+
+    /// The reply to CLUSTER SLOTS: each range of slots with the master
+    /// serving it, followed by its replicas.
+    pub fn slots_frame(&self) -> Frame {
+        let mut entries = Vec::new();
+        for (index, master) in self.nodes.iter().enumerate() {
+            for (start, end) in self.slot_ranges(index) {
+                let mut entry = vec![
+                    Frame::Integer(start as i64),
+                    Frame::Integer(end as i64),
+                    node_frame(master),
+                ];
+                entry.extend(self.replicas(master).map(node_frame));
+                entries.push((start, Frame::Array(entry)));
+            }
+        }
+        entries.sort_by_key(|(start, _)| *start);
+
+        Frame::Array(entries.into_iter().map(|(_, entry)| entry).collect())
+    }
+
+    /// The reply to CLUSTER SHARDS: each master with its slots and replicas.
+    pub fn shards_frame(&self) -> Frame {
+        let shards = self
+            .nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.master.is_none() && !node.handshake)
+            .map(|(index, master)| {
+                let slots = self
+                    .slot_ranges(index)
+                    .into_iter()
+                    .flat_map(|(start, end)| {
+                        [Frame::Integer(start as i64), Frame::Integer(end as i64)]
+                    })
+                    .collect();
+                let nodes = std::iter::once(master)
+                    .chain(self.replicas(master))
+                    .map(|node| {
+                        let role = if node.master.is_some() {
+                            "replica"
+                        } else {
+                            "master"
+                        };
+                        let health = if node.fail { "fail" } else { "online" };
+                        Frame::Array(vec![
+                            Frame::Bulk(Bytes::from_static(b"id")),
+                            Frame::Bulk(Bytes::from(node.id.clone())),
+                            Frame::Bulk(Bytes::from_static(b"port")),
+                            Frame::Integer(node.port as i64),
+                            Frame::Bulk(Bytes::from_static(b"ip")),
+                            Frame::Bulk(Bytes::from(node.host.clone())),
+                            Frame::Bulk(Bytes::from_static(b"endpoint")),
+                            Frame::Bulk(Bytes::from(node.host.clone())),
+                            Frame::Bulk(Bytes::from_static(b"role")),
+                            Frame::Bulk(Bytes::from_static(role.as_bytes())),
+                            Frame::Bulk(Bytes::from_static(b"replication-offset")),
+                            Frame::Integer(node.offset as i64),
+                            Frame::Bulk(Bytes::from_static(b"health")),
+                            Frame::Bulk(Bytes::from_static(health.as_bytes())),
+                        ])
+                    })
+                    .collect();
+                Frame::Array(vec![
+                    Frame::Bulk(Bytes::from_static(b"slots")),
+                    Frame::Array(slots),
+                    Frame::Bulk(Bytes::from_static(b"nodes")),
+                    Frame::Array(nodes),
+                ])
+            })
+            .collect();
+
+        Frame::Array(shards)
+    }
+
+    /// The reply to CLUSTER INFO.
+    pub fn info(&self) -> String {
+        let assigned = self.slots_assigned();
+        let count_slots = |failing: fn(&Node) -> bool| {
+            self.owners
+                .iter()
+                .flatten()
+                .filter(|&&owner| failing(&self.nodes[owner]))
+                .count()
+        };
+        let pfail = count_slots(|node| node.pfail && !node.fail);
+        let fail = count_slots(|node| node.fail);
+        let size = (0..self.nodes.len())
+            .filter(|&index| self.is_voter(index))
+            .count();
+        let my_epoch = self
+            .master_index(0)
+            .map_or(0, |master| self.nodes[master].config_epoch);
+
+        let mut info = String::new();
+        writeln!(info, "cluster_enabled:1").unwrap();
+        let state = if self.is_ok() { "ok" } else { "fail" };
+        writeln!(info, "cluster_state:{}", state).unwrap();
+        writeln!(info, "cluster_slots_assigned:{}", assigned).unwrap();
+        writeln!(info, "cluster_slots_ok:{}", assigned - pfail - fail).unwrap();
+        writeln!(info, "cluster_slots_pfail:{}", pfail).unwrap();
+        writeln!(info, "cluster_slots_fail:{}", fail).unwrap();
+        writeln!(info, "cluster_known_nodes:{}", self.nodes.len()).unwrap();
+        writeln!(info, "cluster_size:{}", size).unwrap();
+        writeln!(info, "cluster_current_epoch:{}", self.current_epoch).unwrap();
+        writeln!(info, "cluster_my_epoch:{}", my_epoch).unwrap();
+
+        info
+    }
+
+    fn replicas<'a>(&'a self, master: &'a Node) -> impl Iterator<Item = &'a Node> {
+        self.nodes
+            .iter()
+            .filter(move |node| node.master.as_ref() == Some(&master.id))
+    }
+}
+
+fn node_frame(node: &Node) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from(node.host.clone())),
+        Frame::Integer(node.port as i64),
+        Frame::Bulk(Bytes::from(node.id.clone())),
+    ])
+}
+
+fn check_unique(slots: &[u16]) -> anyhow::Result<()> {
+    let mut seen = HashSet::new();
+    match slots.iter().find(|&&slot| !seen.insert(slot)) {
+        Some(slot) => Err(anyhow!("Slot {} specified multiple times", slot)),
+        None => Ok(()),
+    }
+}
+
+/// Parses a node address in the `<host>:<port>@<bus port>` form, possibly
+/// followed by `,<hostname>`.
+fn parse_node_address(s: &str) -> Option<(String, u16, u16)> {
+    let s = s.split(',').next()?;
+    let (address, bus_port) = s.split_once('@')?;
+    let (host, port) = address.rsplit_once(':')?;
+
+    Some((host.to_owned(), port.parse().ok()?, bus_port.parse().ok()?))
+}
+
+/// Parses a slot in migration, in the `[<slot>->-<id>]` form for slots
+/// migrating to another node, or `[<slot>-<-<id>]` for imported ones.
+fn parse_migration(s: &str) -> Option<(u16, &str, &str)> {
+    let s = s.strip_prefix('[')?.strip_suffix(']')?;
+    let split = s.find(['-', '>', '<'])?;
+    let (slot, rest) = s.split_at(split);
+    let (direction, id) = rest.split_at(rest.len().min(3));
+    if direction != "->-" && direction != "-<-" {
+        return None;
+    }
+
+    Some((slot.parse().ok()?, direction, id))
+}
+
+/// Parses a slot, or a range of slots in the `<start>-<end>` form.
+pub fn parse_slot_range(s: &str) -> anyhow::Result<(u16, u16)> {
+    let parse = |slot: &str| {
+        slot.parse::<u16>()
+            .ok()
+            .filter(|&slot| (slot as usize) < SLOTS)
+            .ok_or_else(|| anyhow!("invalid slot: {}", s))
+    };
+    match s.split_once('-') {
+        Some((start, end)) => Ok((parse(start)?, parse(end)?)),
+        None => parse(s).map(|slot| (slot, slot)),
+    }
+}
+
+/// The slot of a key. Only the part between the first `{` and the next `}`
+/// is hashed, if not empty, so that related keys can share a slot.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let hashed = key
+        .iter()
+        .position(|&b| b == b'{')
+        .and_then(|start| {
+            let tag = &key[start + 1..];
+            let end = tag.iter().position(|&b| b == b'}')?;
+            Some(&tag[..end])
+        })
+        .filter(|tag| !tag.is_empty())
+        .unwrap_or(key);
+
+    crc16(hashed) % SLOTS as u16
+}
+
+/// CRC16 in its XMODEM variant, as used for hash slots.
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+const DEFAULT_NODE_TIMEOUT: u64 = 15000;
+// Number of random nodes gossiped about in each ping.
+const GOSSIP_COUNT: usize = 3;
+// Milliseconds during which gossip about a forgotten node is ignored.
+const FORGET_TTL: u64 = 60 * 1000;
+// Milliseconds a manual failover has to complete.
+const MANUAL_FAILOVER_TIMEOUT: u64 = 5000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: &str, port: u16) -> Node {
+        Node::new(id.to_owned(), "127.0.0.1".to_owned(), port, port + 10000)
+    }
+
+    #[test]
+    fn test_key_slot() {
+        assert_eq!(0x31c3, crc16(b"123456789"));
+        assert_eq!(12182, key_slot(b"foo"));
+        assert_eq!(key_slot(b"user1000"), key_slot(b"{user1000}.following"));
+        assert_eq!(
+            key_slot(b"{user1000}.followers"),
+            key_slot(b"{user1000}.following")
+        );
+        // Empty tags hash the whole key, and only the first tag counts.
+        assert_eq!(crc16(b"foo{}{bar}") % 16384, key_slot(b"foo{}{bar}"));
+        assert_eq!(key_slot(b"{bar"), key_slot(b"foo{{bar}}zap"));
+    }
+
+    #[test]
+    fn test_slots_and_config_roundtrip() {
+        let mut state = ClusterState::new(node("a", 7000));
+        state.add_slots(&(0..100).collect::<Vec<_>>()).unwrap();
+        state.add_slots(&[200]).unwrap();
+        assert!(state.add_slots(&[5]).is_err());
+        assert!(state.add_slots(&[300, 300]).is_err());
+        state.del_slots(&[50]).unwrap();
+        assert!(state.del_slots(&[50]).is_err());
+        state.current_epoch = 3;
+
+        let config = state.to_config();
+        assert_eq!(
+            "a 127.0.0.1:7000@17000 myself,master - 0 0 0 connected 0-49 51-99 200\n\
+             vars currentEpoch 3 lastVoteEpoch 0\n",
+            config
+        );
+
+        let other = "b 127.0.0.1:7001@17001 master - 0 0 1 connected 1000-1999\n\
+                     c 127.0.0.1:7002@17002 slave,fail b 0 0 1 disconnected\n\
+                     d 127.0.0.1:7003@17003 master,handshake - 0 0 0 connected\n";
+        let parsed = ClusterState::parse(&format!("{other}{config}")).unwrap();
+        assert_eq!("a", parsed.myself().id);
+        assert_eq!(3, parsed.current_epoch);
+        assert_eq!(Some("a"), parsed.owner(200).map(|n| n.id.as_str()));
+        assert_eq!(Some("b"), parsed.owner(1500).map(|n| n.id.as_str()));
+        assert_eq!(None, parsed.owner(50));
+        assert_eq!(Some("b"), parsed.nodes()[2].master.as_deref());
+        assert!(parsed.nodes()[2].fail);
+        assert_eq!(3, parsed.nodes().len());
+        assert_eq!(100 + 1000, parsed.slots_assigned());
+    }
+
+    /// Nodes talking over a simulated bus, where nodes can be taken down.
+    struct Cluster {
+        states: Vec<ClusterState>,
+        down: Vec<bool>,
+        now: u64,
+    }
+
+    impl Cluster {
+        fn new(count: usize) -> Self {
+            let states = (0..count)
+                .map(|i| {
+                    let id = ((b'a' + i as u8) as char).to_string();
+                    let mut state = ClusterState::new(node(&id, 7000 + i as u16));
+                    state.node_timeout = 1000;
+                    state
+                })
+                .collect();
+            Cluster {
+                states,
+                down: vec![false; count],
+                now: 0,
+            }
+        }
+
+        /// Runs the crons of the nodes for the given milliseconds, and
+        /// returns the roles they were asked to switch to.
+        fn run(&mut self, millis: u64) -> Vec<(usize, Option<(String, u16)>)> {
+            let mut switches = Vec::new();
+            for _ in 0..millis / 100 {
+                self.now += 100;
+                for index in 0..self.states.len() {
+                    if !self.down[index] {
+                        let effects = self.states[index].cron(0, self.now);
+                        self.deliver(index, effects, &mut switches);
+                    }
+                }
+            }
+            switches
+        }
+
+        /// Delivers the messages of `from`, and the ones they lead to.
+        fn deliver(
+            &mut self,
+            from: usize,
+            effects: Effects,
+            switches: &mut Vec<(usize, Option<(String, u16)>)>,
+        ) {
+            let mut pending = vec![(from, effects)];
+            while let Some((from, effects)) = pending.pop() {
+                if let Some(replicate) = effects.replicate {
+                    switches.push((from, replicate));
+                }
+                for (id, message) in effects.messages {
+                    // Handshake nodes only have a placeholder ID.
+                    let port = self.states[from].node(&id).map(|node| node.port);
+                    let Some(to) = self
+                        .states
+                        .iter()
+                        .position(|state| Some(state.myself().port) == port)
+                        .filter(|&to| !self.down[to])
+                    else {
+                        continue;
+                    };
+                    let (reply, effects) =
+                        self.states[to].handle(message, "127.0.0.1", None, 0, self.now);
+                    pending.push((to, effects));
+                    if let Some(reply) = reply {
+                        let (_, effects) =
+                            self.states[from].handle(reply, "127.0.0.1", Some(&id), 0, self.now);
+                        pending.push((from, effects));
+                    }
+                }
+            }
+        }
+
+        fn owner(&self, index: usize, slot: u16) -> Option<&str> {
+            self.states[index].owner(slot).map(|node| node.id.as_str())
+        }
+    }
+
+    /// Starts masters a, b and c serving a third of the slots each, and d
+    /// replicating a.
+    fn start_cluster() -> Cluster {
+        let mut cluster = Cluster::new(4);
+        let ranges = [0..5000, 5000..10000, 10000..16384];
+        for (state, range) in cluster.states.iter_mut().zip(ranges) {
+            state.add_slots(&range.collect::<Vec<_>>()).unwrap();
+        }
+        for port in 7001..7004 {
+            cluster.states[0].meet("127.0.0.1", port, port + 10000, 0);
+        }
+        cluster.run(3000);
+        for state in &cluster.states {
+            assert_eq!(4, state.nodes().len());
+            assert!(state.nodes().iter().all(|node| !node.handshake));
+            assert!(state.is_ok());
+        }
+
+        let effects = cluster.states[3].replicate("a").unwrap();
+        assert_eq!(
+            Some(Some(("127.0.0.1".to_owned(), 7000))),
+            effects.replicate
+        );
+        cluster.run(1000);
+        for state in &cluster.states {
+            let d = state.node("d").unwrap();
+            assert_eq!(Some("a"), d.master.as_deref());
+        }
+
+        cluster
+    }
+
+    #[test]
+    fn test_failover_of_failed_master() {
+        let mut cluster = start_cluster();
+        cluster.down[0] = true;
+        let switches = cluster.run(5000);
+        assert_eq!(vec![(3, None)], switches);
+        for index in 1..4 {
+            assert!(cluster.states[index].node("a").unwrap().fail);
+            assert_eq!(Some("d"), cluster.owner(index, 0));
+            assert_eq!(Some("d"), cluster.owner(index, 4999));
+            assert!(cluster.states[index].is_ok());
+        }
+        assert!(cluster.states[3].myself().master.is_none());
+
+        // a comes back, and learns that it has to replicate d.
+        cluster.down[0] = false;
+        let switches = cluster.run(3000);
+        assert_eq!(vec![(0, Some(("127.0.0.1".to_owned(), 7003)))], switches);
+        assert_eq!(Some("d"), cluster.states[0].myself().master.as_deref());
+        assert_eq!(Some("d"), cluster.owner(0, 0));
+        assert!(!cluster.states[1].node("a").unwrap().fail);
+    }
+
+    #[test]
+    fn test_manual_failover() {
+        let mut cluster = start_cluster();
+        assert!(cluster.states[0]
+            .failover(FailoverMode::Default, 0, cluster.now)
+            .is_err());
+        let effects = cluster.states[3]
+            .failover(FailoverMode::Default, 0, cluster.now)
+            .unwrap();
+        let mut switches = Vec::new();
+        cluster.deliver(3, effects, &mut switches);
+        switches.extend(cluster.run(1000));
+        assert_eq!(
+            vec![(3, None), (0, Some(("127.0.0.1".to_owned(), 7003)))],
+            switches
+        );
+        for index in 0..4 {
+            assert_eq!(Some("d"), cluster.owner(index, 0));
+        }
+        assert!(cluster.states[3].myself().config_epoch > cluster.states[1].myself().config_epoch);
+    }
+
+    #[test]
+    fn test_slot_migration() {
+        let mut cluster = start_cluster();
+        let now = cluster.now;
+        let (a, b) = (0, 1);
+        let importing = SetSlot::Importing("a".to_owned());
+        let migrating = SetSlot::Migrating("b".to_owned());
+        assert!(cluster.states[a]
+            .set_slot(0, importing.clone(), 0, 0, now)
+            .is_err());
+        assert!(cluster.states[b]
+            .set_slot(0, migrating.clone(), 0, 0, now)
+            .is_err());
+        assert!(cluster.states[3]
+            .set_slot(0, importing.clone(), 0, 0, now)
+            .is_err());
+        let unknown = SetSlot::Importing("unknown".to_owned());
+        assert!(cluster.states[b].set_slot(0, unknown, 0, 0, now).is_err());
+        cluster.states[b].set_slot(0, importing, 0, 0, now).unwrap();
+        cluster.states[a].set_slot(0, migrating, 0, 0, now).unwrap();
+        assert_eq!("b", cluster.states[a].migrating_to(0).unwrap().id);
+        assert_eq!("a", cluster.states[b].importing_from(0).unwrap().id);
+
+        // The migration state survives a restart.
+        let parsed = ClusterState::parse(&cluster.states[a].to_config()).unwrap();
+        assert_eq!("b", parsed.migrating_to(0).unwrap().id);
+        assert!(parsed.describe_nodes().contains(" 0-4999 [0->-b]\n"));
+        let parsed = ClusterState::parse(&cluster.states[b].to_config()).unwrap();
+        assert_eq!("a", parsed.importing_from(0).unwrap().id);
+
+        // Pings from a do not take the slot back while b imports it.
+        cluster.run(1000);
+        assert_eq!(Some("a"), cluster.owner(b, 0));
+
+        let node_b = SetSlot::Node("b".to_owned());
+        assert!(cluster.states[a]
+            .set_slot(0, node_b.clone(), 3, 0, now)
+            .is_err());
+        let effects = cluster.states[b]
+            .set_slot(0, node_b.clone(), 3, 0, cluster.now)
+            .unwrap();
+        cluster.deliver(b, effects, &mut Vec::new());
+        assert!(cluster.states[b].importing_from(0).is_none());
+        // a learns about the new owner of the slot from b.
+        assert_eq!(Some("b"), cluster.owner(a, 0));
+        cluster.states[a].set_slot(0, node_b, 0, 0, now).unwrap();
+        assert!(cluster.states[a].migrating_to(0).is_none());
+
+        cluster.run(1000);
+        for index in 0..4 {
+            assert_eq!(Some("b"), cluster.owner(index, 0));
+            assert_eq!(Some("a"), cluster.owner(index, 1));
+        }
+        // a keeps its other slots, and so its replica.
+        assert_eq!(Some("a"), cluster.states[3].myself().master.as_deref());
+    }
+
+    #[test]
+    fn test_forget_and_reset() {
+        let mut cluster = start_cluster();
+        let a = &mut cluster.states[0];
+        assert!(a.forget("a", 0).is_err());
+        assert!(a.forget("unknown", 0).is_err());
+        a.forget("b", 0).unwrap();
+        assert!(a.node("b").is_none());
+        assert_eq!(None, a.owner(5000));
+        // Gossip about b is ignored for a while.
+        cluster.run(2000);
+        assert!(cluster.states[0].node("b").is_none());
+        assert!(cluster.states[3].forget("a", 0).is_err());
+
+        let a = &mut cluster.states[0];
+        a.current_epoch = 5;
+        a.reset(false);
+        assert_eq!("a", a.myself().id);
+        assert_eq!(1, a.nodes().len());
+        assert_eq!(0, a.slots_assigned());
+        assert_eq!(5, a.current_epoch);
+        let effects = cluster.states[3].reset(true);
+        assert_eq!(Some(None), effects.replicate);
+        assert_ne!("d", cluster.states[3].myself().id);
+        assert_eq!(0, cluster.states[3].current_epoch);
+    }
+}
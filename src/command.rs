@@ -1,7 +1,10 @@
 use anyhow::anyhow;
 use bytes::Bytes;
 
-use crate::frame::Frame;
+use crate::{
+    cluster::{self, FailoverMode, SetSlot},
+    frame::Frame,
+};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Command {
@@ -11,14 +14,208 @@ pub enum Command {
     Set {
         key: String,
         value: Bytes,
+        /// Time to live in milliseconds.
         px: Option<u64>,
+        /// Unix time in milliseconds the key expires at.
+        pxat: Option<u64>,
     },
-    Info,
-    Replconf,
+    Info(Option<String>),
+    Replconf(Replconf),
     Psync {
         replication_id: String,
-        offset: i32,
+        offset: i64,
+    },
+    ConfigGet(Vec<String>),
+    Save,
+    Bgsave,
+    Lastsave,
+    Bgrewriteaof,
+    Wait {
+        numreplicas: u64,
+        timeout: u64,
+    },
+    /// Replicate the given master, or become a master with `None`.
+    Replicaof(Option<(String, u16)>),
+    Select(usize),
+    Move {
+        key: String,
+        db: usize,
+    },
+    Swapdb(usize, usize),
+    Flushdb,
+    Dbsize,
+    Copy {
+        source: String,
+        destination: String,
+        /// Database of the destination, the selected one if `None`.
+        db: Option<usize>,
+        replace: bool,
+    },
+    Del(Vec<String>),
+    Object(Object),
+    Cluster(Cluster),
+    /// Moves keys to another instance, through DUMP and RESTORE.
+    Migrate {
+        host: String,
+        port: u16,
+        keys: Vec<String>,
+        db: usize,
+        /// Timeout in milliseconds.
+        timeout: u64,
+        /// Keeps the keys on this instance.
+        copy: bool,
+        replace: bool,
+    },
+    /// Serializes the value of a key, in the format of RESTORE.
+    Dump(String),
+    Restore {
+        key: String,
+        /// Time to live in milliseconds, 0 for none.
+        ttl: u64,
+        /// A value serialized as by DUMP.
+        payload: Bytes,
+        replace: bool,
+        /// Whether `ttl` is a Unix time in milliseconds.
+        absttl: bool,
+        /// Seconds since the value was last accessed, under an LRU policy.
+        idletime: Option<u64>,
+        /// Access frequency of the value, under an LFU policy.
+        freq: Option<u8>,
+    },
+    /// Lets the next command access a slot being imported.
+    Asking,
+    Subscribe(Vec<String>),
+    /// Unsubscribes from the given channels, or from all of them if empty.
+    Unsubscribe(Vec<String>),
+    Psubscribe(Vec<String>),
+    /// Unsubscribes from the given patterns, or from all of them if empty.
+    Punsubscribe(Vec<String>),
+    Publish {
+        channel: String,
+        message: Bytes,
+    },
+    Sentinel(Sentinel),
+    /// Switches the connection to the given protocol version, 2 or 3.
+    Hello(Option<u8>),
+    Client(Client),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Object {
+    /// Access frequency of a key, under an LFU policy.
+    Freq(String),
+    /// Seconds since a key was last accessed.
+    Idletime(String),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Cluster {
+    Info,
+    Myid,
+    Nodes,
+    Slots,
+    Shards,
+    Keyslot(String),
+    Countkeysinslot(u16),
+    Getkeysinslot {
+        slot: u16,
+        count: usize,
+    },
+    /// Assigns slots to this node, from ADDSLOTS or ADDSLOTSRANGE.
+    Addslots(Vec<u16>),
+    /// Unassigns slots, from DELSLOTS or DELSLOTSRANGE.
+    Delslots(Vec<u16>),
+    Meet {
+        host: String,
+        port: u16,
+        /// Defaults to the client port plus 10000.
+        bus_port: Option<u16>,
+    },
+    Forget(String),
+    Replicate(String),
+    Failover(FailoverMode),
+    /// Forgets the other nodes, and with `hard` takes a new ID.
+    Reset {
+        hard: bool,
+    },
+    Setslot {
+        slot: u16,
+        action: SetSlot,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Sentinel {
+    Masters,
+    Master(String),
+    /// From REPLICAS or SLAVES.
+    Replicas(String),
+    Sentinels(String),
+    GetMasterAddrByName(String),
+    /// Asks whether the master at the given address is down, and to vote for
+    /// `run_id` as failover leader unless it is `*`.
+    IsMasterDownByAddr {
+        host: String,
+        port: u16,
+        epoch: u64,
+        run_id: String,
+    },
+    Monitor {
+        name: String,
+        host: String,
+        port: u16,
+        quorum: usize,
     },
+    Remove(String),
+    /// Changes options of a master, as name and value pairs.
+    Set {
+        name: String,
+        options: Vec<(String, String)>,
+    },
+    /// Resets the masters matching a glob-style pattern.
+    Reset(String),
+    Failover(String),
+    Ckquorum(String),
+    Myid,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Client {
+    Id,
+    /// Enables tracking of the keys the client reads with the given options,
+    /// or disables it with `None`.
+    Tracking(Option<TrackingOptions>),
+    /// Whether the keys of the next command are tracked, in OPTIN or OPTOUT
+    /// mode.
+    Caching(bool),
+    Getredir,
+}
+
+/// Options of CLIENT TRACKING ON.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct TrackingOptions {
+    /// ID of the client invalidations are sent to instead.
+    pub redirect: Option<u64>,
+    /// Sends invalidations of every key matching the prefixes, whether the
+    /// client read it or not.
+    pub bcast: bool,
+    /// Prefixes of the keys broadcast, every key if empty.
+    pub prefixes: Vec<String>,
+    /// Only tracks the keys of commands following CLIENT CACHING YES.
+    pub optin: bool,
+    /// Tracks the keys of every command but those following CLIENT CACHING
+    /// NO.
+    pub optout: bool,
+    /// Skips invalidations of the keys the client modified itself.
+    pub noloop: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Replconf {
+    ListeningPort(u16),
+    Capa(Vec<String>),
+    Ack(u64),
+    GetAck,
 }
 
 impl Command {
@@ -37,7 +234,7 @@ impl Command {
                     }
                 }
 
-                match &elements[0][..] {
+                match &elements[0].to_ascii_uppercase()[..] {
                     b"PING" => {
                         if elements.len() != 1 {
                             return Err(anyhow!("expected: PING (no arguments)"));
@@ -62,46 +259,1256 @@ impl Command {
                     }
                     b"SET" => {
                         if elements.len() < 3 {
-                            return Err(anyhow!("expected: SET <key> <value> [PX milliseconds] "));
+                            return Err(anyhow!(
+                                "expected: SET <key> <value> [EX seconds | PX milliseconds | EXAT unix-time-seconds | PXAT unix-time-milliseconds]"
+                            ));
                         }
                         let key = String::from_utf8(elements[1].to_vec())?;
                         let value = elements[2].clone();
 
                         let mut px = None;
-                        if let Some(px_pos) = elements.iter().position(|e| &e[..] == b"px") {
-                            if let Some(millis) = elements.get(px_pos + 1) {
-                                if let Some(millis) = atoi::atoi::<u64>(&millis) {
-                                    px = Some(millis);
+                        let mut pxat = None;
+                        let mut options = elements[3..].iter();
+                        while let Some(option) = options.next() {
+                            let option = option.to_ascii_lowercase();
+                            let time = options
+                                .next()
+                                .and_then(|t| atoi::atoi::<u64>(t))
+                                .ok_or(anyhow!("invalid expire time in SET"))?;
+                            match &option[..] {
+                                b"px" => px = Some(time),
+                                b"ex" => px = Some(time.saturating_mul(1000)),
+                                b"pxat" => pxat = Some(time),
+                                b"exat" => pxat = Some(time.saturating_mul(1000)),
+                                _ => {
+                                    return Err(anyhow!(
+                                        "unsupported SET option: {}",
+                                        option.escape_ascii()
+                                    ))
                                 }
                             }
                         }
+                        if px.is_some() && pxat.is_some() {
+                            return Err(anyhow!("syntax error"));
+                        }
 
-                        Ok(Command::Set { key, value, px })
+                        Ok(Command::Set {
+                            key,
+                            value,
+                            px,
+                            pxat,
+                        })
                     }
                     b"INFO" => {
                         if elements.len() > 2 {
                             return Err(anyhow!("expected: INFO [replication] "));
                         }
 
-                        Ok(Command::Info)
+                        let section = elements
+                            .get(1)
+                            .map(|s| String::from_utf8_lossy(s).to_lowercase());
+
+                        Ok(Command::Info(section))
+                    }
+                    b"REPLCONF" => {
+                        if elements.len() < 3 {
+                            return Err(anyhow!("expected: REPLCONF <option> <value> ..."));
+                        }
+                        let value = &elements[2];
+                        let replconf = match &elements[1].to_ascii_lowercase()[..] {
+                            b"listening-port" => Replconf::ListeningPort(
+                                atoi::atoi(value).ok_or(anyhow!("invalid listening port"))?,
+                            ),
+                            b"capa" => {
+                                let mut capabilities = Vec::new();
+                                for pair in elements[1..].chunks(2) {
+                                    if !pair[0].eq_ignore_ascii_case(b"capa") || pair.len() != 2 {
+                                        return Err(anyhow!(
+                                            "expected: REPLCONF capa <capability> ..."
+                                        ));
+                                    }
+                                    capabilities
+                                        .push(String::from_utf8_lossy(&pair[1]).to_lowercase());
+                                }
+                                Replconf::Capa(capabilities)
+                            }
+                            b"ack" => Replconf::Ack(
+                                atoi::atoi(value).ok_or(anyhow!("invalid replication offset"))?,
+                            ),
+                            b"getack" => Replconf::GetAck,
+                            _ => {
+                                return Err(anyhow!(
+                                    "unrecognized REPLCONF option: {}",
+                                    elements[1].escape_ascii()
+                                ))
+                            }
+                        };
+
+                        Ok(Command::Replconf(replconf))
                     }
-                    b"REPLCONF" => Ok(Command::Replconf),
                     b"PSYNC" => {
                         if elements.len() != 3 {
                             return Err(anyhow!("expected: PSYNC <replication_id> <offset> "));
                         }
 
+                        let replication_id = String::from_utf8(elements[1].to_vec())?;
+                        let offset = std::str::from_utf8(&elements[2])
+                            .ok()
+                            .and_then(|o| o.parse().ok())
+                            .ok_or(anyhow!("invalid replication offset"))?;
+
                         Ok(Command::Psync {
-                            replication_id: "?".to_owned(),
-                            offset: -1,
+                            replication_id,
+                            offset,
+                        })
+                    }
+                    b"CONFIG" => {
+                        if elements.len() < 3 || !elements[1].eq_ignore_ascii_case(b"GET") {
+                            return Err(anyhow!(
+                                "expected: CONFIG GET <parameter> [parameter ...]"
+                            ));
+                        }
+                        let parameters = elements[2..]
+                            .iter()
+                            .map(|p| String::from_utf8_lossy(p).to_lowercase())
+                            .collect();
+
+                        Ok(Command::ConfigGet(parameters))
+                    }
+                    b"SAVE" => {
+                        if elements.len() != 1 {
+                            return Err(anyhow!("expected: SAVE (no arguments)"));
+                        }
+
+                        Ok(Command::Save)
+                    }
+                    b"BGSAVE" => {
+                        if elements.len() > 2 {
+                            return Err(anyhow!("expected: BGSAVE [SCHEDULE]"));
+                        }
+
+                        Ok(Command::Bgsave)
+                    }
+                    b"LASTSAVE" => {
+                        if elements.len() != 1 {
+                            return Err(anyhow!("expected: LASTSAVE (no arguments)"));
+                        }
+
+                        Ok(Command::Lastsave)
+                    }
+                    b"BGREWRITEAOF" => {
+                        if elements.len() != 1 {
+                            return Err(anyhow!("expected: BGREWRITEAOF (no arguments)"));
+                        }
+
+                        Ok(Command::Bgrewriteaof)
+                    }
+                    b"WAIT" => {
+                        if elements.len() != 3 {
+                            return Err(anyhow!("expected: WAIT <numreplicas> <timeout>"));
+                        }
+                        let numreplicas =
+                            atoi::atoi(&elements[1]).ok_or(anyhow!("invalid numreplicas"))?;
+                        let timeout = atoi::atoi(&elements[2]).ok_or(anyhow!("invalid timeout"))?;
+
+                        Ok(Command::Wait {
+                            numreplicas,
+                            timeout,
                         })
                     }
+                    b"REPLICAOF" | b"SLAVEOF" => {
+                        if elements.len() != 3 {
+                            return Err(anyhow!("expected: REPLICAOF <host> <port> | NO ONE"));
+                        }
+                        if elements[1].eq_ignore_ascii_case(b"NO")
+                            && elements[2].eq_ignore_ascii_case(b"ONE")
+                        {
+                            return Ok(Command::Replicaof(None));
+                        }
+                        let host = String::from_utf8(elements[1].to_vec())?;
+                        let port =
+                            atoi::atoi(&elements[2]).ok_or(anyhow!("invalid master port"))?;
+
+                        Ok(Command::Replicaof(Some((host, port))))
+                    }
+                    b"SELECT" => {
+                        if elements.len() != 2 {
+                            return Err(anyhow!("expected: SELECT <index>"));
+                        }
+                        let index = atoi::atoi(&elements[1]).ok_or(anyhow!("invalid DB index"))?;
+
+                        Ok(Command::Select(index))
+                    }
+                    b"MOVE" => {
+                        if elements.len() != 3 {
+                            return Err(anyhow!("expected: MOVE <key> <db>"));
+                        }
+                        let key = String::from_utf8(elements[1].to_vec())?;
+                        let db = atoi::atoi(&elements[2]).ok_or(anyhow!("invalid DB index"))?;
+
+                        Ok(Command::Move { key, db })
+                    }
+                    b"SWAPDB" => {
+                        if elements.len() != 3 {
+                            return Err(anyhow!("expected: SWAPDB <index1> <index2>"));
+                        }
+                        let index1 =
+                            atoi::atoi(&elements[1]).ok_or(anyhow!("invalid first DB index"))?;
+                        let index2 =
+                            atoi::atoi(&elements[2]).ok_or(anyhow!("invalid second DB index"))?;
+
+                        Ok(Command::Swapdb(index1, index2))
+                    }
+                    b"FLUSHDB" => {
+                        let valid = match elements.len() {
+                            1 => true,
+                            2 => {
+                                elements[1].eq_ignore_ascii_case(b"ASYNC")
+                                    || elements[1].eq_ignore_ascii_case(b"SYNC")
+                            }
+                            _ => false,
+                        };
+                        if !valid {
+                            return Err(anyhow!("expected: FLUSHDB [ASYNC | SYNC]"));
+                        }
+
+                        Ok(Command::Flushdb)
+                    }
+                    b"DBSIZE" => {
+                        if elements.len() != 1 {
+                            return Err(anyhow!("expected: DBSIZE (no arguments)"));
+                        }
+
+                        Ok(Command::Dbsize)
+                    }
+                    b"COPY" => {
+                        if elements.len() < 3 {
+                            return Err(anyhow!(
+                                "expected: COPY <source> <destination> [DB destination-db] [REPLACE]"
+                            ));
+                        }
+                        let source = String::from_utf8(elements[1].to_vec())?;
+                        let destination = String::from_utf8(elements[2].to_vec())?;
+
+                        let mut db = None;
+                        let mut replace = false;
+                        let mut options = elements[3..].iter();
+                        while let Some(option) = options.next() {
+                            match &option.to_ascii_lowercase()[..] {
+                                b"db" => {
+                                    db = Some(
+                                        options
+                                            .next()
+                                            .and_then(|d| atoi::atoi(d))
+                                            .ok_or(anyhow!("invalid DB index"))?,
+                                    )
+                                }
+                                b"replace" => replace = true,
+                                _ => return Err(anyhow!("syntax error")),
+                            }
+                        }
+
+                        Ok(Command::Copy {
+                            source,
+                            destination,
+                            db,
+                            replace,
+                        })
+                    }
+                    b"DEL" => {
+                        if elements.len() < 2 {
+                            return Err(anyhow!("expected: DEL <key> [key ...]"));
+                        }
+                        let keys = elements[1..]
+                            .iter()
+                            .map(|key| String::from_utf8(key.to_vec()))
+                            .collect::<Result<_, _>>()?;
+
+                        Ok(Command::Del(keys))
+                    }
+                    b"OBJECT" => {
+                        if elements.len() != 3 {
+                            return Err(anyhow!("expected: OBJECT <FREQ | IDLETIME> <key>"));
+                        }
+                        let key = String::from_utf8(elements[2].to_vec())?;
+                        let object = match &elements[1].to_ascii_lowercase()[..] {
+                            b"freq" => Object::Freq(key),
+                            b"idletime" => Object::Idletime(key),
+                            _ => {
+                                return Err(anyhow!(
+                                    "unsupported OBJECT subcommand: {}",
+                                    elements[1].escape_ascii()
+                                ))
+                            }
+                        };
+
+                        Ok(Command::Object(object))
+                    }
+                    b"CLUSTER" => {
+                        if elements.len() < 2 {
+                            return Err(anyhow!("expected: CLUSTER <subcommand> [arg ...]"));
+                        }
+
+                        Ok(Command::Cluster(parse_cluster(&elements[1..])?))
+                    }
+                    b"MIGRATE" => {
+                        let usage =
+                            "expected: MIGRATE <host> <port> <key | \"\"> <destination-db> \
+                                     <timeout> [COPY] [REPLACE] [KEYS key [key ...]]";
+                        if elements.len() < 6 {
+                            return Err(anyhow!(usage));
+                        }
+                        let host = String::from_utf8(elements[1].to_vec())?;
+                        let port = atoi::atoi(&elements[2]).ok_or(anyhow!("invalid port"))?;
+                        let db = atoi::atoi(&elements[4]).ok_or(anyhow!("invalid DB index"))?;
+                        let timeout = atoi::atoi(&elements[5]).ok_or(anyhow!("invalid timeout"))?;
+
+                        let mut copy = false;
+                        let mut replace = false;
+                        let mut keys = None;
+                        let mut options = elements[6..].iter();
+                        while let Some(option) = options.next() {
+                            match &option.to_ascii_lowercase()[..] {
+                                b"copy" => copy = true,
+                                b"replace" => replace = true,
+                                b"keys" => {
+                                    if !elements[3].is_empty() {
+                                        return Err(anyhow!(
+                                            "When using MIGRATE KEYS option, the key argument \
+                                             must be set to the empty string"
+                                        ));
+                                    }
+                                    keys = Some(
+                                        options
+                                            .by_ref()
+                                            .map(|key| String::from_utf8(key.to_vec()))
+                                            .collect::<Result<Vec<_>, _>>()?,
+                                    );
+                                }
+                                _ => return Err(anyhow!("syntax error")),
+                            }
+                        }
+                        let keys = match keys {
+                            Some(keys) => keys,
+                            None => vec![String::from_utf8(elements[3].to_vec())?],
+                        };
+
+                        Ok(Command::Migrate {
+                            host,
+                            port,
+                            keys,
+                            db,
+                            timeout,
+                            copy,
+                            replace,
+                        })
+                    }
+                    b"DUMP" => {
+                        if elements.len() != 2 {
+                            return Err(anyhow!("expected: DUMP <key>"));
+                        }
+                        let key = String::from_utf8(elements[1].to_vec())?;
+
+                        Ok(Command::Dump(key))
+                    }
+                    b"RESTORE" => {
+                        if elements.len() < 4 {
+                            return Err(anyhow!(
+                                "expected: RESTORE <key> <ttl> <serialized-value> [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ frequency]"
+                            ));
+                        }
+                        let key = String::from_utf8(elements[1].to_vec())?;
+                        let ttl = atoi::atoi::<i64>(&elements[2])
+                            .filter(|&ttl| ttl >= 0)
+                            .ok_or(anyhow!("Invalid TTL value, must be >= 0"))?;
+
+                        let mut replace = false;
+                        let mut absttl = false;
+                        let mut idletime = None;
+                        let mut freq = None;
+                        let mut options = elements[4..].iter();
+                        while let Some(option) = options.next() {
+                            match &option.to_ascii_lowercase()[..] {
+                                b"replace" => replace = true,
+                                b"absttl" => absttl = true,
+                                // IDLETIME and FREQ exclude each other.
+                                b"idletime" if freq.is_none() => {
+                                    let seconds = options.next().ok_or(anyhow!("syntax error"))?;
+                                    let seconds = atoi::atoi::<i64>(seconds).ok_or(anyhow!(
+                                        "value is not an integer or out of range"
+                                    ))?;
+                                    if seconds < 0 {
+                                        return Err(anyhow!(
+                                            "Invalid IDLETIME value, must be >= 0"
+                                        ));
+                                    }
+                                    idletime = Some(seconds as u64);
+                                }
+                                b"freq" if idletime.is_none() => {
+                                    let frequency =
+                                        options.next().ok_or(anyhow!("syntax error"))?;
+                                    let frequency = atoi::atoi::<i64>(frequency).ok_or(anyhow!(
+                                        "value is not an integer or out of range"
+                                    ))?;
+                                    let frequency = u8::try_from(frequency).map_err(|_| {
+                                        anyhow!("Invalid FREQ value, must be >= 0 and <= 255")
+                                    })?;
+                                    freq = Some(frequency);
+                                }
+                                _ => return Err(anyhow!("syntax error")),
+                            }
+                        }
+
+                        Ok(Command::Restore {
+                            key,
+                            ttl: ttl as u64,
+                            payload: elements[3].clone(),
+                            replace,
+                            absttl,
+                            idletime,
+                            freq,
+                        })
+                    }
+                    b"ASKING" => {
+                        if elements.len() != 1 {
+                            return Err(anyhow!("expected: ASKING (no arguments)"));
+                        }
+
+                        Ok(Command::Asking)
+                    }
+                    b"SUBSCRIBE" | b"PSUBSCRIBE" => {
+                        if elements.len() < 2 {
+                            return Err(anyhow!(
+                                "expected: {} <channel> [channel ...]",
+                                elements[0].to_ascii_uppercase().escape_ascii()
+                            ));
+                        }
+                        let channels = strings(&elements[1..])?;
+                        if elements[0].eq_ignore_ascii_case(b"SUBSCRIBE") {
+                            Ok(Command::Subscribe(channels))
+                        } else {
+                            Ok(Command::Psubscribe(channels))
+                        }
+                    }
+                    b"UNSUBSCRIBE" => Ok(Command::Unsubscribe(strings(&elements[1..])?)),
+                    b"PUNSUBSCRIBE" => Ok(Command::Punsubscribe(strings(&elements[1..])?)),
+                    b"PUBLISH" => {
+                        if elements.len() != 3 {
+                            return Err(anyhow!("expected: PUBLISH <channel> <message>"));
+                        }
+
+                        Ok(Command::Publish {
+                            channel: String::from_utf8(elements[1].to_vec())?,
+                            message: elements[2].clone(),
+                        })
+                    }
+                    b"SENTINEL" => {
+                        if elements.len() < 2 {
+                            return Err(anyhow!("expected: SENTINEL <subcommand> [arg ...]"));
+                        }
+
+                        Ok(Command::Sentinel(parse_sentinel(&elements[1..])?))
+                    }
+                    b"HELLO" => {
+                        if elements.len() > 2 {
+                            return Err(anyhow!("expected: HELLO [protover]"));
+                        }
+                        let protover = match elements.get(1) {
+                            Some(protover) => Some(atoi::atoi(protover).ok_or(anyhow!(
+                                "Protocol version is not an integer or out of range"
+                            ))?),
+                            None => None,
+                        };
+
+                        Ok(Command::Hello(protover))
+                    }
+                    b"CLIENT" => {
+                        if elements.len() < 2 {
+                            return Err(anyhow!("expected: CLIENT <subcommand> [arg ...]"));
+                        }
+
+                        Ok(Command::Client(parse_client(&elements[1..])?))
+                    }
                     _ => Err(anyhow!("unknown command: {}", elements[0].escape_ascii())),
                 }
             }
             _ => Err(anyhow!("invalid frame for command: {:?}", frame)),
         }
     }
+
+    /// Whether the command modifies the dataset, and so has to be propagated
+    /// to replicas.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Move { .. }
+                | Command::Swapdb(..)
+                | Command::Flushdb
+                | Command::Copy { .. }
+                | Command::Del(_)
+                | Command::Migrate { .. }
+                | Command::Restore { .. }
+        )
+    }
+
+    /// Whether the command reads the dataset.
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            Command::Get(_) | Command::Dbsize | Command::Object(_) | Command::Dump(_)
+        )
+    }
+
+    /// Whether the command may use more memory, and so is refused when the
+    /// memory limit is reached.
+    pub fn is_denyoom(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. } | Command::Copy { .. } | Command::Restore { .. }
+        )
+    }
+
+    /// The keys the command accesses.
+    pub fn keys(&self) -> Vec<&str> {
+        match self {
+            Command::Get(key)
+            | Command::Set { key, .. }
+            | Command::Move { key, .. }
+            | Command::Dump(key)
+            | Command::Restore { key, .. }
+            | Command::Object(Object::Freq(key) | Object::Idletime(key)) => vec![key],
+            Command::Copy {
+                source,
+                destination,
+                ..
+            } => vec![source, destination],
+            Command::Del(keys) => keys.iter().map(String::as_str).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// The name of the command, in lowercase.
+    pub fn name(&self) -> String {
+        let Frame::Array(frames) = self.to_frame() else {
+            unreachable!("commands are arrays");
+        };
+        match frames.first() {
+            Some(Frame::Bulk(name)) => String::from_utf8_lossy(name).to_lowercase(),
+            _ => unreachable!("commands start with their name"),
+        }
+    }
+
+    pub fn to_frame(&self) -> Frame {
+        let mut elements = vec![];
+        match self {
+            Command::Ping => elements.push(Bytes::from_static(b"PING")),
+            Command::Echo(message) => {
+                elements.extend([Bytes::from_static(b"ECHO"), message.clone()]);
+            }
+            Command::Get(key) => {
+                elements.extend([Bytes::from_static(b"GET"), Bytes::from(key.clone())]);
+            }
+            Command::Set {
+                key,
+                value,
+                px,
+                pxat,
+            } => {
+                elements.extend([
+                    Bytes::from_static(b"SET"),
+                    Bytes::from(key.clone()),
+                    value.clone(),
+                ]);
+                if let Some(millis) = px {
+                    elements.extend([Bytes::from_static(b"px"), Bytes::from(millis.to_string())]);
+                }
+                if let Some(at) = pxat {
+                    elements.extend([Bytes::from_static(b"pxat"), Bytes::from(at.to_string())]);
+                }
+            }
+            Command::Info(section) => {
+                elements.push(Bytes::from_static(b"INFO"));
+                elements.extend(section.clone().map(Bytes::from));
+            }
+            Command::Replconf(replconf) => {
+                elements.push(Bytes::from_static(b"REPLCONF"));
+                match replconf {
+                    Replconf::ListeningPort(port) => elements.extend([
+                        Bytes::from_static(b"listening-port"),
+                        Bytes::from(port.to_string()),
+                    ]),
+                    Replconf::Capa(capabilities) => {
+                        for capability in capabilities {
+                            elements.extend([
+                                Bytes::from_static(b"capa"),
+                                Bytes::from(capability.clone()),
+                            ]);
+                        }
+                    }
+                    Replconf::Ack(offset) => elements
+                        .extend([Bytes::from_static(b"ACK"), Bytes::from(offset.to_string())]),
+                    Replconf::GetAck => {
+                        elements.extend([Bytes::from_static(b"GETACK"), Bytes::from_static(b"*")])
+                    }
+                }
+            }
+            Command::Psync {
+                replication_id,
+                offset,
+            } => {
+                elements.extend([
+                    Bytes::from_static(b"PSYNC"),
+                    Bytes::from(replication_id.clone()),
+                    Bytes::from(offset.to_string()),
+                ]);
+            }
+            Command::ConfigGet(parameters) => {
+                elements.extend([Bytes::from_static(b"CONFIG"), Bytes::from_static(b"GET")]);
+                elements.extend(parameters.iter().cloned().map(Bytes::from));
+            }
+            Command::Save => elements.push(Bytes::from_static(b"SAVE")),
+            Command::Bgsave => elements.push(Bytes::from_static(b"BGSAVE")),
+            Command::Lastsave => elements.push(Bytes::from_static(b"LASTSAVE")),
+            Command::Bgrewriteaof => elements.push(Bytes::from_static(b"BGREWRITEAOF")),
+            Command::Wait {
+                numreplicas,
+                timeout,
+            } => elements.extend([
+                Bytes::from_static(b"WAIT"),
+                Bytes::from(numreplicas.to_string()),
+                Bytes::from(timeout.to_string()),
+            ]),
+            Command::Replicaof(master) => {
+                elements.push(Bytes::from_static(b"REPLICAOF"));
+                match master {
+                    Some((host, port)) => {
+                        elements.extend([Bytes::from(host.clone()), Bytes::from(port.to_string())])
+                    }
+                    None => {
+                        elements.extend([Bytes::from_static(b"NO"), Bytes::from_static(b"ONE")])
+                    }
+                }
+            }
+            Command::Select(index) => elements.extend([
+                Bytes::from_static(b"SELECT"),
+                Bytes::from(index.to_string()),
+            ]),
+            Command::Move { key, db } => elements.extend([
+                Bytes::from_static(b"MOVE"),
+                Bytes::from(key.clone()),
+                Bytes::from(db.to_string()),
+            ]),
+            Command::Swapdb(index1, index2) => elements.extend([
+                Bytes::from_static(b"SWAPDB"),
+                Bytes::from(index1.to_string()),
+                Bytes::from(index2.to_string()),
+            ]),
+            Command::Flushdb => elements.push(Bytes::from_static(b"FLUSHDB")),
+            Command::Dbsize => elements.push(Bytes::from_static(b"DBSIZE")),
+            Command::Copy {
+                source,
+                destination,
+                db,
+                replace,
+            } => {
+                elements.extend([
+                    Bytes::from_static(b"COPY"),
+                    Bytes::from(source.clone()),
+                    Bytes::from(destination.clone()),
+                ]);
+                if let Some(db) = db {
+                    elements.extend([Bytes::from_static(b"DB"), Bytes::from(db.to_string())]);
+                }
+                if *replace {
+                    elements.push(Bytes::from_static(b"REPLACE"));
+                }
+            }
+            Command::Del(keys) => {
+                elements.push(Bytes::from_static(b"DEL"));
+                elements.extend(keys.iter().cloned().map(Bytes::from));
+            }
+            Command::Cluster(cluster) => {
+                elements.push(Bytes::from_static(b"CLUSTER"));
+                match cluster {
+                    Cluster::Info => elements.push(Bytes::from_static(b"INFO")),
+                    Cluster::Myid => elements.push(Bytes::from_static(b"MYID")),
+                    Cluster::Nodes => elements.push(Bytes::from_static(b"NODES")),
+                    Cluster::Slots => elements.push(Bytes::from_static(b"SLOTS")),
+                    Cluster::Shards => elements.push(Bytes::from_static(b"SHARDS")),
+                    Cluster::Keyslot(key) => {
+                        elements.extend([Bytes::from_static(b"KEYSLOT"), Bytes::from(key.clone())])
+                    }
+                    Cluster::Countkeysinslot(slot) => elements.extend([
+                        Bytes::from_static(b"COUNTKEYSINSLOT"),
+                        Bytes::from(slot.to_string()),
+                    ]),
+                    Cluster::Getkeysinslot { slot, count } => elements.extend([
+                        Bytes::from_static(b"GETKEYSINSLOT"),
+                        Bytes::from(slot.to_string()),
+                        Bytes::from(count.to_string()),
+                    ]),
+                    Cluster::Addslots(list) => {
+                        elements.push(Bytes::from_static(b"ADDSLOTS"));
+                        elements.extend(list.iter().map(|slot| Bytes::from(slot.to_string())));
+                    }
+                    Cluster::Delslots(list) => {
+                        elements.push(Bytes::from_static(b"DELSLOTS"));
+                        elements.extend(list.iter().map(|slot| Bytes::from(slot.to_string())));
+                    }
+                    Cluster::Meet {
+                        host,
+                        port,
+                        bus_port,
+                    } => {
+                        elements.extend([
+                            Bytes::from_static(b"MEET"),
+                            Bytes::from(host.clone()),
+                            Bytes::from(port.to_string()),
+                        ]);
+                        elements.extend(bus_port.map(|port| Bytes::from(port.to_string())));
+                    }
+                    Cluster::Forget(id) => {
+                        elements.extend([Bytes::from_static(b"FORGET"), Bytes::from(id.clone())])
+                    }
+                    Cluster::Replicate(id) => {
+                        elements.extend([Bytes::from_static(b"REPLICATE"), Bytes::from(id.clone())])
+                    }
+                    Cluster::Failover(mode) => {
+                        elements.push(Bytes::from_static(b"FAILOVER"));
+                        match mode {
+                            FailoverMode::Default => (),
+                            FailoverMode::Force => elements.push(Bytes::from_static(b"FORCE")),
+                            FailoverMode::Takeover => {
+                                elements.push(Bytes::from_static(b"TAKEOVER"))
+                            }
+                        }
+                    }
+                    Cluster::Reset { hard } => elements.extend([
+                        Bytes::from_static(b"RESET"),
+                        Bytes::from_static(if *hard { b"HARD" } else { b"SOFT" }),
+                    ]),
+                    Cluster::Setslot { slot, action } => {
+                        elements.extend([
+                            Bytes::from_static(b"SETSLOT"),
+                            Bytes::from(slot.to_string()),
+                        ]);
+                        match action {
+                            SetSlot::Importing(id) => elements.extend([
+                                Bytes::from_static(b"IMPORTING"),
+                                Bytes::from(id.clone()),
+                            ]),
+                            SetSlot::Migrating(id) => elements.extend([
+                                Bytes::from_static(b"MIGRATING"),
+                                Bytes::from(id.clone()),
+                            ]),
+                            SetSlot::Node(id) => elements
+                                .extend([Bytes::from_static(b"NODE"), Bytes::from(id.clone())]),
+                            SetSlot::Stable => elements.push(Bytes::from_static(b"STABLE")),
+                        }
+                    }
+                }
+            }
+            Command::Object(object) => {
+                elements.push(Bytes::from_static(b"OBJECT"));
+                match object {
+                    Object::Freq(key) => {
+                        elements.extend([Bytes::from_static(b"FREQ"), Bytes::from(key.clone())])
+                    }
+                    Object::Idletime(key) => {
+                        elements.extend([Bytes::from_static(b"IDLETIME"), Bytes::from(key.clone())])
+                    }
+                }
+            }
+            Command::Migrate {
+                host,
+                port,
+                keys,
+                db,
+                timeout,
+                copy,
+                replace,
+            } => {
+                elements.extend([
+                    Bytes::from_static(b"MIGRATE"),
+                    Bytes::from(host.clone()),
+                    Bytes::from(port.to_string()),
+                    Bytes::new(),
+                    Bytes::from(db.to_string()),
+                    Bytes::from(timeout.to_string()),
+                ]);
+                if *copy {
+                    elements.push(Bytes::from_static(b"COPY"));
+                }
+                if *replace {
+                    elements.push(Bytes::from_static(b"REPLACE"));
+                }
+                elements.push(Bytes::from_static(b"KEYS"));
+                elements.extend(keys.iter().cloned().map(Bytes::from));
+            }
+            Command::Dump(key) => {
+                elements.extend([Bytes::from_static(b"DUMP"), Bytes::from(key.clone())]);
+            }
+            Command::Restore {
+                key,
+                ttl,
+                payload,
+                replace,
+                absttl,
+                idletime,
+                freq,
+            } => {
+                elements.extend([
+                    Bytes::from_static(b"RESTORE"),
+                    Bytes::from(key.clone()),
+                    Bytes::from(ttl.to_string()),
+                    payload.clone(),
+                ]);
+                if *replace {
+                    elements.push(Bytes::from_static(b"REPLACE"));
+                }
+                if *absttl {
+                    elements.push(Bytes::from_static(b"ABSTTL"));
+                }
+                if let Some(seconds) = idletime {
+                    elements.extend([
+                        Bytes::from_static(b"IDLETIME"),
+                        Bytes::from(seconds.to_string()),
+                    ]);
+                }
+                if let Some(frequency) = freq {
+                    elements.extend([
+                        Bytes::from_static(b"FREQ"),
+                        Bytes::from(frequency.to_string()),
+                    ]);
+                }
+            }
+            Command::Asking => elements.push(Bytes::from_static(b"ASKING")),
+            Command::Subscribe(channels)
+            | Command::Unsubscribe(channels)
+            | Command::Psubscribe(channels)
+            | Command::Punsubscribe(channels) => {
+                elements.push(Bytes::from_static(match self {
+                    Command::Subscribe(_) => b"SUBSCRIBE",
+                    Command::Unsubscribe(_) => b"UNSUBSCRIBE",
+                    Command::Psubscribe(_) => b"PSUBSCRIBE",
+                    _ => b"PUNSUBSCRIBE",
+                }));
+                elements.extend(channels.iter().cloned().map(Bytes::from));
+            }
+            Command::Publish { channel, message } => elements.extend([
+                Bytes::from_static(b"PUBLISH"),
+                Bytes::from(channel.clone()),
+                message.clone(),
+            ]),
+            Command::Sentinel(sentinel) => {
+                elements.push(Bytes::from_static(b"SENTINEL"));
+                match sentinel {
+                    Sentinel::Masters => elements.push(Bytes::from_static(b"MASTERS")),
+                    Sentinel::Master(name) => {
+                        elements.extend([Bytes::from_static(b"MASTER"), Bytes::from(name.clone())])
+                    }
+                    Sentinel::Replicas(name) => elements
+                        .extend([Bytes::from_static(b"REPLICAS"), Bytes::from(name.clone())]),
+                    Sentinel::Sentinels(name) => elements
+                        .extend([Bytes::from_static(b"SENTINELS"), Bytes::from(name.clone())]),
+                    Sentinel::GetMasterAddrByName(name) => elements.extend([
+                        Bytes::from_static(b"GET-MASTER-ADDR-BY-NAME"),
+                        Bytes::from(name.clone()),
+                    ]),
+                    Sentinel::IsMasterDownByAddr {
+                        host,
+                        port,
+                        epoch,
+                        run_id,
+                    } => elements.extend([
+                        Bytes::from_static(b"IS-MASTER-DOWN-BY-ADDR"),
+                        Bytes::from(host.clone()),
+                        Bytes::from(port.to_string()),
+                        Bytes::from(epoch.to_string()),
+                        Bytes::from(run_id.clone()),
+                    ]),
+                    Sentinel::Monitor {
+                        name,
+                        host,
+                        port,
+                        quorum,
+                    } => elements.extend([
+                        Bytes::from_static(b"MONITOR"),
+                        Bytes::from(name.clone()),
+                        Bytes::from(host.clone()),
+                        Bytes::from(port.to_string()),
+                        Bytes::from(quorum.to_string()),
+                    ]),
+                    Sentinel::Remove(name) => {
+                        elements.extend([Bytes::from_static(b"REMOVE"), Bytes::from(name.clone())])
+                    }
+                    Sentinel::Set { name, options } => {
+                        elements.extend([Bytes::from_static(b"SET"), Bytes::from(name.clone())]);
+                        for (option, value) in options {
+                            elements
+                                .extend([Bytes::from(option.clone()), Bytes::from(value.clone())]);
+                        }
+                    }
+                    Sentinel::Reset(pattern) => elements
+                        .extend([Bytes::from_static(b"RESET"), Bytes::from(pattern.clone())]),
+                    Sentinel::Failover(name) => elements
+                        .extend([Bytes::from_static(b"FAILOVER"), Bytes::from(name.clone())]),
+                    Sentinel::Ckquorum(name) => elements
+                        .extend([Bytes::from_static(b"CKQUORUM"), Bytes::from(name.clone())]),
+                    Sentinel::Myid => elements.push(Bytes::from_static(b"MYID")),
+                }
+            }
+            Command::Hello(protover) => {
+                elements.push(Bytes::from_static(b"HELLO"));
+                elements.extend(protover.map(|protover| Bytes::from(protover.to_string())));
+            }
+            Command::Client(client) => {
+                elements.push(Bytes::from_static(b"CLIENT"));
+                match client {
+                    Client::Id => elements.push(Bytes::from_static(b"ID")),
+                    Client::Tracking(None) => elements
+                        .extend([Bytes::from_static(b"TRACKING"), Bytes::from_static(b"OFF")]),
+                    Client::Tracking(Some(options)) => {
+                        elements
+                            .extend([Bytes::from_static(b"TRACKING"), Bytes::from_static(b"ON")]);
+                        if let Some(id) = options.redirect {
+                            elements.extend([
+                                Bytes::from_static(b"REDIRECT"),
+                                Bytes::from(id.to_string()),
+                            ]);
+                        }
+                        for prefix in &options.prefixes {
+                            elements.extend([
+                                Bytes::from_static(b"PREFIX"),
+                                Bytes::from(prefix.clone()),
+                            ]);
+                        }
+                        let flags = [
+                            (options.bcast, "BCAST"),
+                            (options.optin, "OPTIN"),
+                            (options.optout, "OPTOUT"),
+                            (options.noloop, "NOLOOP"),
+                        ];
+                        elements.extend(
+                            flags
+                                .into_iter()
+                                .filter(|(set, _)| *set)
+                                .map(|(_, flag)| Bytes::from_static(flag.as_bytes())),
+                        );
+                    }
+                    Client::Caching(yes) => elements.extend([
+                        Bytes::from_static(b"CACHING"),
+                        Bytes::from_static(if *yes { b"YES" } else { b"NO" }),
+                    ]),
+                    Client::Getredir => elements.push(Bytes::from_static(b"GETREDIR")),
+                }
+            }
+        }
+
+        Frame::bulk_array(elements)
+    }
+}
+
+/// Parses the arguments of CLUSTER, starting with the subcommand.
+fn parse_cluster(args: &[Bytes]) -> anyhow::Result<Cluster> {
+    let slot = |arg: &Bytes| {
+        atoi::atoi::<u16>(arg)
+            .filter(|&slot| (slot as usize) < cluster::SLOTS)
+            .ok_or(anyhow!("Invalid or out of range slot"))
+    };
+    let subcommand = args[0].to_ascii_uppercase();
+    let args = &args[1..];
+    let expect_args = |count: usize, usage: &str| {
+        if args.len() == count {
+            Ok(())
+        } else {
+            Err(anyhow!("expected: CLUSTER {}", usage))
+        }
+    };
+    match &subcommand[..] {
+        b"INFO" => expect_args(0, "INFO").map(|()| Cluster::Info),
+        b"MYID" => expect_args(0, "MYID").map(|()| Cluster::Myid),
+        b"NODES" => expect_args(0, "NODES").map(|()| Cluster::Nodes),
+        b"SLOTS" => expect_args(0, "SLOTS").map(|()| Cluster::Slots),
+        b"SHARDS" => expect_args(0, "SHARDS").map(|()| Cluster::Shards),
+        b"KEYSLOT" => {
+            expect_args(1, "KEYSLOT <key>")?;
+            Ok(Cluster::Keyslot(String::from_utf8(args[0].to_vec())?))
+        }
+        b"COUNTKEYSINSLOT" => {
+            expect_args(1, "COUNTKEYSINSLOT <slot>")?;
+            Ok(Cluster::Countkeysinslot(slot(&args[0])?))
+        }
+        b"GETKEYSINSLOT" => {
+            expect_args(2, "GETKEYSINSLOT <slot> <count>")?;
+            let count = atoi::atoi(&args[1]).ok_or(anyhow!("Invalid number of keys"))?;
+            Ok(Cluster::Getkeysinslot {
+                slot: slot(&args[0])?,
+                count,
+            })
+        }
+        b"ADDSLOTS" | b"DELSLOTS" => {
+            if args.is_empty() {
+                return Err(anyhow!(
+                    "expected: CLUSTER {} <slot> [slot ...]",
+                    subcommand.escape_ascii()
+                ));
+            }
+            let slots = args.iter().map(slot).collect::<anyhow::Result<_>>()?;
+            if &subcommand[..] == b"ADDSLOTS" {
+                Ok(Cluster::Addslots(slots))
+            } else {
+                Ok(Cluster::Delslots(slots))
+            }
+        }
+        b"ADDSLOTSRANGE" | b"DELSLOTSRANGE" => {
+            let ranges = args.chunks_exact(2);
+            if args.is_empty() || !ranges.remainder().is_empty() {
+                return Err(anyhow!(
+                    "expected: CLUSTER {} <start slot> <end slot> [start slot end slot ...]",
+                    subcommand.escape_ascii()
+                ));
+            }
+            let mut slots = Vec::new();
+            for range in ranges {
+                let (start, end) = (slot(&range[0])?, slot(&range[1])?);
+                if start > end {
+                    return Err(anyhow!(
+                        "start slot number {} is greater than end slot number {}",
+                        start,
+                        end
+                    ));
+                }
+                slots.extend(start..=end);
+            }
+            if &subcommand[..] == b"ADDSLOTSRANGE" {
+                Ok(Cluster::Addslots(slots))
+            } else {
+                Ok(Cluster::Delslots(slots))
+            }
+        }
+        b"MEET" => {
+            if args.len() != 2 && args.len() != 3 {
+                return Err(anyhow!("expected: CLUSTER MEET <ip> <port> [<bus port>]"));
+            }
+            let port = |arg: &Bytes| {
+                atoi::atoi(arg).ok_or(anyhow!(
+                    "Invalid base port specified: {}",
+                    arg.escape_ascii()
+                ))
+            };
+            Ok(Cluster::Meet {
+                host: String::from_utf8(args[0].to_vec())?,
+                port: port(&args[1])?,
+                bus_port: args.get(2).map(port).transpose()?,
+            })
+        }
+        b"FORGET" => {
+            expect_args(1, "FORGET <node id>")?;
+            Ok(Cluster::Forget(String::from_utf8(args[0].to_vec())?))
+        }
+        b"REPLICATE" => {
+            expect_args(1, "REPLICATE <node id>")?;
+            Ok(Cluster::Replicate(String::from_utf8(args[0].to_vec())?))
+        }
+        b"FAILOVER" => {
+            let mode = match args.first().map(|arg| arg.to_ascii_uppercase()).as_deref() {
+                None => FailoverMode::Default,
+                Some(b"FORCE") => FailoverMode::Force,
+                Some(b"TAKEOVER") => FailoverMode::Takeover,
+                Some(_) => return Err(anyhow!("expected: CLUSTER FAILOVER [FORCE|TAKEOVER]")),
+            };
+            if args.len() > 1 {
+                return Err(anyhow!("expected: CLUSTER FAILOVER [FORCE|TAKEOVER]"));
+            }
+            Ok(Cluster::Failover(mode))
+        }
+        b"RESET" => {
+            let hard = match args.first().map(|arg| arg.to_ascii_uppercase()).as_deref() {
+                None | Some(b"SOFT") => false,
+                Some(b"HARD") => true,
+                Some(_) => return Err(anyhow!("expected: CLUSTER RESET [HARD|SOFT]")),
+            };
+            if args.len() > 1 {
+                return Err(anyhow!("expected: CLUSTER RESET [HARD|SOFT]"));
+            }
+            Ok(Cluster::Reset { hard })
+        }
+        b"SETSLOT" => {
+            let usage = "expected: CLUSTER SETSLOT <slot> \
+                         <IMPORTING node-id | MIGRATING node-id | NODE node-id | STABLE>";
+            if args.len() < 2 {
+                return Err(anyhow!(usage));
+            }
+            let id = || match args {
+                [_, _, id] => Ok(String::from_utf8(id.to_vec())?),
+                _ => Err(anyhow!(usage)),
+            };
+            let action = match &args[1].to_ascii_uppercase()[..] {
+                b"IMPORTING" => SetSlot::Importing(id()?),
+                b"MIGRATING" => SetSlot::Migrating(id()?),
+                b"NODE" => SetSlot::Node(id()?),
+                b"STABLE" if args.len() == 2 => SetSlot::Stable,
+                _ => return Err(anyhow!(usage)),
+            };
+            Ok(Cluster::Setslot {
+                slot: slot(&args[0])?,
+                action,
+            })
+        }
+        _ => Err(anyhow!(
+            "unsupported CLUSTER subcommand: {}",
+            subcommand.escape_ascii()
+        )),
+    }
+}
+
+/// Parses the arguments of SENTINEL, starting with the subcommand.
+fn parse_sentinel(args: &[Bytes]) -> anyhow::Result<Sentinel> {
+    let subcommand = args[0].to_ascii_uppercase();
+    let args = &args[1..];
+    let name = |usage: &str| match args {
+        [name] => Ok(String::from_utf8(name.to_vec())?),
+        _ => Err(anyhow!("expected: SENTINEL {} <master name>", usage)),
+    };
+    let port = |arg: &Bytes| atoi::atoi(arg).ok_or(anyhow!("invalid port"));
+    match &subcommand[..] {
+        b"MASTERS" if args.is_empty() => Ok(Sentinel::Masters),
+        b"MYID" if args.is_empty() => Ok(Sentinel::Myid),
+        b"MASTER" => Ok(Sentinel::Master(name("MASTER")?)),
+        b"REPLICAS" | b"SLAVES" => Ok(Sentinel::Replicas(name("REPLICAS")?)),
+        b"SENTINELS" => Ok(Sentinel::Sentinels(name("SENTINELS")?)),
+        b"GET-MASTER-ADDR-BY-NAME" => Ok(Sentinel::GetMasterAddrByName(name(
+            "GET-MASTER-ADDR-BY-NAME",
+        )?)),
+        b"REMOVE" => Ok(Sentinel::Remove(name("REMOVE")?)),
+        b"FAILOVER" => Ok(Sentinel::Failover(name("FAILOVER")?)),
+        b"CKQUORUM" => Ok(Sentinel::Ckquorum(name("CKQUORUM")?)),
+        b"RESET" => match args {
+            [pattern] => Ok(Sentinel::Reset(String::from_utf8(pattern.to_vec())?)),
+            _ => Err(anyhow!("expected: SENTINEL RESET <pattern>")),
+        },
+        b"IS-MASTER-DOWN-BY-ADDR" => {
+            let [host, port_arg, epoch, run_id] = args else {
+                return Err(anyhow!(
+                    "expected: SENTINEL IS-MASTER-DOWN-BY-ADDR <ip> <port> <current-epoch> <runid>"
+                ));
+            };
+            Ok(Sentinel::IsMasterDownByAddr {
+                host: String::from_utf8(host.to_vec())?,
+                port: port(port_arg)?,
+                epoch: atoi::atoi(epoch).ok_or(anyhow!("invalid epoch"))?,
+                run_id: String::from_utf8(run_id.to_vec())?,
+            })
+        }
+        b"MONITOR" => {
+            let [name, host, port_arg, quorum] = args else {
+                return Err(anyhow!(
+                    "expected: SENTINEL MONITOR <name> <ip> <port> <quorum>"
+                ));
+            };
+            Ok(Sentinel::Monitor {
+                name: String::from_utf8(name.to_vec())?,
+                host: String::from_utf8(host.to_vec())?,
+                port: port(port_arg)?,
+                quorum: atoi::atoi(quorum).ok_or(anyhow!("Invalid quorum"))?,
+            })
+        }
+        b"SET" => {
+            let options = args.get(1..).unwrap_or_default().chunks_exact(2);
+            if args.len() < 3 || !options.remainder().is_empty() {
+                return Err(anyhow!(
+                    "expected: SENTINEL SET <master name> <option> <value> [option value ...]"
+                ));
+            }
+            Ok(Sentinel::Set {
+                name: String::from_utf8(args[0].to_vec())?,
+                options: options
+                    .map(|pair| {
+                        Ok((
+                            String::from_utf8(pair[0].to_vec())?,
+                            String::from_utf8(pair[1].to_vec())?,
+                        ))
+                    })
+                    .collect::<anyhow::Result<_>>()?,
+            })
+        }
+        _ => Err(anyhow!(
+            "unsupported SENTINEL subcommand or wrong number of arguments: {}",
+            subcommand.escape_ascii()
+        )),
+    }
+}
+
+/// Parses the arguments of CLIENT, starting with the subcommand.
+fn parse_client(args: &[Bytes]) -> anyhow::Result<Client> {
+    let subcommand = args[0].to_ascii_uppercase();
+    let args = &args[1..];
+    match &subcommand[..] {
+        b"ID" if args.is_empty() => Ok(Client::Id),
+        b"GETREDIR" if args.is_empty() => Ok(Client::Getredir),
+        b"CACHING" => match args {
+            [yes] if yes.eq_ignore_ascii_case(b"YES") => Ok(Client::Caching(true)),
+            [no] if no.eq_ignore_ascii_case(b"NO") => Ok(Client::Caching(false)),
+            _ => Err(anyhow!("syntax error")),
+        },
+        b"TRACKING" => {
+            let Some((switch, args)) = args.split_first() else {
+                return Err(anyhow!(
+                    "expected: CLIENT TRACKING <ON | OFF> [REDIRECT client-id] [PREFIX prefix ...] [BCAST] [OPTIN] [OPTOUT] [NOLOOP]"
+                ));
+            };
+            let mut options = TrackingOptions::default();
+            let mut args = args.iter();
+            while let Some(option) = args.next() {
+                match &option.to_ascii_uppercase()[..] {
+                    b"REDIRECT" => {
+                        let id = args.next().ok_or(anyhow!("syntax error"))?;
+                        options.redirect =
+                            Some(atoi::atoi(id).ok_or(anyhow!("Invalid client ID"))?);
+                    }
+                    b"PREFIX" => {
+                        let prefix = args.next().ok_or(anyhow!("syntax error"))?;
+                        options.prefixes.push(String::from_utf8(prefix.to_vec())?);
+                    }
+                    b"BCAST" => options.bcast = true,
+                    b"OPTIN" => options.optin = true,
+                    b"OPTOUT" => options.optout = true,
+                    b"NOLOOP" => options.noloop = true,
+                    _ => return Err(anyhow!("syntax error")),
+                }
+            }
+            if switch.eq_ignore_ascii_case(b"OFF") {
+                return Ok(Client::Tracking(None));
+            }
+            if !switch.eq_ignore_ascii_case(b"ON") {
+                return Err(anyhow!("syntax error"));
+            }
+            if !options.prefixes.is_empty() && !options.bcast {
+                return Err(anyhow!("PREFIX option requires BCAST mode to be enabled"));
+            }
+            if options.optin && options.optout {
+                return Err(anyhow!("You can't use both OPTIN and OPTOUT"));
+            }
+            if options.bcast && (options.optin || options.optout) {
+                return Err(anyhow!("OPTIN and OPTOUT are not compatible with BCAST"));
+            }
+
+            Ok(Client::Tracking(Some(options)))
+        }
+        _ => Err(anyhow!(
+            "unsupported CLIENT subcommand or wrong number of arguments: {}",
+            subcommand.escape_ascii()
+        )),
+    }
+}
+
+fn strings(elements: &[Bytes]) -> anyhow::Result<Vec<String>> {
+    Ok(elements
+        .iter()
+        .map(|element| String::from_utf8(element.to_vec()))
+        .collect::<Result<_, _>>()?)
 }
 
 #[cfg(test)]
@@ -130,4 +1537,352 @@ mod tests {
 
         assert!(matches![command, Command::Echo(bytes) if &bytes[..] == b"hey"]);
     }
+
+    #[test]
+    fn set_to_frame_roundtrip() {
+        let set = Command::Set {
+            key: "key".to_owned(),
+            value: Bytes::from("value"),
+            px: Some(100),
+            pxat: None,
+        };
+
+        assert_eq!(set, Command::parse(set.to_frame()).unwrap());
+
+        let set = Frame::bulk_array(["SET", "key", "value", "EXAT", "1700000000"]);
+        assert!(matches!(
+            Command::parse(set).unwrap(),
+            Command::Set {
+                px: None,
+                pxat: Some(1700000000000),
+                ..
+            }
+        ));
+
+        let set = Frame::bulk_array(["SET", "key", "value", "PX", "x"]);
+        assert!(Command::parse(set).is_err());
+    }
+
+    #[test]
+    fn parse_replconf() {
+        let capa = Command::Replconf(Replconf::Capa(vec!["eof".to_owned(), "psync2".to_owned()]));
+        assert_eq!(capa, Command::parse(capa.to_frame()).unwrap());
+
+        let getack = Frame::bulk_array(["REPLCONF", "GETACK", "*"]);
+        assert_eq!(
+            Command::Replconf(Replconf::GetAck),
+            Command::parse(getack).unwrap()
+        );
+
+        let ack = Frame::bulk_array(["REPLCONF", "ACK", "154"]);
+        assert_eq!(
+            Command::Replconf(Replconf::Ack(154)),
+            Command::parse(ack).unwrap()
+        );
+    }
+
+    #[test]
+    fn parse_psync() {
+        let psync = Frame::bulk_array(["PSYNC", "?", "-1"]);
+        assert_eq!(
+            Command::Psync {
+                replication_id: "?".to_owned(),
+                offset: -1
+            },
+            Command::parse(psync).unwrap()
+        );
+
+        let psync = Command::Psync {
+            replication_id: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_owned(),
+            offset: 1025,
+        };
+        assert_eq!(psync, Command::parse(psync.to_frame()).unwrap());
+
+        assert!(Command::parse(Frame::bulk_array(["PSYNC", "?", "x"])).is_err());
+    }
+
+    #[test]
+    fn parse_replicaof() {
+        let replicaof = Command::Replicaof(Some(("127.0.0.1".to_owned(), 6380)));
+        assert_eq!(replicaof, Command::parse(replicaof.to_frame()).unwrap());
+
+        let no_one = Frame::bulk_array(["slaveof", "no", "one"]);
+        assert_eq!(Command::Replicaof(None), Command::parse(no_one).unwrap());
+    }
+
+    #[test]
+    fn parse_database_commands() {
+        let select = Frame::bulk_array(["select", "3"]);
+        assert_eq!(Command::Select(3), Command::parse(select).unwrap());
+        assert!(Command::parse(Frame::bulk_array(["SELECT", "-1"])).is_err());
+
+        let swapdb = Command::Swapdb(0, 3);
+        assert_eq!(swapdb, Command::parse(swapdb.to_frame()).unwrap());
+
+        let copy = Frame::bulk_array(["COPY", "a", "b", "replace", "db", "3"]);
+        let copy = Command::parse(copy).unwrap();
+        assert_eq!(
+            Command::Copy {
+                source: "a".to_owned(),
+                destination: "b".to_owned(),
+                db: Some(3),
+                replace: true,
+            },
+            copy
+        );
+        assert_eq!(copy, Command::parse(copy.to_frame()).unwrap());
+
+        let flushdb = Frame::bulk_array(["FLUSHDB", "async"]);
+        assert_eq!(Command::Flushdb, Command::parse(flushdb).unwrap());
+        assert!(Command::parse(Frame::bulk_array(["FLUSHDB", "now"])).is_err());
+    }
+
+    #[test]
+    fn parse_del_and_object() {
+        let del = Command::Del(vec!["a".to_owned(), "b".to_owned()]);
+        assert_eq!(del, Command::parse(del.to_frame()).unwrap());
+        assert!(Command::parse(Frame::bulk_array(["DEL"])).is_err());
+
+        let freq = Frame::bulk_array(["object", "freq", "key"]);
+        assert_eq!(
+            Command::Object(Object::Freq("key".to_owned())),
+            Command::parse(freq).unwrap()
+        );
+        let idletime = Command::Object(Object::Idletime("key".to_owned()));
+        assert_eq!(idletime, Command::parse(idletime.to_frame()).unwrap());
+    }
+
+    #[test]
+    fn command_keys() {
+        let copy = Command::parse(Frame::bulk_array(["COPY", "a", "b", "DB", "1"])).unwrap();
+        assert_eq!(vec!["a", "b"], copy.keys());
+        let del = Command::parse(Frame::bulk_array(["DEL", "a", "b", "c"])).unwrap();
+        assert_eq!(vec!["a", "b", "c"], del.keys());
+        assert!(Command::Dbsize.keys().is_empty());
+    }
+
+    #[test]
+    fn parse_cluster_commands() {
+        let range = Frame::bulk_array(["cluster", "addslotsrange", "0", "2", "10", "10"]);
+        assert_eq!(
+            Command::Cluster(Cluster::Addslots(vec![0, 1, 2, 10])),
+            Command::parse(range).unwrap()
+        );
+        let getkeys = Command::Cluster(Cluster::Getkeysinslot {
+            slot: 16383,
+            count: 10,
+        });
+        assert_eq!(getkeys, Command::parse(getkeys.to_frame()).unwrap());
+        let delslots = Command::Cluster(Cluster::Delslots(vec![5, 7]));
+        assert_eq!(delslots, Command::parse(delslots.to_frame()).unwrap());
+        assert!(Command::parse(Frame::bulk_array(["CLUSTER", "KEYSLOT"])).is_err());
+        assert!(Command::parse(Frame::bulk_array(["CLUSTER", "ADDSLOTS", "16384"])).is_err());
+        assert!(Command::parse(Frame::bulk_array(["CLUSTER", "DELSLOTSRANGE", "5", "1"])).is_err());
+    }
+
+    #[test]
+    fn parse_cluster_node_commands() {
+        let meet = Frame::bulk_array(["cluster", "meet", "127.0.0.1", "7001"]);
+        assert_eq!(
+            Command::Cluster(Cluster::Meet {
+                host: "127.0.0.1".to_owned(),
+                port: 7001,
+                bus_port: None,
+            }),
+            Command::parse(meet).unwrap()
+        );
+        let commands = [
+            Cluster::Meet {
+                host: "::1".to_owned(),
+                port: 7001,
+                bus_port: Some(7101),
+            },
+            Cluster::Forget("a".repeat(40)),
+            Cluster::Replicate("b".repeat(40)),
+            Cluster::Failover(FailoverMode::Default),
+            Cluster::Failover(FailoverMode::Takeover),
+            Cluster::Reset { hard: true },
+            Cluster::Setslot {
+                slot: 12,
+                action: SetSlot::Importing("c".repeat(40)),
+            },
+            Cluster::Setslot {
+                slot: 12,
+                action: SetSlot::Stable,
+            },
+        ];
+        for command in commands {
+            let command = Command::Cluster(command);
+            assert_eq!(command, Command::parse(command.to_frame()).unwrap());
+        }
+        let reset = Frame::bulk_array(["CLUSTER", "RESET"]);
+        assert_eq!(
+            Command::Cluster(Cluster::Reset { hard: false }),
+            Command::parse(reset).unwrap()
+        );
+        assert!(Command::parse(Frame::bulk_array(["CLUSTER", "FAILOVER", "NOW"])).is_err());
+        assert!(Command::parse(Frame::bulk_array(["CLUSTER", "MEET", "::1", "port"])).is_err());
+    }
+
+    #[test]
+    fn parse_migrate_and_restore() {
+        let migrate = Frame::bulk_array(["MIGRATE", "::1", "7001", "key", "0", "1000", "copy"]);
+        assert_eq!(
+            Command::Migrate {
+                host: "::1".to_owned(),
+                port: 7001,
+                keys: vec!["key".to_owned()],
+                db: 0,
+                timeout: 1000,
+                copy: true,
+                replace: false,
+            },
+            Command::parse(migrate).unwrap()
+        );
+        let migrate = Command::Migrate {
+            host: "::1".to_owned(),
+            port: 7001,
+            keys: vec!["a".to_owned(), "b".to_owned()],
+            db: 2,
+            timeout: 10,
+            copy: false,
+            replace: true,
+        };
+        assert_eq!(migrate, Command::parse(migrate.to_frame()).unwrap());
+        assert!(migrate.keys().is_empty());
+        let keys_with_key = ["MIGRATE", "::1", "7001", "a", "0", "10", "KEYS", "b"];
+        assert!(Command::parse(Frame::bulk_array(keys_with_key)).is_err());
+
+        let restore = Command::Restore {
+            key: "key".to_owned(),
+            ttl: 0,
+            payload: Bytes::from_static(b"\x00\x01a"),
+            replace: true,
+            absttl: true,
+            idletime: Some(10),
+            freq: None,
+        };
+        assert_eq!(restore, Command::parse(restore.to_frame()).unwrap());
+        assert_eq!(vec!["key"], restore.keys());
+        assert!(Command::parse(Frame::bulk_array(["RESTORE", "key", "-1", "x"])).is_err());
+        let both = ["RESTORE", "key", "0", "x", "IDLETIME", "1", "FREQ", "1"];
+        assert!(Command::parse(Frame::bulk_array(both)).is_err());
+        let freq = ["RESTORE", "key", "0", "x", "FREQ", "256"];
+        assert!(Command::parse(Frame::bulk_array(freq)).is_err());
+
+        let dump = Command::Dump("key".to_owned());
+        assert_eq!(dump, Command::parse(dump.to_frame()).unwrap());
+        assert!(dump.is_read());
+    }
+
+    #[test]
+    fn parse_pubsub_commands() {
+        let subscribe = Frame::bulk_array(["subscribe", "a", "b"]);
+        assert_eq!(
+            Command::Subscribe(vec!["a".to_owned(), "b".to_owned()]),
+            Command::parse(subscribe).unwrap()
+        );
+        let commands = [
+            Command::Unsubscribe(vec![]),
+            Command::Psubscribe(vec!["news.*".to_owned()]),
+            Command::Punsubscribe(vec!["news.*".to_owned()]),
+            Command::Publish {
+                channel: "news".to_owned(),
+                message: Bytes::from_static(b"hi"),
+            },
+        ];
+        for command in commands {
+            assert_eq!(command, Command::parse(command.to_frame()).unwrap());
+        }
+        assert!(Command::parse(Frame::bulk_array(["SUBSCRIBE"])).is_err());
+        assert!(Command::parse(Frame::bulk_array(["PUBLISH", "news"])).is_err());
+    }
+
+    #[test]
+    fn parse_sentinel_commands() {
+        let slaves = Frame::bulk_array(["sentinel", "slaves", "mymaster"]);
+        assert_eq!(
+            Command::Sentinel(Sentinel::Replicas("mymaster".to_owned())),
+            Command::parse(slaves).unwrap()
+        );
+        let commands = [
+            Sentinel::Masters,
+            Sentinel::GetMasterAddrByName("mymaster".to_owned()),
+            Sentinel::IsMasterDownByAddr {
+                host: "127.0.0.1".to_owned(),
+                port: 6379,
+                epoch: 3,
+                run_id: "*".to_owned(),
+            },
+            Sentinel::Monitor {
+                name: "mymaster".to_owned(),
+                host: "::1".to_owned(),
+                port: 6379,
+                quorum: 2,
+            },
+            Sentinel::Set {
+                name: "mymaster".to_owned(),
+                options: vec![("quorum".to_owned(), "3".to_owned())],
+            },
+            Sentinel::Reset("*".to_owned()),
+            Sentinel::Failover("mymaster".to_owned()),
+        ];
+        for command in commands {
+            let command = Command::Sentinel(command);
+            assert_eq!(command, Command::parse(command.to_frame()).unwrap());
+        }
+        assert!(Command::parse(Frame::bulk_array(["SENTINEL", "MASTER"])).is_err());
+        let set = ["SENTINEL", "SET", "mymaster", "quorum"];
+        assert!(Command::parse(Frame::bulk_array(set)).is_err());
+    }
+
+    #[test]
+    fn parse_client_commands() {
+        let commands = [
+            Command::Hello(Some(3)),
+            Command::Client(Client::Id),
+            Command::Client(Client::Tracking(None)),
+            Command::Client(Client::Tracking(Some(TrackingOptions::default()))),
+            Command::Client(Client::Tracking(Some(TrackingOptions {
+                redirect: Some(7),
+                bcast: true,
+                prefixes: vec!["user:".to_owned(), "order:".to_owned()],
+                noloop: true,
+                ..TrackingOptions::default()
+            }))),
+            Command::Client(Client::Tracking(Some(TrackingOptions {
+                optin: true,
+                ..TrackingOptions::default()
+            }))),
+            Command::Client(Client::Caching(false)),
+            Command::Client(Client::Getredir),
+        ];
+        for command in commands {
+            assert_eq!(command, Command::parse(command.to_frame()).unwrap());
+        }
+        let invalid = [
+            &["CLIENT", "TRACKING", "ON", "PREFIX", "user:"][..],
+            &["CLIENT", "TRACKING", "ON", "OPTIN", "OPTOUT"],
+            &["CLIENT", "TRACKING", "ON", "BCAST", "OPTOUT"],
+            &["CLIENT", "TRACKING", "ON", "REDIRECT"],
+            &["CLIENT", "TRACKING", "MAYBE"],
+            &["CLIENT", "CACHING", "MAYBE"],
+        ];
+        for args in invalid {
+            assert!(Command::parse(Frame::bulk_array(args)).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_config_get() {
+        let config_frame = Frame::Array(vec![
+            Frame::Bulk(Bytes::from("config")),
+            Frame::Bulk(Bytes::from("get")),
+            Frame::Bulk(Bytes::from("dir")),
+        ]);
+
+        let command = Command::parse(config_frame).unwrap();
+
+        assert_eq!(Command::ConfigGet(vec!["dir".to_owned()]), command);
+    }
 }
@@ -0,0 +1,585 @@
+use std::{ops::BitOr, path::PathBuf};
+
+use anyhow::anyhow;
+
+use crate::db::Lfu;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub port: u16,
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    /// Number of logical databases.
+    pub databases: usize,
+    /// Snapshot after the given number of seconds if at least the given number
+    /// of keys changed.
+    pub save: Vec<SavePoint>,
+    /// Size in bytes of the replication backlog kept for partial resyncs.
+    pub repl_backlog_size: usize,
+    /// Whether replicas refuse writes from their clients.
+    pub replica_read_only: bool,
+    /// Whether replicas serve reads while their master link is down.
+    pub replica_serve_stale_data: bool,
+    /// Addresses to listen on. Addresses prefixed with `-` are skipped when
+    /// they are not available.
+    pub bind: Vec<String>,
+    /// Whether masters stream snapshots straight into replica sockets.
+    pub repl_diskless_sync: bool,
+    /// Seconds to wait for more replicas before starting a diskless transfer.
+    pub repl_diskless_sync_delay: u64,
+    pub repl_diskless_load: DisklessLoad,
+    /// Whether writes are logged to the append-only file.
+    pub appendonly: bool,
+    pub appendfilename: String,
+    /// Directory of the append-only file, relative to `dir`.
+    pub appenddirname: String,
+    pub appendfsync: AppendFsync,
+    /// Whether a truncated append-only file is loaded up to the truncation.
+    pub aof_load_truncated: bool,
+    /// Memory limit of the dataset in bytes, 0 for no limit.
+    pub maxmemory: usize,
+    pub maxmemory_policy: MaxmemoryPolicy,
+    /// Number of keys sampled per database to pick the key to evict.
+    pub maxmemory_samples: usize,
+    pub lfu_log_factor: u64,
+    /// Minutes after which an idle LFU counter is decremented.
+    pub lfu_decay_time: u64,
+    /// Number of threads each running a single-threaded executor, to which
+    /// the commands on its partition of the keyspace are routed, 0 to run
+    /// every connection on the shared runtime.
+    pub shard_threads: usize,
+    /// Whether the node is part of a cluster, serving a subset of the slots.
+    pub cluster_enabled: bool,
+    /// File the cluster state is persisted to, relative to `dir`.
+    pub cluster_config_file: String,
+    /// Milliseconds a node can be unreachable before it is flagged as
+    /// failing.
+    pub cluster_node_timeout: u64,
+    /// Address the node advertises to the other nodes and in redirections,
+    /// instead of its first bind address, for nodes behind NAT or bound to a
+    /// wildcard.
+    pub cluster_announce_ip: Option<String>,
+    /// Most keys remembered for the caches of tracking clients, 0 for no
+    /// limit. Beyond it, keys are forgotten and invalidated.
+    pub tracking_table_max_keys: usize,
+    /// Whether the server runs as a sentinel, monitoring masters instead of
+    /// serving data.
+    pub sentinel: bool,
+    /// File the sentinel state is persisted to, relative to `dir`.
+    pub sentinel_config_file: String,
+    /// Keyspace events published to pub/sub channels, none by default.
+    pub notify_keyspace_events: KeyspaceEvents,
+}
+
+/// What to do when the memory limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxmemoryPolicy {
+    /// Refuse commands that use more memory.
+    Noeviction,
+    AllkeysLru,
+    VolatileLru,
+    AllkeysLfu,
+    VolatileLfu,
+    AllkeysRandom,
+    VolatileRandom,
+    /// Evict the keys closest to expire first.
+    VolatileTtl,
+}
+
+impl MaxmemoryPolicy {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match &s.to_ascii_lowercase()[..] {
+            "noeviction" => Ok(MaxmemoryPolicy::Noeviction),
+            "allkeys-lru" => Ok(MaxmemoryPolicy::AllkeysLru),
+            "volatile-lru" => Ok(MaxmemoryPolicy::VolatileLru),
+            "allkeys-lfu" => Ok(MaxmemoryPolicy::AllkeysLfu),
+            "volatile-lfu" => Ok(MaxmemoryPolicy::VolatileLfu),
+            "allkeys-random" => Ok(MaxmemoryPolicy::AllkeysRandom),
+            "volatile-random" => Ok(MaxmemoryPolicy::VolatileRandom),
+            "volatile-ttl" => Ok(MaxmemoryPolicy::VolatileTtl),
+            _ => Err(anyhow!("invalid maxmemory-policy: {}", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MaxmemoryPolicy::Noeviction => "noeviction",
+            MaxmemoryPolicy::AllkeysLru => "allkeys-lru",
+            MaxmemoryPolicy::VolatileLru => "volatile-lru",
+            MaxmemoryPolicy::AllkeysLfu => "allkeys-lfu",
+            MaxmemoryPolicy::VolatileLfu => "volatile-lfu",
+            MaxmemoryPolicy::AllkeysRandom => "allkeys-random",
+            MaxmemoryPolicy::VolatileRandom => "volatile-random",
+            MaxmemoryPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    /// Whether only keys with an expiry are evicted.
+    pub fn is_volatile(&self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::VolatileLru
+                | MaxmemoryPolicy::VolatileLfu
+                | MaxmemoryPolicy::VolatileRandom
+                | MaxmemoryPolicy::VolatileTtl
+        )
+    }
+
+    pub fn is_lfu(&self) -> bool {
+        matches!(
+            self,
+            MaxmemoryPolicy::AllkeysLfu | MaxmemoryPolicy::VolatileLfu
+        )
+    }
+}
+
+/// When the append-only file is flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every write.
+    Always,
+    /// Once per second.
+    Everysec,
+    /// Whenever the OS decides to.
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match &s.to_ascii_lowercase()[..] {
+            "always" => Ok(AppendFsync::Always),
+            "everysec" => Ok(AppendFsync::Everysec),
+            "no" => Ok(AppendFsync::No),
+            _ => Err(anyhow!("invalid appendfsync: {}", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::Everysec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+/// How replicas load the snapshot received from their master.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisklessLoad {
+    /// Save the snapshot to disk before loading it.
+    Disabled,
+    /// Load from the socket if the dataset is empty, else save to disk first.
+    OnEmptyDb,
+    /// Load from the socket, keeping the current dataset until loading
+    /// succeeds.
+    Swapdb,
+}
+
+impl DisklessLoad {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match &s.to_ascii_lowercase()[..] {
+            "disabled" => Ok(DisklessLoad::Disabled),
+            "on-empty-db" => Ok(DisklessLoad::OnEmptyDb),
+            "swapdb" => Ok(DisklessLoad::Swapdb),
+            _ => Err(anyhow!("invalid repl-diskless-load: {}", s)),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisklessLoad::Disabled => "disabled",
+            DisklessLoad::OnEmptyDb => "on-empty-db",
+            DisklessLoad::Swapdb => "swapdb",
+        }
+    }
+}
+
+/// Classes of keyspace events, selected by the flags of
+/// `notify-keyspace-events`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyspaceEvents(u16);
+
+impl KeyspaceEvents {
+    /// Events are published to `__keyspace@<db>__:<key>`, with the event name
+    /// as the message.
+    pub const KEYSPACE: Self = Self(1 << 0);
+    /// Events are published to `__keyevent@<db>__:<event>`, with the key as
+    /// the message.
+    pub const KEYEVENT: Self = Self(1 << 1);
+    /// Type-independent commands, such as DEL or RESTORE.
+    pub const GENERIC: Self = Self(1 << 2);
+    pub const STRING: Self = Self(1 << 3);
+    pub const LIST: Self = Self(1 << 4);
+    pub const SET: Self = Self(1 << 5);
+    pub const HASH: Self = Self(1 << 6);
+    pub const ZSET: Self = Self(1 << 7);
+    pub const EXPIRED: Self = Self(1 << 8);
+    pub const EVICTED: Self = Self(1 << 9);
+    pub const STREAM: Self = Self(1 << 10);
+    /// Reads of missing keys.
+    pub const KEY_MISS: Self = Self(1 << 11);
+    pub const MODULE: Self = Self(1 << 12);
+    /// Keys added to the keyspace.
+    pub const NEW: Self = Self(1 << 13);
+    /// The classes selected by the `A` flag, all but key misses and new keys.
+    pub const ALL: Self = Self(0b1_0111_1111_1100);
+
+    const FLAGS: [(char, Self); 14] = [
+        ('g', Self::GENERIC),
+        ('$', Self::STRING),
+        ('l', Self::LIST),
+        ('s', Self::SET),
+        ('h', Self::HASH),
+        ('z', Self::ZSET),
+        ('x', Self::EXPIRED),
+        ('e', Self::EVICTED),
+        ('t', Self::STREAM),
+        ('d', Self::MODULE),
+        ('K', Self::KEYSPACE),
+        ('E', Self::KEYEVENT),
+        ('m', Self::KEY_MISS),
+        ('n', Self::NEW),
+    ];
+
+    /// Parses flags such as `"KEx"`; an empty string disables notifications.
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        s.chars().try_fold(Self::default(), |events, flag| {
+            let class = match flag {
+                'A' => Self::ALL,
+                _ => Self::FLAGS
+                    .iter()
+                    .find(|(c, _)| *c == flag)
+                    .map(|(_, class)| *class)
+                    .ok_or_else(|| anyhow!("invalid notify-keyspace-events: {}", s))?,
+            };
+            Ok(events | class)
+        })
+    }
+
+    /// Formats the flags the way redis does, with `A` standing for its
+    /// classes.
+    pub fn as_string(&self) -> String {
+        let mut s = String::new();
+        let mut remaining = *self;
+        if self.contains(Self::ALL) {
+            s.push('A');
+            remaining = Self(self.0 & !Self::ALL.0);
+        }
+        for (flag, class) in Self::FLAGS {
+            if remaining.contains(class) {
+                s.push(flag);
+            }
+        }
+
+        s
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether the events of `class` are published to any channel.
+    pub fn is_enabled(&self, class: Self) -> bool {
+        self.contains(class) && (self.contains(Self::KEYSPACE) || self.contains(Self::KEYEVENT))
+    }
+}
+
+impl BitOr for KeyspaceEvents {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: 6379,
+            dir: std::env::current_dir().unwrap_or_else(|_| PathBuf::from(".")),
+            dbfilename: "dump.rdb".to_owned(),
+            databases: 16,
+            save: vec![
+                SavePoint {
+                    seconds: 3600,
+                    changes: 1,
+                },
+                SavePoint {
+                    seconds: 300,
+                    changes: 100,
+                },
+                SavePoint {
+                    seconds: 60,
+                    changes: 10000,
+                },
+            ],
+            repl_backlog_size: 1024 * 1024,
+            replica_read_only: true,
+            replica_serve_stale_data: true,
+            bind: vec!["127.0.0.1".to_owned()],
+            repl_diskless_sync: false,
+            repl_diskless_sync_delay: 5,
+            repl_diskless_load: DisklessLoad::Disabled,
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_owned(),
+            appenddirname: "appendonlydir".to_owned(),
+            appendfsync: AppendFsync::Everysec,
+            aof_load_truncated: true,
+            maxmemory: 0,
+            maxmemory_policy: MaxmemoryPolicy::Noeviction,
+            maxmemory_samples: 5,
+            lfu_log_factor: 10,
+            lfu_decay_time: 1,
+            shard_threads: 0,
+            cluster_enabled: false,
+            cluster_config_file: "nodes.conf".to_owned(),
+            cluster_node_timeout: 15000,
+            cluster_announce_ip: None,
+            tracking_table_max_keys: 1_000_000,
+            sentinel: false,
+            sentinel_config_file: "sentinel.conf".to_owned(),
+            notify_keyspace_events: KeyspaceEvents::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "port" => self.port.to_string(),
+            "databases" => self.databases.to_string(),
+            "dir" => self.dir.display().to_string(),
+            "dbfilename" => self.dbfilename.clone(),
+            "save" => self
+                .save
+                .iter()
+                .map(|p| format!("{} {}", p.seconds, p.changes))
+                .collect::<Vec<_>>()
+                .join(" "),
+            "bind" => self.bind.join(" "),
+            "repl-diskless-sync" => yes_no(self.repl_diskless_sync),
+            "repl-diskless-sync-delay" => self.repl_diskless_sync_delay.to_string(),
+            "repl-diskless-load" => self.repl_diskless_load.as_str().to_owned(),
+            "appendonly" => yes_no(self.appendonly),
+            "appendfilename" => self.appendfilename.clone(),
+            "appenddirname" => self.appenddirname.clone(),
+            "appendfsync" => self.appendfsync.as_str().to_owned(),
+            "aof-load-truncated" => yes_no(self.aof_load_truncated),
+            "repl-backlog-size" => self.repl_backlog_size.to_string(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "maxmemory-policy" => self.maxmemory_policy.as_str().to_owned(),
+            "maxmemory-samples" => self.maxmemory_samples.to_string(),
+            "lfu-log-factor" => self.lfu_log_factor.to_string(),
+            "lfu-decay-time" => self.lfu_decay_time.to_string(),
+            "shard-threads" => self.shard_threads.to_string(),
+            "cluster-enabled" => yes_no(self.cluster_enabled),
+            "cluster-config-file" => self.cluster_config_file.clone(),
+            "cluster-node-timeout" => self.cluster_node_timeout.to_string(),
+            "cluster-announce-ip" => self.cluster_announce_ip.clone().unwrap_or_default(),
+            "tracking-table-max-keys" => self.tracking_table_max_keys.to_string(),
+            "notify-keyspace-events" => self.notify_keyspace_events.as_string(),
+            "replica-read-only" | "slave-read-only" => yes_no(self.replica_read_only),
+            "replica-serve-stale-data" | "slave-serve-stale-data" => {
+                yes_no(self.replica_serve_stale_data)
+            }
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    pub fn lfu(&self) -> Lfu {
+        Lfu {
+            log_factor: self.lfu_log_factor,
+            decay_time: self.lfu_decay_time,
+        }
+    }
+
+    pub fn rdb_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    pub fn aof_dir(&self) -> PathBuf {
+        self.dir.join(&self.appenddirname)
+    }
+
+    pub fn cluster_config_path(&self) -> PathBuf {
+        self.dir.join(&self.cluster_config_file)
+    }
+
+    pub fn sentinel_config_path(&self) -> PathBuf {
+        self.dir.join(&self.sentinel_config_file)
+    }
+
+    /// Parses save points in the `"<seconds> <changes> ..."` form; an empty
+    /// string disables automatic snapshots.
+    pub fn parse_save(s: &str) -> anyhow::Result<Vec<SavePoint>> {
+        let numbers = s
+            .split_whitespace()
+            .map(|n| n.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| anyhow!("invalid save parameters: {}", s))?;
+        let chunks = numbers.chunks_exact(2);
+        if !chunks.remainder().is_empty() {
+            return Err(anyhow!("invalid save parameters: {}", s));
+        }
+
+        Ok(chunks
+            .map(|c| SavePoint {
+                seconds: c[0],
+                changes: c[1],
+            })
+            .collect())
+    }
+
+    /// Parses the address of a master, in the `"<host> <port>"`, `<host>:<port>`
+    /// or `[<ipv6>]:<port>` form.
+    pub fn parse_host_port(s: &str) -> anyhow::Result<(String, u16)> {
+        let s = s.trim();
+        let (host, port) = match s.split_once(char::is_whitespace) {
+            Some((host, port)) => (host, port.trim()),
+            None => {
+                let (host, port) = s
+                    .rsplit_once(':')
+                    .ok_or_else(|| anyhow!("invalid address: {}", s))?;
+                // IPv6 addresses need brackets to be told apart from the port.
+                if host.contains(':') && !host.starts_with('[') {
+                    return Err(anyhow!("invalid address: {}", s));
+                }
+                (host, port)
+            }
+        };
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        if host.is_empty() {
+            return Err(anyhow!("invalid address: {}", s));
+        }
+        let port = port
+            .parse()
+            .map_err(|_| anyhow!("invalid port in address: {}", s))?;
+
+        Ok((host.to_owned(), port))
+    }
+
+    /// Parses a `yes`/`no` option.
+    pub fn parse_bool(s: &str) -> anyhow::Result<bool> {
+        match &s.to_ascii_lowercase()[..] {
+            "yes" => Ok(true),
+            "no" => Ok(false),
+            _ => Err(anyhow!("argument must be 'yes' or 'no': {}", s)),
+        }
+    }
+
+    /// Parses a memory amount such as `"1mb"`. Like redis, `k`, `m` and `g`
+    /// are powers of 1000 while `kb`, `mb` and `gb` are powers of 1024.
+    pub fn parse_memory(s: &str) -> anyhow::Result<usize> {
+        let lower = s.to_ascii_lowercase();
+        let digits = lower.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let multiplier: usize = match &lower[digits.len()..] {
+            "" | "b" => 1,
+            "k" => 1000,
+            "kb" => 1024,
+            "m" => 1000 * 1000,
+            "mb" => 1024 * 1024,
+            "g" => 1000 * 1000 * 1000,
+            "gb" => 1024 * 1024 * 1024,
+            _ => return Err(anyhow!("invalid memory amount: {}", s)),
+        };
+
+        digits
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .ok_or_else(|| anyhow!("invalid memory amount: {}", s))
+    }
+}
+
+fn yes_no(b: bool) -> String {
+    if b { "yes" } else { "no" }.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_save() {
+        assert_eq!(
+            vec![
+                SavePoint {
+                    seconds: 900,
+                    changes: 1
+                },
+                SavePoint {
+                    seconds: 60,
+                    changes: 100
+                }
+            ],
+            Config::parse_save("900 1 60 100").unwrap()
+        );
+        assert!(Config::parse_save("").unwrap().is_empty());
+        assert!(Config::parse_save("900").is_err());
+        assert!(Config::parse_save("900 x").is_err());
+    }
+
+    #[test]
+    fn test_parse_host_port() {
+        let parse = |s| Config::parse_host_port(s).unwrap();
+        assert_eq!(("localhost".to_owned(), 6379), parse("localhost 6379"));
+        assert_eq!(("redis.svc".to_owned(), 6380), parse("redis.svc:6380"));
+        assert_eq!(("::1".to_owned(), 6381), parse("::1 6381"));
+        assert_eq!(("::1".to_owned(), 6382), parse("[::1]:6382"));
+        assert!(Config::parse_host_port("::1:6382").is_err());
+        assert!(Config::parse_host_port("localhost").is_err());
+        assert!(Config::parse_host_port("localhost:x").is_err());
+        assert!(Config::parse_host_port(":6379").is_err());
+    }
+
+    #[test]
+    fn test_parse_bool() {
+        assert!(Config::parse_bool("yes").unwrap());
+        assert!(!Config::parse_bool("NO").unwrap());
+        assert!(Config::parse_bool("1").is_err());
+    }
+
+    #[test]
+    fn test_parse_keyspace_events() {
+        let events = KeyspaceEvents::parse("Kx$").unwrap();
+        assert!(events.is_enabled(KeyspaceEvents::EXPIRED));
+        assert!(events.is_enabled(KeyspaceEvents::STRING));
+        assert!(!events.is_enabled(KeyspaceEvents::GENERIC));
+        assert_eq!("$xK", events.as_string());
+        assert_eq!("AKEm", KeyspaceEvents::parse("EKAm").unwrap().as_string());
+        assert_eq!(
+            "AE",
+            KeyspaceEvents::parse("Eg$lshzxetd").unwrap().as_string()
+        );
+        // Without K or E, events go nowhere.
+        let events = KeyspaceEvents::parse("A").unwrap();
+        assert!(!events.is_enabled(KeyspaceEvents::GENERIC));
+        assert_eq!(
+            KeyspaceEvents::default(),
+            KeyspaceEvents::parse("").unwrap()
+        );
+        assert!(KeyspaceEvents::parse("Kq").is_err());
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(100, Config::parse_memory("100").unwrap());
+        assert_eq!(2000, Config::parse_memory("2k").unwrap());
+        assert_eq!(1024 * 1024, Config::parse_memory("1MB").unwrap());
+        assert_eq!(3 * 1024 * 1024 * 1024, Config::parse_memory("3gb").unwrap());
+        assert!(Config::parse_memory("").is_err());
+        assert!(Config::parse_memory("mb").is_err());
+        assert!(Config::parse_memory("1tb").is_err());
+    }
+}
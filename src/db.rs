@@ -0,0 +1,696 @@
+use std::{
+    cell::Cell,
+    collections::{hash_map::RandomState, BTreeMap, HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, Hasher},
+    ops::{Deref, DerefMut, Index},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::Duration,
+};
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use crate::cluster;
+
+/// The logical databases, split into shards by the hash of their keys. Each
+/// shard has its own lock and holds its part of every logical database, so
+/// that commands on different keys rarely wait for each other.
+///
+/// Commands involving several shards lock them in increasing order, and
+/// always before any other lock of the server.
+pub struct Db {
+    shards: Box<[Shard]>,
+    databases: usize,
+    hasher: RandomState,
+}
+
+struct Shard {
+    keyspaces: Mutex<Vec<Keyspace>>,
+    // Memory used by the shard as of its last unlock, so that the memory in
+    // use can be read without locking every shard.
+    used_memory: AtomicUsize,
+}
+
+/// The keyspaces of one shard, indexed by logical database.
+pub struct ShardGuard<'a> {
+    keyspaces: MutexGuard<'a, Vec<Keyspace>>,
+    used_memory: &'a AtomicUsize,
+}
+
+/// Shards locked together, in increasing order.
+pub struct LockedShards<'a> {
+    db: &'a Db,
+    guards: Vec<(usize, ShardGuard<'a>)>,
+}
+
+/// A copy of the databases, as held by the shards. Taking one only copies
+/// the key tables, since values are shared until replaced.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    shards: Vec<Vec<Keyspace>>,
+}
+
+impl Db {
+    /// Creates `databases` logical databases, in a number of shards suited to
+    /// the number of cores.
+    pub fn new(databases: usize) -> Self {
+        let cores = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Self::with_shards(databases, (cores * 4).next_power_of_two())
+    }
+
+    pub fn with_shards(databases: usize, shards: usize) -> Self {
+        Db {
+            shards: (0..shards.max(1))
+                .map(|_| Shard {
+                    keyspaces: Mutex::new(vec![Keyspace::default(); databases]),
+                    used_memory: AtomicUsize::new(0),
+                })
+                .collect(),
+            databases,
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Number of logical databases.
+    pub fn databases(&self) -> usize {
+        self.databases
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The index of the shard holding `key`.
+    pub fn shard_of(&self, key: &str) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write(key.as_bytes());
+        hasher.finish() as usize % self.shards.len()
+    }
+
+    /// Locks the shard holding `key`.
+    pub fn lock(&self, key: &str) -> ShardGuard<'_> {
+        self.lock_shard(self.shard_of(key))
+    }
+
+    /// Locks a shard. A command that panicked while holding the lock may
+    /// have left it poisoned, which does not prevent later commands from
+    /// using the shard.
+    pub fn lock_shard(&self, index: usize) -> ShardGuard<'_> {
+        let shard = &self.shards[index];
+        ShardGuard {
+            keyspaces: shard
+                .keyspaces
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+            used_memory: &shard.used_memory,
+        }
+    }
+
+    /// Locks the shards holding the given keys.
+    pub fn lock_keys<'k>(&self, keys: impl IntoIterator<Item = &'k str>) -> LockedShards<'_> {
+        let mut indexes = keys
+            .into_iter()
+            .map(|key| self.shard_of(key))
+            .collect::<Vec<_>>();
+        indexes.sort_unstable();
+        indexes.dedup();
+        self.lock_shards(indexes)
+    }
+
+    /// Locks every shard, for commands and snapshots covering every key.
+    pub fn lock_all(&self) -> LockedShards<'_> {
+        self.lock_shards((0..self.shards.len()).collect())
+    }
+
+    fn lock_shards(&self, indexes: Vec<usize>) -> LockedShards<'_> {
+        LockedShards {
+            db: self,
+            guards: indexes
+                .into_iter()
+                .map(|index| (index, self.lock_shard(index)))
+                .collect(),
+        }
+    }
+
+    /// Estimated memory used by the keys of every database, in bytes.
+    pub fn used_memory(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.used_memory.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Number of keys in each database, locking one shard at a time.
+    pub fn key_counts(&self) -> Vec<(usize, usize)> {
+        let mut counts = vec![(0, 0); self.databases];
+        for index in 0..self.shards.len() {
+            let shard = self.lock_shard(index);
+            for (count, keyspace) in counts.iter_mut().zip(shard.iter()) {
+                count.0 += keyspace.len();
+                count.1 += keyspace.volatile_len();
+            }
+        }
+
+        counts
+    }
+
+    /// Splits the databases of an RDB file into shards, which must fit in the
+    /// configured number of databases.
+    pub fn shard(&self, databases: BTreeMap<usize, Keyspace>) -> anyhow::Result<Snapshot> {
+        let mut shards = vec![vec![Keyspace::default(); self.databases]; self.shards.len()];
+        for (index, keyspace) in databases {
+            if index >= self.databases {
+                return Err(anyhow!(
+                    "found db {} but only {} databases are configured",
+                    index,
+                    self.databases
+                ));
+            }
+            for (key, value) in keyspace.into_entries() {
+                shards[self.shard_of(&key)][index].insert(key, value);
+            }
+        }
+
+        Ok(Snapshot { shards })
+    }
+}
+
+impl Deref for ShardGuard<'_> {
+    type Target = Vec<Keyspace>;
+
+    fn deref(&self) -> &Vec<Keyspace> {
+        &self.keyspaces
+    }
+}
+
+impl DerefMut for ShardGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<Keyspace> {
+        &mut self.keyspaces
+    }
+}
+
+impl Drop for ShardGuard<'_> {
+    fn drop(&mut self) {
+        let used = self.keyspaces.iter().map(Keyspace::used_memory).sum();
+        self.used_memory.store(used, Ordering::Relaxed);
+    }
+}
+
+impl<'a> LockedShards<'a> {
+    /// The keyspace of database `db` holding `key`, whose shard must be locked.
+    pub fn keyspace(&mut self, db: usize, key: &str) -> &mut Keyspace {
+        let index = self.db.shard_of(key);
+        let (_, guard) = self
+            .guards
+            .iter_mut()
+            .find(|(i, _)| *i == index)
+            .expect("shard of key not locked");
+        &mut guard[db]
+    }
+
+    /// The databases of every locked shard.
+    pub fn shards_mut(&mut self) -> impl Iterator<Item = &mut ShardGuard<'a>> + '_ {
+        self.guards.iter_mut().map(|(_, guard)| guard)
+    }
+
+    /// Copies the locked shards, which must be all of them.
+    pub fn snapshot(&self) -> Snapshot {
+        debug_assert_eq!(self.guards.len(), self.db.shards.len());
+        Snapshot {
+            shards: self
+                .guards
+                .iter()
+                .map(|(_, guard)| guard.keyspaces.clone())
+                .collect(),
+        }
+    }
+
+    /// Replaces the contents of every shard, which must all be locked.
+    pub fn replace(&mut self, snapshot: Snapshot) {
+        debug_assert_eq!(self.guards.len(), snapshot.shards.len());
+        for ((_, guard), keyspaces) in self.guards.iter_mut().zip(snapshot.shards) {
+            **guard = keyspaces;
+        }
+    }
+}
+
+impl Snapshot {
+    /// The parts of every database, numbered as RDB files store them.
+    pub fn databases(&self) -> Vec<(usize, Vec<&Keyspace>)> {
+        let count = self.shards.first().map(Vec::len).unwrap_or(0);
+        (0..count)
+            .map(|index| {
+                let parts = self.shards.iter().map(|shard| &shard[index]).collect();
+                (index, parts)
+            })
+            .collect()
+    }
+
+    pub fn key_count(&self) -> usize {
+        self.shards.iter().flatten().map(Keyspace::len).sum()
+    }
+}
+
+/// The keys of one logical database, along with the memory they use.
+#[derive(Debug, Clone, Default)]
+pub struct Keyspace {
+    entries: HashMap<String, Entry>,
+    // Keys are kept in vectors too, so that eviction can sample them.
+    keys: Vec<String>,
+    // Keys with an expiry.
+    volatile: Vec<String>,
+    // Positions in `keys` of the keys of each cluster slot, so that the keys
+    // of a slot are found without scanning them all.
+    slots: HashMap<u16, Vec<usize>>,
+    used_memory: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    value: DbValue,
+    // Positions of the key in `keys`, `volatile` and the keys of its slot.
+    index: usize,
+    volatile_index: Option<usize>,
+    slot: u16,
+    slot_index: usize,
+}
+
+impl Keyspace {
+    pub fn get(&self, key: &str) -> Option<&DbValue> {
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    /// Returns the value of a key after recording an access to it.
+    pub fn touch(&mut self, key: &str, lfu: Lfu) -> Option<&DbValue> {
+        let entry = self.entries.get_mut(key)?;
+        entry.value.touch(lfu);
+
+        Some(&entry.value)
+    }
+
+    pub fn insert(&mut self, key: String, value: DbValue) -> Option<DbValue> {
+        let old = self.remove(&key);
+        self.used_memory += entry_memory(&key, &value);
+        let volatile_index = value.expiry.map(|_| {
+            self.volatile.push(key.clone());
+            self.volatile.len() - 1
+        });
+        self.keys.push(key.clone());
+        let index = self.keys.len() - 1;
+        let slot = cluster::key_slot(key.as_bytes());
+        let slot_keys = self.slots.entry(slot).or_default();
+        slot_keys.push(index);
+        let slot_index = slot_keys.len() - 1;
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                index,
+                volatile_index,
+                slot,
+                slot_index,
+            },
+        );
+
+        old
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<DbValue> {
+        let entry = self.entries.remove(key)?;
+        self.used_memory -= entry_memory(key, &entry.value);
+        let slot_keys = self.slots.get_mut(&entry.slot).unwrap();
+        slot_keys.swap_remove(entry.slot_index);
+        match slot_keys.get(entry.slot_index) {
+            Some(&moved) => {
+                self.entries.get_mut(&self.keys[moved]).unwrap().slot_index = entry.slot_index
+            }
+            None if slot_keys.is_empty() => {
+                self.slots.remove(&entry.slot);
+            }
+            None => (),
+        }
+        self.keys.swap_remove(entry.index);
+        if let Some(moved) = self.keys.get(entry.index) {
+            let moved = self.entries.get_mut(moved).unwrap();
+            moved.index = entry.index;
+            self.slots.get_mut(&moved.slot).unwrap()[moved.slot_index] = entry.index;
+        }
+        if let Some(index) = entry.volatile_index {
+            self.volatile.swap_remove(index);
+            if let Some(moved) = self.volatile.get(index) {
+                self.entries.get_mut(moved).unwrap().volatile_index = Some(index);
+            }
+        }
+
+        Some(entry.value)
+    }
+
+    /// Returns a key picked at random, among the keys with an expiry if
+    /// `volatile` is set.
+    pub fn random_key(&self, volatile: bool) -> Option<&str> {
+        let keys = if volatile { &self.volatile } else { &self.keys };
+        if keys.is_empty() {
+            return None;
+        }
+
+        Some(&keys[random() as usize % keys.len()])
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn volatile_len(&self) -> usize {
+        self.volatile.len()
+    }
+
+    /// Number of keys hashing to a cluster slot.
+    pub fn slot_len(&self, slot: u16) -> usize {
+        self.slots.get(&slot).map_or(0, Vec::len)
+    }
+
+    /// The keys hashing to a cluster slot.
+    pub fn slot_keys(&self, slot: u16) -> impl Iterator<Item = &str> {
+        self.slots
+            .get(&slot)
+            .into_iter()
+            .flatten()
+            .map(|&index| self.keys[index].as_str())
+    }
+
+    /// Estimated memory used by the keys and their values, in bytes.
+    pub fn used_memory(&self) -> usize {
+        self.used_memory
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.entries.reserve(additional);
+        self.keys.reserve(additional);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &DbValue)> {
+        self.entries.iter().map(|(key, entry)| (key, &entry.value))
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &DbValue> {
+        self.entries.values().map(|entry| &entry.value)
+    }
+
+    pub fn into_entries(self) -> impl Iterator<Item = (String, DbValue)> {
+        self.entries
+            .into_iter()
+            .map(|(key, entry)| (key, entry.value))
+    }
+}
+
+impl Index<&str> for Keyspace {
+    type Output = DbValue;
+
+    fn index(&self, key: &str) -> &DbValue {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+impl Extend<(String, DbValue)> for Keyspace {
+    fn extend<T: IntoIterator<Item = (String, DbValue)>>(&mut self, iter: T) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl FromIterator<(String, DbValue)> for Keyspace {
+    fn from_iter<T: IntoIterator<Item = (String, DbValue)>>(iter: T) -> Self {
+        let mut keyspace = Keyspace::default();
+        keyspace.extend(iter);
+
+        keyspace
+    }
+}
+
+fn entry_memory(key: &str, value: &DbValue) -> usize {
+    ENTRY_OVERHEAD + key.len() + value.size
+}
+
+/// A value stored in the database. Values are shared with snapshots taken for
+/// background saves, so they are replaced rather than modified in place.
+#[derive(Debug, Clone)]
+pub struct DbValue {
+    pub value: Arc<Value>,
+    pub expiry: Option<Instant>,
+    // Estimated memory used by the value.
+    size: usize,
+    // Last access, which is also when the LFU counter last decayed.
+    accessed: Instant,
+    // Logarithmic access counter, for the LFU eviction policies.
+    lfu_counter: u8,
+}
+
+/// Parameters of the logarithmic access counters, as in redis.
+#[derive(Debug, Clone, Copy)]
+pub struct Lfu {
+    /// How many accesses it takes to increment a counter, the higher the more.
+    pub log_factor: u64,
+    /// Minutes after which an idle counter is decremented.
+    pub decay_time: u64,
+}
+
+impl DbValue {
+    pub fn new(value: Value) -> Self {
+        DbValue {
+            size: value.memory_usage(),
+            value: Arc::new(value),
+            expiry: None,
+            accessed: Instant::now(),
+            lfu_counter: LFU_INIT_VAL,
+        }
+    }
+
+    pub fn with_expiry(mut self, expiry: Option<Instant>) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expiry
+            .map(|expiry| expiry < Instant::now())
+            .unwrap_or(false)
+    }
+
+    /// Keeps the access history of the value it replaces.
+    pub fn inherit_access(&mut self, old: &DbValue) {
+        self.accessed = old.accessed;
+        self.lfu_counter = old.lfu_counter;
+    }
+
+    pub fn touch(&mut self, lfu: Lfu) {
+        let counter = self.freq(lfu);
+        self.lfu_counter = lfu_log_incr(counter, lfu.log_factor);
+        self.accessed = Instant::now();
+    }
+
+    /// Sets the access counter, as restored along with the value.
+    pub fn set_freq(&mut self, freq: u8) {
+        self.lfu_counter = freq;
+        self.accessed = Instant::now();
+    }
+
+    /// Backdates the last access, as restored along with the value.
+    pub fn set_idle_time(&mut self, idle: Duration) {
+        let now = Instant::now();
+        self.accessed = now.checked_sub(idle).unwrap_or(now);
+    }
+
+    pub fn idle_time(&self) -> Duration {
+        self.accessed.elapsed()
+    }
+
+    /// The access counter, decremented once per `decay_time` minutes idle.
+    pub fn freq(&self, lfu: Lfu) -> u8 {
+        if lfu.decay_time == 0 {
+            return self.lfu_counter;
+        }
+        let periods = self.idle_time().as_secs() / 60 / lfu.decay_time;
+
+        self.lfu_counter
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+}
+
+/// Increments a counter with a probability that decreases as it grows, so
+/// that 8 bits are enough for millions of accesses.
+fn lfu_log_incr(counter: u8, log_factor: u64) -> u8 {
+    if counter == u8::MAX {
+        return counter;
+    }
+    let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+    let p = 1.0 / (base * log_factor as f64 + 1.0);
+    let r = (random() >> 11) as f64 / (1u64 << 53) as f64;
+    if r < p {
+        counter + 1
+    } else {
+        counter
+    }
+}
+
+/// Returns a pseudo-random number, from a generator seeded once per thread.
+pub fn random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+    }
+    STATE.with(|state| {
+        // xorshift64*
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    })
+}
+
+// Counter of new values, so that they are not evicted right away by LFU.
+const LFU_INIT_VAL: u8 = 5;
+// Estimated memory used by an entry besides its key and value.
+const ENTRY_OVERHEAD: usize = 64;
+// Estimated memory used by an element of a collection besides its contents.
+const ELEMENT_OVERHEAD: usize = 32;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Set(HashSet<Bytes>),
+    SortedSet(HashMap<Bytes, f64>),
+    Hash(HashMap<Bytes, Bytes>),
+    Stream(Stream),
+}
+
+impl Value {
+    /// Estimates the memory used by the value, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        match self {
+            Value::String(bytes) => bytes.len(),
+            Value::List(list) => list.iter().map(|e| e.len() + ELEMENT_OVERHEAD).sum(),
+            Value::Set(set) => set.iter().map(|m| m.len() + ELEMENT_OVERHEAD).sum(),
+            Value::SortedSet(zset) => zset.keys().map(|m| m.len() + ELEMENT_OVERHEAD).sum(),
+            Value::Hash(hash) => hash
+                .iter()
+                .map(|(f, v)| f.len() + v.len() + ELEMENT_OVERHEAD)
+                .sum(),
+            Value::Stream(stream) => stream
+                .entries
+                .values()
+                .map(|fields| {
+                    fields
+                        .iter()
+                        .map(|(f, v)| f.len() + v.len() + ELEMENT_OVERHEAD)
+                        .sum::<usize>()
+                        + ELEMENT_OVERHEAD
+                })
+                .sum(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    pub entries: BTreeMap<StreamId, Vec<(Bytes, Bytes)>>,
+    pub last_id: StreamId,
+    pub first_id: StreamId,
+    pub max_deleted_id: StreamId,
+    pub entries_added: u64,
+    pub groups: Vec<ConsumerGroup>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConsumerGroup {
+    pub name: Bytes,
+    pub last_id: StreamId,
+    // -1 when the number of entries read by the group is unknown.
+    pub entries_read: i64,
+    pub pending: Vec<PendingEntry>,
+    pub consumers: Vec<Consumer>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingEntry {
+    pub id: StreamId,
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Consumer {
+    pub name: Bytes,
+    pub seen_time: u64,
+    pub active_time: u64,
+    pub pending: Vec<StreamId>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyspace_tracks_keys_and_memory() {
+        let mut keyspace = Keyspace::default();
+        let expiry = Some(Instant::now() + Duration::from_secs(60));
+        for key in ["a", "b", "c"] {
+            let value = DbValue::new(Value::String(Bytes::from("value")));
+            keyspace.insert(key.to_owned(), value.with_expiry(expiry));
+        }
+        keyspace.insert(
+            "b".to_owned(),
+            DbValue::new(Value::String(Bytes::from("v"))),
+        );
+        assert_eq!(3, keyspace.len());
+        assert_eq!(2, keyspace.volatile_len());
+        assert_eq!(3 * (ENTRY_OVERHEAD + 1) + 5 + 5 + 1, keyspace.used_memory());
+
+        keyspace.remove("a");
+        for _ in 0..10 {
+            let key = keyspace.random_key(true).unwrap();
+            assert_eq!("c", key);
+        }
+        keyspace.remove("c");
+        keyspace.remove("b");
+        assert!(keyspace.random_key(false).is_none());
+        assert_eq!(0, keyspace.used_memory());
+    }
+
+    #[test]
+    fn test_keyspace_indexes_slots() {
+        let mut keyspace = Keyspace::default();
+        for key in ["{a}1", "b", "{a}2", "{a}3"] {
+            keyspace.insert(key.to_owned(), DbValue::new(Value::String(Bytes::new())));
+        }
+        let slot = cluster::key_slot(b"a");
+        assert_eq!(3, keyspace.slot_len(slot));
+
+        keyspace.remove("{a}1");
+        keyspace.remove("b");
+        let mut keys = keyspace.slot_keys(slot).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(["{a}2", "{a}3"], keys[..]);
+        assert_eq!(0, keyspace.slot_len(cluster::key_slot(b"b")));
+        keyspace.remove("{a}3");
+        assert_eq!(["{a}2"], keyspace.slot_keys(slot).collect::<Vec<_>>()[..]);
+    }
+}
@@ -0,0 +1,205 @@
+use tokio::time::Instant;
+
+use crate::{
+    config::MaxmemoryPolicy,
+    db::{self, Db, DbValue, Keyspace, Lfu},
+};
+
+/// Picks the keys to evict under an LRU, LFU or TTL policy. Like redis, it
+/// approximates the policy by sampling a few keys of every database and
+/// keeping the best candidates seen so far, rather than tracking every key.
+pub struct EvictionPool {
+    policy: MaxmemoryPolicy,
+    samples: usize,
+    lfu: Lfu,
+    // Sorted by increasing score, so the best candidate is last.
+    candidates: Vec<Candidate>,
+}
+
+struct Candidate {
+    db: usize,
+    key: String,
+    // The higher the better to evict.
+    score: u64,
+}
+
+impl EvictionPool {
+    pub fn new(policy: MaxmemoryPolicy, samples: usize, lfu: Lfu) -> Self {
+        EvictionPool {
+            policy,
+            samples,
+            lfu,
+            candidates: Vec::with_capacity(EVICTION_POOL_SIZE),
+        }
+    }
+
+    /// Returns the database and key to evict next, if any key can be. Shards
+    /// are locked one at a time, so by the time the caller locks its shard,
+    /// the key may be gone or, under volatile policies, no longer expire.
+    pub fn next(&mut self, db: &Db) -> Option<(usize, String)> {
+        match self.policy {
+            MaxmemoryPolicy::Noeviction => None,
+            MaxmemoryPolicy::AllkeysRandom | MaxmemoryPolicy::VolatileRandom => {
+                random_key(db, self.policy.is_volatile())
+            }
+            _ => {
+                // Each sample comes from a random shard.
+                for _ in 0..self.samples {
+                    let index = db::random() as usize % db.shard_count();
+                    self.sample(&db.lock_shard(index));
+                }
+                // Sampling may miss the few shards holding candidates.
+                if self.candidates.is_empty() {
+                    for index in 0..db.shard_count() {
+                        self.sample(&db.lock_shard(index));
+                    }
+                }
+
+                self.candidates
+                    .pop()
+                    .map(|candidate| (candidate.db, candidate.key))
+            }
+        }
+    }
+
+    /// Samples a key of every database of a shard.
+    fn sample(&mut self, keyspaces: &[Keyspace]) {
+        let volatile = self.policy.is_volatile();
+        for (index, keyspace) in keyspaces.iter().enumerate() {
+            let Some(key) = keyspace.random_key(volatile) else {
+                continue;
+            };
+            if self
+                .candidates
+                .iter()
+                .any(|c| c.db == index && c.key == key)
+            {
+                continue;
+            }
+            let score = self.score(&keyspace[key]);
+            let position = self.candidates.partition_point(|c| c.score < score);
+            if position == 0 && self.candidates.len() == EVICTION_POOL_SIZE {
+                continue;
+            }
+            self.candidates.insert(
+                position,
+                Candidate {
+                    db: index,
+                    key: key.to_owned(),
+                    score,
+                },
+            );
+            if self.candidates.len() > EVICTION_POOL_SIZE {
+                self.candidates.remove(0);
+            }
+        }
+    }
+
+    fn score(&self, value: &DbValue) -> u64 {
+        match self.policy {
+            MaxmemoryPolicy::AllkeysLfu | MaxmemoryPolicy::VolatileLfu => {
+                (u8::MAX - value.freq(self.lfu)) as u64
+            }
+            MaxmemoryPolicy::VolatileTtl => {
+                let ttl = value
+                    .expiry
+                    .map(|expiry| expiry.saturating_duration_since(Instant::now()))
+                    .unwrap_or_default();
+                u64::MAX - ttl.as_millis() as u64
+            }
+            _ => value.idle_time().as_millis() as u64,
+        }
+    }
+}
+
+/// Picks a key at random, visiting the shards and their databases from
+/// random ones.
+fn random_key(db: &Db, volatile: bool) -> Option<(usize, String)> {
+    let shards = db.shard_count();
+    let databases = db.databases();
+    let first_shard = db::random() as usize % shards;
+    let first_db = db::random() as usize % databases;
+    (0..shards).find_map(|i| {
+        let shard = db.lock_shard((first_shard + i) % shards);
+        (0..databases)
+            .map(|j| (first_db + j) % databases)
+            .find_map(|index| {
+                shard[index]
+                    .random_key(volatile)
+                    .map(|key| (index, key.to_owned()))
+            })
+    })
+}
+
+const EVICTION_POOL_SIZE: usize = 16;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::db::Value;
+
+    fn value(s: &'static str) -> DbValue {
+        DbValue::new(Value::String(Bytes::from(s)))
+    }
+
+    #[test]
+    fn test_lfu_evicts_least_used() {
+        let lfu = Lfu {
+            log_factor: 0,
+            decay_time: 1,
+        };
+        let db = Db::with_shards(1, 1);
+        {
+            let mut shard = db.lock_shard(0);
+            shard[0].insert("hot".to_owned(), value("a"));
+            shard[0].insert("cold".to_owned(), value("b"));
+            for _ in 0..10 {
+                shard[0].touch("hot", lfu);
+            }
+        }
+
+        let mut pool = EvictionPool::new(MaxmemoryPolicy::AllkeysLfu, 20, lfu);
+
+        assert_eq!(Some((0, "cold".to_owned())), pool.next(&db));
+    }
+
+    #[test]
+    fn test_volatile_ttl_evicts_soonest_expiring() {
+        let lfu = Lfu {
+            log_factor: 10,
+            decay_time: 1,
+        };
+        let now = Instant::now();
+        let db = Db::with_shards(2, 4);
+        db.lock("persistent")[0].insert("persistent".to_owned(), value("a"));
+        db.lock("later")[0].insert(
+            "later".to_owned(),
+            value("b").with_expiry(Some(now + Duration::from_secs(60))),
+        );
+        db.lock("sooner")[1].insert(
+            "sooner".to_owned(),
+            value("c").with_expiry(Some(now + Duration::from_secs(10))),
+        );
+
+        let mut pool = EvictionPool::new(MaxmemoryPolicy::VolatileTtl, 20, lfu);
+
+        assert_eq!(Some((1, "sooner".to_owned())), pool.next(&db));
+        db.lock("sooner")[1].remove("sooner");
+        assert_eq!(Some((0, "later".to_owned())), pool.next(&db));
+        db.lock("later")[0].remove("later");
+        assert_eq!(None, pool.next(&db));
+    }
+
+    #[test]
+    fn test_random_skips_empty_databases() {
+        let db = Db::with_shards(3, 8);
+        db.lock("key")[1].insert("key".to_owned(), value("a"));
+
+        assert_eq!(Some((1, "key".to_owned())), random_key(&db, false));
+        assert_eq!(None, random_key(&db, true));
+    }
+}
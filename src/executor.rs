@@ -0,0 +1,73 @@
+use std::cell::Cell;
+
+use anyhow::Context;
+use tokio::runtime::{Builder, Handle};
+
+/// Threads each running a single-threaded executor, for thread-per-core
+/// execution. Tasks are handed to an executor by spawning them on its handle,
+/// which queues them to its thread.
+#[derive(Default)]
+pub struct Executors {
+    handles: Vec<Handle>,
+}
+
+thread_local! {
+    // Index of the executor running on the current thread.
+    static CURRENT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+impl Executors {
+    /// Starts `threads` executors. They run until the process exits.
+    pub fn start(threads: usize) -> anyhow::Result<Self> {
+        let mut handles = Vec::with_capacity(threads);
+        for index in 0..threads {
+            let runtime = Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .context("failed to build executor runtime")?;
+            handles.push(runtime.handle().clone());
+            std::thread::Builder::new()
+                .name(format!("executor-{index}"))
+                .spawn(move || {
+                    CURRENT.with(|current| current.set(Some(index)));
+                    runtime.block_on(std::future::pending::<()>())
+                })
+                .context("failed to spawn executor thread")?;
+        }
+
+        Ok(Executors { handles })
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    pub fn handle(&self, index: usize) -> &Handle {
+        &self.handles[index]
+    }
+}
+
+/// The index of the executor running on the current thread, if any.
+pub fn current() -> Option<usize> {
+    CURRENT.with(Cell::get)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tasks_run_on_their_executor() {
+        let executors = Executors::start(2).unwrap();
+
+        for index in 0..executors.len() {
+            let ran_on = executors.handle(index).spawn(async { current() });
+            assert_eq!(Some(index), ran_on.await.unwrap());
+        }
+        assert_eq!(None, current());
+    }
+}
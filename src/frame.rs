@@ -1,7 +1,7 @@
 use std::{fmt::Display, io::Cursor};
 
 use anyhow::anyhow;
-use bytes::{Buf, Bytes};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
 #[derive(Debug)]
 pub enum Frame {
@@ -9,7 +9,11 @@ pub enum Frame {
     Simple(String),
     Array(Vec<Frame>),
     Error(Bytes),
+    Integer(i64),
     Null,
+    /// Out-of-band data, such as pub/sub messages, sent to RESP3 clients.
+    Push(Vec<Frame>),
+    Map(Vec<(Frame, Frame)>),
 }
 
 #[derive(Debug)]
@@ -33,21 +37,103 @@ impl Frame {
     //     todo!();
     // }
 
+    pub fn encode(&self, dst: &mut BytesMut) {
+        match self {
+            Frame::Bulk(bytes) => {
+                dst.put_u8(b'$');
+                dst.put(bytes.len().to_string().as_bytes());
+                dst.put(&b"\r\n"[..]);
+                dst.put(&bytes[..]);
+                dst.put(&b"\r\n"[..]);
+            }
+            Frame::Simple(s) => {
+                dst.put_u8(b'+');
+                dst.put(s.as_bytes());
+                dst.put(&b"\r\n"[..]);
+            }
+            Frame::Array(frames) => {
+                dst.put_u8(b'*');
+                dst.put(frames.len().to_string().as_bytes());
+                dst.put(&b"\r\n"[..]);
+                for frame in frames {
+                    frame.encode(dst);
+                }
+            }
+            Frame::Error(bytes) => {
+                dst.put_u8(b'-');
+                dst.put(&bytes[..]);
+                dst.put(&b"\r\n"[..]);
+            }
+            Frame::Integer(n) => {
+                dst.put_u8(b':');
+                dst.put(n.to_string().as_bytes());
+                dst.put(&b"\r\n"[..]);
+            }
+            Frame::Null => dst.put(&b"_\r\n"[..]),
+            Frame::Push(frames) => {
+                dst.put_u8(b'>');
+                dst.put(frames.len().to_string().as_bytes());
+                dst.put(&b"\r\n"[..]);
+                for frame in frames {
+                    frame.encode(dst);
+                }
+            }
+            Frame::Map(entries) => {
+                dst.put_u8(b'%');
+                dst.put(entries.len().to_string().as_bytes());
+                dst.put(&b"\r\n"[..]);
+                for (key, value) in entries {
+                    key.encode(dst);
+                    value.encode(dst);
+                }
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buf = BytesMut::new();
+        self.encode(&mut buf);
+
+        buf.freeze()
+    }
+
+    pub fn bulk_array(elements: impl IntoIterator<Item = impl AsRef<[u8]>>) -> Self {
+        Frame::Array(
+            elements
+                .into_iter()
+                .map(|e| Frame::Bulk(Bytes::copy_from_slice(e.as_ref())))
+                .collect(),
+        )
+    }
+
     pub fn parse(input: &mut Cursor<&[u8]>) -> Result<Self, ParseError> {
         if !input.has_remaining() {
             return Err(ParseError::Incomplete);
         }
 
         match input.get_u8() {
-            // Array
-            b'*' => {
+            // Array, or push
+            token @ (b'*' | b'>') => {
                 let len = Self::parse_u64(input)? as usize;
                 let mut frames = Vec::with_capacity(len);
                 for _ in 0..len {
                     frames.push(Self::parse(input)?);
                 }
 
-                Ok(Frame::Array(frames))
+                Ok(match token {
+                    b'*' => Frame::Array(frames),
+                    _ => Frame::Push(frames),
+                })
+            }
+            // Map
+            b'%' => {
+                let len = Self::parse_u64(input)? as usize;
+                let mut entries = Vec::with_capacity(len);
+                for _ in 0..len {
+                    entries.push((Self::parse(input)?, Self::parse(input)?));
+                }
+
+                Ok(Frame::Map(entries))
             }
             // Bulk string
             b'$' => {
@@ -69,6 +155,20 @@ impl Frame {
 
                 Ok(Frame::Simple(String::from_utf8_lossy(line).to_string()))
             }
+            // Error
+            b'-' => {
+                let line = Frame::get_line(input)?;
+
+                Ok(Frame::Error(Bytes::copy_from_slice(line)))
+            }
+            // Integer
+            b':' => Ok(Frame::Integer(Self::parse_i64(input)?)),
+            // Null
+            b'_' => {
+                Frame::get_line(input)?;
+
+                Ok(Frame::Null)
+            }
             b => Err(ParseError::Other(anyhow!("unknown data type token: {}", b))),
         }
     }
@@ -89,6 +189,22 @@ impl Frame {
         Ok(len.unwrap())
     }
 
+    fn parse_i64(input: &mut Cursor<&[u8]>) -> Result<i64, ParseError> {
+        let line = Self::get_line(input)?;
+        let (n, used) = <i64 as atoi::FromRadix10SignedChecked>::from_radix_10_signed_checked(line);
+        if n.is_none() {
+            return Err(ParseError::Other(anyhow!("number too large for i64")));
+        }
+        if used < line.len() {
+            return Err(ParseError::Other(anyhow!(
+                "expected number, got {}",
+                line.escape_ascii()
+            )));
+        }
+
+        Ok(n.unwrap())
+    }
+
     fn get_line<'a>(input: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], ParseError> {
         let crlf_pos = Self::crlf_pos(input).ok_or(ParseError::Incomplete)?;
         let line = &input.get_ref()[input.position() as usize..crlf_pos as usize];
@@ -173,6 +289,50 @@ mod tests {
         assert!(matches![a, Err(ParseError::Incomplete)]);
     }
 
+    #[test]
+    fn test_parse_integer() {
+        let n = Frame::parse(&mut Cursor::new(b":-42\r\n")).unwrap();
+        assert!(matches![n, Frame::Integer(-42)]);
+
+        let n = Frame::parse(&mut Cursor::new(b":4x\r\n"));
+        assert!(matches![n, Err(ParseError::Other(_))]);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let error = Frame::parse(&mut Cursor::new(b"-ERR not a master\r\n")).unwrap();
+        assert!(matches![error, Frame::Error(bytes) if &bytes[..] == b"ERR not a master"]);
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let frame = Frame::Array(vec![
+            Frame::Bulk(Bytes::from("SET")),
+            Frame::Simple("OK".to_owned()),
+            Frame::Integer(-3),
+        ]);
+
+        let bytes = frame.to_bytes();
+
+        assert_eq!(&b"*3\r\n$3\r\nSET\r\n+OK\r\n:-3\r\n"[..], &bytes[..]);
+        let parsed = Frame::parse(&mut Cursor::new(&bytes[..])).unwrap();
+        assert!(matches![parsed, Frame::Array(frames) if frames.len() == 3]);
+    }
+
+    #[test]
+    fn test_resp3_roundtrip() {
+        let frame = Frame::Map(vec![(
+            Frame::Bulk(Bytes::from("proto")),
+            Frame::Push(vec![Frame::Integer(3), Frame::Null]),
+        )]);
+
+        let bytes = frame.to_bytes();
+
+        assert_eq!(&b"%1\r\n$5\r\nproto\r\n>2\r\n:3\r\n_\r\n"[..], &bytes[..]);
+        let parsed = Frame::parse(&mut Cursor::new(&bytes[..])).unwrap();
+        assert_eq!(bytes, parsed.to_bytes());
+    }
+
     #[test]
     fn test_get_line() {
         let mut cursor = Cursor::new(&b"hello\r\n"[..]);
@@ -184,13 +344,13 @@ mod tests {
 
     #[test]
     fn test_crlf_pos() {
-        let mut cursor = Cursor::new(&b"hello\r\n"[..]);
-        let pos = Frame::crlf_pos(&mut cursor).unwrap();
+        let cursor = Cursor::new(&b"hello\r\n"[..]);
+        let pos = Frame::crlf_pos(&cursor).unwrap();
         assert_eq!(5, pos);
 
         let mut cursor = Cursor::new(&b"hi\r\nhello\r\nyo\r\n"[..]);
         cursor.set_position(4);
-        let pos = Frame::crlf_pos(&mut cursor).unwrap();
+        let pos = Frame::crlf_pos(&cursor).unwrap();
         assert_eq!(9, pos);
     }
 }
@@ -1,4 +1,16 @@
+pub mod aof;
+pub mod bus;
+pub mod cluster;
 pub mod command;
+pub mod config;
+pub mod db;
+pub mod evict;
+pub mod executor;
 pub mod frame;
 pub mod net;
+pub mod pubsub;
+pub mod rdb;
+pub mod replication;
+pub mod sentinel;
 pub mod server;
+pub mod tracking;
@@ -1,49 +1,227 @@
-use anyhow::Context;
+use anyhow::{anyhow, Context};
 use clap::Parser;
-use redis::server::{Role, Server};
-use std::net::Ipv4Addr;
+use redis::{
+    config::{AppendFsync, Config, DisklessLoad, KeyspaceEvents, MaxmemoryPolicy},
+    server::{Role, Server},
+};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command()]
 struct Args {
     #[arg(long)]
     port: Option<u16>,
-    #[arg(long = "replicaof", value_name = "HOST PORT")]
+    #[arg(long = "replicaof", value_name = "HOST PORT|HOST:PORT")]
     replica_of: Option<String>,
+    /// Addresses to listen on, `-` marks optional ones
+    #[arg(long, allow_hyphen_values = true, value_name = "ADDRESS ...")]
+    bind: Option<String>,
+    #[arg(long)]
+    dir: Option<PathBuf>,
+    #[arg(long)]
+    dbfilename: Option<String>,
+    #[arg(long)]
+    databases: Option<usize>,
+    #[arg(long, value_name = "SECONDS CHANGES")]
+    save: Option<String>,
+    #[arg(long = "repl-backlog-size", value_name = "BYTES")]
+    repl_backlog_size: Option<String>,
+    #[arg(long = "replica-read-only", value_name = "yes|no")]
+    replica_read_only: Option<String>,
+    #[arg(long = "replica-serve-stale-data", value_name = "yes|no")]
+    replica_serve_stale_data: Option<String>,
+    #[arg(long = "repl-diskless-sync", value_name = "yes|no")]
+    repl_diskless_sync: Option<String>,
+    #[arg(long = "repl-diskless-sync-delay", value_name = "SECONDS")]
+    repl_diskless_sync_delay: Option<u64>,
+    #[arg(
+        long = "repl-diskless-load",
+        value_name = "disabled|on-empty-db|swapdb"
+    )]
+    repl_diskless_load: Option<String>,
+    #[arg(long, value_name = "yes|no")]
+    appendonly: Option<String>,
+    #[arg(long)]
+    appendfilename: Option<String>,
+    #[arg(long)]
+    appenddirname: Option<String>,
+    #[arg(long, value_name = "always|everysec|no")]
+    appendfsync: Option<String>,
+    #[arg(long = "aof-load-truncated", value_name = "yes|no")]
+    aof_load_truncated: Option<String>,
+    #[arg(long, value_name = "BYTES")]
+    maxmemory: Option<String>,
+    #[arg(long = "maxmemory-policy", value_name = "POLICY")]
+    maxmemory_policy: Option<String>,
+    #[arg(long = "maxmemory-samples")]
+    maxmemory_samples: Option<usize>,
+    #[arg(long = "lfu-log-factor")]
+    lfu_log_factor: Option<u64>,
+    #[arg(long = "lfu-decay-time", value_name = "MINUTES")]
+    lfu_decay_time: Option<u64>,
+    /// Run one executor per thread, each serving the commands on a partition of the keyspace
+    #[arg(long = "shard-threads", value_name = "N|auto")]
+    shard_threads: Option<String>,
+    #[arg(long = "cluster-enabled", value_name = "yes|no")]
+    cluster_enabled: Option<String>,
+    #[arg(long = "cluster-config-file")]
+    cluster_config_file: Option<String>,
+    #[arg(long = "cluster-node-timeout", value_name = "MILLISECONDS")]
+    cluster_node_timeout: Option<u64>,
+    /// Address advertised to the other nodes of the cluster, instead of the first bind address
+    #[arg(long = "cluster-announce-ip")]
+    cluster_announce_ip: Option<String>,
+    /// Most keys remembered for client side caching, 0 for no limit
+    #[arg(long = "tracking-table-max-keys", value_name = "KEYS")]
+    tracking_table_max_keys: Option<usize>,
+    /// Run as a sentinel, monitoring masters and failing them over
+    #[arg(long)]
+    sentinel: bool,
+    #[arg(long = "sentinel-config-file")]
+    sentinel_config_file: Option<String>,
+    /// Keyspace events to publish, such as `KEA` for all of them
+    #[arg(long = "notify-keyspace-events", value_name = "FLAGS")]
+    notify_keyspace_events: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
-    let port = args.port.unwrap_or(6379);
+    let mut config = Config {
+        sentinel: args.sentinel,
+        ..Config::default()
+    };
+    if config.sentinel {
+        config.port = SENTINEL_PORT;
+    }
+    if let Some(port) = args.port {
+        config.port = port;
+    }
+    if let Some(bind) = args.bind {
+        config.bind = bind.split_whitespace().map(str::to_owned).collect();
+    }
+    if let Some(dir) = args.dir {
+        config.dir = dir;
+    }
+    if let Some(dbfilename) = args.dbfilename {
+        config.dbfilename = dbfilename;
+    }
+    if let Some(databases) = args.databases {
+        if databases == 0 {
+            return Err(anyhow!("invalid value for --databases: {}", databases));
+        }
+        config.databases = databases;
+    }
+    if let Some(save) = args.save {
+        config.save = Config::parse_save(&save)?;
+    }
+    if let Some(size) = args.repl_backlog_size {
+        config.repl_backlog_size = Config::parse_memory(&size)?;
+    }
+    if let Some(read_only) = args.replica_read_only {
+        config.replica_read_only = Config::parse_bool(&read_only)?;
+    }
+    if let Some(serve_stale_data) = args.replica_serve_stale_data {
+        config.replica_serve_stale_data = Config::parse_bool(&serve_stale_data)?;
+    }
+    if let Some(diskless_sync) = args.repl_diskless_sync {
+        config.repl_diskless_sync = Config::parse_bool(&diskless_sync)?;
+    }
+    if let Some(delay) = args.repl_diskless_sync_delay {
+        config.repl_diskless_sync_delay = delay;
+    }
+    if let Some(diskless_load) = args.repl_diskless_load {
+        config.repl_diskless_load = DisklessLoad::parse(&diskless_load)?;
+    }
+    if let Some(appendonly) = args.appendonly {
+        config.appendonly = Config::parse_bool(&appendonly)?;
+    }
+    if let Some(appendfilename) = args.appendfilename {
+        config.appendfilename = appendfilename;
+    }
+    if let Some(appenddirname) = args.appenddirname {
+        config.appenddirname = appenddirname;
+    }
+    if let Some(appendfsync) = args.appendfsync {
+        config.appendfsync = AppendFsync::parse(&appendfsync)?;
+    }
+    if let Some(load_truncated) = args.aof_load_truncated {
+        config.aof_load_truncated = Config::parse_bool(&load_truncated)?;
+    }
+    if let Some(maxmemory) = args.maxmemory {
+        config.maxmemory = Config::parse_memory(&maxmemory)?;
+    }
+    if let Some(policy) = args.maxmemory_policy {
+        config.maxmemory_policy = MaxmemoryPolicy::parse(&policy)?;
+    }
+    if let Some(samples) = args.maxmemory_samples {
+        if samples == 0 {
+            return Err(anyhow!(
+                "invalid value for --maxmemory-samples: {}",
+                samples
+            ));
+        }
+        config.maxmemory_samples = samples;
+    }
+    if let Some(log_factor) = args.lfu_log_factor {
+        config.lfu_log_factor = log_factor;
+    }
+    if let Some(decay_time) = args.lfu_decay_time {
+        config.lfu_decay_time = decay_time;
+    }
+    if let Some(threads) = args.shard_threads {
+        // One executor per core.
+        config.shard_threads = match &threads[..] {
+            "auto" => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            _ => threads
+                .parse()
+                .map_err(|_| anyhow!("invalid value for --shard-threads: {}", threads))?,
+        };
+    }
+    if let Some(cluster_enabled) = args.cluster_enabled {
+        config.cluster_enabled = Config::parse_bool(&cluster_enabled)?;
+    }
+    if let Some(cluster_config_file) = args.cluster_config_file {
+        config.cluster_config_file = cluster_config_file;
+    }
+    if let Some(node_timeout) = args.cluster_node_timeout {
+        config.cluster_node_timeout = node_timeout;
+    }
+    if let Some(announce_ip) = args.cluster_announce_ip {
+        config.cluster_announce_ip = Some(announce_ip);
+    }
+    if let Some(max_keys) = args.tracking_table_max_keys {
+        config.tracking_table_max_keys = max_keys;
+    }
+    if let Some(sentinel_config_file) = args.sentinel_config_file {
+        config.sentinel_config_file = sentinel_config_file;
+    }
+    if let Some(events) = args.notify_keyspace_events {
+        config.notify_keyspace_events = KeyspaceEvents::parse(&events)?;
+    }
+    if config.sentinel && (config.cluster_enabled || args.replica_of.is_some()) {
+        return Err(anyhow!(
+            "--sentinel cannot be combined with --cluster-enabled or --replicaof"
+        ));
+    }
     let role = match args.replica_of {
         Some(s) => {
-            let (master_host, master_port) = s.split_once(' ').context("invalid value for --replicaof")?;
-            let master_host: Ipv4Addr = if master_host == "localhost" {
-                "127.0.0.1".parse().unwrap()
-            } else {
-                master_host
-                    .parse()
-                    .expect("master host should be a valid ipv4 address")
-            };
-            let master_port: u16 = master_port.parse().unwrap();
+            let (master_host, master_port) =
+                Config::parse_host_port(&s).context("invalid value for --replicaof")?;
 
             Role::Slave {
                 master_host,
                 master_port,
             }
         }
-        _ => Role::Master {
-            replication_id: "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_owned(),
-            replication_offset: 0,
-        },
+        _ => Role::Master,
     };
-    dbg!(port);
-    dbg!(&role);
 
-    let server = Server::new(role, port);
+    let server = Server::new(role, config);
     server.start().await?;
 
     Ok(())
 }
+
+const SENTINEL_PORT: u16 = 26379;
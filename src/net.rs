@@ -1,14 +1,23 @@
-use std::io::Cursor;
+use std::{
+    io::{self, Cursor, Read},
+    net::SocketAddr,
+};
 
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use bytes::{Buf, Bytes, BytesMut};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, BufWriter},
-    net::TcpStream,
+    net::{TcpListener, TcpSocket, TcpStream},
+    runtime::Handle,
 };
 
 use crate::frame::{Frame, ParseError};
 
+/// Length of the mark ending diskless RDB transfers.
+pub const EOF_MARK_LEN: usize = 40;
+// Same as the one of `TcpListener::bind`.
+const LISTEN_BACKLOG: u32 = 1024;
+
 pub struct FrameStream {
     stream: BufWriter<TcpStream>,
     buf: BytesMut,
@@ -23,15 +32,18 @@ impl FrameStream {
     }
 
     pub async fn read_frame(&mut self) -> anyhow::Result<Option<Frame>> {
+        Ok(self.read_frame_raw().await?.map(|(frame, _)| frame))
+    }
+
+    /// Reads a frame along with its bytes as received on the wire.
+    pub async fn read_frame_raw(&mut self) -> anyhow::Result<Option<(Frame, Bytes)>> {
         loop {
-            dbg!(&self.buf);
             let mut cursor = Cursor::new(&self.buf[..]);
             match Frame::parse(&mut cursor) {
                 Ok(frame) => {
-                    let frame_len = cursor.position();
-                    self.buf.advance(frame_len as usize);
-                    println!("received {:?}", frame);
-                    return Ok(Some(frame));
+                    let frame_len = cursor.position() as usize;
+                    let raw = self.buf.split_to(frame_len).freeze();
+                    return Ok(Some((frame, raw)));
                 }
                 Err(ParseError::Incomplete) => (),
                 Err(ParseError::Other(err)) => return Err(err),
@@ -46,47 +58,70 @@ impl FrameStream {
         }
     }
 
-    pub async fn write_frame(&mut self, frame: Frame) -> anyhow::Result<()> {
-        println!("write {:?}", frame);
-        match frame {
-            Frame::Bulk(mut bytes) => {
-                self.stream.write_all(b"$").await?;
-                self.stream
-                    .write_all(bytes.len().to_string().as_bytes())
-                    .await?;
-                self.stream.write_all(b"\r\n").await?;
-                self.stream.write_all_buf(&mut bytes).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Simple(s) => {
-                self.stream.write_all(b"+").await?;
-                self.stream.write_all(s.as_bytes()).await?;
-                self.stream.write_all(b"\r\n").await?;
-            }
-            Frame::Array(frames) => {
-                self.stream.write_all(b"*").await?;
-                self.stream
-                    .write_all(frames.len().to_string().as_bytes())
-                    .await?;
-                self.stream.write_all(b"\r\n").await?;
-                for frame in frames {
-                    Box::pin(self.write_frame(frame)).await?;
-                }
-            }
-            Frame::Error(mut bytes) => {
-                self.stream.write_all(b"-").await?;
-                self.stream.write_all_buf(&mut bytes).await?;
-                self.stream.write_all(b"\r\n").await?;
+    /// Reads the header of an RDB payload sent during a full resync:
+    /// `$<len>`, or for diskless transfers, `$EOF:<mark>`.
+    pub async fn read_rdb_header(&mut self) -> anyhow::Result<RdbTransfer> {
+        let header = loop {
+            if let Some(pos) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = self.buf.split_to(pos + 2).freeze();
+                break line.slice(..pos);
             }
-            Frame::Null => {
-                self.stream.write_all(b"_\r\n").await?;
+            self.fill_buf().await?;
+        };
+        if header.first() != Some(&b'$') {
+            return Err(anyhow!(
+                "expected rdb payload, got {}",
+                header.escape_ascii()
+            ));
+        }
+
+        if let Some(mark) = header.strip_prefix(b"$EOF:") {
+            if mark.len() != EOF_MARK_LEN {
+                return Err(anyhow!("invalid rdb eof mark: {}", mark.escape_ascii()));
             }
+            return Ok(RdbTransfer::EofMark(Bytes::copy_from_slice(mark)));
         }
+
+        let len = std::str::from_utf8(&header[1..])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .with_context(|| format!("invalid rdb length: {}", header.escape_ascii()))?;
+
+        Ok(RdbTransfer::Len(len))
+    }
+
+    async fn fill_buf(&mut self) -> anyhow::Result<()> {
+        let n = self.stream.read_buf(&mut self.buf).await?;
+        if n == 0 {
+            return Err(anyhow!("connection reset by peer"));
+        }
+
+        Ok(())
+    }
+
+    pub async fn write_frame(&mut self, frame: Frame) -> anyhow::Result<()> {
+        self.stream.write_all(&frame.to_bytes()).await?;
         self.stream.flush().await?;
 
         Ok(())
     }
 
+    pub async fn write_raw(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.stream.write_all(bytes).await?;
+        self.stream.flush().await?;
+
+        Ok(())
+    }
+
+    /// Writes an RDB payload as sent during a full resync: a bulk string without
+    /// the trailing CRLF.
+    pub async fn write_rdb(&mut self, rdb: &[u8]) -> anyhow::Result<()> {
+        self.stream
+            .write_all(format!("${}\r\n", rdb.len()).as_bytes())
+            .await?;
+        self.write_raw(rdb).await
+    }
+
     pub async fn write_bulk(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
         self.write_frame(Frame::Bulk(Bytes::copy_from_slice(bytes)))
             .await
@@ -96,14 +131,182 @@ impl FrameStream {
         &mut self,
         frames: impl IntoIterator<Item = impl AsRef<[u8]>>,
     ) -> anyhow::Result<()> {
-        let frames: Vec<_> = frames
-            .into_iter()
-            .map(|f| Frame::Bulk(Bytes::copy_from_slice(f.as_ref())))
-            .collect();
-        self.write_frame(Frame::Array(frames)).await
+        self.write_frame(Frame::bulk_array(frames)).await
     }
 
     pub fn stream(&mut self) -> &mut BufWriter<TcpStream> {
         &mut self.stream
     }
 }
+
+/// How the master delimits the RDB payload of a full resync.
+pub enum RdbTransfer {
+    /// A bulk string of this many bytes, without the trailing CRLF.
+    Len(usize),
+    /// The payload followed by this mark, for diskless transfers.
+    EofMark(Bytes),
+}
+
+/// Reads an RDB payload from the connection on a blocking thread, so that it
+/// is loaded or saved as it arrives rather than buffered whole.
+pub struct RdbReader {
+    stream: FrameStream,
+    /// What is left to read: the mark, or the number of bytes.
+    transfer: RdbTransfer,
+    runtime: Handle,
+}
+
+impl RdbReader {
+    pub fn new(stream: FrameStream, transfer: RdbTransfer, runtime: Handle) -> Self {
+        RdbReader {
+            stream,
+            transfer,
+            runtime,
+        }
+    }
+
+    /// Skips what is left of the payload, and returns the connection, whose
+    /// buffer keeps whatever the master sent after it.
+    pub fn finish(mut self) -> anyhow::Result<FrameStream> {
+        io::copy(&mut self, &mut io::sink())?;
+
+        Ok(self.stream)
+    }
+
+    fn fill_buf(&mut self) -> io::Result<()> {
+        let stream = &mut self.stream;
+        let n = self
+            .runtime
+            .block_on(stream.stream.read_buf(&mut stream.buf))?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection reset by peer",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for RdbReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let available = loop {
+            match &self.transfer {
+                RdbTransfer::Len(0) => return Ok(0),
+                RdbTransfer::Len(left) => {
+                    if !self.stream.buf.is_empty() {
+                        break (*left).min(self.stream.buf.len());
+                    }
+                }
+                RdbTransfer::EofMark(mark) => {
+                    let buf = &self.stream.buf;
+                    match buf.windows(EOF_MARK_LEN).position(|w| w == &mark[..]) {
+                        Some(0) => {
+                            self.stream.buf.advance(EOF_MARK_LEN);
+                            self.transfer = RdbTransfer::Len(0);
+                            return Ok(0);
+                        }
+                        Some(pos) => break pos,
+                        // Holds back what may be the start of the mark.
+                        None if buf.len() >= EOF_MARK_LEN => break buf.len() - EOF_MARK_LEN + 1,
+                        None => (),
+                    }
+                }
+            }
+            self.fill_buf()?;
+        };
+
+        let n = available.min(out.len());
+        out[..n].copy_from_slice(&self.stream.buf[..n]);
+        self.stream.buf.advance(n);
+        if let RdbTransfer::Len(left) = &mut self.transfer {
+            *left -= n;
+        }
+
+        Ok(n)
+    }
+}
+
+/// Binds a listener as [`TcpListener::bind`] does, except that IPv6 listeners
+/// only accept IPv6 connections, so that the IPv4 and IPv6 wildcards can be
+/// bound together.
+pub async fn listen(host: &str, port: u16) -> io::Result<TcpListener> {
+    let mut last_err = None;
+    for addr in tokio::net::lookup_host((host, port)).await? {
+        match bind(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            set_only_v6(&socket)?;
+            socket
+        }
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// Sets `IPV6_V6ONLY`, which tokio does not expose.
+#[cfg(unix)]
+fn set_only_v6(socket: &TcpSocket) -> io::Result<()> {
+    use std::{
+        ffi::{c_int, c_void},
+        os::fd::AsRawFd,
+    };
+
+    extern "C" {
+        fn setsockopt(
+            socket: c_int,
+            level: c_int,
+            name: c_int,
+            value: *const c_void,
+            len: u32,
+        ) -> c_int;
+    }
+    const IPPROTO_IPV6: c_int = 41;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const IPV6_V6ONLY: c_int = 26;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const IPV6_V6ONLY: c_int = 27;
+
+    let on: c_int = 1;
+    // SAFETY: the descriptor stays open for the duration of the call, and
+    // the option value is an int as the length says.
+    let ret = unsafe {
+        setsockopt(
+            socket.as_raw_fd(),
+            IPPROTO_IPV6,
+            IPV6_V6ONLY,
+            &on as *const c_int as *const c_void,
+            std::mem::size_of::<c_int>() as u32,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Windows sockets are IPv6 only by default.
+#[cfg(not(unix))]
+fn set_only_v6(_socket: &TcpSocket) -> io::Result<()> {
+    Ok(())
+}
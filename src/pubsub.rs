@@ -0,0 +1,188 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use bytes::Bytes;
+use tokio::sync::mpsc;
+
+use crate::frame::Frame;
+
+/// The connection of a subscribed client, which pushes the messages to it.
+pub type Subscriber = mpsc::UnboundedSender<Frame>;
+
+/// The channels and patterns clients subscribed to, with the client IDs.
+#[derive(Default)]
+pub struct PubSub {
+    channels: Mutex<HashMap<String, HashMap<u64, Subscriber>>>,
+    patterns: Mutex<HashMap<String, HashMap<u64, Subscriber>>>,
+}
+
+impl PubSub {
+    pub fn subscribe(&self, channel: &str, id: u64, subscriber: &Subscriber) {
+        add(&self.channels, channel, id, subscriber);
+    }
+
+    pub fn unsubscribe(&self, channel: &str, id: u64) {
+        remove(&self.channels, channel, id);
+    }
+
+    pub fn psubscribe(&self, pattern: &str, id: u64, subscriber: &Subscriber) {
+        add(&self.patterns, pattern, id, subscriber);
+    }
+
+    pub fn punsubscribe(&self, pattern: &str, id: u64) {
+        remove(&self.patterns, pattern, id);
+    }
+
+    /// Sends a message to the subscribers of a channel, and to the ones of
+    /// the patterns matching it. Returns the number of clients it was sent
+    /// to.
+    pub fn publish(&self, channel: &str, message: Bytes) -> usize {
+        let mut receivers = 0;
+        if let Some(subscribers) = self.channels.lock().unwrap().get(channel) {
+            let frame = || {
+                Frame::Push(vec![
+                    Frame::Bulk(Bytes::from_static(b"message")),
+                    Frame::Bulk(Bytes::from(channel.to_owned())),
+                    Frame::Bulk(message.clone()),
+                ])
+            };
+            receivers += subscribers
+                .values()
+                .filter(|subscriber| subscriber.send(frame()).is_ok())
+                .count();
+        }
+        for (pattern, subscribers) in self.patterns.lock().unwrap().iter() {
+            if !glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                continue;
+            }
+            let frame = || {
+                Frame::Push(vec![
+                    Frame::Bulk(Bytes::from_static(b"pmessage")),
+                    Frame::Bulk(Bytes::from(pattern.clone())),
+                    Frame::Bulk(Bytes::from(channel.to_owned())),
+                    Frame::Bulk(message.clone()),
+                ])
+            };
+            receivers += subscribers
+                .values()
+                .filter(|subscriber| subscriber.send(frame()).is_ok())
+                .count();
+        }
+
+        receivers
+    }
+}
+
+fn add(
+    subscriptions: &Mutex<HashMap<String, HashMap<u64, Subscriber>>>,
+    name: &str,
+    id: u64,
+    subscriber: &Subscriber,
+) {
+    subscriptions
+        .lock()
+        .unwrap()
+        .entry(name.to_owned())
+        .or_default()
+        .insert(id, subscriber.clone());
+}
+
+fn remove(subscriptions: &Mutex<HashMap<String, HashMap<u64, Subscriber>>>, name: &str, id: u64) {
+    let mut subscriptions = subscriptions.lock().unwrap();
+    if let Some(subscribers) = subscriptions.get_mut(name) {
+        subscribers.remove(&id);
+        if subscribers.is_empty() {
+            subscriptions.remove(name);
+        }
+    }
+}
+
+/// Matches a string against a glob-style pattern, where `*` matches any
+/// sequence, `?` any byte, `[...]` a set of bytes or ranges, possibly negated
+/// with `^`, and `\` escapes the next byte.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => string.is_empty(),
+        Some((b'*', rest)) => (0..=string.len()).any(|skip| glob_match(rest, &string[skip..])),
+        Some((b'?', rest)) => !string.is_empty() && glob_match(rest, &string[1..]),
+        Some((b'[', rest)) => {
+            let Some((&byte, string_rest)) = string.split_first() else {
+                return false;
+            };
+            let (negated, mut set) = match rest.split_first() {
+                Some((b'^', set)) => (true, set),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match set {
+                    // An unclosed set ends with the pattern.
+                    [] => break,
+                    [b']', tail @ ..] => {
+                        set = tail;
+                        break;
+                    }
+                    [b'\\', escaped, tail @ ..] => {
+                        matched |= *escaped == byte;
+                        set = tail;
+                    }
+                    [start, b'-', end, tail @ ..] if *end != b']' => {
+                        let (low, high) = if start <= end {
+                            (*start, *end)
+                        } else {
+                            (*end, *start)
+                        };
+                        matched |= (low..=high).contains(&byte);
+                        set = tail;
+                    }
+                    [other, tail @ ..] => {
+                        matched |= *other == byte;
+                        set = tail;
+                    }
+                }
+            }
+            matched != negated && glob_match(set, string_rest)
+        }
+        Some((b'\\', [escaped, rest @ ..])) => {
+            string.first() == Some(escaped) && glob_match(rest, &string[1..])
+        }
+        Some((&literal, rest)) => {
+            string.first() == Some(&literal) && glob_match(rest, &string[1..])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"__keyspace@0__:*", b"__keyspace@0__:foo"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(!glob_match(b"a*", b"ba"));
+    }
+
+    #[test]
+    fn test_publish() {
+        let pubsub = PubSub::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        pubsub.subscribe("news", 1, &tx);
+        pubsub.psubscribe("n*", 1, &tx);
+
+        assert_eq!(2, pubsub.publish("news", Bytes::from_static(b"hi")));
+        assert!(matches!(rx.try_recv(), Ok(Frame::Push(frames)) if frames.len() == 3));
+        assert!(matches!(rx.try_recv(), Ok(Frame::Push(frames)) if frames.len() == 4));
+        pubsub.unsubscribe("news", 1);
+        pubsub.punsubscribe("n*", 1);
+        assert_eq!(0, pubsub.publish("news", Bytes::from_static(b"hi")));
+    }
+}
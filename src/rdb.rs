@@ -0,0 +1,1532 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+use tokio::time::Instant;
+
+use crate::db::{
+    Consumer, ConsumerGroup, DbValue, Keyspace, PendingEntry, Stream, StreamId, Value,
+};
+
+const RDB_TYPE_STRING: u8 = 0;
+const RDB_TYPE_LIST: u8 = 1;
+const RDB_TYPE_SET: u8 = 2;
+const RDB_TYPE_ZSET: u8 = 3;
+const RDB_TYPE_HASH: u8 = 4;
+const RDB_TYPE_ZSET_2: u8 = 5;
+const RDB_TYPE_MODULE_PRE_GA: u8 = 6;
+const RDB_TYPE_MODULE_2: u8 = 7;
+const RDB_TYPE_HASH_ZIPMAP: u8 = 9;
+const RDB_TYPE_LIST_ZIPLIST: u8 = 10;
+const RDB_TYPE_SET_INTSET: u8 = 11;
+const RDB_TYPE_ZSET_ZIPLIST: u8 = 12;
+const RDB_TYPE_HASH_ZIPLIST: u8 = 13;
+const RDB_TYPE_LIST_QUICKLIST: u8 = 14;
+const RDB_TYPE_STREAM_LISTPACKS: u8 = 15;
+const RDB_TYPE_HASH_LISTPACK: u8 = 16;
+const RDB_TYPE_ZSET_LISTPACK: u8 = 17;
+const RDB_TYPE_LIST_QUICKLIST_2: u8 = 18;
+const RDB_TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const RDB_TYPE_SET_LISTPACK: u8 = 20;
+const RDB_TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+const RDB_OPCODE_FUNCTION_PRE_GA: u8 = 0xF5;
+const RDB_OPCODE_FUNCTION_2: u8 = 0xF6;
+const RDB_OPCODE_FREQ: u8 = 0xF7;
+const RDB_OPCODE_IDLE: u8 = 0xF8;
+const RDB_OPCODE_MODULE_AUX: u8 = 0xF9;
+const RDB_OPCODE_AUX: u8 = 0xFA;
+const RDB_OPCODE_RESIZEDB: u8 = 0xFB;
+const RDB_OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const RDB_OPCODE_EXPIRETIME: u8 = 0xFD;
+const RDB_OPCODE_SELECTDB: u8 = 0xFE;
+const RDB_OPCODE_EOF: u8 = 0xFF;
+
+const RDB_ENC_INT8: u8 = 0;
+const RDB_ENC_INT16: u8 = 1;
+const RDB_ENC_INT32: u8 = 2;
+const RDB_ENC_LZF: u8 = 3;
+
+const QUICKLIST_NODE_CONTAINER_PLAIN: u64 = 1;
+const QUICKLIST_NODE_CONTAINER_PACKED: u64 = 2;
+
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+pub const RDB_VERSION: u32 = 11;
+
+// Most elements preallocated for a collection read from an input of unknown
+// length.
+const MAX_STREAMED_CAPACITY: u64 = 4096;
+
+/// The contents of an RDB snapshot.
+#[derive(Debug, Default)]
+pub struct Rdb {
+    pub version: u32,
+    pub aux: Vec<(Bytes, Bytes)>,
+    pub databases: BTreeMap<usize, Keyspace>,
+}
+
+impl Rdb {
+    /// Parses an RDB file. Expired keys are dropped unless `keep_expired`, as
+    /// replicas keep them until their master deletes them.
+    pub fn parse(input: &[u8], keep_expired: bool) -> anyhow::Result<Self> {
+        Self::read(Reader::new(input), keep_expired)
+    }
+
+    /// Parses an RDB file as it is read from `input`, which is left right
+    /// after the checksum.
+    pub fn load(input: impl Read, keep_expired: bool) -> anyhow::Result<Self> {
+        Self::read(Reader::streaming(input), keep_expired)
+    }
+
+    fn read<R: Read>(mut reader: Reader<R>, keep_expired: bool) -> anyhow::Result<Self> {
+        let magic = reader.read_exact(5)?;
+        if magic != b"REDIS" {
+            return Err(anyhow!(
+                "not an rdb file: bad magic {}",
+                magic.escape_ascii()
+            ));
+        }
+        let version = reader.read_exact(4)?;
+        let version = std::str::from_utf8(version)
+            .ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .ok_or_else(|| anyhow!("invalid rdb version: {}", version.escape_ascii()))?;
+        if version == 0 || version > RDB_VERSION {
+            return Err(anyhow!("unsupported rdb version: {}", version));
+        }
+
+        let mut rdb = Rdb {
+            version,
+            ..Default::default()
+        };
+        let now = unix_time_ms();
+        let mut db_index = 0;
+        let mut expire_at = None;
+        loop {
+            let opcode = reader.read_u8()?;
+            match opcode {
+                RDB_OPCODE_EOF => break,
+                RDB_OPCODE_SELECTDB => db_index = reader.read_length()? as usize,
+                RDB_OPCODE_RESIZEDB => {
+                    let db_size = reader.read_length()?;
+                    let _expires_size = reader.read_length()?;
+                    let capacity = reader.capacity(db_size);
+                    rdb.databases.entry(db_index).or_default().reserve(capacity);
+                }
+                RDB_OPCODE_AUX => {
+                    let key = reader.read_string()?;
+                    let value = reader.read_string()?;
+                    rdb.aux.push((key, value));
+                }
+                RDB_OPCODE_EXPIRETIME_MS => expire_at = Some(reader.read_u64_le()?),
+                RDB_OPCODE_EXPIRETIME => expire_at = Some(reader.read_u32_le()? as u64 * 1000),
+                RDB_OPCODE_IDLE => {
+                    reader.read_length()?;
+                }
+                RDB_OPCODE_FREQ => {
+                    reader.read_u8()?;
+                }
+                RDB_OPCODE_FUNCTION_2 => {
+                    reader.read_string()?;
+                }
+                RDB_OPCODE_MODULE_AUX | RDB_OPCODE_FUNCTION_PRE_GA => {
+                    return Err(anyhow!("unsupported rdb opcode: {:#x}", opcode));
+                }
+                value_type => {
+                    let key = reader.read_string()?;
+                    let value = reader
+                        .read_value(value_type)
+                        .with_context(|| format!("failed to load key {}", key.escape_ascii()))?;
+                    // Keys are strings here, so others are left out rather
+                    // than failing the whole load.
+                    let Ok(key) = String::from_utf8(key.to_vec()) else {
+                        println!("skipping non utf-8 key {}", key.escape_ascii());
+                        expire_at = None;
+                        continue;
+                    };
+
+                    let expiry = match expire_at.take() {
+                        Some(at) if at <= now && !keep_expired => continue,
+                        // Already expired, however far in the past.
+                        Some(at) if at <= now => Some(
+                            Instant::now()
+                                .checked_sub(Duration::from_millis(now - at))
+                                .unwrap_or_else(Instant::now),
+                        ),
+                        Some(at) => Instant::now().checked_add(Duration::from_millis(at - now)),
+                        None => None,
+                    };
+                    rdb.databases
+                        .entry(db_index)
+                        .or_default()
+                        .insert(key, DbValue::new(value).with_expiry(expiry));
+                }
+            }
+        }
+
+        if version >= 5 {
+            let actual = reader.crc;
+            let expected = reader.read_u64_le()?;
+            if expected != 0 && expected != actual {
+                return Err(anyhow!(
+                    "rdb checksum mismatch: expected {:#x}, got {:#x}",
+                    expected,
+                    actual
+                ));
+            }
+        }
+
+        Ok(rdb)
+    }
+}
+
+struct Reader<R> {
+    input: R,
+    /// Length of the input, when known up front.
+    len: Option<usize>,
+    pos: usize,
+    /// CRC64 of the bytes read so far.
+    crc: u64,
+    buf: Vec<u8>,
+}
+
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+impl<'a> Reader<&'a [u8]> {
+    fn new(input: &'a [u8]) -> Self {
+        Reader {
+            len: Some(input.len()),
+            ..Reader::streaming(input)
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        // Reading a slice advances it.
+        self.input.is_empty()
+    }
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads an input of unknown length, such as a file or a socket.
+    fn streaming(input: R) -> Self {
+        Reader {
+            input,
+            len: None,
+            pos: 0,
+            crc: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Bounds a capacity read from the input by the bytes left, as every
+    /// element takes at least one, so that corrupt lengths fail on the
+    /// missing data rather than on the allocation.
+    fn capacity(&self, len: u64) -> usize {
+        let left = self.len.map_or(MAX_STREAMED_CAPACITY, |input_len| {
+            (input_len - self.pos) as u64
+        });
+        len.min(left) as usize
+    }
+
+    fn read_exact(&mut self, n: usize) -> anyhow::Result<&[u8]> {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        self.read_into(n, &mut buf)?;
+        self.buf = buf;
+
+        Ok(&self.buf)
+    }
+
+    fn read_bytes(&mut self, n: usize) -> anyhow::Result<Bytes> {
+        let mut buf = Vec::new();
+        self.read_into(n, &mut buf)?;
+
+        Ok(buf.into())
+    }
+
+    fn read_into(&mut self, n: usize, buf: &mut Vec<u8>) -> anyhow::Result<()> {
+        if self.len.is_some_and(|len| len - self.pos < n) {
+            return Err(anyhow!("unexpected end of input"));
+        }
+        // The buffer only grows as data arrives, in case `n` is corrupt.
+        (&mut self.input).take(n as u64).read_to_end(buf)?;
+        if buf.len() < n {
+            return Err(anyhow!("unexpected end of input"));
+        }
+        self.pos += n;
+        self.crc = crc64(self.crc, buf);
+
+        Ok(())
+    }
+
+    fn read_u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.read_exact(1)?[0])
+    }
+
+    fn read_u16_le(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_le_bytes(self.read_exact(2)?.try_into()?))
+    }
+
+    fn read_u32_le(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.read_exact(4)?.try_into()?))
+    }
+
+    fn read_u64_le(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.read_exact(8)?.try_into()?))
+    }
+
+    fn read_u64_be(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_be_bytes(self.read_exact(8)?.try_into()?))
+    }
+
+    fn read_length_encoding(&mut self) -> anyhow::Result<Length> {
+        let first = self.read_u8()?;
+        let len = match first >> 6 {
+            0b00 => (first & 0x3F) as u64,
+            0b01 => (((first & 0x3F) as u64) << 8) | self.read_u8()? as u64,
+            0b10 => match first {
+                0x80 => u32::from_be_bytes(self.read_exact(4)?.try_into()?) as u64,
+                0x81 => self.read_u64_be()?,
+                _ => return Err(anyhow!("invalid length encoding: {:#x}", first)),
+            },
+            _ => return Ok(Length::Encoded(first & 0x3F)),
+        };
+
+        Ok(Length::Len(len))
+    }
+
+    fn read_length(&mut self) -> anyhow::Result<u64> {
+        match self.read_length_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(anyhow!("expected length, got encoded string")),
+        }
+    }
+
+    fn read_string(&mut self) -> anyhow::Result<Bytes> {
+        match self.read_length_encoding()? {
+            Length::Len(len) => self.read_bytes(len as usize),
+            Length::Encoded(RDB_ENC_INT8) => Ok(int_to_bytes(self.read_u8()? as i8 as i64)),
+            Length::Encoded(RDB_ENC_INT16) => Ok(int_to_bytes(self.read_u16_le()? as i16 as i64)),
+            Length::Encoded(RDB_ENC_INT32) => Ok(int_to_bytes(self.read_u32_le()? as i32 as i64)),
+            Length::Encoded(RDB_ENC_LZF) => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                let compressed = self.read_exact(compressed_len)?;
+
+                Ok(lzf_decompress(compressed, len)?.into())
+            }
+            Length::Encoded(enc) => Err(anyhow!("unknown string encoding: {}", enc)),
+        }
+    }
+
+    fn read_string_double(&mut self) -> anyhow::Result<f64> {
+        match self.read_u8()? {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            len => parse_double(self.read_exact(len as usize)?),
+        }
+    }
+
+    fn read_binary_double(&mut self) -> anyhow::Result<f64> {
+        Ok(f64::from_bits(self.read_u64_le()?))
+    }
+
+    fn read_stream_id(&mut self) -> anyhow::Result<StreamId> {
+        Ok(StreamId {
+            ms: self.read_length()?,
+            seq: self.read_length()?,
+        })
+    }
+
+    fn read_raw_stream_id(&mut self) -> anyhow::Result<StreamId> {
+        Ok(StreamId {
+            ms: self.read_u64_be()?,
+            seq: self.read_u64_be()?,
+        })
+    }
+
+    fn read_value(&mut self, value_type: u8) -> anyhow::Result<Value> {
+        let value = match value_type {
+            RDB_TYPE_STRING => Value::String(self.read_string()?),
+            RDB_TYPE_LIST => {
+                let len = self.read_length()?;
+                let mut list = VecDeque::with_capacity(self.capacity(len));
+                for _ in 0..len {
+                    list.push_back(self.read_string()?);
+                }
+
+                Value::List(list)
+            }
+            RDB_TYPE_SET => {
+                let len = self.read_length()?;
+                let mut set = HashSet::with_capacity(self.capacity(len));
+                for _ in 0..len {
+                    set.insert(self.read_string()?);
+                }
+
+                Value::Set(set)
+            }
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut zset = HashMap::with_capacity(self.capacity(len));
+                for _ in 0..len {
+                    let member = self.read_string()?;
+                    let score = if value_type == RDB_TYPE_ZSET_2 {
+                        self.read_binary_double()?
+                    } else {
+                        self.read_string_double()?
+                    };
+                    zset.insert(member, score);
+                }
+
+                Value::SortedSet(zset)
+            }
+            RDB_TYPE_HASH => {
+                let len = self.read_length()?;
+                let mut hash = HashMap::with_capacity(self.capacity(len));
+                for _ in 0..len {
+                    let field = self.read_string()?;
+                    let value = self.read_string()?;
+                    hash.insert(field, value);
+                }
+
+                Value::Hash(hash)
+            }
+            RDB_TYPE_HASH_ZIPMAP => {
+                let blob = self.read_string()?;
+                Value::Hash(pairs(parse_zipmap(&blob)?)?.into_iter().collect())
+            }
+            RDB_TYPE_LIST_ZIPLIST => {
+                let blob = self.read_string()?;
+                Value::List(to_bytes(parse_ziplist(&blob)?).collect())
+            }
+            RDB_TYPE_SET_INTSET => {
+                let blob = self.read_string()?;
+                Value::Set(parse_intset(&blob)?.into_iter().map(int_to_bytes).collect())
+            }
+            RDB_TYPE_SET_LISTPACK => {
+                let blob = self.read_string()?;
+                Value::Set(to_bytes(parse_listpack(&blob)?).collect())
+            }
+            RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+                let blob = self.read_string()?;
+                let entries = if value_type == RDB_TYPE_ZSET_ZIPLIST {
+                    parse_ziplist(&blob)?
+                } else {
+                    parse_listpack(&blob)?
+                };
+                let mut zset = HashMap::new();
+                for (member, score) in pairs(to_bytes(entries).collect())? {
+                    zset.insert(member, parse_double(&score)?);
+                }
+
+                Value::SortedSet(zset)
+            }
+            RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK => {
+                let blob = self.read_string()?;
+                let entries = if value_type == RDB_TYPE_HASH_ZIPLIST {
+                    parse_ziplist(&blob)?
+                } else {
+                    parse_listpack(&blob)?
+                };
+
+                Value::Hash(pairs(to_bytes(entries).collect())?.into_iter().collect())
+            }
+            RDB_TYPE_LIST_QUICKLIST => {
+                let nodes = self.read_length()?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    let blob = self.read_string()?;
+                    list.extend(to_bytes(parse_ziplist(&blob)?));
+                }
+
+                Value::List(list)
+            }
+            RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut list = VecDeque::new();
+                for _ in 0..nodes {
+                    let container = self.read_length()?;
+                    let blob = self.read_string()?;
+                    match container {
+                        QUICKLIST_NODE_CONTAINER_PLAIN => list.push_back(blob),
+                        QUICKLIST_NODE_CONTAINER_PACKED => {
+                            list.extend(to_bytes(parse_listpack(&blob)?))
+                        }
+                        _ => return Err(anyhow!("unknown quicklist container: {}", container)),
+                    }
+                }
+
+                Value::List(list)
+            }
+            RDB_TYPE_STREAM_LISTPACKS
+            | RDB_TYPE_STREAM_LISTPACKS_2
+            | RDB_TYPE_STREAM_LISTPACKS_3 => Value::Stream(self.read_stream(value_type)?),
+            RDB_TYPE_MODULE_PRE_GA | RDB_TYPE_MODULE_2 => {
+                return Err(anyhow!("module values are not supported"))
+            }
+            _ => return Err(anyhow!("unknown value type: {}", value_type)),
+        };
+
+        Ok(value)
+    }
+
+    fn read_stream(&mut self, value_type: u8) -> anyhow::Result<Stream> {
+        let mut stream = Stream::default();
+
+        let nodes = self.read_length()?;
+        for _ in 0..nodes {
+            let master_id = self.read_string()?;
+            if master_id.len() != 16 {
+                return Err(anyhow!(
+                    "invalid stream node key length: {}",
+                    master_id.len()
+                ));
+            }
+            let master_id = Reader::new(&master_id).read_raw_stream_id()?;
+            let listpack = self.read_string()?;
+            read_stream_listpack(master_id, parse_listpack(&listpack)?, &mut stream)?;
+        }
+
+        let _len = self.read_length()?;
+        stream.last_id = self.read_stream_id()?;
+        if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            stream.first_id = self.read_stream_id()?;
+            stream.max_deleted_id = self.read_stream_id()?;
+            stream.entries_added = self.read_length()?;
+        } else {
+            stream.first_id = stream.entries.keys().next().copied().unwrap_or_default();
+            stream.entries_added = stream.entries.len() as u64;
+        }
+
+        let groups = self.read_length()?;
+        for _ in 0..groups {
+            let name = self.read_string()?;
+            let last_id = self.read_stream_id()?;
+            let entries_read = if value_type >= RDB_TYPE_STREAM_LISTPACKS_2 {
+                self.read_length()? as i64
+            } else {
+                -1
+            };
+
+            let pending_len = self.read_length()?;
+            let mut pending = Vec::with_capacity(self.capacity(pending_len));
+            for _ in 0..pending_len {
+                pending.push(PendingEntry {
+                    id: self.read_raw_stream_id()?,
+                    delivery_time: self.read_u64_le()?,
+                    delivery_count: self.read_length()?,
+                });
+            }
+
+            let consumers_len = self.read_length()?;
+            let mut consumers = Vec::with_capacity(self.capacity(consumers_len));
+            for _ in 0..consumers_len {
+                let name = self.read_string()?;
+                let seen_time = self.read_u64_le()?;
+                let active_time = if value_type >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                    self.read_u64_le()?
+                } else {
+                    seen_time
+                };
+                let pending_len = self.read_length()?;
+                let mut pending = Vec::with_capacity(self.capacity(pending_len));
+                for _ in 0..pending_len {
+                    pending.push(self.read_raw_stream_id()?);
+                }
+                consumers.push(Consumer {
+                    name,
+                    seen_time,
+                    active_time,
+                    pending,
+                });
+            }
+
+            stream.groups.push(ConsumerGroup {
+                name,
+                last_id,
+                entries_read,
+                pending,
+                consumers,
+            });
+        }
+
+        Ok(stream)
+    }
+}
+
+/// An element of a ziplist or listpack, which stores small integers natively.
+#[derive(Debug, PartialEq)]
+enum PackedEntry {
+    Int(i64),
+    Str(Bytes),
+}
+
+impl PackedEntry {
+    fn into_bytes(self) -> Bytes {
+        match self {
+            PackedEntry::Int(n) => int_to_bytes(n),
+            PackedEntry::Str(bytes) => bytes,
+        }
+    }
+
+    fn as_int(&self) -> anyhow::Result<i64> {
+        match self {
+            PackedEntry::Int(n) => Ok(*n),
+            PackedEntry::Str(bytes) => atoi::atoi(bytes)
+                .ok_or_else(|| anyhow!("expected integer, got {}", bytes.escape_ascii())),
+        }
+    }
+}
+
+fn to_bytes(entries: Vec<PackedEntry>) -> impl Iterator<Item = Bytes> {
+    entries.into_iter().map(PackedEntry::into_bytes)
+}
+
+fn pairs(entries: Vec<Bytes>) -> anyhow::Result<Vec<(Bytes, Bytes)>> {
+    let chunks = entries.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return Err(anyhow!(
+            "expected an even number of entries, got {}",
+            entries.len()
+        ));
+    }
+
+    Ok(chunks.map(|c| (c[0].clone(), c[1].clone())).collect())
+}
+
+fn read_stream_listpack(
+    master_id: StreamId,
+    entries: Vec<PackedEntry>,
+    stream: &mut Stream,
+) -> anyhow::Result<()> {
+    let mut entries = entries.into_iter();
+    let entries_len = entries.len();
+    let mut next = || {
+        entries
+            .next()
+            .ok_or_else(|| anyhow!("truncated stream listpack"))
+    };
+
+    let count = next()?.as_int()?;
+    let deleted = next()?.as_int()?;
+    let master_fields_len = next()?.as_int()?;
+    let mut master_fields = Vec::with_capacity((master_fields_len as usize).min(entries_len));
+    for _ in 0..master_fields_len {
+        master_fields.push(next()?.into_bytes());
+    }
+    // Terminator of the master entry.
+    next()?;
+
+    for _ in 0..count + deleted {
+        let flags = next()?.as_int()?;
+        let id = StreamId {
+            ms: master_id.ms.wrapping_add(next()?.as_int()? as u64),
+            seq: master_id.seq.wrapping_add(next()?.as_int()? as u64),
+        };
+        let mut fields = Vec::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in &master_fields {
+                fields.push((field.clone(), next()?.into_bytes()));
+            }
+        } else {
+            let fields_len = next()?.as_int()?;
+            for _ in 0..fields_len {
+                let field = next()?.into_bytes();
+                let value = next()?.into_bytes();
+                fields.push((field, value));
+            }
+        }
+        // Number of listpack elements in the entry, used for iterating backwards.
+        next()?;
+
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            stream.entries.insert(id, fields);
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_ziplist(input: &[u8]) -> anyhow::Result<Vec<PackedEntry>> {
+    let mut reader = Reader::new(input);
+    let _zlbytes = reader.read_u32_le()?;
+    let _zltail = reader.read_u32_le()?;
+    let _zllen = reader.read_u16_le()?;
+
+    let mut entries = Vec::new();
+    loop {
+        let prevlen = reader.read_u8()?;
+        if prevlen == 0xFF {
+            break;
+        }
+        if prevlen == 0xFE {
+            reader.read_u32_le()?;
+        }
+
+        let encoding = reader.read_u8()?;
+        let entry = match encoding >> 6 {
+            0b00 => {
+                let len = (encoding & 0x3F) as usize;
+                PackedEntry::Str(Bytes::copy_from_slice(reader.read_exact(len)?))
+            }
+            0b01 => {
+                let len = (((encoding & 0x3F) as usize) << 8) | reader.read_u8()? as usize;
+                PackedEntry::Str(Bytes::copy_from_slice(reader.read_exact(len)?))
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(reader.read_exact(4)?.try_into()?) as usize;
+                PackedEntry::Str(Bytes::copy_from_slice(reader.read_exact(len)?))
+            }
+            _ => PackedEntry::Int(match encoding {
+                0xC0 => reader.read_u16_le()? as i16 as i64,
+                0xD0 => reader.read_u32_le()? as i32 as i64,
+                0xE0 => reader.read_u64_le()? as i64,
+                0xF0 => {
+                    let bytes = reader.read_exact(3)?;
+                    i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as i64 >> 8
+                }
+                0xFE => reader.read_u8()? as i8 as i64,
+                0xF1..=0xFD => (encoding & 0x0F) as i64 - 1,
+                _ => return Err(anyhow!("invalid ziplist encoding: {:#x}", encoding)),
+            }),
+        };
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn parse_listpack(input: &[u8]) -> anyhow::Result<Vec<PackedEntry>> {
+    let mut reader = Reader::new(input);
+    let _total_bytes = reader.read_u32_le()?;
+    let _len = reader.read_u16_le()?;
+
+    let mut entries = Vec::new();
+    loop {
+        let start = reader.pos;
+        let encoding = reader.read_u8()?;
+        let entry = if encoding == 0xFF {
+            break;
+        } else if encoding & 0x80 == 0 {
+            PackedEntry::Int((encoding & 0x7F) as i64)
+        } else if encoding & 0xC0 == 0x80 {
+            let len = (encoding & 0x3F) as usize;
+            PackedEntry::Str(Bytes::copy_from_slice(reader.read_exact(len)?))
+        } else if encoding & 0xE0 == 0xC0 {
+            let n = (((encoding & 0x1F) as i64) << 8) | reader.read_u8()? as i64;
+            PackedEntry::Int(if n >= 1 << 12 { n - (1 << 13) } else { n })
+        } else if encoding & 0xF0 == 0xE0 {
+            let len = (((encoding & 0x0F) as usize) << 8) | reader.read_u8()? as usize;
+            PackedEntry::Str(Bytes::copy_from_slice(reader.read_exact(len)?))
+        } else {
+            match encoding {
+                0xF0 => {
+                    let len = reader.read_u32_le()? as usize;
+                    PackedEntry::Str(Bytes::copy_from_slice(reader.read_exact(len)?))
+                }
+                0xF1 => PackedEntry::Int(reader.read_u16_le()? as i16 as i64),
+                0xF2 => {
+                    let bytes = reader.read_exact(3)?;
+                    PackedEntry::Int(
+                        i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) as i64 >> 8,
+                    )
+                }
+                0xF3 => PackedEntry::Int(reader.read_u32_le()? as i32 as i64),
+                0xF4 => PackedEntry::Int(reader.read_u64_le()? as i64),
+                _ => return Err(anyhow!("invalid listpack encoding: {:#x}", encoding)),
+            }
+        };
+        reader.read_exact(listpack_backlen_size(reader.pos - start))?;
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn listpack_backlen_size(entry_len: usize) -> usize {
+    match entry_len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+fn parse_intset(input: &[u8]) -> anyhow::Result<Vec<i64>> {
+    let mut reader = Reader::new(input);
+    let encoding = reader.read_u32_le()?;
+    let len = reader.read_u32_le()?;
+
+    let mut values = Vec::with_capacity(reader.capacity(len as u64));
+    for _ in 0..len {
+        let value = match encoding {
+            2 => reader.read_u16_le()? as i16 as i64,
+            4 => reader.read_u32_le()? as i32 as i64,
+            8 => reader.read_u64_le()? as i64,
+            _ => return Err(anyhow!("invalid intset encoding: {}", encoding)),
+        };
+        values.push(value);
+    }
+
+    Ok(values)
+}
+
+fn parse_zipmap(input: &[u8]) -> anyhow::Result<Vec<Bytes>> {
+    let mut reader = Reader::new(input);
+    let _zmlen = reader.read_u8()?;
+
+    let mut entries = Vec::new();
+    while !reader.is_empty() {
+        let len = match reader.read_u8()? {
+            0xFF => break,
+            0xFE => reader.read_u32_le()? as usize,
+            len => len as usize,
+        };
+        // Only values carry a count of trailing free bytes.
+        let is_value = entries.len() % 2 == 1;
+        let free = if is_value {
+            reader.read_u8()? as usize
+        } else {
+            0
+        };
+        entries.push(Bytes::copy_from_slice(reader.read_exact(len)?));
+        reader.read_exact(free)?;
+    }
+
+    Ok(entries)
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> anyhow::Result<Vec<u8>> {
+    // Back references expand the input, so it only bounds the initial
+    // capacity.
+    let mut output = Vec::with_capacity(len.min(input.len()));
+    let mut reader = Reader::new(input);
+    while !reader.is_empty() {
+        let ctrl = reader.read_u8()? as usize;
+        if ctrl < 32 {
+            output.extend_from_slice(reader.read_exact(ctrl + 1)?);
+            continue;
+        }
+
+        let mut backref_len = ctrl >> 5;
+        if backref_len == 7 {
+            backref_len += reader.read_u8()? as usize;
+        }
+        let offset = ((ctrl & 0x1F) << 8) + reader.read_u8()? as usize + 1;
+        if offset > output.len() {
+            return Err(anyhow!("invalid lzf back reference"));
+        }
+        let start = output.len() - offset;
+        for i in 0..backref_len + 2 {
+            output.push(output[start + i]);
+        }
+    }
+    if output.len() != len {
+        return Err(anyhow!(
+            "lzf decompressed to {} bytes, expected {}",
+            output.len(),
+            len
+        ));
+    }
+
+    Ok(output)
+}
+
+/// Writes `databases` as an RDB file to `path`, replacing it atomically once complete.
+pub fn save(path: &Path, databases: &[(usize, Vec<&Keyspace>)]) -> anyhow::Result<()> {
+    write_file(path, |writer| write(writer, databases, &[]))
+}
+
+/// Saves an RDB payload as it is received from a master.
+pub fn save_from(path: &Path, input: &mut impl Read) -> anyhow::Result<()> {
+    write_file(path, |writer| {
+        io::copy(input, writer)?;
+        Ok(())
+    })
+}
+
+/// Writes a file through a temp file, so that `path` never holds a partial
+/// RDB file.
+fn write_file(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    // Each write has a temp file of its own, as a save may run while a
+    // replica stores the payload of its master.
+    static NEXT_TEMP: AtomicU64 = AtomicU64::new(0);
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let temp = NEXT_TEMP.fetch_add(1, Ordering::SeqCst);
+    let temp_path = dir.join(format!("temp-{}-{}.rdb", std::process::id(), temp));
+    let file = File::create(&temp_path)
+        .with_context(|| format!("failed to create {}", temp_path.display()))?;
+
+    let mut writer = BufWriter::new(file);
+    write(&mut writer)?;
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)
+        .with_context(|| format!("failed to rename rdb file to {}", path.display()))?;
+
+    Ok(())
+}
+
+/// Writes `databases` as an RDB file, with the given auxiliary fields on top
+/// of the usual ones. Databases may come in several parts, as held by shards.
+pub fn write(
+    writer: impl Write,
+    databases: &[(usize, Vec<&Keyspace>)],
+    aux: &[(&str, &str)],
+) -> anyhow::Result<()> {
+    let mut writer = Writer {
+        inner: writer,
+        crc: 0,
+    };
+
+    writer.write_bytes(format!("REDIS{:04}", RDB_VERSION).as_bytes())?;
+    writer.write_aux("redis-ver", "7.2.0")?;
+    writer.write_aux("redis-bits", "64")?;
+    writer.write_aux("ctime", &(unix_time_ms() / 1000).to_string())?;
+    writer.write_aux("used-mem", "0")?;
+    writer.write_aux("aof-base", "0")?;
+    for (key, value) in aux {
+        writer.write_aux(key, value)?;
+    }
+
+    let now = Instant::now();
+    let now_ms = unix_time_ms();
+    for (index, parts) in databases {
+        let len = parts.iter().map(|part| part.len()).sum::<usize>();
+        if len == 0 {
+            continue;
+        }
+        writer.write_bytes(&[RDB_OPCODE_SELECTDB])?;
+        writer.write_length(*index as u64)?;
+        let expires = parts.iter().map(|part| part.volatile_len()).sum::<usize>();
+        writer.write_bytes(&[RDB_OPCODE_RESIZEDB])?;
+        writer.write_length(len as u64)?;
+        writer.write_length(expires as u64)?;
+
+        for (key, db_value) in parts.iter().flat_map(|part| part.iter()) {
+            if let Some(expiry) = db_value.expiry {
+                if expiry <= now {
+                    continue;
+                }
+                let expire_at = now_ms + (expiry - now).as_millis() as u64;
+                writer.write_bytes(&[RDB_OPCODE_EXPIRETIME_MS])?;
+                writer.write_bytes(&expire_at.to_le_bytes())?;
+            }
+            writer.write_bytes(&[value_type(&db_value.value)])?;
+            writer.write_string(key.as_bytes())?;
+            writer.write_value(&db_value.value)?;
+        }
+    }
+
+    writer.write_bytes(&[RDB_OPCODE_EOF])?;
+    let crc = writer.crc;
+    writer.write_bytes(&crc.to_le_bytes())?;
+    writer.inner.flush()?;
+
+    Ok(())
+}
+
+/// Serializes a value the way DUMP does: its RDB encoding, followed by the
+/// RDB version and a CRC64 of everything before it.
+pub fn dump(value: &Value) -> Vec<u8> {
+    let mut writer = Writer {
+        inner: Vec::new(),
+        crc: 0,
+    };
+    // Writing to a Vec never fails.
+    writer.write_bytes(&[value_type(value)]).unwrap();
+    writer.write_value(value).unwrap();
+    writer
+        .write_bytes(&(RDB_VERSION as u16).to_le_bytes())
+        .unwrap();
+    let crc = writer.crc;
+    let mut payload = writer.inner;
+    payload.extend_from_slice(&crc.to_le_bytes());
+
+    payload
+}
+
+/// Parses a payload serialized by [`dump`], or by DUMP on Redis.
+pub fn restore(payload: &[u8]) -> anyhow::Result<Value> {
+    let Some(body_len) = payload.len().checked_sub(10) else {
+        return Err(anyhow!("DUMP payload version or checksum are wrong"));
+    };
+    let (body, footer) = payload.split_at(body_len + 2);
+    let version = u16::from_le_bytes([body[body_len], body[body_len + 1]]);
+    let checksum = u64::from_le_bytes(footer.try_into().unwrap());
+    // Unlike RDB files, payloads must come with their checksum, as they are
+    // sent by clients.
+    if version as u32 > RDB_VERSION || checksum != crc64(0, body) {
+        return Err(anyhow!("DUMP payload version or checksum are wrong"));
+    }
+
+    let mut reader = Reader::new(&body[..body_len]);
+    let value = reader
+        .read_u8()
+        .and_then(|value_type| reader.read_value(value_type))
+        .ok()
+        .filter(|_| reader.is_empty())
+        .ok_or(anyhow!("Bad data format"))?;
+
+    Ok(value)
+}
+
+fn value_type(value: &Value) -> u8 {
+    // Lists, sets and hashes use the plain encodings, which every Redis version
+    // loads and converts to its preferred in-memory encoding.
+    match value {
+        Value::String(_) => RDB_TYPE_STRING,
+        Value::List(_) => RDB_TYPE_LIST,
+        Value::Set(_) => RDB_TYPE_SET,
+        Value::SortedSet(_) => RDB_TYPE_ZSET_2,
+        Value::Hash(_) => RDB_TYPE_HASH,
+        Value::Stream(_) => RDB_TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+struct Writer<W> {
+    inner: W,
+    crc: u64,
+}
+
+impl<W: Write> Writer<W> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.crc = crc64(self.crc, bytes);
+        self.inner.write_all(bytes)?;
+
+        Ok(())
+    }
+
+    fn write_length(&mut self, len: u64) -> anyhow::Result<()> {
+        if len < 1 << 6 {
+            self.write_bytes(&[len as u8])
+        } else if len < 1 << 14 {
+            self.write_bytes(&[0x40 | (len >> 8) as u8, len as u8])
+        } else if len <= u32::MAX as u64 {
+            self.write_bytes(&[0x80])?;
+            self.write_bytes(&(len as u32).to_be_bytes())
+        } else {
+            self.write_bytes(&[0x81])?;
+            self.write_bytes(&len.to_be_bytes())
+        }
+    }
+
+    fn write_string(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.write_length(bytes.len() as u64)?;
+        self.write_bytes(bytes)
+    }
+
+    fn write_aux(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        self.write_bytes(&[RDB_OPCODE_AUX])?;
+        self.write_string(key.as_bytes())?;
+        self.write_string(value.as_bytes())
+    }
+
+    fn write_stream_id(&mut self, id: StreamId) -> anyhow::Result<()> {
+        self.write_length(id.ms)?;
+        self.write_length(id.seq)
+    }
+
+    fn write_raw_stream_id(&mut self, id: StreamId) -> anyhow::Result<()> {
+        self.write_bytes(&id.ms.to_be_bytes())?;
+        self.write_bytes(&id.seq.to_be_bytes())
+    }
+
+    fn write_value(&mut self, value: &Value) -> anyhow::Result<()> {
+        match value {
+            Value::String(bytes) => self.write_string(bytes)?,
+            Value::List(list) => {
+                self.write_length(list.len() as u64)?;
+                for element in list {
+                    self.write_string(element)?;
+                }
+            }
+            Value::Set(set) => {
+                self.write_length(set.len() as u64)?;
+                for member in set {
+                    self.write_string(member)?;
+                }
+            }
+            Value::SortedSet(zset) => {
+                self.write_length(zset.len() as u64)?;
+                for (member, score) in zset {
+                    self.write_string(member)?;
+                    self.write_bytes(&score.to_bits().to_le_bytes())?;
+                }
+            }
+            Value::Hash(hash) => {
+                self.write_length(hash.len() as u64)?;
+                for (field, value) in hash {
+                    self.write_string(field)?;
+                    self.write_string(value)?;
+                }
+            }
+            Value::Stream(stream) => self.write_stream(stream)?,
+        }
+
+        Ok(())
+    }
+
+    fn write_stream(&mut self, stream: &Stream) -> anyhow::Result<()> {
+        // Every entry gets a listpack node of its own, with the entry acting as
+        // the node's master entry.
+        self.write_length(stream.entries.len() as u64)?;
+        for (id, fields) in &stream.entries {
+            let mut master_id = Vec::with_capacity(16);
+            master_id.extend_from_slice(&id.ms.to_be_bytes());
+            master_id.extend_from_slice(&id.seq.to_be_bytes());
+            self.write_string(&master_id)?;
+
+            let mut entries = vec![
+                PackedEntry::Int(1),
+                PackedEntry::Int(0),
+                PackedEntry::Int(fields.len() as i64),
+            ];
+            entries.extend(fields.iter().map(|(f, _)| PackedEntry::Str(f.clone())));
+            entries.extend([
+                PackedEntry::Int(0),
+                PackedEntry::Int(STREAM_ITEM_FLAG_SAMEFIELDS),
+                PackedEntry::Int(0),
+                PackedEntry::Int(0),
+            ]);
+            entries.extend(fields.iter().map(|(_, v)| PackedEntry::Str(v.clone())));
+            entries.push(PackedEntry::Int(fields.len() as i64 + 3));
+            self.write_string(&encode_listpack(&entries))?;
+        }
+
+        self.write_length(stream.entries.len() as u64)?;
+        self.write_stream_id(stream.last_id)?;
+        self.write_stream_id(stream.first_id)?;
+        self.write_stream_id(stream.max_deleted_id)?;
+        self.write_length(stream.entries_added)?;
+
+        self.write_length(stream.groups.len() as u64)?;
+        for group in &stream.groups {
+            self.write_string(&group.name)?;
+            self.write_stream_id(group.last_id)?;
+            self.write_length(group.entries_read as u64)?;
+
+            self.write_length(group.pending.len() as u64)?;
+            for pending in &group.pending {
+                self.write_raw_stream_id(pending.id)?;
+                self.write_bytes(&pending.delivery_time.to_le_bytes())?;
+                self.write_length(pending.delivery_count)?;
+            }
+
+            self.write_length(group.consumers.len() as u64)?;
+            for consumer in &group.consumers {
+                self.write_string(&consumer.name)?;
+                self.write_bytes(&consumer.seen_time.to_le_bytes())?;
+                self.write_bytes(&consumer.active_time.to_le_bytes())?;
+                self.write_length(consumer.pending.len() as u64)?;
+                for id in &consumer.pending {
+                    self.write_raw_stream_id(*id)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn encode_listpack(entries: &[PackedEntry]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for entry in entries {
+        let start = body.len();
+        match entry {
+            PackedEntry::Int(n @ 0..=127) => body.push(*n as u8),
+            PackedEntry::Int(n @ -4096..=4095) => {
+                let n = (*n as u16) & 0x1FFF;
+                body.extend_from_slice(&[0xC0 | (n >> 8) as u8, n as u8]);
+            }
+            PackedEntry::Int(n) => match i32::try_from(*n) {
+                Ok(n) if i16::try_from(n).is_ok() => {
+                    body.push(0xF1);
+                    body.extend_from_slice(&(n as i16).to_le_bytes());
+                }
+                Ok(n) if (-(1 << 23)..1 << 23).contains(&n) => {
+                    body.push(0xF2);
+                    body.extend_from_slice(&n.to_le_bytes()[..3]);
+                }
+                Ok(n) => {
+                    body.push(0xF3);
+                    body.extend_from_slice(&n.to_le_bytes());
+                }
+                Err(_) => {
+                    body.push(0xF4);
+                    body.extend_from_slice(&n.to_le_bytes());
+                }
+            },
+            PackedEntry::Str(bytes) => {
+                let len = bytes.len();
+                if len < 64 {
+                    body.push(0x80 | len as u8);
+                } else if len < 4096 {
+                    body.extend_from_slice(&[0xE0 | (len >> 8) as u8, len as u8]);
+                } else {
+                    body.push(0xF0);
+                    body.extend_from_slice(&(len as u32).to_le_bytes());
+                }
+                body.extend_from_slice(bytes);
+            }
+        }
+        encode_listpack_backlen(body.len() - start, &mut body);
+    }
+
+    let total_bytes = 6 + body.len() + 1;
+    let mut listpack = Vec::with_capacity(total_bytes);
+    listpack.extend_from_slice(&(total_bytes as u32).to_le_bytes());
+    listpack.extend_from_slice(&(entries.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    listpack.extend_from_slice(&body);
+    listpack.push(0xFF);
+
+    listpack
+}
+
+fn encode_listpack_backlen(entry_len: usize, out: &mut Vec<u8>) {
+    let size = listpack_backlen_size(entry_len);
+    // The most significant 7-bit group comes first; all but it have the high bit set.
+    for i in (0..size).rev() {
+        let group = ((entry_len >> (7 * i)) & 0x7F) as u8;
+        out.push(if i == size - 1 { group } else { group | 0x80 });
+    }
+}
+
+fn int_to_bytes(n: i64) -> Bytes {
+    Bytes::from(n.to_string())
+}
+
+fn parse_double(bytes: &[u8]) -> anyhow::Result<f64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| match s {
+            "inf" | "+inf" => Some(f64::INFINITY),
+            "-inf" => Some(f64::NEG_INFINITY),
+            s => s.parse().ok(),
+        })
+        .ok_or_else(|| anyhow!("invalid double: {}", bytes.escape_ascii()))
+}
+
+pub fn unix_time_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time before unix epoch")
+        .as_millis() as u64
+}
+
+const CRC64_TABLE: [u64; 256] = crc64_table();
+
+// Reflected form of the Jones polynomial 0xad93d23594c935a9 used by Redis.
+const fn crc64_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x95ac9329ac4bc9b5
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+pub fn crc64(mut crc: u64, bytes: &[u8]) -> u64 {
+    for &b in bytes {
+        crc = CRC64_TABLE[((crc ^ b as u64) & 0xFF) as usize] ^ (crc >> 8);
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc64() {
+        assert_eq!(0xe9c6d914c4b8d9ca, crc64(0, b"123456789"));
+    }
+
+    #[test]
+    fn test_parse_empty_rdb() {
+        let rdb = hex::decode("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2").unwrap();
+
+        let rdb = Rdb::parse(&rdb, false).unwrap();
+
+        assert_eq!(11, rdb.version);
+        assert!(rdb.databases.is_empty());
+        assert!(rdb
+            .aux
+            .iter()
+            .any(|(k, v)| k == "redis-ver" && v == "7.2.0"));
+    }
+
+    #[test]
+    fn test_parse_strings_with_expiry() {
+        let mut rdb = b"REDIS0009\xfe\x00\xfb\x02\x01".to_vec();
+        rdb.extend_from_slice(b"\x00\x03foo\x03bar");
+        rdb.push(RDB_OPCODE_EXPIRETIME_MS);
+        rdb.extend_from_slice(&u64::MAX.to_le_bytes());
+        rdb.extend_from_slice(b"\x00\x03baz\xc0\x7b");
+        rdb.push(RDB_OPCODE_EXPIRETIME_MS);
+        rdb.extend_from_slice(&1u64.to_le_bytes());
+        rdb.extend_from_slice(b"\x00\x04gone\x01x");
+        rdb.push(RDB_OPCODE_EOF);
+        let checksum = crc64(0, &rdb);
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+
+        let rdb = Rdb::parse(&rdb, false).unwrap();
+
+        let db = &rdb.databases[&0];
+        assert_eq!(2, db.len());
+        assert!(matches![db["foo"].value.as_ref(), Value::String(v) if v == "bar"]);
+        assert!(db["foo"].expiry.is_none());
+        assert!(matches![db["baz"].value.as_ref(), Value::String(v) if v == "123"]);
+        assert!(db["baz"].expiry.is_some());
+    }
+
+    #[test]
+    fn test_parse_keeps_expired_keys() {
+        let mut rdb = b"REDIS0011".to_vec();
+        rdb.push(RDB_OPCODE_EXPIRETIME_MS);
+        rdb.extend_from_slice(&1u64.to_le_bytes());
+        rdb.extend_from_slice(b"\x00\x04gone\x01x\xff");
+        let checksum = crc64(0, &rdb);
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+
+        assert!(Rdb::parse(&rdb, false).unwrap().databases.is_empty());
+        let rdb = Rdb::parse(&rdb, true).unwrap();
+
+        let db = &rdb.databases[&0];
+        assert_eq!(1, db.len());
+        assert!(db["gone"].is_expired());
+    }
+
+    #[test]
+    fn test_parse_skips_non_utf8_keys() {
+        let mut rdb = b"REDIS0011".to_vec();
+        rdb.push(RDB_OPCODE_EXPIRETIME_MS);
+        rdb.extend_from_slice(&u64::MAX.to_le_bytes());
+        rdb.extend_from_slice(b"\x00\x02\xff\xfe\x01x\x00\x03foo\x03bar\xff");
+        let checksum = crc64(0, &rdb);
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+
+        let rdb = Rdb::parse(&rdb, false).unwrap();
+
+        let db = &rdb.databases[&0];
+        assert_eq!(1, db.len());
+        assert!(db["foo"].expiry.is_none());
+    }
+
+    #[test]
+    fn test_load_streamed() {
+        let mut rdb = b"REDIS0011\x00\x03foo\x03bar\xff".to_vec();
+        let checksum = crc64(0, &rdb);
+        rdb.extend_from_slice(&checksum.to_le_bytes());
+        rdb.extend_from_slice(b"after");
+
+        let mut input = Trickle(&rdb);
+        let loaded = Rdb::load(&mut input, false).unwrap();
+
+        let db = &loaded.databases[&0];
+        assert!(matches![db["foo"].value.as_ref(), Value::String(v) if v == "bar"]);
+        assert_eq!(b"after", input.0);
+    }
+
+    /// Reads one byte at a time, as a socket may return less than asked.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.0.len()).min(1);
+            buf[..len].copy_from_slice(&self.0[..len]);
+            self.0 = &self.0[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_parse_checksum_mismatch() {
+        let mut rdb = b"REDIS0011\xff".to_vec();
+        rdb.extend_from_slice(&1u64.to_le_bytes());
+
+        assert!(Rdb::parse(&rdb, false).is_err());
+    }
+
+    #[test]
+    fn test_lzf_decompress() {
+        // "aaaaaaaaaa": a literal 'a' followed by a back reference of length 9.
+        let output = lzf_decompress(b"\x00a\xe0\x00\x00", 10).unwrap();
+
+        assert_eq!(b"aaaaaaaaaa", &output[..]);
+    }
+
+    #[test]
+    fn test_parse_ziplist() {
+        let ziplist = b"\x00\x00\x00\x00\x00\x00\x00\x00\x02\x00\x00\x03abc\x05\xf2\xff";
+
+        let entries = parse_ziplist(ziplist).unwrap();
+
+        assert_eq!(
+            vec![PackedEntry::Str(Bytes::from("abc")), PackedEntry::Int(1)],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_parse_listpack() {
+        let listpack = b"\x00\x00\x00\x00\x03\x00\x83abc\x04\x05\x01\xdf\xff\x02\xff";
+
+        let entries = parse_listpack(listpack).unwrap();
+
+        assert_eq!(
+            vec![
+                PackedEntry::Str(Bytes::from("abc")),
+                PackedEntry::Int(5),
+                PackedEntry::Int(-1),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_parse_intset() {
+        let intset = b"\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\xff\xff";
+
+        assert_eq!(vec![1, -1], parse_intset(intset).unwrap());
+    }
+
+    #[test]
+    fn test_write_roundtrip() {
+        let mut entries = Keyspace::default();
+        entries.insert(
+            "string".to_owned(),
+            DbValue::new(Value::String(Bytes::from("value"))),
+        );
+        entries.insert(
+            "list".to_owned(),
+            DbValue::new(Value::List(VecDeque::from([
+                Bytes::from("a"),
+                Bytes::from("b"),
+            ]))),
+        );
+        entries.insert(
+            "zset".to_owned(),
+            DbValue::new(Value::SortedSet(HashMap::from([(Bytes::from("m"), 1.5)]))),
+        );
+        let mut stream = Stream::default();
+        let id = StreamId { ms: 1, seq: 2 };
+        stream
+            .entries
+            .insert(id, vec![(Bytes::from("field"), Bytes::from("value"))]);
+        stream.last_id = id;
+        stream.first_id = id;
+        stream.entries_added = 1;
+        entries.insert("stream".to_owned(), DbValue::new(Value::Stream(stream)));
+        let mut expiring = DbValue::new(Value::String(Bytes::from("soon")));
+        expiring.expiry = Some(Instant::now() + Duration::from_secs(60));
+        entries.insert("expiring".to_owned(), expiring);
+
+        let mut out = Vec::new();
+        let (expiring, rest) = entries
+            .into_entries()
+            .partition::<Keyspace, _>(|(key, _)| key == "expiring");
+        write(
+            &mut out,
+            &[(3, vec![&expiring, &rest])],
+            &[("repl-stream-db", "3")],
+        )
+        .unwrap();
+        let rdb = Rdb::parse(&out, false).unwrap();
+
+        assert!(rdb
+            .aux
+            .iter()
+            .any(|(key, value)| key == "repl-stream-db" && value == "3"));
+        let db = &rdb.databases[&3];
+        assert_eq!(5, db.len());
+        assert!(matches![db["string"].value.as_ref(), Value::String(v) if v == "value"]);
+        assert!(matches![db["list"].value.as_ref(), Value::List(l) if l.len() == 2 && l[1] == "b"]);
+        assert!(
+            matches![db["zset"].value.as_ref(), Value::SortedSet(z) if z[&Bytes::from("m")] == 1.5]
+        );
+        assert!(
+            matches![db["stream"].value.as_ref(), Value::Stream(s) if s.entries[&id][0].1 == "value"]
+        );
+        assert!(db["expiring"].expiry.is_some());
+    }
+
+    #[test]
+    fn test_dump_restore() {
+        let value = Value::List(VecDeque::from([Bytes::from("a"), Bytes::from("b")]));
+
+        let mut payload = dump(&value);
+
+        assert_eq!(RDB_TYPE_LIST, payload[0]);
+        assert!(matches![restore(&payload).unwrap(), Value::List(l) if l == ["a", "b"]]);
+        payload[2] ^= 1;
+        assert!(restore(&payload).is_err());
+        assert!(restore(b"\x00").is_err());
+    }
+
+    #[test]
+    fn test_restore_redis_payload() {
+        // DUMP of the integer-encoded string 10 by Redis, with RDB version 9.
+        let payload = b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n";
+        assert!(matches![restore(payload).unwrap(), Value::String(s) if s == "10"]);
+        let payload = dump(&Value::String(Bytes::from_static(b"bar")));
+        assert_eq!(b"\x00\x03bar\x0b\x00", &payload[..7]);
+    }
+
+    #[test]
+    fn test_restore_huge_length() {
+        // A list claiming 2^40 elements, without and then with a checksum.
+        let mut payload = b"\x01\x81\x00\x00\x01\x00\x00\x00\x00\x00\x0b\x00".to_vec();
+        payload.extend_from_slice(&[0; 8]);
+        assert!(restore(&payload).is_err());
+        let body_len = payload.len() - 8;
+        let checksum = crc64(0, &payload[..body_len]);
+        payload[body_len..].copy_from_slice(&checksum.to_le_bytes());
+        assert!(restore(&payload).is_err());
+    }
+
+    #[test]
+    fn test_listpack_roundtrip() {
+        let entries = vec![
+            PackedEntry::Int(100),
+            PackedEntry::Int(-4000),
+            PackedEntry::Int(30000),
+            PackedEntry::Int(-5_000_000),
+            PackedEntry::Int(1 << 40),
+            PackedEntry::Str(Bytes::from(vec![b'x'; 200])),
+        ];
+
+        assert_eq!(entries, parse_listpack(&encode_listpack(&entries)).unwrap());
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::Read,
+    net::SocketAddr,
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use tokio::{sync::mpsc, time::Instant};
+
+/// The replication stream of a server. Writes are fed into it, kept in the
+/// backlog for partial resyncs and queued for every registered replica.
+///
+/// Replicas take over the ID and offset of their master, and feed its stream
+/// into their own backlog, so they can serve partial resyncs after promotion.
+#[derive(Debug)]
+pub struct Replication {
+    pub replid: String,
+    /// Previous replication ID, accepted for partial resyncs up to
+    /// `second_replid_offset` after a replica got promoted.
+    pub replid2: String,
+    pub second_replid_offset: i64,
+    /// Number of bytes fed into the replication stream.
+    pub offset: u64,
+    /// Database selected at the end of the replication stream. Snapshots sent
+    /// to replicas carry it, as their stream starts there.
+    pub stream_db: usize,
+    backlog: Option<VecDeque<u8>>,
+    backlog_size: usize,
+    replicas: Vec<Replica>,
+    next_replica_id: u64,
+}
+
+#[derive(Debug)]
+pub struct Replica {
+    pub id: u64,
+    pub addr: SocketAddr,
+    pub listening_port: Option<u16>,
+    pub state: ReplicaState,
+    /// Replication offset acknowledged by the replica with REPLCONF ACK.
+    pub ack_offset: u64,
+    pub last_ack: Instant,
+    tx: mpsc::UnboundedSender<Bytes>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicaState {
+    /// The RDB snapshot is being generated and transferred.
+    SendBulk,
+    /// The replica receives the replication stream.
+    Online,
+}
+
+impl ReplicaState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReplicaState::SendBulk => "send_bulk",
+            ReplicaState::Online => "online",
+        }
+    }
+}
+
+impl Replication {
+    pub fn new(backlog_size: usize) -> Self {
+        Replication {
+            replid: random_replid(),
+            replid2: "0".repeat(40),
+            second_replid_offset: -1,
+            offset: 0,
+            stream_db: 0,
+            backlog: None,
+            backlog_size,
+            replicas: Vec::new(),
+            next_replica_id: 0,
+        }
+    }
+
+    /// Starts keeping the replication stream. Masters do so once the first
+    /// replica attaches, replicas once they synced with their master.
+    pub fn create_backlog(&mut self) {
+        if self.backlog.is_none() {
+            self.backlog = Some(VecDeque::with_capacity(self.backlog_size.min(1 << 20)));
+        }
+    }
+
+    pub fn has_backlog(&self) -> bool {
+        self.backlog.is_some()
+    }
+
+    /// Number of bytes currently held in the backlog.
+    pub fn backlog_histlen(&self) -> usize {
+        self.backlog.as_ref().map(|b| b.len()).unwrap_or(0)
+    }
+
+    pub fn backlog_size(&self) -> usize {
+        self.backlog_size
+    }
+
+    /// Discards the backlog, as after a full resync with a new master.
+    pub fn reset_backlog(&mut self) {
+        if let Some(backlog) = &mut self.backlog {
+            backlog.clear();
+        }
+    }
+
+    /// Returns the part of the stream that a replica which processed the stream
+    /// of `replid` up to `psync_offset - 1` is missing, if the backlog still
+    /// holds it. Offsets in PSYNC are those of the next byte the replica wants.
+    pub fn continuation(&self, replid: &str, psync_offset: i64) -> Option<Bytes> {
+        let backlog = self.backlog.as_ref()?;
+        let known_replid = replid == self.replid
+            || (replid == self.replid2 && psync_offset <= self.second_replid_offset);
+        if !known_replid || psync_offset < 1 {
+            return None;
+        }
+
+        let processed = psync_offset as u64 - 1;
+        let first = self.offset - backlog.len() as u64;
+        if processed < first || processed > self.offset {
+            return None;
+        }
+        let skip = (processed - first) as usize;
+
+        Some(
+            backlog
+                .iter()
+                .skip(skip)
+                .copied()
+                .collect::<Vec<_>>()
+                .into(),
+        )
+    }
+
+    /// Moves to a new replication ID, keeping the current one as the secondary
+    /// ID so that replicas of it can still continue partially.
+    pub fn shift_replid(&mut self, new_replid: String) {
+        self.replid2 = std::mem::replace(&mut self.replid, new_replid);
+        self.second_replid_offset = self.offset as i64 + 1;
+    }
+
+    /// Registers a replica. Everything fed after this call is queued on the
+    /// returned receiver until the replica is ready to consume it.
+    pub fn add_replica(
+        &mut self,
+        addr: SocketAddr,
+        listening_port: Option<u16>,
+        ack_offset: u64,
+    ) -> (u64, mpsc::UnboundedReceiver<Bytes>) {
+        self.create_backlog();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let id = self.next_replica_id;
+        self.next_replica_id += 1;
+        self.replicas.push(Replica {
+            id,
+            addr,
+            listening_port,
+            state: ReplicaState::SendBulk,
+            ack_offset,
+            last_ack: Instant::now(),
+            tx,
+        });
+
+        (id, rx)
+    }
+
+    pub fn remove_replica(&mut self, id: u64) {
+        self.replicas.retain(|r| r.id != id);
+    }
+
+    /// Drops every replica, closing their replication streams.
+    pub fn disconnect_replicas(&mut self) {
+        self.replicas.clear();
+    }
+
+    pub fn set_replica_state(&mut self, id: u64, state: ReplicaState) {
+        if let Some(replica) = self.replicas.iter_mut().find(|r| r.id == id) {
+            replica.state = state;
+        }
+    }
+
+    pub fn ack(&mut self, id: u64, offset: u64) {
+        if let Some(replica) = self.replicas.iter_mut().find(|r| r.id == id) {
+            replica.ack_offset = replica.ack_offset.max(offset);
+            replica.last_ack = Instant::now();
+        }
+    }
+
+    /// Number of online replicas that have acknowledged at least `offset`.
+    pub fn acked_replicas(&self, offset: u64) -> usize {
+        self.replicas
+            .iter()
+            .filter(|r| r.state == ReplicaState::Online && r.ack_offset >= offset)
+            .count()
+    }
+
+    pub fn replicas(&self) -> &[Replica] {
+        &self.replicas
+    }
+
+    pub fn feed(&mut self, bytes: Bytes) {
+        let Some(backlog) = &mut self.backlog else {
+            return;
+        };
+        backlog.extend(&bytes[..]);
+        let excess = backlog.len().saturating_sub(self.backlog_size);
+        backlog.drain(..excess);
+
+        self.offset += bytes.len() as u64;
+        self.replicas.retain(|r| r.tx.send(bytes.clone()).is_ok());
+    }
+}
+
+/// Generates a random replication ID of 40 hex characters, from the
+/// randomness of the OS.
+pub fn random_replid() -> String {
+    let mut bytes = [0; 20];
+    let urandom = File::open("/dev/urandom").and_then(|mut file| file.read_exact(&mut bytes));
+    if let Err(err) = urandom {
+        println!(
+            "failed to read /dev/urandom, falling back to the hasher: {}",
+            err
+        );
+        bytes = hasher_random_bytes();
+    }
+
+    hex::encode(bytes)
+}
+
+/// Draws bytes from the randomly seeded std hasher, for systems without
+/// `/dev/urandom`.
+fn hasher_random_bytes() -> [u8; 20] {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let mut bytes = Vec::with_capacity(24);
+    for i in 0..3 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_usize(i);
+        bytes.extend(hasher.finish().to_le_bytes());
+    }
+
+    bytes[..20].try_into().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_replid() {
+        let replid = random_replid();
+
+        assert_eq!(40, replid.len());
+        assert!(replid.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert_ne!(replid, random_replid());
+    }
+
+    #[test]
+    fn test_feed_without_backlog() {
+        let mut replication = Replication::new(16);
+        let replid = replication.replid.clone();
+
+        replication.feed(Bytes::from("abc"));
+
+        assert_eq!(0, replication.offset);
+        assert!(replication.continuation(&replid, 1).is_none());
+    }
+
+    #[test]
+    fn test_continuation() {
+        let mut replication = Replication::new(8);
+        let replid = replication.replid.clone();
+        replication.create_backlog();
+        replication.feed(Bytes::from("0123456789"));
+
+        assert_eq!(10, replication.offset);
+        assert_eq!(8, replication.backlog_histlen());
+        assert_eq!(
+            Some(Bytes::from("6789")),
+            replication.continuation(&replid, 7)
+        );
+        assert_eq!(
+            Some(Bytes::from("23456789")),
+            replication.continuation(&replid, 3)
+        );
+        assert_eq!(Some(Bytes::new()), replication.continuation(&replid, 11));
+        // Already dropped from the backlog.
+        assert!(replication.continuation(&replid, 2).is_none());
+        // Ahead of the master.
+        assert!(replication.continuation(&replid, 12).is_none());
+        assert!(replication.continuation("unknown", 7).is_none());
+    }
+
+    #[test]
+    fn test_continuation_with_secondary_replid() {
+        let mut replication = Replication::new(64);
+        let replid = replication.replid.clone();
+        replication.create_backlog();
+        replication.feed(Bytes::from("0123"));
+        replication.shift_replid("new".to_owned());
+        replication.feed(Bytes::from("4567"));
+
+        assert_eq!(5, replication.second_replid_offset);
+        assert_eq!(
+            Some(Bytes::from("4567")),
+            replication.continuation(&replid, 5)
+        );
+        // The old history diverged from the new one after the shift.
+        assert!(replication.continuation(&replid, 6).is_none());
+        assert_eq!(Some(Bytes::from("67")), replication.continuation("new", 7));
+    }
+}
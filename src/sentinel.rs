@@ -0,0 +1,2099 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    path::Path,
+};
+
+use anyhow::{anyhow, Context};
+use bytes::Bytes;
+
+use crate::{db, frame::Frame, pubsub, replication};
+
+/// A host and a port.
+pub type Address = (String, u16);
+
+/// Channel sentinels announce themselves on, through the instances they
+/// monitor.
+pub const HELLO_CHANNEL: &str = "__sentinel__:hello";
+
+/// The view of a sentinel: the masters it monitors, with their replicas and
+/// the other sentinels monitoring them. It is persisted to the sentinel
+/// config file, in the format of Redis Sentinel.
+///
+/// Instances are checked with requests the server sends for
+/// [`SentinelState::cron`], and whose replies it hands back to
+/// [`SentinelState::handle_reply`]. Times are Unix times in milliseconds.
+#[derive(Debug, Clone)]
+pub struct SentinelState {
+    pub myid: String,
+    /// Port announced to the other sentinels.
+    pub port: u16,
+    pub current_epoch: u64,
+    masters: BTreeMap<String, Master>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Master {
+    pub name: String,
+    pub addr: Address,
+    /// Number of sentinels that have to agree that the master is down.
+    pub quorum: usize,
+    /// Milliseconds without a valid reply after which an instance is down.
+    pub down_after: u64,
+    pub failover_timeout: u64,
+    /// Number of replicas reconfigured at once after a failover.
+    pub parallel_syncs: usize,
+    /// Epoch of the failover that promoted the current master.
+    pub config_epoch: u64,
+    /// The sentinel this one voted for as failover leader, in `leader_epoch`.
+    pub leader: Option<String>,
+    pub leader_epoch: u64,
+    link: Link,
+    pub replicas: BTreeMap<Address, Replica>,
+    /// The other sentinels, by ID.
+    pub sentinels: BTreeMap<String, Peer>,
+    /// Time the master went subjectively down, 0 if it is up.
+    pub sdown_since: u64,
+    /// Time a quorum of sentinels agreed that the master is down, 0 if not.
+    pub odown_since: u64,
+    failover: Option<Failover>,
+    // Time the last failover started, plus a random delay so that sentinels
+    // do not all start one at once.
+    failover_start_time: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Replica {
+    pub addr: Address,
+    link: Link,
+    pub sdown_since: u64,
+    /// The master the replica reported in INFO.
+    pub master_addr: Option<Address>,
+    pub master_link_up: bool,
+    /// Milliseconds the link with its master has been down.
+    pub master_link_down_time: u64,
+    /// Replicas with a lower priority are promoted first, never with 0.
+    pub priority: u64,
+    pub offset: u64,
+    reconf: Reconf,
+    // Time the replica was last told to replicate the master, to fix its
+    // config.
+    fix_sent: u64,
+}
+
+/// Another sentinel monitoring the same master.
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub run_id: String,
+    pub addr: Address,
+    link: Link,
+    pub sdown_since: u64,
+    pub last_hello: u64,
+    /// Whether the sentinel last replied that the master is down.
+    pub master_down: bool,
+    // Time of that reply.
+    down_reply_time: u64,
+    // Time the sentinel was last asked whether the master is down.
+    last_ask: u64,
+    /// The sentinel it voted for as failover leader, in `leader_epoch`.
+    pub leader: Option<String>,
+    pub leader_epoch: u64,
+}
+
+/// What the sentinel knows of the connection with an instance.
+#[derive(Debug, Clone, Default)]
+struct Link {
+    // Time of the oldest ping still awaiting a reply, 0 if none.
+    ping_sent: u64,
+    last_ping: u64,
+    last_available: u64,
+    last_info: u64,
+    // Time of the last INFO reply.
+    info_refresh: u64,
+    last_hello: u64,
+    run_id: Option<String>,
+    role_reported: Option<Reported>,
+    role_reported_time: u64,
+}
+
+/// The role an instance reports in INFO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reported {
+    Master,
+    Replica,
+}
+
+/// How far a replica got in switching to the promoted one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reconf {
+    None,
+    /// Told to replicate the promoted replica, at the given time.
+    Sent(u64),
+    InProgress,
+    Done,
+}
+
+#[derive(Debug, Clone)]
+struct Failover {
+    epoch: u64,
+    state: FailoverState,
+    state_time: u64,
+    /// Started with SENTINEL FAILOVER, which needs no agreement.
+    forced: bool,
+    promoted: Option<Address>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailoverState {
+    /// Waiting to be elected leader.
+    WaitStart,
+    SelectReplica,
+    SendReplicaofNoOne,
+    WaitPromotion,
+    /// Telling the other replicas to replicate the promoted one.
+    ReconfReplicas,
+}
+
+impl FailoverState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FailoverState::WaitStart => "wait_start",
+            FailoverState::SelectReplica => "select_slave",
+            FailoverState::SendReplicaofNoOne => "send_slaveof_noone",
+            FailoverState::WaitPromotion => "wait_promotion",
+            FailoverState::ReconfReplicas => "reconf_slaves",
+        }
+    }
+}
+
+/// What the server has to carry out after the sentinel state changed.
+#[derive(Debug, Default)]
+pub struct Effects {
+    /// Requests to send, with the instance they are about and its address.
+    pub requests: Vec<(Instance, Address, Request)>,
+    /// Events to log and publish, as their channel and message.
+    pub events: Vec<(String, String)>,
+    /// Whether the sentinel config file has to be saved.
+    pub save: bool,
+}
+
+impl Effects {
+    fn event(&mut self, kind: &str, message: String) {
+        self.events.push((kind.to_owned(), message));
+    }
+}
+
+/// An instance, by the name of its master.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instance {
+    Master(String),
+    Replica(String, Address),
+    /// A sentinel, by its ID.
+    Sentinel(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Ping,
+    Info,
+    /// Publishes a hello message on [`HELLO_CHANNEL`]. The message misses
+    /// its leading IP, the one the connection to the instance goes out from.
+    Hello(String),
+    /// Asks a sentinel whether the master at the given address is down, and
+    /// to vote for the given leader unless it is `*`.
+    IsMasterDownByAddr {
+        addr: Address,
+        epoch: u64,
+        run_id: String,
+    },
+    /// Replicate the given master, or become a master with `None`.
+    Replicaof(Option<Address>),
+}
+
+/// What the sentinel uses of the INFO of an instance.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Info {
+    pub run_id: Option<String>,
+    pub role: Option<Reported>,
+    pub master_addr: Option<Address>,
+    pub master_link_up: bool,
+    pub master_link_down_since: u64,
+    pub priority: Option<u64>,
+    pub offset: u64,
+    /// The replicas a master lists.
+    pub replicas: Vec<Address>,
+}
+
+impl Link {
+    fn new(now: u64) -> Self {
+        Link {
+            last_available: now,
+            ..Link::default()
+        }
+    }
+
+    fn is_down(&self, down_after: u64, now: u64) -> bool {
+        now.saturating_sub(self.last_available) > down_after
+    }
+
+    fn report_role(&mut self, role: Option<Reported>, now: u64) {
+        if role != self.role_reported {
+            self.role_reported = role;
+            self.role_reported_time = now;
+        }
+    }
+
+    /// Queues the pings, INFOs and hellos that are due.
+    fn send_periodic(
+        &mut self,
+        target: (Instance, &Address),
+        info_period: Option<u64>,
+        hello: &str,
+        ping_period: u64,
+        now: u64,
+        effects: &mut Effects,
+    ) {
+        let (instance, addr) = target;
+        if now.saturating_sub(self.last_ping) >= ping_period {
+            self.last_ping = now;
+            if self.ping_sent == 0 {
+                self.ping_sent = now;
+            }
+            effects
+                .requests
+                .push((instance.clone(), addr.clone(), Request::Ping));
+        }
+        if let Some(period) = info_period {
+            if now.saturating_sub(self.last_info) >= period {
+                self.last_info = now;
+                effects
+                    .requests
+                    .push((instance.clone(), addr.clone(), Request::Info));
+            }
+        }
+        if now.saturating_sub(self.last_hello) >= HELLO_PERIOD {
+            self.last_hello = now;
+            let request = Request::Hello(hello.to_owned());
+            effects.requests.push((instance, addr.clone(), request));
+        }
+    }
+}
+
+impl Master {
+    pub fn new(name: String, addr: Address, quorum: usize, now: u64) -> Self {
+        Master {
+            name,
+            addr,
+            quorum,
+            down_after: DEFAULT_DOWN_AFTER,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            parallel_syncs: 1,
+            config_epoch: 0,
+            leader: None,
+            leader_epoch: 0,
+            link: Link::new(now),
+            replicas: BTreeMap::new(),
+            sentinels: BTreeMap::new(),
+            sdown_since: 0,
+            odown_since: 0,
+            failover: None,
+            failover_start_time: 0,
+        }
+    }
+
+    /// The address clients should use, which is the promoted replica's once
+    /// it took over in a failover.
+    pub fn current_addr(&self) -> &Address {
+        match &self.failover {
+            Some(Failover {
+                state: FailoverState::ReconfReplicas,
+                promoted: Some(promoted),
+                ..
+            }) => promoted,
+            _ => &self.addr,
+        }
+    }
+
+    pub fn failover_in_progress(&self) -> bool {
+        self.failover.is_some()
+    }
+
+    fn describe(&self) -> String {
+        format!("master {} {} {}", self.name, self.addr.0, self.addr.1)
+    }
+
+    fn describe_replica(&self, addr: &Address) -> String {
+        format!(
+            "slave {} {} {} @ {} {} {}",
+            format_addr(addr),
+            addr.0,
+            addr.1,
+            self.name,
+            self.addr.0,
+            self.addr.1
+        )
+    }
+
+    fn describe_sentinel(&self, peer: &Peer) -> String {
+        format!(
+            "sentinel {} {} {} @ {} {} {}",
+            peer.run_id, peer.addr.0, peer.addr.1, self.name, self.addr.0, self.addr.1
+        )
+    }
+
+    /// Picks the replica to promote: among the healthy ones, the one with
+    /// the lowest priority, then the most data, then the lowest run ID.
+    fn select_replica(&self, now: u64) -> Option<Address> {
+        let (info_validity, max_link_down) = if self.sdown_since == 0 {
+            (3 * INFO_PERIOD, self.down_after * 10)
+        } else {
+            (
+                5 * PING_PERIOD,
+                now.saturating_sub(self.sdown_since) + self.down_after * 10,
+            )
+        };
+        let mut candidates = self
+            .replicas
+            .values()
+            .filter(|replica| {
+                replica.sdown_since == 0
+                    && replica.priority != 0
+                    && now.saturating_sub(replica.link.last_available) <= 5 * PING_PERIOD
+                    && now.saturating_sub(replica.link.info_refresh) <= info_validity
+                    && replica.master_link_down_time <= max_link_down
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| {
+            a.priority
+                .cmp(&b.priority)
+                .then(b.offset.cmp(&a.offset))
+                .then_with(|| match (&a.link.run_id, &b.link.run_id) {
+                    (Some(a), Some(b)) => a.cmp(b),
+                    (a, b) => b.is_some().cmp(&a.is_some()),
+                })
+        });
+
+        candidates.first().map(|replica| replica.addr.clone())
+    }
+
+    /// Forgets what the sentinel learnt about the master, which now is at
+    /// `addr` with the given replicas.
+    fn reset(&mut self, addr: Address, replicas: Vec<Address>, keep_sentinels: bool, now: u64) {
+        self.addr = addr;
+        self.link = Link::new(now);
+        self.replicas = replicas
+            .into_iter()
+            .map(|addr| (addr.clone(), Replica::new(addr, now)))
+            .collect();
+        if keep_sentinels {
+            for peer in self.sentinels.values_mut() {
+                peer.master_down = false;
+                peer.leader = None;
+            }
+        } else {
+            self.sentinels.clear();
+        }
+        self.sdown_since = 0;
+        self.odown_since = 0;
+        self.failover = None;
+    }
+
+    /// Moves the master to `addr`, its old address becoming one of its
+    /// replicas.
+    fn switch_to(&mut self, addr: Address, now: u64, effects: &mut Effects) {
+        effects.event(
+            "+switch-master",
+            format!(
+                "{} {} {} {} {}",
+                self.name, self.addr.0, self.addr.1, addr.0, addr.1
+            ),
+        );
+        let mut replicas = self
+            .replicas
+            .keys()
+            .filter(|replica| **replica != addr)
+            .cloned()
+            .collect::<Vec<_>>();
+        if self.addr != addr {
+            replicas.push(self.addr.clone());
+        }
+        self.reset(addr, replicas, true, now);
+        effects.save = true;
+    }
+
+    fn abort_failover(&mut self, reason: &str, effects: &mut Effects) {
+        effects.event(reason, self.describe());
+        self.failover = None;
+        for replica in self.replicas.values_mut() {
+            replica.reconf = Reconf::None;
+        }
+    }
+
+    fn flags(&self) -> String {
+        let mut flags = "master".to_owned();
+        if self.sdown_since != 0 {
+            flags.push_str(",s_down");
+        }
+        if self.odown_since != 0 {
+            flags.push_str(",o_down");
+        }
+        if self.failover.is_some() {
+            flags.push_str(",failover_in_progress");
+        }
+        flags
+    }
+
+    fn to_frame(&self, now: u64) -> Frame {
+        let mut fields = vec![
+            ("name", self.name.clone()),
+            ("ip", self.addr.0.clone()),
+            ("port", self.addr.1.to_string()),
+            ("runid", self.link.run_id.clone().unwrap_or_default()),
+            ("flags", self.flags()),
+        ];
+        fields.extend(link_fields(&self.link, now));
+        fields.extend([
+            ("down-after-milliseconds", self.down_after.to_string()),
+            ("config-epoch", self.config_epoch.to_string()),
+            ("num-slaves", self.replicas.len().to_string()),
+            ("num-other-sentinels", self.sentinels.len().to_string()),
+            ("quorum", self.quorum.to_string()),
+            ("failover-timeout", self.failover_timeout.to_string()),
+            ("parallel-syncs", self.parallel_syncs.to_string()),
+        ]);
+        if self.sdown_since != 0 {
+            fields.push(("s-down-time", (now - self.sdown_since).to_string()));
+        }
+        if self.odown_since != 0 {
+            fields.push(("o-down-time", (now - self.odown_since).to_string()));
+        }
+        if let Some(failover) = &self.failover {
+            fields.push(("failover-state", failover.state.as_str().to_owned()));
+        }
+
+        fields_frame(fields)
+    }
+}
+
+impl Replica {
+    pub fn new(addr: Address, now: u64) -> Self {
+        Replica {
+            addr,
+            link: Link::new(now),
+            sdown_since: 0,
+            master_addr: None,
+            master_link_up: false,
+            master_link_down_time: 0,
+            priority: DEFAULT_PRIORITY,
+            offset: 0,
+            reconf: Reconf::None,
+            fix_sent: 0,
+        }
+    }
+
+    fn to_frame(&self, promoted: Option<&Address>, now: u64) -> Frame {
+        let mut flags = "slave".to_owned();
+        if self.sdown_since != 0 {
+            flags.push_str(",s_down");
+        }
+        if promoted == Some(&self.addr) {
+            flags.push_str(",promoted");
+        }
+        match self.reconf {
+            Reconf::None => (),
+            Reconf::Sent(_) => flags.push_str(",reconf_sent"),
+            Reconf::InProgress => flags.push_str(",reconf_inprog"),
+            Reconf::Done => flags.push_str(",reconf_done"),
+        }
+        let (master_host, master_port) = self
+            .master_addr
+            .clone()
+            .map_or((String::new(), String::new()), |(host, port)| {
+                (host, port.to_string())
+            });
+        let mut fields = vec![
+            ("name", format_addr(&self.addr)),
+            ("ip", self.addr.0.clone()),
+            ("port", self.addr.1.to_string()),
+            ("runid", self.link.run_id.clone().unwrap_or_default()),
+            ("flags", flags),
+        ];
+        fields.extend(link_fields(&self.link, now));
+        fields.extend([
+            (
+                "master-link-down-time",
+                self.master_link_down_time.to_string(),
+            ),
+            (
+                "master-link-status",
+                if self.master_link_up { "ok" } else { "err" }.to_owned(),
+            ),
+            ("master-host", master_host),
+            ("master-port", master_port),
+            ("slave-priority", self.priority.to_string()),
+            ("slave-repl-offset", self.offset.to_string()),
+        ]);
+
+        fields_frame(fields)
+    }
+}
+
+impl Peer {
+    fn new(run_id: String, addr: Address, now: u64) -> Self {
+        Peer {
+            run_id,
+            addr,
+            link: Link::new(now),
+            sdown_since: 0,
+            last_hello: now,
+            master_down: false,
+            down_reply_time: 0,
+            last_ask: 0,
+            leader: None,
+            leader_epoch: 0,
+        }
+    }
+
+    fn to_frame(&self, now: u64) -> Frame {
+        let mut flags = "sentinel".to_owned();
+        if self.sdown_since != 0 {
+            flags.push_str(",s_down");
+        }
+        if self.master_down {
+            flags.push_str(",master_down");
+        }
+        let mut fields = vec![
+            ("name", self.run_id.clone()),
+            ("ip", self.addr.0.clone()),
+            ("port", self.addr.1.to_string()),
+            ("runid", self.run_id.clone()),
+            ("flags", flags),
+        ];
+        fields.extend(link_fields(&self.link, now));
+        fields.extend([
+            (
+                "last-hello-message",
+                now.saturating_sub(self.last_hello).to_string(),
+            ),
+            (
+                "voted-leader",
+                self.leader.clone().unwrap_or("?".to_owned()),
+            ),
+            ("voted-leader-epoch", self.leader_epoch.to_string()),
+        ]);
+
+        fields_frame(fields)
+    }
+}
+
+impl SentinelState {
+    pub fn new(myid: String, port: u16) -> Self {
+        SentinelState {
+            myid,
+            port,
+            current_epoch: 0,
+            masters: BTreeMap::new(),
+        }
+    }
+
+    /// Loads the sentinel config file, or starts with no master if there is
+    /// none.
+    pub fn load(path: &Path, port: u16, now: u64) -> anyhow::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        let mut state = SentinelState::parse(&contents, now)
+            .with_context(|| format!("invalid sentinel config file {}", path.display()))?;
+        state.port = port;
+
+        Ok(state)
+    }
+
+    /// Parses a sentinel config file. A new ID is picked if it has none.
+    pub fn parse(contents: &str, now: u64) -> anyhow::Result<Self> {
+        let mut state = SentinelState::new(replication::random_replid(), 0);
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if !fields[0].eq_ignore_ascii_case("sentinel") || fields.len() < 3 {
+                return Err(anyhow!("unsupported sentinel config line: {}", line));
+            }
+            let number = |s: &str| {
+                s.parse::<u64>()
+                    .map_err(|_| anyhow!("invalid number in sentinel config: {}", line))
+            };
+            let directive = fields[1].to_ascii_lowercase();
+            match (&directive[..], &fields[2..]) {
+                ("myid", [id]) => state.myid = id.to_string(),
+                ("current-epoch", [epoch]) => state.current_epoch = number(epoch)?,
+                ("monitor", [name, host, port, quorum]) => {
+                    let addr = (host.to_string(), number(port)? as u16);
+                    let master = Master::new(name.to_string(), addr, number(quorum)? as usize, now);
+                    state.masters.insert(name.to_string(), master);
+                }
+                (_, [name, args @ ..]) => {
+                    let master = state
+                        .masters
+                        .get_mut(*name)
+                        .ok_or_else(|| anyhow!("no such master in sentinel config: {}", line))?;
+                    match (&directive[..], args) {
+                        ("down-after-milliseconds", [millis]) => {
+                            master.down_after = number(millis)?
+                        }
+                        ("failover-timeout", [millis]) => master.failover_timeout = number(millis)?,
+                        ("parallel-syncs", [count]) => {
+                            master.parallel_syncs = number(count)? as usize
+                        }
+                        ("config-epoch", [epoch]) => master.config_epoch = number(epoch)?,
+                        ("leader-epoch", [epoch]) => master.leader_epoch = number(epoch)?,
+                        ("known-replica" | "known-slave", [host, port]) => {
+                            let addr = (host.to_string(), number(port)? as u16);
+                            master
+                                .replicas
+                                .insert(addr.clone(), Replica::new(addr, now));
+                        }
+                        ("known-sentinel", [host, port, run_id]) => {
+                            let addr = (host.to_string(), number(port)? as u16);
+                            let peer = Peer::new(run_id.to_string(), addr, now);
+                            master.sentinels.insert(run_id.to_string(), peer);
+                        }
+                        _ => return Err(anyhow!("unsupported sentinel config line: {}", line)),
+                    }
+                }
+                _ => return Err(anyhow!("unsupported sentinel config line: {}", line)),
+            }
+        }
+
+        Ok(state)
+    }
+
+    /// Writes the sentinel config file, through a temp file so that `path`
+    /// never holds a partial config.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let temp_path = path.with_extension(format!("tmp-{}", std::process::id()));
+        std::fs::write(&temp_path, self.to_config())
+            .with_context(|| format!("failed to write {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, path)
+            .with_context(|| format!("failed to rename sentinel config to {}", path.display()))
+    }
+
+    pub fn to_config(&self) -> String {
+        let mut config = String::new();
+        writeln!(config, "sentinel myid {}", self.myid).unwrap();
+        for master in self.masters.values() {
+            let name = &master.name;
+            writeln!(
+                config,
+                "sentinel monitor {} {} {} {}",
+                name, master.addr.0, master.addr.1, master.quorum
+            )
+            .unwrap();
+            writeln!(
+                config,
+                "sentinel down-after-milliseconds {} {}",
+                name, master.down_after
+            )
+            .unwrap();
+            writeln!(
+                config,
+                "sentinel failover-timeout {} {}",
+                name, master.failover_timeout
+            )
+            .unwrap();
+            writeln!(
+                config,
+                "sentinel parallel-syncs {} {}",
+                name, master.parallel_syncs
+            )
+            .unwrap();
+            writeln!(
+                config,
+                "sentinel config-epoch {} {}",
+                name, master.config_epoch
+            )
+            .unwrap();
+            writeln!(
+                config,
+                "sentinel leader-epoch {} {}",
+                name, master.leader_epoch
+            )
+            .unwrap();
+            for (host, port) in master.replicas.keys() {
+                writeln!(config, "sentinel known-replica {} {} {}", name, host, port).unwrap();
+            }
+            for peer in master.sentinels.values() {
+                writeln!(
+                    config,
+                    "sentinel known-sentinel {} {} {} {}",
+                    name, peer.addr.0, peer.addr.1, peer.run_id
+                )
+                .unwrap();
+            }
+        }
+        writeln!(config, "sentinel current-epoch {}", self.current_epoch).unwrap();
+
+        config
+    }
+
+    pub fn master(&self, name: &str) -> Option<&Master> {
+        self.masters.get(name)
+    }
+
+    pub fn masters(&self) -> impl Iterator<Item = &Master> {
+        self.masters.values()
+    }
+
+    /// Addresses of the masters and replicas, whose hello messages the
+    /// sentinel listens to.
+    pub fn hello_sources(&self) -> Vec<Address> {
+        let mut addresses = Vec::new();
+        for master in self.masters.values() {
+            addresses.push(master.addr.clone());
+            addresses.extend(master.replicas.keys().cloned());
+        }
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+
+    /// Addresses of every instance the sentinel talks to.
+    pub fn addresses(&self) -> Vec<Address> {
+        let mut addresses = self.hello_sources();
+        for master in self.masters.values() {
+            addresses.extend(master.sentinels.values().map(|peer| peer.addr.clone()));
+        }
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+
+    pub fn monitor(
+        &mut self,
+        name: &str,
+        addr: Address,
+        quorum: usize,
+        now: u64,
+    ) -> anyhow::Result<Effects> {
+        if self.masters.contains_key(name) {
+            return Err(anyhow!("ERR Duplicated master name"));
+        }
+        if quorum == 0 {
+            return Err(anyhow!("ERR Quorum must be 1 or greater."));
+        }
+        let master = Master::new(name.to_owned(), addr, quorum, now);
+        let mut effects = Effects {
+            save: true,
+            ..Effects::default()
+        };
+        effects.event(
+            "+monitor",
+            format!("{} quorum {}", master.describe(), quorum),
+        );
+        self.masters.insert(name.to_owned(), master);
+
+        Ok(effects)
+    }
+
+    pub fn remove(&mut self, name: &str) -> anyhow::Result<Effects> {
+        let master = self.masters.remove(name).ok_or(anyhow!(NO_SUCH_MASTER))?;
+        let mut effects = Effects {
+            save: true,
+            ..Effects::default()
+        };
+        effects.event("-monitor", master.describe());
+
+        Ok(effects)
+    }
+
+    /// Changes options of a master, given as name and value pairs.
+    pub fn set(&mut self, name: &str, options: &[(String, String)]) -> anyhow::Result<Effects> {
+        let master = self.masters.get_mut(name).ok_or(anyhow!(NO_SUCH_MASTER))?;
+        let mut effects = Effects {
+            save: true,
+            ..Effects::default()
+        };
+        for (option, value) in options {
+            let invalid = || {
+                anyhow!(
+                    "ERR Invalid argument '{}' for SENTINEL SET '{}'",
+                    value,
+                    option
+                )
+            };
+            let number = value.parse::<u64>().ok().filter(|&n| n > 0);
+            match &option.to_ascii_lowercase()[..] {
+                "down-after-milliseconds" => master.down_after = number.ok_or_else(invalid)?,
+                "failover-timeout" => master.failover_timeout = number.ok_or_else(invalid)?,
+                "parallel-syncs" => master.parallel_syncs = number.ok_or_else(invalid)? as usize,
+                "quorum" => master.quorum = number.ok_or_else(invalid)? as usize,
+                _ => {
+                    return Err(anyhow!(
+                        "ERR Unknown option or number of arguments for SENTINEL SET '{}'",
+                        option
+                    ))
+                }
+            }
+            effects.event(
+                "+set",
+                format!("{} {} {}", master.describe(), option, value),
+            );
+        }
+
+        Ok(effects)
+    }
+
+    /// Forgets the replicas and sentinels of the masters matching a
+    /// glob-style pattern, and returns how many there are.
+    pub fn reset(&mut self, pattern: &str, now: u64) -> (usize, Effects) {
+        let mut effects = Effects::default();
+        let mut count = 0;
+        for master in self.masters.values_mut() {
+            if pubsub::glob_match(pattern.as_bytes(), master.name.as_bytes()) {
+                master.reset(master.addr.clone(), Vec::new(), false, now);
+                effects.event("+reset-master", master.describe());
+                effects.save = true;
+                count += 1;
+            }
+        }
+
+        (count, effects)
+    }
+
+    /// Fails over a master right away, without the agreement of the other
+    /// sentinels.
+    pub fn failover(&mut self, name: &str, now: u64) -> anyhow::Result<Effects> {
+        let master = self.masters.get_mut(name).ok_or(anyhow!(NO_SUCH_MASTER))?;
+        if master.failover.is_some() {
+            return Err(anyhow!("INPROG Failover already in progress"));
+        }
+        if master.select_replica(now).is_none() {
+            return Err(anyhow!("NOGOODSLAVE No suitable replica to promote"));
+        }
+
+        let mut effects = Effects::default();
+        start_failover(master, &mut self.current_epoch, true, now, &mut effects);
+
+        Ok(effects)
+    }
+
+    /// Checks whether enough sentinels are reachable to agree that a master
+    /// is down and to authorize a failover.
+    pub fn ckquorum(&self, name: &str) -> anyhow::Result<String> {
+        let master = self.masters.get(name).ok_or(anyhow!(NO_SUCH_MASTER))?;
+        let voters = master.sentinels.len() + 1;
+        let usable = 1 + master
+            .sentinels
+            .values()
+            .filter(|peer| peer.sdown_since == 0)
+            .count();
+        if usable < master.quorum {
+            return Err(anyhow!(
+                "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the \
+                 specified quorum for this master",
+                usable
+            ));
+        }
+        if usable < voters / 2 + 1 {
+            return Err(anyhow!(
+                "NOQUORUM {} usable Sentinels. Not enough available Sentinels to reach the \
+                 majority and authorize a failover",
+                usable
+            ));
+        }
+
+        Ok(format!(
+            "OK {} usable Sentinels. Quorum and failover authorization can be reached",
+            usable
+        ))
+    }
+
+    pub fn master_frame(&self, name: &str, now: u64) -> Option<Frame> {
+        self.masters.get(name).map(|master| master.to_frame(now))
+    }
+
+    pub fn masters_frame(&self, now: u64) -> Frame {
+        Frame::Array(
+            self.masters
+                .values()
+                .map(|master| master.to_frame(now))
+                .collect(),
+        )
+    }
+
+    pub fn replicas_frame(&self, name: &str, now: u64) -> Option<Frame> {
+        let master = self.masters.get(name)?;
+        let promoted = master
+            .failover
+            .as_ref()
+            .and_then(|failover| failover.promoted.as_ref());
+
+        Some(Frame::Array(
+            master
+                .replicas
+                .values()
+                .map(|replica| replica.to_frame(promoted, now))
+                .collect(),
+        ))
+    }
+
+    pub fn sentinels_frame(&self, name: &str, now: u64) -> Option<Frame> {
+        let master = self.masters.get(name)?;
+
+        Some(Frame::Array(
+            master
+                .sentinels
+                .values()
+                .map(|peer| peer.to_frame(now))
+                .collect(),
+        ))
+    }
+
+    /// The sentinel section of INFO.
+    pub fn info(&self) -> String {
+        let mut info = String::new();
+        writeln!(info, "# Sentinel").unwrap();
+        writeln!(info, "sentinel_masters:{}", self.masters.len()).unwrap();
+        for (index, master) in self.masters.values().enumerate() {
+            let status = if master.odown_since != 0 {
+                "odown"
+            } else if master.sdown_since != 0 {
+                "sdown"
+            } else {
+                "ok"
+            };
+            writeln!(
+                info,
+                "master{}:name={},status={},address={},slaves={},sentinels={}",
+                index,
+                master.name,
+                status,
+                format_addr(master.current_addr()),
+                master.replicas.len(),
+                master.sentinels.len() + 1
+            )
+            .unwrap();
+        }
+
+        info
+    }
+
+    /// Replies to another sentinel asking whether the master at `addr` is
+    /// down, voting for `run_id` as failover leader unless it is `*`.
+    pub fn is_master_down_by_addr(
+        &mut self,
+        addr: &Address,
+        epoch: u64,
+        run_id: &str,
+        now: u64,
+    ) -> (Frame, Effects) {
+        let mut effects = Effects::default();
+        let Some(master) = self
+            .masters
+            .values_mut()
+            .find(|master| master.addr == *addr)
+        else {
+            let reply = Frame::Array(vec![
+                Frame::Integer(0),
+                Frame::Bulk(Bytes::from_static(b"*")),
+                Frame::Integer(0),
+            ]);
+            return (reply, effects);
+        };
+        let down = master.sdown_since != 0;
+        let (leader, leader_epoch) = if run_id == "*" {
+            ("*".to_owned(), 0)
+        } else {
+            vote(
+                master,
+                (&mut self.current_epoch, &self.myid),
+                epoch,
+                run_id,
+                now,
+                &mut effects,
+            );
+            (
+                master.leader.clone().unwrap_or("*".to_owned()),
+                master.leader_epoch,
+            )
+        };
+        let reply = Frame::Array(vec![
+            Frame::Integer(down as i64),
+            Frame::Bulk(Bytes::from(leader)),
+            Frame::Integer(leader_epoch as i64),
+        ]);
+
+        (reply, effects)
+    }
+
+    /// Handles a hello message another sentinel published, in the
+    /// `ip,port,runid,current_epoch,master_name,master_ip,master_port,master_config_epoch`
+    /// form.
+    pub fn handle_hello(&mut self, hello: &str, now: u64) -> Effects {
+        let mut effects = Effects::default();
+        let fields = hello.split(',').collect::<Vec<_>>();
+        let [ip, port, run_id, current_epoch, name, master_ip, master_port, config_epoch] =
+            fields[..]
+        else {
+            return effects;
+        };
+        let (Ok(port), Ok(current_epoch), Ok(master_port), Ok(config_epoch)) = (
+            port.parse::<u16>(),
+            current_epoch.parse::<u64>(),
+            master_port.parse::<u16>(),
+            config_epoch.parse::<u64>(),
+        ) else {
+            return effects;
+        };
+        if run_id == self.myid {
+            return effects;
+        }
+        let Some(master) = self.masters.get_mut(name) else {
+            return effects;
+        };
+
+        if current_epoch > self.current_epoch {
+            self.current_epoch = current_epoch;
+            effects.event("+new-epoch", current_epoch.to_string());
+            effects.save = true;
+        }
+        let addr = (ip.to_owned(), port);
+        if !master.sentinels.contains_key(run_id) {
+            // A sentinel restarted with a new ID, or another one took over
+            // its address.
+            let duplicates = master
+                .sentinels
+                .values()
+                .filter(|peer| peer.addr == addr)
+                .map(|peer| peer.run_id.clone())
+                .collect::<Vec<_>>();
+            for duplicate in duplicates {
+                let peer = master.sentinels.remove(&duplicate).unwrap();
+                effects.event("-dup-sentinel", master.describe_sentinel(&peer));
+            }
+            let peer = Peer::new(run_id.to_owned(), addr.clone(), now);
+            effects.event("+sentinel", master.describe_sentinel(&peer));
+            master.sentinels.insert(run_id.to_owned(), peer);
+            effects.save = true;
+        }
+        let peer = master.sentinels.get_mut(run_id).unwrap();
+        peer.last_hello = now;
+        if peer.addr != addr {
+            peer.addr = addr;
+            effects.save = true;
+        }
+
+        // The sender knows of a more recent failover.
+        if config_epoch > master.config_epoch {
+            master.config_epoch = config_epoch;
+            effects.save = true;
+            let new_addr = (master_ip.to_owned(), master_port);
+            if new_addr != master.addr {
+                let description = master.describe_sentinel(&master.sentinels[run_id]);
+                effects.event("+config-update-from", description);
+                master.switch_to(new_addr, now, &mut effects);
+            }
+        }
+
+        effects
+    }
+
+    /// Handles the reply of an instance to a request, `None` if the request
+    /// failed.
+    pub fn handle_reply(
+        &mut self,
+        instance: &Instance,
+        addr: &Address,
+        request: &Request,
+        reply: Option<Frame>,
+        now: u64,
+    ) -> Effects {
+        let mut effects = Effects::default();
+        match (request, reply) {
+            (Request::Ping, Some(reply)) => {
+                let available = match &reply {
+                    Frame::Simple(s) => s == "PONG",
+                    Frame::Bulk(bytes) => bytes == "PONG",
+                    Frame::Error(error) => {
+                        error.starts_with(b"LOADING") || error.starts_with(b"MASTERDOWN")
+                    }
+                    _ => false,
+                };
+                if let Some(link) = self.link_mut(instance, addr).filter(|_| available) {
+                    link.last_available = now;
+                    link.ping_sent = 0;
+                }
+            }
+            (Request::Info, Some(Frame::Bulk(info))) => {
+                let info = parse_info(&String::from_utf8_lossy(&info));
+                self.refresh_info(instance, addr, info, now, &mut effects);
+            }
+            (Request::IsMasterDownByAddr { .. }, Some(Frame::Array(reply))) => {
+                let Instance::Sentinel(name, run_id) = instance else {
+                    return effects;
+                };
+                let Some(peer) = self
+                    .masters
+                    .get_mut(name)
+                    .and_then(|master| master.sentinels.get_mut(run_id))
+                else {
+                    return effects;
+                };
+                if let [Frame::Integer(down), Frame::Bulk(leader), Frame::Integer(leader_epoch)] =
+                    &reply[..]
+                {
+                    peer.master_down = *down == 1;
+                    peer.down_reply_time = now;
+                    if &leader[..] != b"*" {
+                        peer.leader = Some(String::from_utf8_lossy(leader).into_owned());
+                        peer.leader_epoch = *leader_epoch as u64;
+                    }
+                }
+            }
+            _ => (),
+        }
+
+        effects
+    }
+
+    fn link_mut(&mut self, instance: &Instance, addr: &Address) -> Option<&mut Link> {
+        match instance {
+            Instance::Master(name) => self
+                .masters
+                .get_mut(name)
+                .filter(|master| master.addr == *addr)
+                .map(|master| &mut master.link),
+            Instance::Replica(name, replica) => self
+                .masters
+                .get_mut(name)?
+                .replicas
+                .get_mut(replica)
+                .map(|replica| &mut replica.link),
+            Instance::Sentinel(name, run_id) => self
+                .masters
+                .get_mut(name)?
+                .sentinels
+                .get_mut(run_id)
+                .filter(|peer| peer.addr == *addr)
+                .map(|peer| &mut peer.link),
+        }
+    }
+
+    fn refresh_info(
+        &mut self,
+        instance: &Instance,
+        addr: &Address,
+        info: Info,
+        now: u64,
+        effects: &mut Effects,
+    ) {
+        let Some(link) = self.link_mut(instance, addr) else {
+            return;
+        };
+        link.info_refresh = now;
+        link.run_id = info.run_id.clone();
+        link.report_role(info.role, now);
+        match instance {
+            Instance::Master(name) => {
+                let master = self.masters.get_mut(name).unwrap();
+                for replica in info.replicas {
+                    if !master.replicas.contains_key(&replica) {
+                        effects.event("+slave", master.describe_replica(&replica));
+                        master
+                            .replicas
+                            .insert(replica.clone(), Replica::new(replica, now));
+                        effects.save = true;
+                    }
+                }
+            }
+            Instance::Replica(name, _) => {
+                let master = self.masters.get_mut(name).unwrap();
+                refresh_replica_info(master, addr, info, now, effects);
+            }
+            Instance::Sentinel(..) => (),
+        }
+    }
+
+    /// Runs the periodic tasks: queues the requests that are due, checks
+    /// which instances are down, and moves failovers forward.
+    pub fn cron(&mut self, now: u64) -> Effects {
+        let mut effects = Effects::default();
+        let names = self.masters.keys().cloned().collect::<Vec<_>>();
+        for name in names {
+            self.send_periodic(&name, now, &mut effects);
+            let master = self.masters.get_mut(&name).unwrap();
+            check_sdown(master, now, &mut effects);
+            check_odown(master, now, &mut effects);
+            if master.odown_since != 0
+                && master.failover.is_none()
+                && now.saturating_sub(master.failover_start_time) > 2 * master.failover_timeout
+            {
+                start_failover(master, &mut self.current_epoch, false, now, &mut effects);
+            }
+            ask_sentinels(master, &self.myid, self.current_epoch, now, &mut effects);
+            handle_failover(
+                master,
+                (&mut self.current_epoch, &self.myid),
+                now,
+                &mut effects,
+            );
+        }
+
+        effects
+    }
+
+    fn send_periodic(&mut self, name: &str, now: u64, effects: &mut Effects) {
+        let master = self.masters.get_mut(name).unwrap();
+        let current_addr = master.current_addr();
+        let hello = format!(
+            "{},{},{},{},{},{},{}",
+            self.port,
+            self.myid,
+            self.current_epoch,
+            master.name,
+            current_addr.0,
+            current_addr.1,
+            master.config_epoch
+        );
+        let ping_period = PING_PERIOD.min(master.down_after);
+        // Replicas are watched closely while they may be promoted.
+        let replica_info_period = if master.odown_since != 0 || master.failover.is_some() {
+            INFO_PERIOD_FAILOVER
+        } else {
+            INFO_PERIOD
+        };
+
+        let instance = Instance::Master(name.to_owned());
+        master.link.send_periodic(
+            (instance, &master.addr),
+            Some(INFO_PERIOD),
+            &hello,
+            ping_period,
+            now,
+            effects,
+        );
+        for replica in master.replicas.values_mut() {
+            let instance = Instance::Replica(name.to_owned(), replica.addr.clone());
+            replica.link.send_periodic(
+                (instance, &replica.addr),
+                Some(replica_info_period),
+                &hello,
+                ping_period,
+                now,
+                effects,
+            );
+        }
+        for peer in master.sentinels.values_mut() {
+            let instance = Instance::Sentinel(name.to_owned(), peer.run_id.clone());
+            peer.link.send_periodic(
+                (instance, &peer.addr),
+                None,
+                &hello,
+                ping_period,
+                now,
+                effects,
+            );
+        }
+    }
+}
+
+/// Updates what the sentinel knows of a replica from its INFO, and moves a
+/// failover forward or fixes the config of the replica accordingly.
+fn refresh_replica_info(
+    master: &mut Master,
+    addr: &Address,
+    info: Info,
+    now: u64,
+    effects: &mut Effects,
+) {
+    let description = master.describe_replica(addr);
+    let replica = master.replicas.get_mut(addr).unwrap();
+    if info.master_addr != replica.master_addr {
+        replica.master_addr = info.master_addr.clone();
+        replica.link.role_reported_time = now;
+    }
+    replica.master_link_up = info.master_link_up;
+    replica.master_link_down_time = info.master_link_down_since * 1000;
+    replica.priority = info.priority.unwrap_or(DEFAULT_PRIORITY);
+    replica.offset = info.offset;
+
+    if let Some(failover) = master.failover.as_mut() {
+        let promoted = failover.promoted.as_ref() == Some(addr);
+        if failover.state == FailoverState::WaitPromotion
+            && promoted
+            && info.role == Some(Reported::Master)
+        {
+            failover.state = FailoverState::ReconfReplicas;
+            failover.state_time = now;
+            master.config_epoch = failover.epoch;
+            effects.event("+promoted-slave", description);
+            effects.event("+failover-state-reconf-slaves", master.describe());
+            effects.save = true;
+            return;
+        }
+        if failover.state == FailoverState::ReconfReplicas && !promoted {
+            let target = failover.promoted.clone();
+            if matches!(replica.reconf, Reconf::Sent(_))
+                && info.role == Some(Reported::Replica)
+                && info.master_addr == target
+            {
+                replica.reconf = Reconf::InProgress;
+                effects.event("+slave-reconf-inprog", description.clone());
+            }
+            if replica.reconf == Reconf::InProgress && info.master_link_up {
+                replica.reconf = Reconf::Done;
+                effects.event("+slave-reconf-done", description);
+            }
+        }
+        return;
+    }
+
+    // Replicas that report another role or master than expected for a
+    // while are reconfigured, provided the master looks sane.
+    if master.sdown_since != 0 || master.link.role_reported != Some(Reported::Master) {
+        return;
+    }
+    let fix = match info.role {
+        Some(Reported::Master) => "+convert-to-slave",
+        Some(Reported::Replica) if replica.master_addr.as_ref() != Some(&master.addr) => {
+            "+fix-slave-config"
+        }
+        _ => return,
+    };
+    if now.saturating_sub(replica.link.role_reported_time) > 4 * HELLO_PERIOD
+        && now.saturating_sub(replica.fix_sent) > FIX_PERIOD
+    {
+        replica.fix_sent = now;
+        let request = Request::Replicaof(Some(master.addr.clone()));
+        let instance = Instance::Replica(master.name.clone(), addr.clone());
+        effects.requests.push((instance, addr.clone(), request));
+        effects.event(fix, description);
+    }
+}
+
+fn set_sdown(since: &mut u64, down: bool, description: String, now: u64, effects: &mut Effects) {
+    if down && *since == 0 {
+        *since = now;
+        effects.event("+sdown", description);
+    } else if !down && *since != 0 {
+        *since = 0;
+        effects.event("-sdown", description);
+    }
+}
+
+/// Flags the instances that did not reply validly for `down_after` as
+/// subjectively down. So is a master reporting to be a replica.
+fn check_sdown(master: &mut Master, now: u64, effects: &mut Effects) {
+    let down_after = master.down_after;
+    let demoted = master.link.role_reported == Some(Reported::Replica)
+        && now.saturating_sub(master.link.role_reported_time) > down_after + 2 * INFO_PERIOD;
+    let down = master.link.is_down(down_after, now) || demoted;
+    let description = master.describe();
+    set_sdown(&mut master.sdown_since, down, description, now, effects);
+
+    let descriptions = master
+        .replicas
+        .keys()
+        .map(|addr| master.describe_replica(addr))
+        .collect::<Vec<_>>();
+    for (replica, description) in master.replicas.values_mut().zip(descriptions) {
+        let down = replica.link.is_down(down_after, now);
+        set_sdown(&mut replica.sdown_since, down, description, now, effects);
+    }
+    let descriptions = master
+        .sentinels
+        .values()
+        .map(|peer| master.describe_sentinel(peer))
+        .collect::<Vec<_>>();
+    for (peer, description) in master.sentinels.values_mut().zip(descriptions) {
+        let down = peer.link.is_down(down_after, now);
+        set_sdown(&mut peer.sdown_since, down, description, now, effects);
+    }
+}
+
+/// Flags the master as objectively down once a quorum of sentinels recently
+/// replied that it is down.
+fn check_odown(master: &mut Master, now: u64, effects: &mut Effects) {
+    for peer in master.sentinels.values_mut() {
+        if now.saturating_sub(peer.down_reply_time) > ASK_VALIDITY {
+            peer.master_down = false;
+            peer.leader = None;
+        }
+    }
+    let agreeing = 1 + master
+        .sentinels
+        .values()
+        .filter(|peer| peer.master_down)
+        .count();
+    let odown = master.sdown_since != 0 && agreeing >= master.quorum;
+    if odown && master.odown_since == 0 {
+        master.odown_since = now;
+        let quorum = format!("#quorum {}/{}", agreeing, master.quorum);
+        effects.event("+odown", format!("{} {}", master.describe(), quorum));
+    } else if !odown && master.odown_since != 0 {
+        master.odown_since = 0;
+        effects.event("-odown", master.describe());
+    }
+}
+
+/// Asks the other sentinels whether they see the master down too, and while
+/// failing it over, to vote for this sentinel.
+fn ask_sentinels(master: &mut Master, myid: &str, epoch: u64, now: u64, effects: &mut Effects) {
+    if master.sdown_since == 0 {
+        return;
+    }
+    let run_id = match master.failover {
+        Some(_) => myid.to_owned(),
+        None => "*".to_owned(),
+    };
+    for peer in master.sentinels.values_mut() {
+        if now.saturating_sub(peer.last_ask) < ASK_PERIOD {
+            continue;
+        }
+        peer.last_ask = now;
+        let request = Request::IsMasterDownByAddr {
+            addr: master.addr.clone(),
+            epoch,
+            run_id: run_id.clone(),
+        };
+        let instance = Instance::Sentinel(master.name.clone(), peer.run_id.clone());
+        effects
+            .requests
+            .push((instance, peer.addr.clone(), request));
+    }
+}
+
+fn start_failover(
+    master: &mut Master,
+    current_epoch: &mut u64,
+    forced: bool,
+    now: u64,
+    effects: &mut Effects,
+) {
+    *current_epoch += 1;
+    effects.event("+new-epoch", current_epoch.to_string());
+    effects.event("+try-failover", master.describe());
+    master.failover = Some(Failover {
+        epoch: *current_epoch,
+        state: FailoverState::WaitStart,
+        state_time: now,
+        forced,
+        promoted: None,
+    });
+    master.failover_start_time = now + db::random() % MAX_DESYNC;
+    // Votes are asked for right away.
+    for peer in master.sentinels.values_mut() {
+        peer.last_ask = 0;
+    }
+    effects.save = true;
+}
+
+/// Votes for `candidate` as the leader of the failovers of `epoch`, unless
+/// this sentinel already voted in that epoch.
+fn vote(
+    master: &mut Master,
+    (current_epoch, myid): (&mut u64, &str),
+    epoch: u64,
+    candidate: &str,
+    now: u64,
+    effects: &mut Effects,
+) {
+    if epoch > *current_epoch {
+        *current_epoch = epoch;
+        effects.event("+new-epoch", epoch.to_string());
+        effects.save = true;
+    }
+    if master.leader_epoch < epoch && *current_epoch <= epoch {
+        master.leader = Some(candidate.to_owned());
+        master.leader_epoch = *current_epoch;
+        effects.event(
+            "+vote-for-leader",
+            format!("{} {}", candidate, master.leader_epoch),
+        );
+        effects.save = true;
+        // Voting for another sentinel delays any failover by this one.
+        if candidate != myid {
+            master.failover_start_time = now + db::random() % MAX_DESYNC;
+        }
+    }
+}
+
+/// Counts the votes of the sentinels for the leader of the failovers of
+/// `epoch`, voting too, and returns the leader if it has a majority.
+fn elect_leader(
+    master: &mut Master,
+    (current_epoch, myid): (&mut u64, &str),
+    epoch: u64,
+    now: u64,
+    effects: &mut Effects,
+) -> Option<String> {
+    let mut votes = HashMap::<String, usize>::new();
+    for peer in master.sentinels.values() {
+        if let Some(leader) = peer.leader.as_ref().filter(|_| peer.leader_epoch == epoch) {
+            *votes.entry(leader.clone()).or_default() += 1;
+        }
+    }
+    let most_voted = |votes: &HashMap<String, usize>| {
+        votes
+            .iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(id, count)| (id.clone(), *count))
+    };
+    let candidate = most_voted(&votes).map_or(myid.to_owned(), |(id, _)| id);
+    vote(
+        master,
+        (current_epoch, myid),
+        epoch,
+        &candidate,
+        now,
+        effects,
+    );
+    if let Some(leader) = master
+        .leader
+        .as_ref()
+        .filter(|_| master.leader_epoch == epoch)
+    {
+        *votes.entry(leader.clone()).or_default() += 1;
+    }
+
+    let voters = master.sentinels.len() + 1;
+    let needed = (voters / 2 + 1).max(master.quorum);
+    most_voted(&votes)
+        .filter(|(_, count)| *count >= needed)
+        .map(|(id, _)| id)
+}
+
+/// Moves the failover of a master forward.
+fn handle_failover(
+    master: &mut Master,
+    (current_epoch, myid): (&mut u64, &str),
+    now: u64,
+    effects: &mut Effects,
+) {
+    let Some(failover) = master.failover.clone() else {
+        return;
+    };
+    let elapsed = now.saturating_sub(failover.state_time);
+    let set_state = |master: &mut Master, state: FailoverState| {
+        let failover = master.failover.as_mut().unwrap();
+        failover.state = state;
+        failover.state_time = now;
+    };
+    match failover.state {
+        FailoverState::WaitStart => {
+            let leader = if failover.forced {
+                Some(myid.to_owned())
+            } else {
+                elect_leader(master, (current_epoch, myid), failover.epoch, now, effects)
+            };
+            if leader.as_deref() == Some(myid) {
+                effects.event("+elected-leader", master.describe());
+                effects.event("+failover-state-select-slave", master.describe());
+                set_state(master, FailoverState::SelectReplica);
+            } else if elapsed > ELECTION_TIMEOUT.min(master.failover_timeout) {
+                master.abort_failover("-failover-abort-not-elected", effects);
+            }
+        }
+        FailoverState::SelectReplica => match master.select_replica(now) {
+            Some(addr) => {
+                effects.event("+selected-slave", master.describe_replica(&addr));
+                effects.event(
+                    "+failover-state-send-slaveof-noone",
+                    master.describe_replica(&addr),
+                );
+                master.failover.as_mut().unwrap().promoted = Some(addr);
+                set_state(master, FailoverState::SendReplicaofNoOne);
+            }
+            None => master.abort_failover("-failover-abort-no-good-slave", effects),
+        },
+        FailoverState::SendReplicaofNoOne => {
+            let promoted = failover.promoted.unwrap();
+            let reachable = master
+                .replicas
+                .get(&promoted)
+                .is_some_and(|replica| replica.sdown_since == 0);
+            if reachable {
+                let instance = Instance::Replica(master.name.clone(), promoted.clone());
+                effects
+                    .requests
+                    .push((instance, promoted.clone(), Request::Replicaof(None)));
+                effects.event(
+                    "+failover-state-wait-promotion",
+                    master.describe_replica(&promoted),
+                );
+                set_state(master, FailoverState::WaitPromotion);
+            } else if elapsed > master.failover_timeout {
+                master.abort_failover("-failover-abort-slave-timeout", effects);
+            }
+        }
+        FailoverState::WaitPromotion => {
+            if elapsed > master.failover_timeout {
+                master.abort_failover("-failover-abort-slave-timeout", effects);
+            }
+        }
+        FailoverState::ReconfReplicas => {
+            let promoted = failover.promoted.unwrap();
+            reconf_replicas(
+                master,
+                &promoted,
+                elapsed > master.failover_timeout,
+                now,
+                effects,
+            );
+        }
+    }
+}
+
+/// Tells the replicas to replicate the promoted one, a few at a time, and
+/// switches the master to it once they all did. Past the failover timeout,
+/// the remaining replicas are told all at once.
+fn reconf_replicas(
+    master: &mut Master,
+    promoted: &Address,
+    timed_out: bool,
+    now: u64,
+    effects: &mut Effects,
+) {
+    let descriptions = master
+        .replicas
+        .keys()
+        .map(|addr| master.describe_replica(addr))
+        .collect::<Vec<_>>();
+    let mut in_progress = 0;
+    for (replica, description) in master.replicas.values_mut().zip(&descriptions) {
+        match replica.reconf {
+            Reconf::Sent(sent) if now.saturating_sub(sent) > RECONF_TIMEOUT => {
+                replica.reconf = Reconf::Done;
+                effects.event("-slave-reconf-sent-timeout", description.clone());
+            }
+            Reconf::Sent(_) | Reconf::InProgress => in_progress += 1,
+            _ => (),
+        }
+    }
+    for (replica, description) in master.replicas.values_mut().zip(descriptions) {
+        if replica.addr == *promoted
+            || replica.reconf != Reconf::None
+            || (replica.sdown_since != 0 && !timed_out)
+        {
+            continue;
+        }
+        if in_progress >= master.parallel_syncs && !timed_out {
+            break;
+        }
+        let instance = Instance::Replica(master.name.clone(), replica.addr.clone());
+        let request = Request::Replicaof(Some(promoted.clone()));
+        effects
+            .requests
+            .push((instance, replica.addr.clone(), request));
+        replica.reconf = Reconf::Sent(now);
+        effects.event("+slave-reconf-sent", description);
+        in_progress += 1;
+    }
+
+    let done = master.replicas.values().all(|replica| {
+        replica.addr == *promoted || replica.reconf == Reconf::Done || replica.sdown_since != 0
+    });
+    if done || timed_out {
+        if !done {
+            effects.event("+failover-end-for-timeout", master.describe());
+        }
+        effects.event("+failover-end", master.describe());
+        master.switch_to(promoted.clone(), now, effects);
+    }
+}
+
+/// Parses the INFO of an instance.
+pub fn parse_info(info: &str) -> Info {
+    let mut parsed = Info::default();
+    let mut master_host = None;
+    let mut master_port = None;
+    for line in info.lines() {
+        let Some((field, value)) = line.trim_end().split_once(':') else {
+            continue;
+        };
+        match field {
+            "run_id" => parsed.run_id = Some(value.to_owned()),
+            "role" => {
+                parsed.role = match value {
+                    "master" => Some(Reported::Master),
+                    "slave" => Some(Reported::Replica),
+                    _ => None,
+                }
+            }
+            "master_host" => master_host = Some(value.to_owned()),
+            "master_port" => master_port = value.parse().ok(),
+            "master_link_status" => parsed.master_link_up = value == "up",
+            "master_link_down_since_seconds" => {
+                parsed.master_link_down_since = value.parse().unwrap_or(0)
+            }
+            "slave_priority" | "replica_priority" => parsed.priority = value.parse().ok(),
+            "slave_repl_offset" => parsed.offset = value.parse().unwrap_or(0),
+            field if field.starts_with("slave") && field[5..].parse::<usize>().is_ok() => {
+                let mut ip = None;
+                let mut port = None;
+                for pair in value.split(',') {
+                    match pair.split_once('=') {
+                        Some(("ip", value)) => ip = Some(value.to_owned()),
+                        Some(("port", value)) => port = value.parse().ok(),
+                        _ => (),
+                    }
+                }
+                if let (Some(ip), Some(port)) = (ip, port) {
+                    parsed.replicas.push((ip, port));
+                }
+            }
+            _ => (),
+        }
+    }
+    parsed.master_addr = master_host.zip(master_port);
+
+    parsed
+}
+
+fn link_fields(link: &Link, now: u64) -> Vec<(&'static str, String)> {
+    let since = |time: u64| {
+        if time == 0 {
+            "0".to_owned()
+        } else {
+            now.saturating_sub(time).to_string()
+        }
+    };
+    let role = match link.role_reported {
+        Some(Reported::Master) => "master",
+        Some(Reported::Replica) => "slave",
+        None => "unknown",
+    };
+
+    vec![
+        ("last-ping-sent", since(link.ping_sent)),
+        ("last-ok-ping-reply", since(link.last_available)),
+        ("info-refresh", since(link.info_refresh)),
+        ("role-reported", role.to_owned()),
+        ("role-reported-time", since(link.role_reported_time)),
+    ]
+}
+
+fn fields_frame(fields: Vec<(&str, String)>) -> Frame {
+    Frame::bulk_array(
+        fields
+            .into_iter()
+            .flat_map(|(name, value)| [name.to_owned(), value]),
+    )
+}
+
+/// Formats an address as `host:port`, with brackets around IPv6 hosts.
+fn format_addr((host, port): &Address) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+pub const NO_SUCH_MASTER: &str = "ERR No such master with that name";
+
+const DEFAULT_DOWN_AFTER: u64 = 30 * 1000;
+const DEFAULT_FAILOVER_TIMEOUT: u64 = 3 * 60 * 1000;
+const DEFAULT_PRIORITY: u64 = 100;
+const PING_PERIOD: u64 = 1000;
+const INFO_PERIOD: u64 = 10 * 1000;
+// Period of the INFOs sent to replicas while their master is down.
+const INFO_PERIOD_FAILOVER: u64 = 1000;
+const HELLO_PERIOD: u64 = 2000;
+// Period of the questions to other sentinels while a master is down.
+const ASK_PERIOD: u64 = 1000;
+// Time a reply of another sentinel about a master being down is trusted.
+const ASK_VALIDITY: u64 = 5000;
+const ELECTION_TIMEOUT: u64 = 10 * 1000;
+// Maximum random delay added to failover start times.
+const MAX_DESYNC: u64 = 1000;
+// Time after which a replica told to replicate the promoted one counts as
+// reconfigured.
+const RECONF_TIMEOUT: u64 = 10 * 1000;
+// Minimum time between two fixes of the config of a replica.
+const FIX_PERIOD: u64 = 10 * 1000;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> Address {
+        ("127.0.0.1".to_owned(), port)
+    }
+
+    fn master_info(replicas: &[u16]) -> String {
+        let mut info = "# Replication\nrole:master\n".to_owned();
+        for (index, port) in replicas.iter().enumerate() {
+            writeln!(
+                info,
+                "slave{index}:ip=127.0.0.1,port={port},state=online,offset=10,lag=0"
+            )
+            .unwrap();
+        }
+        info
+    }
+
+    fn replica_info(master: u16, offset: u64) -> String {
+        format!(
+            "# Replication\nrole:slave\nmaster_host:127.0.0.1\nmaster_port:{master}\n\
+             master_link_status:up\nslave_repl_offset:{offset}\n"
+        )
+    }
+
+    #[test]
+    fn test_parse_info() {
+        let info = parse_info(&master_info(&[6380, 6381]));
+        assert_eq!(Some(Reported::Master), info.role);
+        assert_eq!(vec![addr(6380), addr(6381)], info.replicas);
+
+        let info = parse_info(
+            "role:slave\r\nmaster_host:::1\r\nmaster_port:6379\r\nmaster_link_status:down\r\n\
+             master_link_down_since_seconds:12\r\nslave_priority:0\r\nslave_repl_offset:42\r\n",
+        );
+        assert_eq!(Some(Reported::Replica), info.role);
+        assert_eq!(Some(("::1".to_owned(), 6379)), info.master_addr);
+        assert!(!info.master_link_up);
+        assert_eq!(12, info.master_link_down_since);
+        assert_eq!(Some(0), info.priority);
+        assert_eq!(42, info.offset);
+    }
+
+    #[test]
+    fn test_config_roundtrip() {
+        let mut state = SentinelState::new("a".repeat(40), 26379);
+        state.monitor("mymaster", addr(6379), 2, 0).unwrap();
+        assert!(state.monitor("mymaster", addr(6380), 2, 0).is_err());
+        assert!(state.monitor("other", addr(6380), 0, 0).is_err());
+        let options = [("down-after-milliseconds".to_owned(), "5000".to_owned())];
+        state.set("mymaster", &options).unwrap();
+        state.current_epoch = 4;
+        let hello = format!(
+            "127.0.0.1,26380,{},3,mymaster,127.0.0.1,6379,0",
+            "b".repeat(40)
+        );
+        state.handle_hello(&hello, 0);
+        let info = Frame::Bulk(Bytes::from(master_info(&[6380])));
+        let instance = Instance::Master("mymaster".to_owned());
+        state.handle_reply(&instance, &addr(6379), &Request::Info, Some(info), 0);
+
+        let config = state.to_config();
+        let parsed = SentinelState::parse(&config, 0).unwrap();
+        assert_eq!(config, parsed.to_config());
+        assert_eq!("a".repeat(40), parsed.myid);
+        assert_eq!(4, parsed.current_epoch);
+        let master = parsed.master("mymaster").unwrap();
+        assert_eq!(5000, master.down_after);
+        assert_eq!(2, master.quorum);
+        assert!(master.replicas.contains_key(&addr(6380)));
+        assert!(master.sentinels.contains_key(&"b".repeat(40)));
+        assert!(SentinelState::parse("port 26379\n", 0).is_err());
+        assert!(SentinelState::parse("sentinel config-epoch nope 1\n", 0).is_err());
+    }
+
+    #[test]
+    fn test_vote() {
+        let mut state = SentinelState::new("a".repeat(40), 26379);
+        state.monitor("mymaster", addr(6379), 2, 0).unwrap();
+        let (reply, _) = state.is_master_down_by_addr(&addr(6379), 5, &"b".repeat(40), 1000);
+        let expected = Frame::Array(vec![
+            Frame::Integer(0),
+            Frame::Bulk(Bytes::from("b".repeat(40))),
+            Frame::Integer(5),
+        ]);
+        assert_eq!(expected.to_bytes(), reply.to_bytes());
+        assert_eq!(5, state.current_epoch);
+        // One vote per epoch.
+        let (reply, _) = state.is_master_down_by_addr(&addr(6379), 5, &"c".repeat(40), 1000);
+        assert_eq!(expected.to_bytes(), reply.to_bytes());
+        let (reply, _) = state.is_master_down_by_addr(&addr(7000), 6, &"c".repeat(40), 1000);
+        assert!(matches!(reply, Frame::Array(frames) if matches!(frames[0], Frame::Integer(0))));
+    }
+
+    /// Sentinels monitoring a master with two replicas, over a simulated
+    /// network where instances can be taken down.
+    struct Deployment {
+        sentinels: Vec<SentinelState>,
+        // Port of the master each data instance replicates, `None` for
+        // masters.
+        instances: BTreeMap<u16, Option<u16>>,
+        down: Vec<u16>,
+        now: u64,
+    }
+
+    impl Deployment {
+        fn new() -> Self {
+            let now = 1_000_000;
+            let sentinels = (0..3)
+                .map(|i| {
+                    let id = ((b'a' + i as u8) as char).to_string().repeat(40);
+                    let mut state = SentinelState::new(id, 26379 + i as u16);
+                    state.monitor("mymaster", addr(6379), 2, now).unwrap();
+                    let options = [
+                        ("down-after-milliseconds".to_owned(), "1000".to_owned()),
+                        ("failover-timeout".to_owned(), "5000".to_owned()),
+                    ];
+                    state.set("mymaster", &options).unwrap();
+                    state
+                })
+                .collect();
+            Deployment {
+                sentinels,
+                instances: BTreeMap::from([(6379, None), (6380, Some(6379)), (6381, Some(6379))]),
+                down: Vec::new(),
+                now,
+            }
+        }
+
+        /// Runs the crons of the sentinels for the given milliseconds.
+        fn run(&mut self, millis: u64) {
+            for _ in 0..millis / 100 {
+                self.now += 100;
+                for index in 0..self.sentinels.len() {
+                    let effects = self.sentinels[index].cron(self.now);
+                    self.deliver(index, effects);
+                }
+            }
+        }
+
+        fn deliver(&mut self, from: usize, effects: Effects) {
+            let mut pending = vec![(from, effects)];
+            while let Some((from, effects)) = pending.pop() {
+                for (instance, target, request) in effects.requests {
+                    if self.down.contains(&target.1) {
+                        let effects = self.sentinels[from]
+                            .handle_reply(&instance, &target, &request, None, self.now);
+                        pending.push((from, effects));
+                        continue;
+                    }
+                    let reply = self.reply(from, &target, &request, &mut pending);
+                    let effects = self.sentinels[from].handle_reply(
+                        &instance,
+                        &target,
+                        &request,
+                        Some(reply),
+                        self.now,
+                    );
+                    pending.push((from, effects));
+                }
+            }
+        }
+
+        fn reply(
+            &mut self,
+            from: usize,
+            target: &Address,
+            request: &Request,
+            pending: &mut Vec<(usize, Effects)>,
+        ) -> Frame {
+            let sentinel = self
+                .sentinels
+                .iter()
+                .position(|sentinel| sentinel.port == target.1);
+            match (request, sentinel) {
+                (Request::Ping, _) => Frame::Simple("PONG".to_owned()),
+                (Request::Hello(hello), _) => {
+                    // Every sentinel listens to the hellos.
+                    let hello = format!("127.0.0.1,{}", hello);
+                    for index in 0..self.sentinels.len() {
+                        let effects = self.sentinels[index].handle_hello(&hello, self.now);
+                        pending.push((index, effects));
+                    }
+                    Frame::Integer(1)
+                }
+                (
+                    Request::IsMasterDownByAddr {
+                        addr,
+                        epoch,
+                        run_id,
+                    },
+                    Some(index),
+                ) => {
+                    let (reply, effects) = self.sentinels[index]
+                        .is_master_down_by_addr(addr, *epoch, run_id, self.now);
+                    pending.push((index, effects));
+                    reply
+                }
+                (Request::Info, None) => {
+                    let info = match self.instances[&target.1] {
+                        None => {
+                            let replicas = self
+                                .instances
+                                .iter()
+                                .filter(|(_, master)| **master == Some(target.1))
+                                .map(|(port, _)| *port)
+                                .collect::<Vec<_>>();
+                            master_info(&replicas)
+                        }
+                        Some(master) => replica_info(master, 100 + target.1 as u64),
+                    };
+                    Frame::Bulk(Bytes::from(info))
+                }
+                (Request::Replicaof(master), None) => {
+                    self.instances
+                        .insert(target.1, master.as_ref().map(|(_, port)| *port));
+                    Frame::Simple("OK".to_owned())
+                }
+                _ => panic!("unexpected request from {}: {:?}", from, request),
+            }
+        }
+    }
+
+    #[test]
+    fn test_failover() {
+        let mut deployment = Deployment::new();
+        deployment.run(3000);
+        for sentinel in &deployment.sentinels {
+            let master = sentinel.master("mymaster").unwrap();
+            assert_eq!(2, master.replicas.len());
+            assert_eq!(2, master.sentinels.len());
+        }
+
+        deployment.down.push(6379);
+        deployment.run(10000);
+        // The replica with the highest offset is promoted, and the other one
+        // replicates it.
+        assert_eq!(None, deployment.instances[&6381]);
+        assert_eq!(Some(6381), deployment.instances[&6380]);
+        for sentinel in &deployment.sentinels {
+            let master = sentinel.master("mymaster").unwrap();
+            assert_eq!(addr(6381), master.addr);
+            assert!(!master.failover_in_progress());
+            assert_eq!(1, master.config_epoch);
+            assert!(master.replicas.contains_key(&addr(6379)));
+        }
+
+        // The old master is turned into a replica once back.
+        deployment.down.clear();
+        deployment.run(20000);
+        assert_eq!(Some(6381), deployment.instances[&6379]);
+    }
+
+    #[test]
+    fn test_forced_failover() {
+        let mut deployment = Deployment::new();
+        let sentinel = &mut deployment.sentinels[0];
+        assert!(sentinel.failover("mymaster", deployment.now).is_err());
+        deployment.run(3000);
+        let now = deployment.now;
+        let effects = deployment.sentinels[0].failover("mymaster", now).unwrap();
+        deployment.deliver(0, effects);
+        assert!(deployment.sentinels[0]
+            .failover("mymaster", now)
+            .is_err_and(|err| err.to_string().starts_with("INPROG")));
+        // The old master is still up, and turned into a replica once the new
+        // config is stable.
+        deployment.run(15000);
+        assert_eq!(None, deployment.instances[&6381]);
+        assert_eq!(Some(6381), deployment.instances[&6380]);
+        assert_eq!(Some(6381), deployment.instances[&6379]);
+        for sentinel in &deployment.sentinels {
+            assert_eq!(addr(6381), sentinel.master("mymaster").unwrap().addr);
+        }
+    }
+}
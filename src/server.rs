@@ -1,244 +1,3662 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap, HashSet},
     fmt::Write,
-    net::Ipv4Addr,
-    sync::{Arc, Mutex},
+    fs::File,
+    io::{self, Write as _},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Duration,
 };
 
 use anyhow::{anyhow, Context};
-use bytes::{BufMut, Bytes, BytesMut};
+use bytes::{Bytes, BytesMut};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    runtime::Handle,
+    sync::{mpsc, oneshot, Notify},
+    task::JoinHandle,
     time::Instant,
 };
 
-use crate::{command::Command, frame::Frame, net::FrameStream};
+use crate::{
+    aof::{self, Aof, Manifest},
+    bus::Message,
+    cluster::{self, ClusterState, Effects, Node},
+    command::{self, Cluster, Command, Object, Replconf, Sentinel, TrackingOptions},
+    config::{Config, DisklessLoad, KeyspaceEvents},
+    db::{Db, DbValue, Keyspace, Snapshot, Value},
+    evict::EvictionPool,
+    executor::{self, Executors},
+    frame::Frame,
+    net::{self, FrameStream, RdbReader},
+    pubsub::{PubSub, Subscriber},
+    rdb::{self, Rdb},
+    replication::{self, ReplicaState, Replication},
+    sentinel::{self, Instance, Request, SentinelState},
+    tracking::Tracking,
+};
 
 pub struct Server {
-    role: Role,
+    // Always locked before `replication` when both are.
+    role: RwLock<Role>,
     db: Db,
-    port: u16,
+    config: Config,
+    // Number of key changes since the last successful save.
+    dirty: AtomicU64,
+    // Unix time in seconds of the last successful save.
+    last_save: AtomicU64,
+    // Set while SAVE or BGSAVE write the RDB file.
+    save_in_progress: AtomicBool,
+    // Number of keys evicted to stay under the memory limit.
+    evicted_keys: AtomicU64,
+    // Number of keys deleted because they expired.
+    expired_keys: AtomicU64,
+    replication: Mutex<Replication>,
+    master_link_up: AtomicBool,
+    // Set while the RDB snapshot of the master is transferred and loaded.
+    master_sync_in_progress: AtomicBool,
+    // Unix time in milliseconds of the last data received from the master.
+    master_last_io: AtomicU64,
+    // Unix time in milliseconds the master link went down, 0 if never up.
+    master_link_down_since: AtomicU64,
+    // The append-only file, when enabled.
+    aof: Mutex<Option<Aof>>,
+    aof_rewrite_in_progress: AtomicBool,
+    // Replicas waiting for the next diskless transfer to start.
+    diskless_waiting: Mutex<Vec<DisklessWaiting>>,
+    // Task replicating the master, while the server is a replica.
+    master_link: Mutex<Option<JoinHandle<()>>>,
+    // Notified whenever a replica acknowledges an offset.
+    replica_acks: Notify,
+    // In thread-per-core mode, one executor per shard of the keyspace.
+    executors: Executors,
+    // The view of the cluster, in cluster mode. Never locked along with
+    // another lock.
+    cluster: Option<Mutex<ClusterState>>,
+    // Connections opened to the other nodes over the cluster bus, by node ID,
+    // each with an ID of its own.
+    bus_links: Mutex<HashMap<String, (u64, mpsc::UnboundedSender<Message>)>>,
+    next_bus_link: AtomicU64,
+    // Unix time in milliseconds until which writes wait, while a replica
+    // takes over in a manual failover.
+    clients_paused_until: AtomicU64,
+    // In cluster mode, a lock per slot held for writing while MIGRATE moves
+    // keys of the slot, and for reading by commands accessing keys of the
+    // slot, which so never see a key on both nodes.
+    migrate_gates: Vec<tokio::sync::RwLock<()>>,
+    pubsub: PubSub,
+    next_client_id: AtomicU64,
+    // Keys and prefixes clients track for their caches. Locked while shard
+    // locks are held, as writes invalidate keys under them, and never locks
+    // anything else.
+    tracking: Tracking,
+    // The view of the sentinel, in sentinel mode. Never locked along with
+    // another lock.
+    sentinel: Option<Mutex<SentinelState>>,
+    // Connections the sentinel sends its requests on, by address of the
+    // instance, each with an ID of its own.
+    sentinel_links: Mutex<HashMap<sentinel::Address, (u64, SentinelLink)>>,
+    next_sentinel_link: AtomicU64,
+    // Connections receiving the hello messages of the other sentinels, by
+    // address of the instance they go through.
+    hello_subscriptions: Mutex<HashMap<sentinel::Address, JoinHandle<()>>>,
 }
 
+/// Requests queued on a connection of the sentinel, with the instance they
+/// are about.
+type SentinelLink = mpsc::UnboundedSender<(Instance, Request)>;
+
 impl Server {
-    pub fn new(role: Role, port: u16) -> Self {
+    pub fn new(role: Role, config: Config) -> Self {
         Server {
-            role,
-            db: Arc::new(Mutex::new(HashMap::new())),
-            port,
+            role: RwLock::new(role),
+            db: match config.shard_threads {
+                0 => Db::new(config.databases),
+                threads => Db::with_shards(config.databases, threads),
+            },
+            dirty: AtomicU64::new(0),
+            last_save: AtomicU64::new(rdb::unix_time_ms() / 1000),
+            save_in_progress: AtomicBool::new(false),
+            evicted_keys: AtomicU64::new(0),
+            expired_keys: AtomicU64::new(0),
+            replication: Mutex::new(Replication::new(config.repl_backlog_size)),
+            master_link_up: AtomicBool::new(false),
+            master_sync_in_progress: AtomicBool::new(false),
+            master_last_io: AtomicU64::new(0),
+            master_link_down_since: AtomicU64::new(0),
+            aof: Mutex::new(None),
+            aof_rewrite_in_progress: AtomicBool::new(false),
+            diskless_waiting: Mutex::new(Vec::new()),
+            master_link: Mutex::new(None),
+            replica_acks: Notify::new(),
+            executors: Executors::default(),
+            cluster: None,
+            bus_links: Mutex::new(HashMap::new()),
+            next_bus_link: AtomicU64::new(0),
+            clients_paused_until: AtomicU64::new(0),
+            migrate_gates: if config.cluster_enabled {
+                (0..cluster::SLOTS)
+                    .map(|_| tokio::sync::RwLock::new(()))
+                    .collect()
+            } else {
+                Vec::new()
+            },
+            pubsub: PubSub::default(),
+            next_client_id: AtomicU64::new(1),
+            tracking: Tracking::new(config.tracking_table_max_keys),
+            sentinel: None,
+            sentinel_links: Mutex::new(HashMap::new()),
+            next_sentinel_link: AtomicU64::new(0),
+            hello_subscriptions: Mutex::new(HashMap::new()),
+            config,
         }
     }
 
-    pub async fn start(self) -> anyhow::Result<()> {
-        match self.role {
-            Role::Slave {
-                master_host,
-                master_port,
-            } => {
-                // TODO: factor out to handshake()
-                let conn = TcpStream::connect(format!("{}:{}", master_host, master_port))
-                    .await
-                    .context("failed to connect to master")?;
-                let mut frame_stream = FrameStream::new(conn);
+    fn load_rdb(&self) -> anyhow::Result<()> {
+        let path = self.config.rdb_path();
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+        let rdb = Rdb::parse(&contents, self.keep_expired())
+            .with_context(|| format!("failed to load rdb file {}", path.display()))?;
+        let snapshot = self
+            .db
+            .shard(rdb.databases)
+            .with_context(|| format!("failed to load rdb file {}", path.display()))?;
 
-                frame_stream.write_array(vec!["PING"]).await?;
-                frame_stream
-                    .read_frame()
-                    .await?
-                    .ok_or(anyhow!("end of frame"))?;
+        println!(
+            "loaded {} keys from {}",
+            snapshot.key_count(),
+            path.display()
+        );
+        self.db.lock_all().replace(snapshot);
 
-                frame_stream
-                    .write_array(vec!["REPLCONF", "listening-port", &self.port.to_string()])
-                    .await?;
-                frame_stream
-                    .read_frame()
-                    .await?
-                    .ok_or(anyhow!("end of frame"))?;
+        Ok(())
+    }
 
-                frame_stream
-                    .write_array(vec!["REPLCONF", "capa", "psync2"])
-                    .await?;
-                frame_stream
-                    .read_frame()
-                    .await?
-                    .ok_or(anyhow!("end of frame"))?;
-                frame_stream.write_array(vec!["PSYNC", "?", "-1"]).await?;
-                frame_stream
-                    .read_frame()
-                    .await?
-                    .ok_or(anyhow!("end of frame"))?;
+    /// Saves a snapshot of the database, off the runtime's workers.
+    async fn save(&self) -> anyhow::Result<()> {
+        if self.save_in_progress.swap(true, Ordering::SeqCst) {
+            return Err(anyhow!("Background save already in progress"));
+        }
+        let dirty = self.dirty.load(Ordering::SeqCst);
+        let snapshot = self.db.lock_all().snapshot();
+        let path = self.config.rdb_path();
+        let result =
+            tokio::task::spawn_blocking(move || rdb::save(&path, &snapshot.databases())).await;
+        self.save_in_progress.store(false, Ordering::SeqCst);
+        result??;
+        self.finish_save(dirty);
+
+        Ok(())
+    }
+
+    /// Saves a snapshot of the database in the background.
+    fn bgsave(self: &Arc<Self>) -> anyhow::Result<()> {
+        if self.save_in_progress.swap(true, Ordering::SeqCst) {
+            return Err(anyhow!("Background save already in progress"));
+        }
+        let dirty = self.dirty.load(Ordering::SeqCst);
+        let snapshot = self.db.lock_all().snapshot();
+
+        let server = self.clone();
+        tokio::task::spawn_blocking(move || {
+            match rdb::save(&server.config.rdb_path(), &snapshot.databases()) {
+                Ok(()) => {
+                    server.finish_save(dirty);
+                    println!("background saving terminated with success");
+                }
+                Err(err) => println!("background saving failed: {:#}", err),
+            }
+            server.save_in_progress.store(false, Ordering::SeqCst);
+        });
+
+        Ok(())
+    }
+
+    fn finish_save(&self, dirty: u64) {
+        self.dirty.fetch_sub(dirty, Ordering::SeqCst);
+        self.last_save
+            .store(rdb::unix_time_ms() / 1000, Ordering::SeqCst);
+    }
 
-                let stream = frame_stream.stream();
-                let mut buf = Vec::new();
-                stream.read_buf(&mut buf).await.unwrap();
-                println!("rdb transfer: {}", buf.escape_ascii());
+    async fn run_save_points(self: Arc<Self>) {
+        if self.config.save.is_empty() {
+            return;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let dirty = self.dirty.load(Ordering::SeqCst);
+            let elapsed =
+                (rdb::unix_time_ms() / 1000).saturating_sub(self.last_save.load(Ordering::SeqCst));
+            let save_point = self
+                .config
+                .save
+                .iter()
+                .find(|p| dirty > 0 && dirty >= p.changes && elapsed >= p.seconds);
+            if let Some(p) = save_point {
+                if !self.save_in_progress.load(Ordering::SeqCst) {
+                    println!("{} changes in {} seconds. saving...", p.changes, p.seconds);
+                    let _ = self.bgsave();
+                }
             }
-            Role::Master { .. } => (),
         }
+    }
 
-        let addr = format!("127.0.0.1:{}", self.port);
-        println!("listening on {addr}");
-        let listener = TcpListener::bind(&addr)
-            .await
-            .with_context(|| anyhow!("failed to bind to {}", addr))?;
+    /// Loads the dataset from the append-only file if enabled, else from the
+    /// RDB file. Without an append-only file yet, one is created from the RDB
+    /// file.
+    fn load_data(self: &Arc<Self>) -> anyhow::Result<()> {
+        if !self.config.appendonly {
+            return self.load_rdb();
+        }
+
+        let dir = self.config.aof_dir();
+        let filename = &self.config.appendfilename;
+        let fsync = self.config.appendfsync;
+        let aof = match Manifest::load(&dir, filename)? {
+            Some(manifest) => {
+                let loaded = aof::load(
+                    &dir,
+                    &manifest,
+                    self.config.aof_load_truncated,
+                    self.keep_expired(),
+                )?;
+                if let Some(rdb) = loaded.base {
+                    let snapshot = self
+                        .db
+                        .shard(rdb.databases)
+                        .context("failed to load the append only file")?;
+                    self.db.lock_all().replace(snapshot);
+                }
+                // Commands are replayed before the AOF is open, so they are not
+                // logged again.
+                let mut client = Client::new(SocketAddr::from(([0, 0, 0, 0], 0)));
+                let count = loaded.commands.len();
+                for frame in loaded.commands {
+                    let command =
+                        Command::parse(frame).context("invalid command in append only file")?;
+                    self.execute(&mut client, command);
+                }
+                println!(
+                    "loaded {} keys and {count} commands from {}",
+                    key_count(&self.db),
+                    dir.display()
+                );
+                Aof::open(&dir, filename, manifest, fsync)?
+            }
+            None => {
+                self.load_rdb()?;
+                let snapshot = self.db.lock_all().snapshot();
+                Aof::create(&dir, filename, fsync, &snapshot)?
+            }
+        };
+        *self.aof.lock().unwrap() = Some(aof);
+        self.dirty.store(0, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Compacts the append-only file in the background, by replacing its base
+    /// file with a snapshot of the dataset.
+    fn bgrewriteaof(self: &Arc<Self>) -> anyhow::Result<()> {
+        if self.aof_rewrite_in_progress.swap(true, Ordering::SeqCst) {
+            return Err(anyhow!(
+                "Background append only file rewriting already in progress"
+            ));
+        }
+        let started = {
+            let db = self.db.lock_all();
+            let mut aof = self.aof.lock().unwrap();
+            match aof.as_mut() {
+                Some(aof) => aof.start_rewrite().map(|rewrite| (db.snapshot(), rewrite)),
+                None => Err(anyhow!("append only file is disabled")),
+            }
+        };
+        let (snapshot, rewrite) = match started {
+            Ok(started) => started,
+            Err(err) => {
+                self.aof_rewrite_in_progress.store(false, Ordering::SeqCst);
+                return Err(err);
+            }
+        };
+
+        let server = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let result = rewrite.write_base(&snapshot).and_then(|()| {
+                match server.aof.lock().unwrap().as_mut() {
+                    Some(aof) => aof.finish_rewrite(rewrite),
+                    None => Ok(()),
+                }
+            });
+            match result {
+                Ok(()) => println!("background AOF rewrite terminated with success"),
+                Err(err) => println!("background AOF rewrite failed: {:#}", err),
+            }
+            server
+                .aof_rewrite_in_progress
+                .store(false, Ordering::SeqCst);
+        });
+
+        Ok(())
+    }
+
+    /// Flushes the append-only file to disk every second, for `appendfsync
+    /// everysec`, and retries failed writes.
+    async fn run_aof_fsync(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let file = match self.aof.lock().unwrap().as_mut() {
+                Some(aof) => {
+                    if let Err(err) = aof.flush() {
+                        println!("failed to write to the append only file: {:#}", err);
+                    }
+                    aof.take_fsync_pending()
+                }
+                None => None,
+            };
+            if let Some(file) = file {
+                let result = match tokio::task::spawn_blocking(move || file.sync_data()).await {
+                    Ok(result) => result,
+                    Err(err) => Err(io::Error::other(err)),
+                };
+                if let Err(err) = &result {
+                    println!("failed to fsync the append only file: {}", err);
+                }
+                if let Some(aof) = self.aof.lock().unwrap().as_mut() {
+                    aof.finish_fsync(result);
+                }
+            }
+        }
+    }
+
+    pub async fn start(mut self) -> anyhow::Result<()> {
+        if self.config.shard_threads > 0 {
+            self.executors = Executors::start(self.config.shard_threads)?;
+            println!("started {} executor threads", self.executors.len());
+        }
+        if self.config.cluster_enabled {
+            let cluster = self.load_cluster()?;
+            // Replicas resume replicating their master.
+            if let Some(master) = cluster.myself().master.as_ref() {
+                let master = cluster
+                    .node(master)
+                    .ok_or_else(|| anyhow!("unknown master {} in cluster config", master))?;
+                *self.role.get_mut().unwrap() = Role::Slave {
+                    master_host: master.host.clone(),
+                    master_port: master.port,
+                };
+            }
+            self.cluster = Some(Mutex::new(cluster));
+        }
+        if self.config.sentinel {
+            let path = self.config.sentinel_config_path();
+            let sentinel = SentinelState::load(&path, self.config.port, rdb::unix_time_ms())?;
+            sentinel.save(&path)?;
+            println!("sentinel {}", sentinel.myid);
+            self.sentinel = Some(Mutex::new(sentinel));
+        }
         let server = Arc::new(self);
+        // Sentinels hold no data.
+        if server.sentinel.is_none() {
+            server.load_data()?;
+        }
+
+        let listeners = server.bind(server.config.port).await?;
+        if server.sentinel.is_some() {
+            tokio::spawn(server.clone().run_sentinel_cron());
+        } else {
+            tokio::spawn(server.clone().run_save_points());
+            tokio::spawn(server.clone().run_active_expire());
+            tokio::spawn(server.clone().run_aof_fsync());
+            tokio::spawn(server.clone().ping_replicas());
+        }
+        let mut accept_loops = tokio::task::JoinSet::new();
+        if server.cluster.is_some() {
+            let bus_port = server.config.port.wrapping_add(CLUSTER_BUS_PORT_OFFSET);
+            for listener in server.bind(bus_port).await? {
+                accept_loops.spawn(server.clone().bus_accept_loop(listener));
+            }
+            tokio::spawn(server.clone().run_cluster_cron());
+        }
+        if let Role::Slave {
+            master_host,
+            master_port,
+        } = server.role()
+        {
+            server.connect_to_master(master_host, master_port);
+        }
+
+        for listener in listeners {
+            accept_loops.spawn(server.clone().accept_loop(listener));
+        }
+        match accept_loops.join_next().await {
+            Some(result) => result?,
+            None => Ok(()),
+        }
+    }
+
+    /// Loads the cluster state, creating a node with a new ID on first start.
+    fn load_cluster(&self) -> anyhow::Result<ClusterState> {
+        let host = match &self.config.cluster_announce_ip {
+            Some(ip) => ip,
+            None => parse_bind(&self.config.bind[0]).0,
+        };
+        let myself = Node::new(
+            replication::random_replid(),
+            host.to_owned(),
+            self.config.port,
+            self.config.port.wrapping_add(CLUSTER_BUS_PORT_OFFSET),
+        );
+        let path = self.config.cluster_config_path();
+        let mut cluster = ClusterState::load(&path, myself)?;
+        cluster.node_timeout = self.config.cluster_node_timeout;
+        cluster.save(&path)?;
+        println!("cluster node {}", cluster.myself().id);
+
+        Ok(cluster)
+    }
+
+    /// Binds every configured address. Failing to bind an address prefixed
+    /// with `-` is not an error, as long as some address gets bound.
+    async fn bind(&self, port: u16) -> anyhow::Result<Vec<TcpListener>> {
+        let mut listeners = Vec::new();
+        for bind in &self.config.bind {
+            let (host, optional) = parse_bind(bind);
+            match net::listen(host, port).await {
+                Ok(listener) => {
+                    println!("listening on {}", listener.local_addr()?);
+                    listeners.push(listener);
+                }
+                Err(err) if optional => println!("skipping {}: {}", bind, err),
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("failed to bind to {}", host_port(host, port)))
+                }
+            }
+        }
+        if listeners.is_empty() {
+            return Err(anyhow!("no address to listen on"));
+        }
+
+        Ok(listeners)
+    }
+
+    /// Accepts connections. In thread-per-core mode, they are spread over the
+    /// executors, which then do all their I/O.
+    async fn accept_loop(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        let mut next_executor = 0;
         loop {
-            let (stream, _) = listener
+            let (stream, addr) = listener
                 .accept()
                 .await
                 .context("failed to accept connection")?;
-            let server = server.clone();
-            tokio::spawn(async move { server.handle_connection(stream).await });
+            // Replies to pipelined commands are written one by one, and must
+            // not wait for the acknowledgement of the previous ones.
+            if let Err(err) = stream.set_nodelay(true) {
+                println!("failed to set TCP_NODELAY for {}: {}", addr, err);
+            }
+            let server = self.clone();
+            if self.executors.is_empty() {
+                tokio::spawn(async move { server.handle_connection(stream, addr).await });
+                continue;
+            }
+
+            // The stream moves to the I/O driver of its executor.
+            let stream = match stream.into_std() {
+                Ok(stream) => stream,
+                Err(err) => {
+                    println!("failed to hand over connection from {}: {}", addr, err);
+                    continue;
+                }
+            };
+            self.executors.handle(next_executor).spawn(async move {
+                let stream = TcpStream::from_std(stream)?;
+                server.handle_connection(stream, addr).await
+            });
+            next_executor = (next_executor + 1) % self.executors.len();
         }
     }
 
-    pub async fn handle_connection(&self, stream: TcpStream) -> anyhow::Result<()> {
-        let mut frame_stream = FrameStream::new(stream);
+    /// Accepts connections from other nodes on the cluster bus.
+    async fn bus_accept_loop(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
         loop {
-            match frame_stream.read_frame().await? {
-                Some(frame) => match Command::parse(frame) {
-                    Ok(command) => self.handle_command(&mut frame_stream, command).await?,
-                    Err(err) => {
-                        frame_stream
-                            .write_frame(Frame::Error(Bytes::copy_from_slice(
-                                format!("{}", err).as_bytes(),
-                            )))
-                            .await?
-                    }
-                },
-                _ => break,
+            let (stream, addr) = listener
+                .accept()
+                .await
+                .context("failed to accept cluster bus connection")?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.handle_bus_connection(stream, addr).await {
+                    println!("error on cluster bus connection from {}: {:#}", addr, err);
+                }
+            });
+        }
+    }
+
+    /// Handles the messages a node sends on a connection it opened, replying
+    /// on the same connection.
+    async fn handle_bus_connection(
+        self: Arc<Self>,
+        stream: TcpStream,
+        addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let mut frame_stream = FrameStream::new(stream);
+        let host = addr.ip().to_string();
+        while let Some(frame) = frame_stream.read_frame().await? {
+            let message = Message::parse(frame)?;
+            if let Some(reply) = self.handle_bus_message(message, &host, None) {
+                frame_stream.write_frame(reply.to_frame()).await?;
             }
         }
 
         Ok(())
     }
 
-    async fn handle_command(
-        &self,
-        frame_stream: &mut FrameStream,
-        command: Command,
+    fn handle_bus_message(
+        self: &Arc<Self>,
+        message: Message,
+        host: &str,
+        link: Option<&str>,
+    ) -> Option<Message> {
+        let cluster = self.cluster.as_ref()?;
+        let offset = self.replication.lock().unwrap().offset;
+        let (reply, effects) =
+            cluster
+                .lock()
+                .unwrap()
+                .handle(message, host, link, offset, rdb::unix_time_ms());
+        self.apply_cluster_effects(effects);
+
+        reply
+    }
+
+    /// Sends a message to a node, opening a connection to it if there is none.
+    fn bus_send(self: &Arc<Self>, id: String, message: Message) {
+        let mut links = self.bus_links.lock().unwrap();
+        let message = match links.get(&id) {
+            Some((_, tx)) => match tx.send(message) {
+                Ok(()) => return,
+                Err(err) => err.0,
+            },
+            None => message,
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(message).unwrap();
+        let link = self.next_bus_link.fetch_add(1, Ordering::SeqCst);
+        links.insert(id.clone(), (link, tx));
+        tokio::spawn(self.clone().run_bus_link(id, link, rx));
+    }
+
+    /// Sends the messages queued for a node, and handles its replies, until
+    /// the connection fails or the link is dropped.
+    async fn run_bus_link(
+        self: Arc<Self>,
+        id: String,
+        link: u64,
+        rx: mpsc::UnboundedReceiver<Message>,
+    ) {
+        if let Err(err) = self.process_bus_link(&id, rx).await {
+            println!("cluster bus link to {} failed: {:#}", id, err);
+        }
+        // The next message opens a new connection.
+        let mut links = self.bus_links.lock().unwrap();
+        if links.get(&id).is_some_and(|(current, _)| *current == link) {
+            links.remove(&id);
+        }
+    }
+
+    async fn process_bus_link(
+        self: &Arc<Self>,
+        id: &str,
+        mut rx: mpsc::UnboundedReceiver<Message>,
     ) -> anyhow::Result<()> {
-        let response = match command.clone() {
-            Command::Ping => Some(Frame::Bulk(Bytes::from_static(b"PONG"))),
-            Command::Echo(bytes) => Some(Frame::Bulk(bytes)),
-            Command::Get(key) => {
-                let mut db = self.db.lock().unwrap();
-                let fr = match db.get(&key) {
-                    Some(DbValue { value, expiry }) => {
-                        if let Some(expiry) = expiry {
-                            if expiry < &tokio::time::Instant::now() {
-                                println!("removing entry with key: {key}");
-                                db.remove(&key);
-
-                                Frame::Null
-                            } else {
-                                Frame::Bulk(value.clone())
-                            }
-                        } else {
-                            Frame::Bulk(value.clone())
-                        }
-                    }
-                    _ => Frame::Null,
-                };
+        let address = self.cluster.as_ref().and_then(|cluster| {
+            let cluster = cluster.lock().unwrap();
+            cluster
+                .node(id)
+                .map(|node| (node.host.clone(), node.bus_port))
+        });
+        let Some((host, bus_port)) = address else {
+            return Ok(());
+        };
+        let stream = match tokio::time::timeout(
+            CLUSTER_BUS_CONNECT_TIMEOUT,
+            TcpStream::connect((&host[..], bus_port)),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            // Unreachable nodes are flagged by failure detection.
+            _ => return Ok(()),
+        };
 
-                Some(fr)
+        let mut frame_stream = FrameStream::new(stream);
+        loop {
+            tokio::select! {
+                message = rx.recv() => match message {
+                    Some(message) => frame_stream.write_frame(message.to_frame()).await?,
+                    None => return Ok(()),
+                },
+                frame = frame_stream.read_frame() => {
+                    let Some(frame) = frame? else {
+                        return Ok(());
+                    };
+                    let message = Message::parse(frame)?;
+                    if let Some(reply) = self.handle_bus_message(message, &host, Some(id)) {
+                        frame_stream.write_frame(reply.to_frame()).await?;
+                    }
+                }
             }
-            Command::Set { key, value, px } => {
-                let expiry =
-                    px.map(|millis| Instant::now() + tokio::time::Duration::from_millis(millis));
-                let db_value = DbValue { value, expiry };
-                self.db.lock().unwrap().insert(key.clone(), db_value);
+        }
+    }
+
+    /// Runs the periodic cluster tasks, and drops the links to nodes that are
+    /// no longer known.
+    async fn run_cluster_cron(self: Arc<Self>) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+        let mut interval = tokio::time::interval(CLUSTER_CRON_PERIOD);
+        loop {
+            interval.tick().await;
+            let offset = self.replication.lock().unwrap().offset;
+            let (effects, known) = {
+                let mut cluster = cluster.lock().unwrap();
+                let effects = cluster.cron(offset, rdb::unix_time_ms());
+                let known = cluster
+                    .nodes()
+                    .iter()
+                    .map(|node| node.id.clone())
+                    .collect::<HashSet<_>>();
+                (effects, known)
+            };
+            self.bus_links
+                .lock()
+                .unwrap()
+                .retain(|id, _| known.contains(id));
+            self.apply_cluster_effects(effects);
+        }
+    }
 
-                Some(Frame::Bulk(Bytes::from_static(b"OK")))
+    /// Carries out what a change of the cluster state requires.
+    fn apply_cluster_effects(self: &Arc<Self>, effects: Effects) {
+        for (id, message) in effects.messages {
+            self.bus_send(id, message);
+        }
+        if let Some(master) = effects.replicate {
+            self.replicaof(master);
+        }
+        if let Some(until) = effects.pause_until {
+            self.clients_paused_until.store(until, Ordering::SeqCst);
+        }
+        if effects.save {
+            if let Some(cluster) = &self.cluster {
+                let cluster = cluster.lock().unwrap();
+                if let Err(err) = cluster.save(&self.config.cluster_config_path()) {
+                    println!("failed to save cluster config: {:#}", err);
+                }
             }
-            Command::Info => {
-                let mut buf = BytesMut::new();
+        }
+    }
 
-                match &self.role {
-                    Role::Master {
-                        replication_id,
-                        replication_offset,
-                    } => {
-                        buf.write_str("role:master\n").unwrap();
-                        write!(buf, "master_replid:{}\n", replication_id).unwrap();
-                        write!(buf, "master_repl_offset:{}\n", replication_offset).unwrap();
+    /// Runs the periodic sentinel tasks, drops the links to instances that are
+    /// no longer known, and keeps a hello subscription on every master and
+    /// replica.
+    async fn run_sentinel_cron(self: Arc<Self>) {
+        let Some(sentinel) = &self.sentinel else {
+            return;
+        };
+        let mut interval = tokio::time::interval(SENTINEL_CRON_PERIOD);
+        loop {
+            interval.tick().await;
+            let (effects, known, hello_sources) = {
+                let mut sentinel = sentinel.lock().unwrap();
+                let effects = sentinel.cron(rdb::unix_time_ms());
+                (effects, sentinel.addresses(), sentinel.hello_sources())
+            };
+            self.sentinel_links
+                .lock()
+                .unwrap()
+                .retain(|addr, _| known.contains(addr));
+            {
+                let mut subscriptions = self.hello_subscriptions.lock().unwrap();
+                subscriptions.retain(|addr, task| {
+                    let keep = hello_sources.contains(addr) && !task.is_finished();
+                    if !keep {
+                        task.abort();
                     }
-                    Role::Slave { .. } => buf.write_str("role:slave").unwrap(),
-                };
+                    keep
+                });
+                for addr in hello_sources {
+                    subscriptions
+                        .entry(addr.clone())
+                        .or_insert_with(|| tokio::spawn(self.clone().run_hello_subscription(addr)));
+                }
+            }
+            self.apply_sentinel_effects(effects);
+        }
+    }
 
-                Some(Frame::Bulk(buf.into()))
+    /// Carries out what a change of the sentinel state requires.
+    fn apply_sentinel_effects(self: &Arc<Self>, effects: sentinel::Effects) {
+        for (instance, addr, request) in effects.requests {
+            self.sentinel_send(addr, instance, request);
+        }
+        for (kind, message) in effects.events {
+            println!("{} {}", kind, message);
+            self.pubsub.publish(&kind, Bytes::from(message));
+        }
+        if effects.save {
+            if let Some(sentinel) = &self.sentinel {
+                let sentinel = sentinel.lock().unwrap();
+                if let Err(err) = sentinel.save(&self.config.sentinel_config_path()) {
+                    println!("failed to save sentinel config: {:#}", err);
+                }
             }
-            Command::Replconf => Some(Frame::Simple("OK".to_owned())),
-            _ => None,
+        }
+    }
+
+    fn handle_sentinel_reply(
+        self: &Arc<Self>,
+        instance: &Instance,
+        addr: &sentinel::Address,
+        request: &Request,
+        reply: Option<Frame>,
+    ) {
+        let Some(sentinel) = &self.sentinel else {
+            return;
         };
+        let effects = sentinel.lock().unwrap().handle_reply(
+            instance,
+            addr,
+            request,
+            reply,
+            rdb::unix_time_ms(),
+        );
+        self.apply_sentinel_effects(effects);
+    }
 
-        if let Some(response) = response {
-            frame_stream
-                .write_frame(response)
-                .await
-                .expect("write response");
-            return Ok(());
+    /// Sends a request of the sentinel to an instance, opening a connection to
+    /// it if there is none.
+    fn sentinel_send(
+        self: &Arc<Self>,
+        addr: sentinel::Address,
+        instance: Instance,
+        request: Request,
+    ) {
+        let mut links = self.sentinel_links.lock().unwrap();
+        let queued = match links.get(&addr) {
+            Some((_, tx)) => match tx.send((instance, request)) {
+                Ok(()) => return,
+                Err(err) => err.0,
+            },
+            None => (instance, request),
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        tx.send(queued).unwrap();
+        let link = self.next_sentinel_link.fetch_add(1, Ordering::SeqCst);
+        links.insert(addr.clone(), (link, tx));
+        tokio::spawn(self.clone().run_sentinel_link(addr, link, rx));
+    }
+
+    /// Sends the requests queued for an instance one at a time, and hands
+    /// their replies to the sentinel state, until the connection fails or the
+    /// link is dropped. Requests still queued then fail.
+    async fn run_sentinel_link(
+        self: Arc<Self>,
+        addr: sentinel::Address,
+        link: u64,
+        mut rx: mpsc::UnboundedReceiver<(Instance, Request)>,
+    ) {
+        if let Err(err) = self.process_sentinel_link(&addr, &mut rx).await {
+            println!(
+                "sentinel link to {} failed: {:#}",
+                host_port(&addr.0, addr.1),
+                err
+            );
+        }
+        // The next request opens a new connection.
+        {
+            let mut links = self.sentinel_links.lock().unwrap();
+            if links
+                .get(&addr)
+                .is_some_and(|(current, _)| *current == link)
+            {
+                links.remove(&addr);
+            }
+        }
+        rx.close();
+        while let Ok((instance, request)) = rx.try_recv() {
+            self.handle_sentinel_reply(&instance, &addr, &request, None);
+        }
+    }
+
+    async fn process_sentinel_link(
+        self: &Arc<Self>,
+        addr: &sentinel::Address,
+        rx: &mut mpsc::UnboundedReceiver<(Instance, Request)>,
+    ) -> anyhow::Result<()> {
+        let stream = match tokio::time::timeout(
+            SENTINEL_CONNECT_TIMEOUT,
+            TcpStream::connect((&addr.0[..], addr.1)),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            // Unreachable instances are flagged as down.
+            _ => return Ok(()),
+        };
+        // Hello messages announce the address the instance sees.
+        let local_ip = stream.local_addr()?.ip().to_string();
+
+        let mut frame_stream = FrameStream::new(stream);
+        while let Some((instance, request)) = rx.recv().await {
+            let frame = sentinel_request_frame(&request, &local_ip);
+            let reply = tokio::time::timeout(SENTINEL_REPLY_TIMEOUT, async {
+                frame_stream.write_frame(frame).await?;
+                frame_stream
+                    .read_frame()
+                    .await?
+                    .ok_or(anyhow!("connection closed"))
+            })
+            .await;
+            match reply {
+                Ok(Ok(reply)) => self.handle_sentinel_reply(&instance, addr, &request, Some(reply)),
+                Ok(Err(err)) => {
+                    self.handle_sentinel_reply(&instance, addr, &request, None);
+                    return Err(err);
+                }
+                Err(_) => {
+                    self.handle_sentinel_reply(&instance, addr, &request, None);
+                    return Err(anyhow!("timeout waiting for reply"));
+                }
+            }
         }
 
-        match command {
-            Command::Psync { .. } => {
-                match &self.role {
-                    Role::Slave { .. } => {
-                        frame_stream
-                            .write_frame(Frame::Error(Bytes::from_static(b"ERR not a master")))
-                            .await?;
-                        return Ok(());
-                    }
-                    Role::Master { replication_id, .. } => {
-                        frame_stream
-                            .write_frame(Frame::Simple(format!("FULLRESYNC {replication_id} 0")))
-                            .await?;
-                    }
+        Ok(())
+    }
+
+    /// Listens to the hello messages published through an instance. The cron
+    /// subscribes again once this returns.
+    async fn run_hello_subscription(self: Arc<Self>, addr: sentinel::Address) {
+        if let Err(err) = self.process_hello_subscription(&addr).await {
+            println!(
+                "hello subscription to {} failed: {:#}",
+                host_port(&addr.0, addr.1),
+                err
+            );
+        }
+        tokio::time::sleep(SENTINEL_RESUBSCRIBE_DELAY).await;
+    }
+
+    async fn process_hello_subscription(
+        self: &Arc<Self>,
+        addr: &sentinel::Address,
+    ) -> anyhow::Result<()> {
+        let stream = match tokio::time::timeout(
+            SENTINEL_CONNECT_TIMEOUT,
+            TcpStream::connect((&addr.0[..], addr.1)),
+        )
+        .await
+        {
+            Ok(Ok(stream)) => stream,
+            _ => return Ok(()),
+        };
+        let mut frame_stream = FrameStream::new(stream);
+        let subscribe = Command::Subscribe(vec![sentinel::HELLO_CHANNEL.to_owned()]);
+        frame_stream.write_frame(subscribe.to_frame()).await?;
+        while let Some(frame) = frame_stream.read_frame().await? {
+            let Frame::Array(frames) = frame else {
+                continue;
+            };
+            if let [Frame::Bulk(kind), _, Frame::Bulk(hello)] = &frames[..] {
+                if &kind[..] == b"message" {
+                    self.handle_hello(hello);
                 }
-                dbg!("write empty rdb");
-                let stream = frame_stream.stream();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_hello(self: &Arc<Self>, hello: &[u8]) {
+        let Some(sentinel) = &self.sentinel else {
+            return;
+        };
+        let hello = String::from_utf8_lossy(hello);
+        let effects = sentinel
+            .lock()
+            .unwrap()
+            .handle_hello(&hello, rdb::unix_time_ms());
+        self.apply_sentinel_effects(effects);
+    }
 
-                static EMPTY_RDB_HEX: &'static str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
-                let empty_rdb_bin = hex::decode(EMPTY_RDB_HEX).unwrap();
-                dbg!(EMPTY_RDB_HEX.len());
-                dbg!(empty_rdb_bin.len());
-                let mut bytes = BytesMut::new();
-                bytes.put(empty_rdb_bin.len().to_string().as_bytes());
-                bytes.put(&b"\r\n"[..]);
+    /// Waits until writes are no longer paused by a manual failover.
+    async fn wait_for_unpause(&self) {
+        loop {
+            let until = self.clients_paused_until.load(Ordering::SeqCst);
+            let now = rdb::unix_time_ms();
+            if until <= now {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(until - now).min(CLUSTER_CRON_PERIOD)).await;
+        }
+    }
 
-                stream.write_all(&bytes).await?;
-                stream.flush().await?;
+    fn role(&self) -> Role {
+        self.role.read().unwrap().clone()
+    }
 
-                bytes.put(&empty_rdb_bin[..]);
+    /// Whether expired keys are kept when loading a snapshot: only a master
+    /// drops them, replicas wait for their master to delete them.
+    fn keep_expired(&self) -> bool {
+        matches!(self.role(), Role::Slave { .. })
+    }
 
-                dbg!(&bytes);
+    /// Starts replicating the given master, replacing any previous master link.
+    fn connect_to_master(self: &Arc<Self>, master_host: String, master_port: u16) {
+        self.disconnect_from_master();
+        let task = tokio::spawn(self.clone().run_master_link(master_host, master_port));
+        *self.master_link.lock().unwrap() = Some(task);
+    }
 
-                stream.write_all(&bytes).await?;
-                stream.flush().await?;
+    /// Keeps the link with the master up: syncs with it, applies its stream and
+    /// reconnects with an exponential backoff whenever that fails.
+    async fn run_master_link(self: Arc<Self>, master_host: String, master_port: u16) {
+        let mut backoff = MIN_RECONNECT_BACKOFF;
+        loop {
+            match self.handshake(&master_host, master_port).await {
+                Ok((frame_stream, master_addr)) => {
+                    backoff = MIN_RECONNECT_BACKOFF;
+                    if let Err(err) = self
+                        .clone()
+                        .process_master_stream(frame_stream, master_addr)
+                        .await
+                    {
+                        println!("error on master link: {:#}", err);
+                    }
+                }
+                Err(err) => {
+                    self.master_sync_in_progress.store(false, Ordering::SeqCst);
+                    println!(
+                        "failed to sync with master {}: {:#}",
+                        host_port(&master_host, master_port),
+                        err
+                    );
+                }
             }
-            _ => unreachable!(),
+
+            println!("reconnecting to master in {}ms", backoff.as_millis());
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
         }
+    }
 
-        Ok(())
+    fn disconnect_from_master(&self) {
+        if let Some(task) = self.master_link.lock().unwrap().take() {
+            task.abort();
+        }
+        self.master_sync_in_progress.store(false, Ordering::SeqCst);
+        if self.master_link_up.swap(false, Ordering::SeqCst) {
+            self.master_link_down_since
+                .store(rdb::unix_time_ms(), Ordering::SeqCst);
+        }
     }
-}
 
-struct DbValue {
-    value: Bytes,
-    expiry: Option<Instant>,
-}
+    /// Switches the role of the server at runtime, and returns the reply to
+    /// REPLICAOF. Demoted masters drop their replicas, promoted replicas keep
+    /// their dataset and start a new history under a new replication ID. The
+    /// master is connected to in the background, so that an unreachable one
+    /// only shows as a link down in INFO, as in redis.
+    fn replicaof(self: &Arc<Self>, master: Option<(String, u16)>) -> String {
+        // Holding the role for the whole switch serializes role changes.
+        let mut role = self.role.write().unwrap();
+        match master {
+            None => {
+                if let Role::Master = *role {
+                    return "OK".to_owned();
+                }
+                *role = Role::Master;
+                self.disconnect_from_master();
+                let mut replication = self.replication.lock().unwrap();
+                replication.shift_replid(replication::random_replid());
+                replication.create_backlog();
+                println!("master mode enabled");
+            }
+            Some((host, port)) => {
+                let master_host = host
+                    .strip_prefix('[')
+                    .and_then(|h| h.strip_suffix(']'))
+                    .unwrap_or(&host)
+                    .to_owned();
+                if let Role::Slave {
+                    master_host: current_host,
+                    master_port: current_port,
+                } = &*role
+                {
+                    if *current_host == master_host && *current_port == port {
+                        return "OK Already connected to specified master".to_owned();
+                    }
+                }
+                println!("replica of {} enabled", host_port(&master_host, port));
+                *role = Role::Slave {
+                    master_host: master_host.clone(),
+                    master_port: port,
+                };
+                self.replication.lock().unwrap().disconnect_replicas();
+                self.connect_to_master(master_host, port);
+            }
+        }
 
-type Db = Arc<Mutex<HashMap<String, DbValue>>>;
+        "OK".to_owned()
+    }
 
-#[derive(Debug)]
-pub enum Role {
-    Master {
-        replication_id: String,
-        replication_offset: u64,
-    },
+    async fn handshake(
+        self: &Arc<Self>,
+        master_host: &str,
+        master_port: u16,
+    ) -> anyhow::Result<(FrameStream, SocketAddr)> {
+        // Resolved on every attempt, so that masters may move to a new address.
+        let conn =
+            tokio::time::timeout(REPL_TIMEOUT, TcpStream::connect((master_host, master_port)))
+                .await
+                .map_err(|_| anyhow!("timeout connecting to master"))?
+                .context("failed to connect to master")?;
+        let master_addr = conn.peer_addr()?;
+        let mut frame_stream = FrameStream::new(conn);
+
+        frame_stream.write_array(vec!["PING"]).await?;
+        match read_reply(&mut frame_stream).await? {
+            Frame::Simple(s) if s == "PONG" => (),
+            Frame::Bulk(bytes) if &bytes[..] == b"PONG" => (),
+            reply => return Err(anyhow!("unexpected reply to PING: {:?}", reply)),
+        }
+
+        let replconfs = [
+            Command::Replconf(Replconf::ListeningPort(self.config.port)),
+            Command::Replconf(Replconf::Capa(vec!["eof".to_owned(), "psync2".to_owned()])),
+        ];
+        for replconf in replconfs {
+            frame_stream.write_frame(replconf.to_frame()).await?;
+            match read_reply(&mut frame_stream).await? {
+                Frame::Simple(s) if s == "OK" => (),
+                // Older masters may not know every option, which is fine.
+                Frame::Error(err) => println!(
+                    "(non critical) master does not understand {:?}: {}",
+                    replconf,
+                    err.escape_ascii()
+                ),
+                reply => return Err(anyhow!("unexpected reply to REPLCONF: {:?}", reply)),
+            }
+        }
+
+        // Ask to continue from where we left off, if we were already synced.
+        let psync = {
+            let replication = self.replication.lock().unwrap();
+            if replication.has_backlog() {
+                Command::Psync {
+                    replication_id: replication.replid.clone(),
+                    offset: replication.offset as i64 + 1,
+                }
+            } else {
+                Command::Psync {
+                    replication_id: "?".to_owned(),
+                    offset: -1,
+                }
+            }
+        };
+        frame_stream.write_frame(psync.to_frame()).await?;
+        let reply = match read_reply(&mut frame_stream).await? {
+            Frame::Simple(s) => s,
+            Frame::Error(err) => {
+                return Err(anyhow!("master refused PSYNC: {}", err.escape_ascii()))
+            }
+            reply => return Err(anyhow!("unexpected reply to PSYNC: {:?}", reply)),
+        };
+        let mut parts = reply.split(' ');
+        match parts.next() {
+            Some("CONTINUE") => {
+                let mut replication = self.replication.lock().unwrap();
+                if let Some(replid) = parts.next() {
+                    if replid != replication.replid {
+                        replication.shift_replid(replid.to_owned());
+                        // Sub-replicas reconnect to learn the new ID.
+                        replication.disconnect_replicas();
+                    }
+                }
+                println!(
+                    "partial resync: continuing at offset {}",
+                    replication.offset
+                );
+            }
+            Some("FULLRESYNC") => {
+                let (replid, offset) = parts
+                    .next()
+                    .filter(|replid| replid.len() == 40)
+                    .zip(parts.next().and_then(|o| o.parse::<u64>().ok()))
+                    .with_context(|| format!("invalid reply to PSYNC: {}", reply))?;
+
+                self.master_sync_in_progress.store(true, Ordering::SeqCst);
+                let transfer = frame_stream.read_rdb_header().await?;
+                let to_disk = match self.config.repl_diskless_load {
+                    DisklessLoad::Disabled => true,
+                    DisklessLoad::OnEmptyDb => key_count(&self.db) > 0,
+                    DisklessLoad::Swapdb => false,
+                };
+                let path = self.config.rdb_path();
+                let mut reader = RdbReader::new(frame_stream, transfer, Handle::current());
+                // The payload is saved or loaded as it arrives, and the
+                // current dataset stays until it is loaded whole.
+                let (rdb, stream) = tokio::task::spawn_blocking(move || {
+                    let rdb = if to_disk {
+                        rdb::save_from(&path, &mut reader)
+                            .context("failed to save rdb from master")?;
+                        File::open(&path)
+                            .map_err(anyhow::Error::from)
+                            .and_then(|file| Rdb::load(io::BufReader::new(file), true))
+                    } else {
+                        Rdb::load(io::BufReader::new(&mut reader), true)
+                    };
+                    let rdb = rdb.context("failed to load rdb from master")?;
+
+                    anyhow::Ok((rdb, reader.finish()?))
+                })
+                .await??;
+                frame_stream = stream;
+                // The stream continues in the database selected when the
+                // snapshot was taken.
+                let stream_db = rdb
+                    .aux
+                    .iter()
+                    .find(|(key, _)| key == "repl-stream-db")
+                    .and_then(|(_, value)| atoi::atoi(value))
+                    .unwrap_or(0);
+                let snapshot = self
+                    .db
+                    .shard(rdb.databases)
+                    .context("failed to load rdb from master")?;
+                println!(
+                    "full resync: loaded {} keys at offset {offset}",
+                    snapshot.key_count()
+                );
+                let mut db = self.db.lock_all();
+                let mut replication = self.replication.lock().unwrap();
+                db.replace(snapshot);
+                replication.stream_db = stream_db;
+                replication.replid = replid.to_owned();
+                replication.replid2 = "0".repeat(40);
+                replication.second_replid_offset = -1;
+                replication.offset = offset;
+                replication.reset_backlog();
+                replication.create_backlog();
+                // Sub-replicas follow a history that no longer exists.
+                replication.disconnect_replicas();
+                drop(replication);
+                drop(db);
+                self.tracking.invalidate_all();
+                self.master_sync_in_progress.store(false, Ordering::SeqCst);
+                // The append-only file does not hold the new dataset yet.
+                if self.config.appendonly {
+                    if let Err(err) = self.bgrewriteaof() {
+                        println!("failed to rewrite the append only file: {:#}", err);
+                    }
+                }
+            }
+            _ => return Err(anyhow!("unexpected reply to PSYNC: {}", reply)),
+        }
+
+        Ok((frame_stream, master_addr))
+    }
+
+    /// Applies the command stream of the master, without replying to it.
+    async fn process_master_stream(
+        self: Arc<Self>,
+        mut frame_stream: FrameStream,
+        master_addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let mut client = Client::new(master_addr);
+        client.db = self.replication.lock().unwrap().stream_db;
+        self.master_link_up.store(true, Ordering::SeqCst);
+        self.master_last_io
+            .store(rdb::unix_time_ms(), Ordering::SeqCst);
+        let result = async {
+            let mut ack_interval = tokio::time::interval(Duration::from_secs(1));
+            let mut last_io = Instant::now();
+            loop {
+                tokio::select! {
+                    frame = frame_stream.read_frame_raw() => {
+                        let Some((frame, raw)) = frame? else {
+                            break;
+                        };
+                        last_io = Instant::now();
+                        self.master_last_io
+                            .store(rdb::unix_time_ms(), Ordering::SeqCst);
+                        // Keep the master's stream as is, for partial resyncs and
+                        // sub-replicas. Writes feed it while executed.
+                        let unfed = match Command::parse(frame) {
+                            Ok(Command::Replconf(Replconf::GetAck)) => {
+                                self.send_ack(&mut frame_stream).await?;
+                                Some(raw)
+                            }
+                            Ok(command) => {
+                                client.master_frame = Some(raw);
+                                self.execute(&mut client, command);
+                                client.master_frame.take()
+                            }
+                            Err(err) => {
+                                println!("invalid command from master: {}", err);
+                                Some(raw)
+                            }
+                        };
+                        // Remember the database of the stream for the next
+                        // partial resync.
+                        let mut replication = self.replication.lock().unwrap();
+                        replication.stream_db = client.db;
+                        if let Some(raw) = unfed {
+                            replication.feed(raw);
+                        }
+                    }
+                    _ = ack_interval.tick() => self.send_ack(&mut frame_stream).await?,
+                    _ = tokio::time::sleep_until(last_io + REPL_TIMEOUT) => {
+                        return Err(anyhow!("timeout on master link"));
+                    }
+                }
+            }
+
+            anyhow::Ok(())
+        }
+        .await;
+        self.master_link_up.store(false, Ordering::SeqCst);
+        self.master_link_down_since
+            .store(rdb::unix_time_ms(), Ordering::SeqCst);
+        println!("lost connection with master");
+
+        result
+    }
+
+    async fn send_ack(&self, frame_stream: &mut FrameStream) -> anyhow::Result<()> {
+        let offset = self.replication.lock().unwrap().offset;
+        frame_stream
+            .write_frame(Command::Replconf(Replconf::Ack(offset)).to_frame())
+            .await
+    }
+
+    /// Pings replicas periodically through the replication stream, so that
+    /// they can tell an idle master from a dead link.
+    async fn ping_replicas(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(REPL_PING_PERIOD);
+        loop {
+            interval.tick().await;
+            let role = self.role();
+            let mut replication = self.replication.lock().unwrap();
+            if let Role::Master = role {
+                if !replication.replicas().is_empty() {
+                    replication.feed(Command::Ping.to_frame().to_bytes());
+                }
+            }
+        }
+    }
+
+    pub async fn handle_connection(
+        self: Arc<Self>,
+        stream: TcpStream,
+        addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let mut frame_stream = FrameStream::new(stream);
+        let mut client = Client::new(addr);
+        // Messages published to the channels the client subscribed to.
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        client.id = self.next_client_id.fetch_add(1, Ordering::SeqCst);
+        self.tracking.connect(client.id, &tx);
+        client.subscriber = Some(tx);
+        let result = async {
+            loop {
+                tokio::select! {
+                    frame = frame_stream.read_frame() => {
+                        let Some(frame) = frame? else {
+                            break;
+                        };
+                        match Command::parse(frame) {
+                            Ok(command) => {
+                                // The connection of a replica ends with its
+                                // replication stream.
+                                let is_psync = matches!(command, Command::Psync { .. });
+                                self.handle_command(&mut frame_stream, &mut client, command)
+                                    .await?;
+                                if is_psync {
+                                    break;
+                                }
+                            }
+                            Err(err) => {
+                                frame_stream
+                                    .write_frame(Frame::Error(Bytes::copy_from_slice(
+                                        format!("{}", err).as_bytes(),
+                                    )))
+                                    .await?
+                            }
+                        }
+                    }
+                    Some(message) = rx.recv() => {
+                        if let Some(message) = client.push(message) {
+                            frame_stream.write_frame(message).await?;
+                        }
+                    }
+                }
+            }
+            anyhow::Ok(())
+        }
+        .await;
+        self.unsubscribe_all(&client);
+        self.tracking.disconnect(client.id);
+
+        result
+    }
+
+    async fn handle_command(
+        self: &Arc<Self>,
+        frame_stream: &mut FrameStream,
+        client: &mut Client,
+        command: Command,
+    ) -> anyhow::Result<()> {
+        if let Some(error) = self.refuse_in_mode(client, &command) {
+            return frame_stream.write_frame(error).await;
+        }
+        // ASKING and CLIENT CACHING only apply to the next command.
+        let asking = std::mem::take(&mut client.asking);
+        let caching = client.caching.take();
+        // Writes wait, and are then redirected, while a replica takes over.
+        if self.cluster.is_some() && command.is_write() {
+            self.wait_for_unpause().await;
+        }
+        let gate = match self.migrate_gate(&command) {
+            Some(gate) => Some(gate.read().await),
+            None => None,
+        };
+        // The gate is released before replying, which may take long for slow
+        // clients.
+        if let Some(redirect) = self.cluster_redirect(client.db, &command, asking) {
+            drop(gate);
+            return frame_stream.write_frame(redirect).await;
+        }
+        if let Some(error) = self.refuse_on_replica(&command) {
+            drop(gate);
+            return frame_stream
+                .write_frame(Frame::Error(Bytes::from_static(error)))
+                .await;
+        }
+        if !self.evict() && command.is_denyoom() {
+            drop(gate);
+            return frame_stream
+                .write_frame(Frame::Error(Bytes::from_static(OOM_ERROR)))
+                .await;
+        }
+        if let Some(error) = self.refuse_on_aof_error(&command) {
+            drop(gate);
+            return frame_stream.write_frame(error).await;
+        }
+
+        match command {
+            Command::Psync {
+                replication_id,
+                offset,
+            } => {
+                self.handle_psync(frame_stream, client, &replication_id, offset)
+                    .await
+            }
+            Command::Save => {
+                let response = match self.save().await {
+                    Ok(()) => Frame::Simple("OK".to_owned()),
+                    Err(err) => Frame::Error(Bytes::from(format!("ERR {:#}", err))),
+                };
+                frame_stream.write_frame(response).await
+            }
+            Command::Wait {
+                numreplicas,
+                timeout,
+            } => {
+                let acked = self.wait(client, numreplicas, timeout).await;
+                frame_stream.write_frame(Frame::Integer(acked as i64)).await
+            }
+            Command::Migrate {
+                host,
+                port,
+                keys,
+                db,
+                timeout,
+                copy,
+                replace,
+            } => {
+                // Gates are taken in the order of their slots, so that
+                // concurrent MIGRATEs don't deadlock.
+                let slots = match self.cluster {
+                    Some(_) => keys
+                        .iter()
+                        .map(|key| cluster::key_slot(key.as_bytes()) as usize)
+                        .collect::<BTreeSet<_>>(),
+                    None => BTreeSet::new(),
+                };
+                let mut gates = Vec::new();
+                for slot in slots {
+                    gates.push(self.migrate_gates[slot].write().await);
+                }
+                let target = Target { host, port, db };
+                let response = self
+                    .migrate(client, &target, keys, timeout, copy, replace)
+                    .await;
+                drop(gates);
+                frame_stream.write_frame(response).await
+            }
+            Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::Psubscribe(_)
+            | Command::Punsubscribe(_) => {
+                for reply in self.subscribe(client, command) {
+                    if let Some(reply) = client.push(reply) {
+                        frame_stream.write_frame(reply).await?;
+                    }
+                }
+                Ok(())
+            }
+            Command::Ping if client.is_subscribed() && !client.resp3 => {
+                let reply = Frame::bulk_array(["pong", ""]);
+                frame_stream.write_frame(reply).await
+            }
+            command => {
+                // Keys are tracked before they are read, so that no write in
+                // between goes unnoticed.
+                self.track_reads(client, &command, caching);
+                let response = self.execute_on_owner(client, command).await?;
+                drop(gate);
+                frame_stream.write_frame(response).await
+            }
+        }
+    }
+
+    /// Refuses the commands a sentinel does not serve, and the ones not
+    /// allowed once the client subscribed to channels.
+    fn refuse_in_mode(&self, client: &Client, command: &Command) -> Option<Frame> {
+        let subscription = matches!(
+            command,
+            Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::Psubscribe(_)
+                | Command::Punsubscribe(_)
+                | Command::Ping
+        );
+        if self.sentinel.is_some()
+            && !subscription
+            && !matches!(
+                command,
+                Command::Info(_)
+                    | Command::Sentinel(_)
+                    | Command::Publish { .. }
+                    | Command::Hello(_)
+            )
+        {
+            let error = format!("ERR unknown command '{}'", command.name());
+            return Some(Frame::Error(Bytes::from(error)));
+        }
+        // RESP3 connections tell replies from messages apart.
+        if client.is_subscribed() && !client.resp3 && !subscription {
+            let error = format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / \
+                 RESET are allowed in this context",
+                command.name()
+            );
+            return Some(Frame::Error(Bytes::from(error)));
+        }
+
+        None
+    }
+
+    /// Subscribes the client to channels or patterns, or unsubscribes it, and
+    /// returns a reply per channel or pattern.
+    fn subscribe(&self, client: &mut Client, command: Command) -> Vec<Frame> {
+        let Some(subscriber) = client.subscriber.clone() else {
+            return vec![Frame::Error(Bytes::from_static(
+                b"ERR command not allowed in this context",
+            ))];
+        };
+        let reply = |kind: &'static str, name: Option<String>, client: &Client| {
+            let count = client.channels.len() + client.patterns.len();
+            Frame::Push(vec![
+                Frame::Bulk(Bytes::from_static(kind.as_bytes())),
+                name.map_or(Frame::Null, |name| Frame::Bulk(Bytes::from(name))),
+                Frame::Integer(count as i64),
+            ])
+        };
+        match command {
+            Command::Subscribe(channels) => channels
+                .into_iter()
+                .map(|channel| {
+                    self.pubsub.subscribe(&channel, client.id, &subscriber);
+                    client.channels.insert(channel.clone());
+                    reply("subscribe", Some(channel), client)
+                })
+                .collect(),
+            Command::Psubscribe(patterns) => patterns
+                .into_iter()
+                .map(|pattern| {
+                    self.pubsub.psubscribe(&pattern, client.id, &subscriber);
+                    client.patterns.insert(pattern.clone());
+                    reply("psubscribe", Some(pattern), client)
+                })
+                .collect(),
+            Command::Unsubscribe(mut channels) => {
+                if channels.is_empty() {
+                    channels = client.channels.iter().cloned().collect();
+                }
+                if channels.is_empty() {
+                    return vec![reply("unsubscribe", None, client)];
+                }
+                channels
+                    .into_iter()
+                    .map(|channel| {
+                        self.pubsub.unsubscribe(&channel, client.id);
+                        client.channels.remove(&channel);
+                        reply("unsubscribe", Some(channel), client)
+                    })
+                    .collect()
+            }
+            Command::Punsubscribe(mut patterns) => {
+                if patterns.is_empty() {
+                    patterns = client.patterns.iter().cloned().collect();
+                }
+                if patterns.is_empty() {
+                    return vec![reply("punsubscribe", None, client)];
+                }
+                patterns
+                    .into_iter()
+                    .map(|pattern| {
+                        self.pubsub.punsubscribe(&pattern, client.id);
+                        client.patterns.remove(&pattern);
+                        reply("punsubscribe", Some(pattern), client)
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn unsubscribe_all(&self, client: &Client) {
+        for channel in &client.channels {
+            self.pubsub.unsubscribe(channel, client.id);
+        }
+        for pattern in &client.patterns {
+            self.pubsub.punsubscribe(pattern, client.id);
+        }
+    }
+
+    /// Records the keys a command reads for the client's cache, if it tracks
+    /// them: in the default mode, unless OPTIN or OPTOUT make it depend on
+    /// the CLIENT CACHING sent just before the command.
+    fn track_reads(&self, client: &Client, command: &Command, caching: Option<bool>) {
+        let Some(options) = &client.tracking else {
+            return;
+        };
+        let tracked = if options.optin {
+            caching == Some(true)
+        } else if options.optout {
+            caching != Some(false)
+        } else {
+            !options.bcast
+        };
+        if tracked && command.is_read() {
+            self.tracking.remember(client.id, &command.keys());
+        }
+    }
+
+    /// Publishes an event about a key of database `db`, if its class is
+    /// enabled by `notify-keyspace-events`.
+    fn notify_keyspace_event(&self, class: KeyspaceEvents, event: &str, key: &str, db: usize) {
+        let events = self.config.notify_keyspace_events;
+        if !events.is_enabled(class) {
+            return;
+        }
+        if events.contains(KeyspaceEvents::KEYSPACE) {
+            let channel = format!("__keyspace@{db}__:{key}");
+            self.pubsub.publish(&channel, Bytes::from(event.to_owned()));
+        }
+        if events.contains(KeyspaceEvents::KEYEVENT) {
+            let channel = format!("__keyevent@{db}__:{event}");
+            self.pubsub.publish(&channel, Bytes::from(key.to_owned()));
+        }
+    }
+
+    /// Publishes a message, which a sentinel also handles as a hello when it
+    /// is sent on the hello channel.
+    fn publish(self: &Arc<Self>, channel: &str, message: Bytes) -> usize {
+        if self.sentinel.is_some() && channel == sentinel::HELLO_CHANNEL {
+            self.handle_hello(&message);
+        }
+        self.pubsub.publish(channel, message)
+    }
+
+    /// Executes a command. In thread-per-core mode, commands whose keys all
+    /// belong to one shard are sent to the executor owning it, so that most
+    /// accesses to a shard come from its own thread. Shards still have locks,
+    /// which other threads take for commands on several shards, eviction,
+    /// active expiry and snapshots.
+    async fn execute_on_owner(
+        self: &Arc<Self>,
+        client: &mut Client,
+        command: Command,
+    ) -> anyhow::Result<Frame> {
+        let owner = match self.owner(&command) {
+            Some(owner) if executor::current() != Some(owner) => owner,
+            _ => return Ok(self.execute(client, command)),
+        };
+
+        // The client moves to the executor and back, rather than being
+        // copied.
+        let server = self.clone();
+        let mut routed = std::mem::replace(client, Client::new(client.addr));
+        let (routed, response) = self
+            .executors
+            .handle(owner)
+            .spawn(async move {
+                let response = server.execute(&mut routed, command);
+                (routed, response)
+            })
+            .await?;
+        *client = routed;
+
+        Ok(response)
+    }
+
+    /// In cluster mode, the gate of the slot of a command's first key, which
+    /// MIGRATE closes while moving keys of the slot.
+    fn migrate_gate(&self, command: &Command) -> Option<&tokio::sync::RwLock<()>> {
+        self.cluster.as_ref()?;
+        let keys = command.keys();
+        let slot = cluster::key_slot(keys.first()?.as_bytes());
+
+        self.migrate_gates.get(slot as usize)
+    }
+
+    /// The executor owning the shard of every key of a command, if they all
+    /// belong to the same one.
+    fn owner(&self, command: &Command) -> Option<usize> {
+        if self.executors.is_empty() {
+            return None;
+        }
+        let mut shards = command.keys().into_iter().map(|key| self.db.shard_of(key));
+        let first = shards.next()?;
+
+        shards.all(|shard| shard == first).then_some(first)
+    }
+
+    /// In cluster mode, returns the redirection or error for commands whose
+    /// keys are not all in one slot served by this node.
+    ///
+    /// While a slot migrates, its keys still here are served here, and the
+    /// others are looked up on the target with an ASK redirection. The target
+    /// only serves them to clients that sent ASKING first.
+    fn cluster_redirect(&self, db: usize, command: &Command, asking: bool) -> Option<Frame> {
+        let cluster = self.cluster.as_ref()?;
+        let keys = command.keys();
+        let (first, rest) = keys.split_first()?;
+        let slot = cluster::key_slot(first.as_bytes());
+        if rest
+            .iter()
+            .any(|key| cluster::key_slot(key.as_bytes()) != slot)
+        {
+            return Some(Frame::Error(Bytes::from_static(CROSSSLOT_ERROR)));
+        }
+
+        let migrating_to = {
+            let cluster = cluster.lock().unwrap();
+            match cluster.owner(slot) {
+                Some(owner) if owner.id == cluster.myself().id => {
+                    match cluster.migrating_to(slot) {
+                        Some(target) => Some(host_port(&target.host, target.port)),
+                        None => return None,
+                    }
+                }
+                _ if asking && cluster.importing_from(slot).is_some() => None,
+                Some(owner) if owner.fail => {
+                    return Some(Frame::Error(Bytes::from_static(CLUSTERDOWN_ERROR)))
+                }
+                Some(owner) => {
+                    return Some(Frame::Error(Bytes::from(format!(
+                        "MOVED {} {}",
+                        slot,
+                        host_port(&owner.host, owner.port)
+                    ))))
+                }
+                None => return Some(Frame::Error(Bytes::from_static(CLUSTERDOWN_UNBOUND_ERROR))),
+            }
+        };
+
+        // Keys are looked up once the cluster is unlocked.
+        let existing = keys.iter().filter(|key| self.key_exists(db, key)).count();
+        if existing == keys.len() {
+            return None;
+        }
+        match migrating_to {
+            Some(target) if existing == 0 => Some(Frame::Error(Bytes::from(format!(
+                "ASK {} {}",
+                slot, target
+            )))),
+            // Imported slots can't serve multiple keys until they all moved.
+            None if keys.len() == 1 => None,
+            _ => Some(Frame::Error(Bytes::from_static(TRYAGAIN_ERROR))),
+        }
+    }
+
+    /// Whether a key exists, without counting as an access.
+    fn key_exists(&self, db: usize, key: &str) -> bool {
+        self.db.lock(key)[db]
+            .get(key)
+            .is_some_and(|db_value| !db_value.is_expired())
+    }
+
+    /// Moves keys to another instance, as MIGRATE does: their values are
+    /// restored there, and then deleted here unless `copy` is set.
+    async fn migrate(
+        &self,
+        client: &mut Client,
+        target: &Target,
+        keys: Vec<String>,
+        timeout: u64,
+        copy: bool,
+        replace: bool,
+    ) -> Frame {
+        let now = Instant::now();
+        let mut values = Vec::new();
+        for key in keys {
+            let mut shard = self.db.lock(&key);
+            let keyspace = &mut shard[client.db];
+            if self.expire_if_needed(client, keyspace, client.db, &key) {
+                continue;
+            }
+            if let Some(db_value) = keyspace.get(&key) {
+                let ttl = db_value
+                    .expiry
+                    .map_or(0, |expiry| (expiry - now).as_millis().max(1) as u64);
+                values.push((key, db_value.value.clone(), ttl));
+            }
+        }
+        if values.is_empty() {
+            return Frame::Simple("NOKEY".to_owned());
+        }
+
+        let restores = values
+            .iter()
+            .map(|(key, value, ttl)| Command::Restore {
+                key: key.clone(),
+                ttl: *ttl,
+                payload: Bytes::from(rdb::dump(value)),
+                replace,
+                absttl: false,
+                idletime: None,
+                freq: None,
+            })
+            .collect::<Vec<_>>();
+        let timeout = Duration::from_millis(if timeout == 0 { 1000 } else { timeout });
+        let replies =
+            match tokio::time::timeout(timeout, self.send_restores(target, restores)).await {
+                Ok(Ok(replies)) => replies,
+                Ok(Err(err)) => {
+                    return Frame::Error(Bytes::from(format!(
+                        "IOERR error or timeout talking to target instance: {:#}",
+                        err
+                    )))
+                }
+                Err(_) => {
+                    return Frame::Error(Bytes::from_static(
+                        b"IOERR error or timeout talking to target instance",
+                    ))
+                }
+            };
+
+        // Keys restored on the target are deleted here, unless they changed
+        // in the meantime.
+        let mut error = None;
+        let mut moved = Vec::new();
+        for ((key, value, _), reply) in values.into_iter().zip(replies) {
+            match reply {
+                Frame::Error(message) => {
+                    error.get_or_insert(message);
+                }
+                _ if copy => (),
+                _ => {
+                    let mut shard = self.db.lock(&key);
+                    let keyspace = &mut shard[client.db];
+                    if keyspace
+                        .get(&key)
+                        .is_some_and(|db_value| Arc::ptr_eq(&db_value.value, &value))
+                    {
+                        keyspace.remove(&key);
+                        self.notify_keyspace_event(KeyspaceEvents::GENERIC, "del", &key, client.db);
+                        self.tracking.invalidate(&key, Some(client.id));
+                        moved.push(key);
+                    }
+                }
+            }
+        }
+        if !moved.is_empty() {
+            self.dirty.fetch_add(moved.len() as u64, Ordering::SeqCst);
+            client.last_write_offset = self.propagate_to(client.db, &Command::Del(moved), None);
+        }
+
+        match error {
+            Some(message) => Frame::Error(Bytes::from(format!(
+                "ERR Target instance replied with error: {}",
+                message.escape_ascii()
+            ))),
+            None => Frame::Simple("OK".to_owned()),
+        }
+    }
+
+    /// Sends RESTORE commands to a MIGRATE target, and returns its replies to
+    /// them.
+    async fn send_restores(
+        &self,
+        target: &Target,
+        restores: Vec<Command>,
+    ) -> anyhow::Result<Vec<Frame>> {
+        let stream = TcpStream::connect((target.host.as_str(), target.port)).await?;
+        let mut frame_stream = FrameStream::new(stream);
+        if target.db != 0 {
+            frame_stream
+                .write_frame(Command::Select(target.db).to_frame())
+                .await?;
+            // No key is restored then.
+            if let error @ Frame::Error(_) = read_target_reply(&mut frame_stream).await? {
+                return Ok(vec![error]);
+            }
+        }
+        for restore in &restores {
+            // The target only accepts keys of slots it is importing after
+            // ASKING.
+            if self.cluster.is_some() {
+                frame_stream.write_frame(Command::Asking.to_frame()).await?;
+            }
+            frame_stream.write_frame(restore.to_frame()).await?;
+        }
+
+        let mut replies = Vec::new();
+        for _ in &restores {
+            if self.cluster.is_some() {
+                read_target_reply(&mut frame_stream).await?;
+            }
+            replies.push(read_target_reply(&mut frame_stream).await?);
+        }
+
+        Ok(replies)
+    }
+
+    /// Returns the error for client commands a replica must not serve: writes
+    /// when read-only, and any access to the dataset while the master link is
+    /// down, unless configured to serve stale data.
+    fn refuse_on_replica(&self, command: &Command) -> Option<&'static [u8]> {
+        if let Role::Master = self.role() {
+            return None;
+        }
+        if command.is_write() && self.config.replica_read_only {
+            return Some(READONLY_ERROR);
+        }
+        if (command.is_read() || command.is_write())
+            && !self.config.replica_serve_stale_data
+            && !self.master_link_up.load(Ordering::SeqCst)
+        {
+            return Some(MASTERDOWN_ERROR);
+        }
+
+        None
+    }
+
+    /// Returns the error for writes while the append-only file fails to be
+    /// written or synced, as they would be lost on a restart.
+    fn refuse_on_aof_error(&self, command: &Command) -> Option<Frame> {
+        if !command.is_write() {
+            return None;
+        }
+        let aof = self.aof.lock().unwrap();
+        let error = aof.as_ref()?.error()?;
+
+        Some(Frame::Error(Bytes::from(format!(
+            "MISCONF Errors writing to the AOF file: {}",
+            error
+        ))))
+    }
+
+    /// Evicts keys under the configured policy until the dataset fits in
+    /// `maxmemory`, and returns whether it does. Replicas leave evictions to
+    /// their master, and apply the DELs it sends instead.
+    fn evict(&self) -> bool {
+        if self.config.maxmemory == 0 {
+            return true;
+        }
+        if let Role::Slave { .. } = self.role() {
+            return true;
+        }
+
+        let mut pool = EvictionPool::new(
+            self.config.maxmemory_policy,
+            self.config.maxmemory_samples,
+            self.config.lfu(),
+        );
+        let volatile = self.config.maxmemory_policy.is_volatile();
+        while self.db.used_memory() > self.config.maxmemory {
+            let Some((index, key)) = pool.next(&self.db) else {
+                return false;
+            };
+            let mut shard = self.db.lock(&key);
+            // Another client may have deleted the key in the meantime, or
+            // removed its expiry, which volatile policies must not evict.
+            let evictable = shard[index]
+                .get(&key)
+                .is_some_and(|value| !volatile || value.expiry.is_some());
+            if !evictable {
+                continue;
+            }
+            shard[index].remove(&key);
+            self.notify_keyspace_event(KeyspaceEvents::EVICTED, "evicted", &key, index);
+            self.tracking.invalidate(&key, None);
+            self.evicted_keys.fetch_add(1, Ordering::SeqCst);
+            self.dirty.fetch_add(1, Ordering::SeqCst);
+            self.propagate_to(index, &Command::Del(vec![key]), None);
+        }
+
+        true
+    }
+
+    /// Returns the value of a key after recording an access to it, deleting it
+    /// first if it expired.
+    fn lookup<'a>(
+        &self,
+        client: &Client,
+        keyspace: &'a mut Keyspace,
+        db: usize,
+        key: &str,
+    ) -> Option<&'a DbValue> {
+        if self.expire_if_needed(client, keyspace, db, key) {
+            return None;
+        }
+        keyspace.touch(key, self.config.lfu())
+    }
+
+    /// Deletes a key if it expired, propagating a DEL, and returns whether it
+    /// did. Replicas leave expirations to their master: expired keys are
+    /// reported as missing to their clients without being deleted, and as
+    /// still there to the master, whose DEL is on its way.
+    fn expire_if_needed(
+        &self,
+        client: &Client,
+        keyspace: &mut Keyspace,
+        db: usize,
+        key: &str,
+    ) -> bool {
+        if !keyspace.get(key).is_some_and(DbValue::is_expired) {
+            return false;
+        }
+        if let Role::Slave { .. } = self.role() {
+            return client.master_frame.is_none();
+        }
+        keyspace.remove(key);
+        self.expired_keys.fetch_add(1, Ordering::SeqCst);
+        self.notify_keyspace_event(KeyspaceEvents::EXPIRED, "expired", key, db);
+        self.tracking.invalidate(key, None);
+        self.dirty.fetch_add(1, Ordering::SeqCst);
+        self.propagate_to(db, &Command::Del(vec![key.to_owned()]), None);
+
+        true
+    }
+
+    /// Deletes the expired keys nobody accesses, as redis does: the keys with
+    /// an expiry of each database are sampled again and again while more than
+    /// a quarter of the sampled ones expired. Replicas leave expirations to
+    /// their master, and apply the DELs it sends instead.
+    async fn run_active_expire(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(ACTIVE_EXPIRE_PERIOD);
+        loop {
+            interval.tick().await;
+            if let Role::Master = self.role() {
+                self.expire_keys();
+            }
+        }
+    }
+
+    fn expire_keys(&self) {
+        let deadline = Instant::now() + ACTIVE_EXPIRE_TIME_LIMIT;
+        for index in 0..self.db.shard_count() {
+            let mut shard = self.db.lock_shard(index);
+            for db in 0..shard.len() {
+                loop {
+                    let keyspace = &mut shard[db];
+                    let samples = keyspace.volatile_len().min(ACTIVE_EXPIRE_SAMPLES);
+                    let mut expired = Vec::new();
+                    for _ in 0..samples {
+                        let Some(key) = keyspace.random_key(true) else {
+                            break;
+                        };
+                        if keyspace[key].is_expired() {
+                            let key = key.to_owned();
+                            keyspace.remove(&key);
+                            self.notify_keyspace_event(
+                                KeyspaceEvents::EXPIRED,
+                                "expired",
+                                &key,
+                                db,
+                            );
+                            self.tracking.invalidate(&key, None);
+                            expired.push(key);
+                        }
+                    }
+                    let count = expired.len();
+                    if count > 0 {
+                        self.expired_keys.fetch_add(count as u64, Ordering::SeqCst);
+                        self.dirty.fetch_add(count as u64, Ordering::SeqCst);
+                        self.propagate_to(db, &Command::Del(expired), None);
+                    }
+                    if count * 4 <= samples || Instant::now() >= deadline {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn execute(self: &Arc<Self>, client: &mut Client, command: Command) -> Frame {
+        match command.clone() {
+            Command::Ping => Frame::Bulk(Bytes::from_static(b"PONG")),
+            Command::Echo(bytes) => Frame::Bulk(bytes),
+            Command::Get(key) => {
+                let mut shard = self.db.lock(&key);
+                let fr = match self.lookup(client, &mut shard[client.db], client.db, &key) {
+                    Some(db_value) => match db_value.value.as_ref() {
+                        Value::String(value) => Frame::Bulk(value.clone()),
+                        _ => Frame::Error(Bytes::from_static(WRONGTYPE_ERROR)),
+                    },
+                    _ => {
+                        self.notify_keyspace_event(
+                            KeyspaceEvents::KEY_MISS,
+                            "keymiss",
+                            &key,
+                            client.db,
+                        );
+                        Frame::Null
+                    }
+                };
+
+                fr
+            }
+            Command::Set {
+                key,
+                value,
+                px,
+                pxat,
+            } => {
+                let now_ms = rdb::unix_time_ms();
+                let expiry = px
+                    .map(|millis| Instant::now() + Duration::from_millis(millis))
+                    .or(pxat.map(|at| {
+                        Instant::now() + Duration::from_millis(at.saturating_sub(now_ms))
+                    }));
+                let mut db_value = DbValue::new(Value::String(value)).with_expiry(expiry);
+                let mut shard = self.db.lock(&key);
+                let keyspace = &mut shard[client.db];
+                self.expire_if_needed(client, keyspace, client.db, &key);
+                if let Some(old) = keyspace.get(&key) {
+                    db_value.inherit_access(old);
+                }
+                // Keys set to expire in the past are deleted right away.
+                if pxat.is_some_and(|at| at <= now_ms) {
+                    if keyspace.remove(&key).is_some() {
+                        self.notify_keyspace_event(KeyspaceEvents::GENERIC, "del", &key, client.db);
+                        self.tracking.invalidate(&key, Some(client.id));
+                    }
+                } else {
+                    if keyspace.insert(key.clone(), db_value).is_none() {
+                        self.notify_keyspace_event(KeyspaceEvents::NEW, "new", &key, client.db);
+                    }
+                    self.notify_keyspace_event(KeyspaceEvents::STRING, "set", &key, client.db);
+                    self.tracking.invalidate(&key, Some(client.id));
+                    if expiry.is_some() {
+                        self.notify_keyspace_event(
+                            KeyspaceEvents::GENERIC,
+                            "expire",
+                            &key,
+                            client.db,
+                        );
+                    }
+                }
+                self.dirty.fetch_add(1, Ordering::SeqCst);
+                client.last_write_offset = self.propagate(client, &command);
+
+                Frame::Bulk(Bytes::from_static(b"OK"))
+            }
+            Command::Info(section) => {
+                let mut buf = BytesMut::new();
+                let all = matches!(
+                    section.as_deref(),
+                    None | Some("all" | "default" | "everything")
+                );
+                let wants = |name: &str| all || section.as_deref() == Some(name);
+                if let Some(sentinel) = &self.sentinel {
+                    if wants("sentinel") {
+                        buf.extend_from_slice(sentinel.lock().unwrap().info().as_bytes());
+                    }
+                    return Frame::Bulk(buf.into());
+                }
+                if wants("memory") {
+                    self.write_memory_info(&mut buf);
+                }
+                if wants("persistence") {
+                    self.write_persistence_info(&mut buf);
+                }
+                if wants("stats") {
+                    self.write_stats_info(&mut buf);
+                }
+                if wants("replication") {
+                    self.write_replication_info(&mut buf);
+                }
+                if wants("cluster") {
+                    self.write_cluster_info(&mut buf);
+                }
+                if wants("keyspace") {
+                    self.write_keyspace_info(&mut buf);
+                }
+
+                Frame::Bulk(buf.into())
+            }
+            Command::Replconf(replconf) => {
+                match replconf {
+                    Replconf::ListeningPort(port) => client.listening_port = Some(port),
+                    Replconf::Capa(capabilities) => client.capabilities.extend(capabilities),
+                    _ => (),
+                }
+
+                Frame::Simple("OK".to_owned())
+            }
+            Command::ConfigGet(parameters) => {
+                let mut frames = Vec::new();
+                for parameter in parameters {
+                    if let Some(value) = self.config.get(&parameter) {
+                        frames.push(Frame::Bulk(Bytes::from(parameter)));
+                        frames.push(Frame::Bulk(Bytes::from(value)));
+                    }
+                }
+
+                Frame::Array(frames)
+            }
+            Command::Bgsave => match self.bgsave() {
+                Ok(()) => Frame::Simple("Background saving started".to_owned()),
+                Err(err) => Frame::Error(Bytes::from(format!("ERR {:#}", err))),
+            },
+            Command::Lastsave => Frame::Integer(self.last_save.load(Ordering::SeqCst) as i64),
+            Command::Bgrewriteaof => match self.bgrewriteaof() {
+                Ok(()) => Frame::Simple("Background append only file rewriting started".to_owned()),
+                Err(err) => Frame::Error(Bytes::from(format!("ERR {:#}", err))),
+            },
+            Command::Replicaof(_) if self.cluster.is_some() => {
+                Frame::Error(Bytes::from_static(CLUSTER_REPLICAOF_ERROR))
+            }
+            Command::Replicaof(master) => Frame::Simple(self.replicaof(master)),
+            Command::Select(index) => {
+                if index != 0 && self.cluster.is_some() {
+                    return Frame::Error(Bytes::from_static(CLUSTER_SELECT_ERROR));
+                }
+                if index >= self.config.databases {
+                    return Frame::Error(Bytes::from_static(DB_INDEX_ERROR));
+                }
+                client.db = index;
+
+                Frame::Simple("OK".to_owned())
+            }
+            Command::Move { key, db: target } => {
+                if self.cluster.is_some() {
+                    return Frame::Error(Bytes::from_static(CLUSTER_MOVE_ERROR));
+                }
+                if target >= self.config.databases {
+                    return Frame::Error(Bytes::from_static(DB_INDEX_ERROR));
+                }
+                if target == client.db {
+                    return Frame::Error(Bytes::from_static(SAME_OBJECT_ERROR));
+                }
+                // The key belongs to the same shard in every database.
+                let mut shard = self.db.lock(&key);
+                if self
+                    .lookup(client, &mut shard[client.db], client.db, &key)
+                    .is_none()
+                    || self
+                        .lookup(client, &mut shard[target], target, &key)
+                        .is_some()
+                {
+                    return Frame::Integer(0);
+                }
+                let db_value = shard[client.db].remove(&key).unwrap();
+                shard[target].insert(key.clone(), db_value);
+                self.notify_keyspace_event(KeyspaceEvents::NEW, "new", &key, target);
+                self.notify_keyspace_event(KeyspaceEvents::GENERIC, "move_from", &key, client.db);
+                self.notify_keyspace_event(KeyspaceEvents::GENERIC, "move_to", &key, target);
+                self.tracking.invalidate(&key, Some(client.id));
+                self.dirty.fetch_add(1, Ordering::SeqCst);
+                client.last_write_offset = self.propagate(client, &command);
+
+                Frame::Integer(1)
+            }
+            Command::Swapdb(index1, index2) => {
+                if self.cluster.is_some() {
+                    return Frame::Error(Bytes::from_static(CLUSTER_SWAPDB_ERROR));
+                }
+                if index1 >= self.config.databases || index2 >= self.config.databases {
+                    return Frame::Error(Bytes::from_static(DB_INDEX_ERROR));
+                }
+                // Clients keep their selected index, and so see the other
+                // database's keys from now on.
+                let mut db = self.db.lock_all();
+                for shard in db.shards_mut() {
+                    shard.swap(index1, index2);
+                }
+                self.tracking.invalidate_all();
+                self.dirty.fetch_add(1, Ordering::SeqCst);
+                client.last_write_offset = self.propagate(client, &command);
+
+                Frame::Simple("OK".to_owned())
+            }
+            Command::Flushdb => {
+                let mut db = self.db.lock_all();
+                let flushed = db
+                    .shards_mut()
+                    .map(|shard| std::mem::take(&mut shard[client.db]))
+                    .collect::<Vec<_>>();
+                let count = flushed.iter().map(Keyspace::len).sum::<usize>();
+                self.dirty.fetch_add(count as u64, Ordering::SeqCst);
+                client.last_write_offset = self.propagate(client, &command);
+                drop(db);
+                self.tracking.invalidate_all();
+                // Values are freed without holding the locks.
+                drop(flushed);
+
+                Frame::Simple("OK".to_owned())
+            }
+            Command::Dbsize => Frame::Integer(self.db.key_counts()[client.db].0 as i64),
+            Command::Copy {
+                source,
+                destination,
+                db: target,
+                replace,
+            } => {
+                let target = target.unwrap_or(client.db);
+                if target != client.db && self.cluster.is_some() {
+                    return Frame::Error(Bytes::from_static(CLUSTER_COPY_ERROR));
+                }
+                if target >= self.config.databases {
+                    return Frame::Error(Bytes::from_static(DB_INDEX_ERROR));
+                }
+                if target == client.db && source == destination {
+                    return Frame::Error(Bytes::from_static(SAME_OBJECT_ERROR));
+                }
+                let mut db = self.db.lock_keys([source.as_str(), destination.as_str()]);
+                // Values are never modified in place, so the copy shares them.
+                let Some(db_value) = self
+                    .lookup(client, db.keyspace(client.db, &source), client.db, &source)
+                    .cloned()
+                else {
+                    return Frame::Integer(0);
+                };
+                let keyspace = db.keyspace(target, &destination);
+                if !replace
+                    && self
+                        .lookup(client, keyspace, target, &destination)
+                        .is_some()
+                {
+                    return Frame::Integer(0);
+                }
+                if keyspace.insert(destination.clone(), db_value).is_none() {
+                    self.notify_keyspace_event(KeyspaceEvents::NEW, "new", &destination, target);
+                }
+                self.notify_keyspace_event(
+                    KeyspaceEvents::GENERIC,
+                    "copy_to",
+                    &destination,
+                    target,
+                );
+                self.tracking.invalidate(&destination, Some(client.id));
+                self.dirty.fetch_add(1, Ordering::SeqCst);
+                client.last_write_offset = self.propagate(client, &command);
+
+                Frame::Integer(1)
+            }
+            Command::Del(keys) => {
+                let mut db = self.db.lock_keys(keys.iter().map(String::as_str));
+                let mut deleted = 0;
+                for key in &keys {
+                    let keyspace = db.keyspace(client.db, key);
+                    let expired = self.expire_if_needed(client, keyspace, client.db, key);
+                    if !expired && keyspace.remove(key).is_some() {
+                        self.notify_keyspace_event(KeyspaceEvents::GENERIC, "del", key, client.db);
+                        self.tracking.invalidate(key, Some(client.id));
+                        deleted += 1;
+                    }
+                }
+                if deleted > 0 {
+                    self.dirty.fetch_add(deleted, Ordering::SeqCst);
+                    client.last_write_offset = self.propagate(client, &command);
+                }
+
+                Frame::Integer(deleted as i64)
+            }
+            Command::Object(object) => {
+                let lfu_policy = self.config.maxmemory_policy.is_lfu();
+                let (Object::Freq(key) | Object::Idletime(key)) = &object;
+                let mut shard = self.db.lock(key);
+                let keyspace = &mut shard[client.db];
+                // Inspecting a key does not count as an access.
+                let expired = self.expire_if_needed(client, keyspace, client.db, key);
+                let Some(db_value) = keyspace.get(key).filter(|_| !expired) else {
+                    return Frame::Null;
+                };
+                match object {
+                    Object::Freq(_) if !lfu_policy => Frame::Error(Bytes::from_static(FREQ_ERROR)),
+                    Object::Freq(_) => Frame::Integer(db_value.freq(self.config.lfu()) as i64),
+                    Object::Idletime(_) if lfu_policy => {
+                        Frame::Error(Bytes::from_static(IDLETIME_ERROR))
+                    }
+                    Object::Idletime(_) => Frame::Integer(db_value.idle_time().as_secs() as i64),
+                }
+            }
+            Command::Cluster(cluster) => self.execute_cluster(cluster),
+            Command::Hello(protover) => {
+                match protover {
+                    None => {}
+                    Some(2) => client.resp3 = false,
+                    Some(3) => client.resp3 = true,
+                    Some(_) => return Frame::Error(Bytes::from_static(NOPROTO_ERROR)),
+                }
+                let mode = if self.cluster.is_some() {
+                    "cluster"
+                } else if self.sentinel.is_some() {
+                    "sentinel"
+                } else {
+                    "standalone"
+                };
+                let role = match self.role() {
+                    Role::Master => "master",
+                    Role::Slave { .. } => "replica",
+                };
+                let fields = [
+                    ("server", Frame::Bulk(Bytes::from_static(b"redis"))),
+                    (
+                        "version",
+                        Frame::Bulk(Bytes::from_static(REDIS_VERSION.as_bytes())),
+                    ),
+                    ("proto", Frame::Integer(if client.resp3 { 3 } else { 2 })),
+                    ("id", Frame::Integer(client.id as i64)),
+                    ("mode", Frame::Bulk(Bytes::from_static(mode.as_bytes()))),
+                    ("role", Frame::Bulk(Bytes::from_static(role.as_bytes()))),
+                    ("modules", Frame::Array(Vec::new())),
+                ]
+                .map(|(name, value)| (Frame::Bulk(Bytes::from_static(name.as_bytes())), value));
+                if client.resp3 {
+                    Frame::Map(fields.into())
+                } else {
+                    Frame::Array(fields.into_iter().flat_map(|(k, v)| [k, v]).collect())
+                }
+            }
+            Command::Client(command) => self.execute_client(client, command),
+            Command::Dump(key) => {
+                let mut shard = self.db.lock(&key);
+                match self.lookup(client, &mut shard[client.db], client.db, &key) {
+                    Some(db_value) => Frame::Bulk(Bytes::from(rdb::dump(&db_value.value))),
+                    None => {
+                        self.notify_keyspace_event(
+                            KeyspaceEvents::KEY_MISS,
+                            "keymiss",
+                            &key,
+                            client.db,
+                        );
+                        Frame::Null
+                    }
+                }
+            }
+            Command::Restore {
+                ref key,
+                ttl,
+                ref payload,
+                replace,
+                absttl,
+                idletime,
+                freq,
+            } => {
+                // Payloads may be large, so they are parsed before taking the
+                // lock, though a busy key is still reported first.
+                let value = rdb::restore(payload);
+                let mut shard = self.db.lock(key);
+                let keyspace = &mut shard[client.db];
+                let expired = self.expire_if_needed(client, keyspace, client.db, key);
+                if !replace && !expired && keyspace.get(key).is_some() {
+                    return Frame::Error(Bytes::from_static(BUSYKEY_ERROR));
+                }
+                let value = match value {
+                    Ok(value) => value,
+                    Err(err) => return Frame::Error(Bytes::from(format!("ERR {}", err))),
+                };
+                let now_ms = rdb::unix_time_ms();
+                let expiry = match ttl {
+                    0 => None,
+                    at if absttl => {
+                        if at <= now_ms {
+                            // Restoring an already expired key only deletes the
+                            // one it replaces.
+                            if keyspace.remove(key).is_some() {
+                                self.notify_keyspace_event(
+                                    KeyspaceEvents::GENERIC,
+                                    "del",
+                                    key,
+                                    client.db,
+                                );
+                                self.tracking.invalidate(key, Some(client.id));
+                                self.dirty.fetch_add(1, Ordering::SeqCst);
+                                let del = Command::Del(vec![key.clone()]);
+                                client.last_write_offset = self.propagate(client, &del);
+                            }
+                            return Frame::Simple("OK".to_owned());
+                        }
+                        Some(Instant::now() + Duration::from_millis(at - now_ms))
+                    }
+                    millis => Some(Instant::now() + Duration::from_millis(millis)),
+                };
+                let mut db_value = DbValue::new(value).with_expiry(expiry);
+                if self.config.maxmemory_policy.is_lfu() {
+                    if let Some(freq) = freq {
+                        db_value.set_freq(freq);
+                    }
+                } else if let Some(seconds) = idletime {
+                    db_value.set_idle_time(Duration::from_secs(seconds));
+                }
+                if keyspace.insert(key.clone(), db_value).is_none() {
+                    self.notify_keyspace_event(KeyspaceEvents::NEW, "new", key, client.db);
+                }
+                self.notify_keyspace_event(KeyspaceEvents::GENERIC, "restore", key, client.db);
+                self.tracking.invalidate(key, Some(client.id));
+                self.dirty.fetch_add(1, Ordering::SeqCst);
+                // A relative TTL would restart when replayed by replicas or
+                // from the append-only file.
+                let propagated = if ttl > 0 && !absttl {
+                    Command::Restore {
+                        key: key.clone(),
+                        ttl: now_ms + ttl,
+                        payload: payload.clone(),
+                        replace,
+                        absttl: true,
+                        idletime,
+                        freq,
+                    }
+                } else {
+                    command
+                };
+                client.last_write_offset = self.propagate(client, &propagated);
+
+                Frame::Simple("OK".to_owned())
+            }
+            Command::Asking => {
+                if self.cluster.is_none() {
+                    return Frame::Error(Bytes::from_static(CLUSTER_DISABLED_ERROR));
+                }
+                client.asking = true;
+
+                Frame::Simple("OK".to_owned())
+            }
+            Command::Publish { channel, message } => {
+                Frame::Integer(self.publish(&channel, message) as i64)
+            }
+            Command::Sentinel(sentinel) => self.execute_sentinel(sentinel),
+            Command::Psync { .. }
+            | Command::Wait { .. }
+            | Command::Migrate { .. }
+            | Command::Save
+            | Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::Psubscribe(_)
+            | Command::Punsubscribe(_) => Frame::Error(Bytes::from_static(
+                b"ERR command not allowed in this context",
+            )),
+        }
+    }
+
+    fn execute_sentinel(self: &Arc<Self>, command: Sentinel) -> Frame {
+        let Some(sentinel) = &self.sentinel else {
+            return Frame::Error(Bytes::from_static(SENTINEL_DISABLED_ERROR));
+        };
+        let now = rdb::unix_time_ms();
+        let no_such_master =
+            || Frame::Error(Bytes::from_static(sentinel::NO_SUCH_MASTER.as_bytes()));
+        match command {
+            Sentinel::Masters => sentinel.lock().unwrap().masters_frame(now),
+            Sentinel::Master(name) => {
+                let frame = sentinel.lock().unwrap().master_frame(&name, now);
+                frame.unwrap_or_else(no_such_master)
+            }
+            Sentinel::Replicas(name) => {
+                let frame = sentinel.lock().unwrap().replicas_frame(&name, now);
+                frame.unwrap_or_else(no_such_master)
+            }
+            Sentinel::Sentinels(name) => {
+                let frame = sentinel.lock().unwrap().sentinels_frame(&name, now);
+                frame.unwrap_or_else(no_such_master)
+            }
+            Sentinel::GetMasterAddrByName(name) => {
+                let sentinel = sentinel.lock().unwrap();
+                match sentinel.master(&name) {
+                    Some(master) => {
+                        let (host, port) = master.current_addr();
+                        Frame::bulk_array([host.clone(), port.to_string()])
+                    }
+                    None => Frame::Null,
+                }
+            }
+            Sentinel::IsMasterDownByAddr {
+                host,
+                port,
+                epoch,
+                run_id,
+            } => {
+                let (reply, effects) = sentinel.lock().unwrap().is_master_down_by_addr(
+                    &(host, port),
+                    epoch,
+                    &run_id,
+                    now,
+                );
+                self.apply_sentinel_effects(effects);
+                reply
+            }
+            Sentinel::Monitor {
+                name,
+                host,
+                port,
+                quorum,
+            } => {
+                let result = sentinel
+                    .lock()
+                    .unwrap()
+                    .monitor(&name, (host, port), quorum, now);
+                self.sentinel_reply(result)
+            }
+            Sentinel::Remove(name) => {
+                let result = sentinel.lock().unwrap().remove(&name);
+                self.sentinel_reply(result)
+            }
+            Sentinel::Set { name, options } => {
+                let result = sentinel.lock().unwrap().set(&name, &options);
+                self.sentinel_reply(result)
+            }
+            Sentinel::Reset(pattern) => {
+                let (count, effects) = sentinel.lock().unwrap().reset(&pattern, now);
+                self.apply_sentinel_effects(effects);
+                Frame::Integer(count as i64)
+            }
+            Sentinel::Failover(name) => {
+                let result = sentinel.lock().unwrap().failover(&name, now);
+                self.sentinel_reply(result)
+            }
+            Sentinel::Ckquorum(name) => match sentinel.lock().unwrap().ckquorum(&name) {
+                Ok(reply) => Frame::Simple(reply),
+                Err(err) => Frame::Error(Bytes::from(err.to_string())),
+            },
+            Sentinel::Myid => Frame::Bulk(Bytes::from(sentinel.lock().unwrap().myid.clone())),
+        }
+    }
+
+    /// Carries out the effects of a sentinel command, and returns its reply.
+    /// Errors are complete replies, with their prefix.
+    fn sentinel_reply(self: &Arc<Self>, result: anyhow::Result<sentinel::Effects>) -> Frame {
+        match result {
+            Ok(effects) => {
+                self.apply_sentinel_effects(effects);
+                Frame::Simple("OK".to_owned())
+            }
+            Err(err) => Frame::Error(Bytes::from(err.to_string())),
+        }
+    }
+
+    fn execute_client(&self, client: &mut Client, command: command::Client) -> Frame {
+        match command {
+            command::Client::Id => Frame::Integer(client.id as i64),
+            command::Client::Tracking(None) => {
+                self.tracking.disable(client.id);
+                client.tracking = None;
+                Frame::Simple("OK".to_owned())
+            }
+            command::Client::Tracking(Some(mut options)) => {
+                if let Some(redirect) = options.redirect {
+                    if !self.tracking.is_connected(redirect) {
+                        return Frame::Error(Bytes::from_static(TRACKING_REDIRECT_ERROR));
+                    }
+                }
+                if let Some(current) = &client.tracking {
+                    if current.bcast != options.bcast {
+                        return Frame::Error(Bytes::from_static(TRACKING_BCAST_ERROR));
+                    }
+                    if current.optin != options.optin || current.optout != options.optout {
+                        return Frame::Error(Bytes::from_static(TRACKING_OPTIN_ERROR));
+                    }
+                    // Prefixes add up to the ones given when tracking was
+                    // enabled.
+                    let mut prefixes = current.prefixes.clone();
+                    for prefix in options.prefixes {
+                        if !prefixes.contains(&prefix) {
+                            prefixes.push(prefix);
+                        }
+                    }
+                    options.prefixes = prefixes;
+                }
+                for (i, prefix) in options.prefixes.iter().enumerate() {
+                    let overlapping = options.prefixes[..i]
+                        .iter()
+                        .find(|other| prefix.starts_with(*other) || other.starts_with(prefix));
+                    if let Some(other) = overlapping {
+                        let error = format!(
+                            "ERR Prefix '{}' overlaps with an existing prefix '{}'. Prefixes for \
+                             a single client must not overlap.",
+                            prefix, other
+                        );
+                        return Frame::Error(Bytes::from(error));
+                    }
+                }
+                self.tracking.enable(client.id, options.clone());
+                client.tracking = Some(options);
+                Frame::Simple("OK".to_owned())
+            }
+            command::Client::Caching(caching) => {
+                let Some(options) = client.tracking.as_ref().filter(|o| o.optin || o.optout) else {
+                    return Frame::Error(Bytes::from_static(CACHING_ERROR));
+                };
+                if caching && !options.optin {
+                    return Frame::Error(Bytes::from_static(CACHING_YES_ERROR));
+                }
+                if !caching && !options.optout {
+                    return Frame::Error(Bytes::from_static(CACHING_NO_ERROR));
+                }
+                client.caching = Some(caching);
+                Frame::Simple("OK".to_owned())
+            }
+            command::Client::Getredir => match &client.tracking {
+                None => Frame::Integer(-1),
+                Some(options) => Frame::Integer(options.redirect.map_or(0, |id| id as i64)),
+            },
+        }
+    }
+
+    fn execute_cluster(self: &Arc<Self>, command: Cluster) -> Frame {
+        let Some(cluster) = &self.cluster else {
+            return Frame::Error(Bytes::from_static(CLUSTER_DISABLED_ERROR));
+        };
+        match command {
+            Cluster::Info => Frame::Bulk(Bytes::from(cluster.lock().unwrap().info())),
+            Cluster::Myid => Frame::Bulk(Bytes::from(cluster.lock().unwrap().myself().id.clone())),
+            Cluster::Nodes => Frame::Bulk(Bytes::from(cluster.lock().unwrap().describe_nodes())),
+            Cluster::Slots => cluster.lock().unwrap().slots_frame(),
+            Cluster::Shards => cluster.lock().unwrap().shards_frame(),
+            Cluster::Keyslot(key) => Frame::Integer(cluster::key_slot(key.as_bytes()) as i64),
+            Cluster::Countkeysinslot(slot) => Frame::Integer(self.keys_in_slot(slot) as i64),
+            Cluster::Getkeysinslot { slot, count } => {
+                let mut keys = Vec::new();
+                for index in 0..self.db.shard_count() {
+                    if keys.len() >= count {
+                        break;
+                    }
+                    let shard = self.db.lock_shard(index);
+                    keys.extend(
+                        shard[0]
+                            .slot_keys(slot)
+                            .take(count - keys.len())
+                            .map(|key| Frame::Bulk(Bytes::from(key.to_owned()))),
+                    );
+                }
+                Frame::Array(keys)
+            }
+            Cluster::Addslots(ref slots) | Cluster::Delslots(ref slots) => {
+                let mut cluster = cluster.lock().unwrap();
+                let result = if matches!(command, Cluster::Addslots(_)) {
+                    cluster.add_slots(slots)
+                } else {
+                    cluster.del_slots(slots)
+                };
+                match result.and_then(|()| cluster.save(&self.config.cluster_config_path())) {
+                    Ok(()) => Frame::Simple("OK".to_owned()),
+                    Err(err) => Frame::Error(Bytes::from(format!("ERR {:#}", err))),
+                }
+            }
+            Cluster::Meet {
+                host,
+                port,
+                bus_port,
+            } => {
+                let bus_port = bus_port.unwrap_or(port.wrapping_add(CLUSTER_BUS_PORT_OFFSET));
+                cluster
+                    .lock()
+                    .unwrap()
+                    .meet(&host, port, bus_port, rdb::unix_time_ms());
+                Frame::Simple("OK".to_owned())
+            }
+            Cluster::Forget(id) => {
+                let mut cluster = cluster.lock().unwrap();
+                let result = cluster
+                    .forget(&id, rdb::unix_time_ms())
+                    .and_then(|()| cluster.save(&self.config.cluster_config_path()));
+                match result {
+                    Ok(()) => Frame::Simple("OK".to_owned()),
+                    Err(err) => Frame::Error(Bytes::from(format!("ERR {:#}", err))),
+                }
+            }
+            Cluster::Replicate(id) => {
+                // Counted first, as the cluster is never locked along with
+                // the shards.
+                let has_keys = key_count(&self.db) > 0;
+                let result = {
+                    let mut cluster = cluster.lock().unwrap();
+                    if has_keys && cluster.myself().master.is_none() {
+                        return Frame::Error(Bytes::from_static(CLUSTER_REPLICATE_ERROR));
+                    }
+                    cluster.replicate(&id)
+                };
+                self.cluster_reply(result)
+            }
+            Cluster::Failover(mode) => {
+                let offset = self.replication.lock().unwrap().offset;
+                let result = cluster
+                    .lock()
+                    .unwrap()
+                    .failover(mode, offset, rdb::unix_time_ms());
+                self.cluster_reply(result)
+            }
+            Cluster::Reset { hard } => {
+                let is_master = cluster.lock().unwrap().myself().master.is_none();
+                if is_master {
+                    if key_count(&self.db) > 0 {
+                        return Frame::Error(Bytes::from_static(CLUSTER_RESET_ERROR));
+                    }
+                } else {
+                    self.flush_all();
+                }
+                let effects = cluster.lock().unwrap().reset(hard);
+                self.cluster_reply(Ok(effects))
+            }
+            Cluster::Setslot { slot, action } => {
+                // Counted first, as the cluster is never locked along with
+                // the shards.
+                let keys = self.keys_in_slot(slot);
+                let offset = self.replication.lock().unwrap().offset;
+                let result = cluster.lock().unwrap().set_slot(
+                    slot,
+                    action,
+                    keys,
+                    offset,
+                    rdb::unix_time_ms(),
+                );
+                self.cluster_reply(result)
+            }
+        }
+    }
+
+    fn keys_in_slot(&self, slot: u16) -> usize {
+        (0..self.db.shard_count())
+            .map(|index| self.db.lock_shard(index)[0].slot_len(slot))
+            .sum()
+    }
+
+    /// Carries out the effects of a cluster command, and returns its reply.
+    fn cluster_reply(self: &Arc<Self>, result: anyhow::Result<Effects>) -> Frame {
+        match result {
+            Ok(effects) => {
+                self.apply_cluster_effects(Effects {
+                    save: true,
+                    ..effects
+                });
+                Frame::Simple("OK".to_owned())
+            }
+            Err(err) => Frame::Error(Bytes::from(format!("ERR {:#}", err))),
+        }
+    }
+
+    /// Empties every database, as replicas leaving their master do.
+    fn flush_all(&self) {
+        let mut db = self.db.lock_all();
+        let flushed = db
+            .shards_mut()
+            .flat_map(|shard| shard.iter_mut().map(std::mem::take).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        drop(db);
+        self.tracking.invalidate_all();
+        // Values are freed without holding the locks.
+        drop(flushed);
+    }
+
+    async fn handle_psync(
+        self: &Arc<Self>,
+        frame_stream: &mut FrameStream,
+        client: &mut Client,
+        replication_id: &str,
+        psync_offset: i64,
+    ) -> anyhow::Result<()> {
+        // Replicas serve their own replicas only while in sync with their master.
+        if let Role::Slave { .. } = self.role() {
+            if !self.master_link_up.load(Ordering::SeqCst) {
+                frame_stream
+                    .write_frame(Frame::Error(Bytes::from_static(NOMASTERLINK_ERROR)))
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        // Registering the replica under the db lock guarantees that every
+        // write missing from the snapshot or the backlog ends up in its queue.
+        let diskless =
+            self.config.repl_diskless_sync && client.capabilities.iter().any(|c| c == "eof");
+        let registered = {
+            let db = self.db.lock_all();
+            let mut replication = self.replication.lock().unwrap();
+            let sync = match replication.continuation(replication_id, psync_offset) {
+                Some(backlog) => Some((Sync::Partial(backlog), psync_offset as u64 - 1)),
+                None if diskless => None,
+                None => Some((
+                    Sync::Full(db.snapshot(), replication.stream_db),
+                    replication.offset,
+                )),
+            };
+            sync.map(|(sync, ack_offset)| {
+                let replica =
+                    replication.add_replica(client.addr, client.listening_port, ack_offset);
+                (
+                    sync,
+                    replication.replid.clone(),
+                    replication.offset,
+                    replica,
+                )
+            })
+        };
+        // Diskless transfers register their replicas once they start.
+        let Some((sync, replid, offset, (id, queue))) = registered else {
+            return self.diskless_resync(frame_stream, client).await;
+        };
+        let result = match sync {
+            Sync::Partial(backlog) => {
+                self.partial_resync(frame_stream, &replid, backlog, id, queue)
+                    .await
+            }
+            Sync::Full(snapshot, stream_db) => {
+                self.full_resync(
+                    frame_stream,
+                    &replid,
+                    offset,
+                    (snapshot, stream_db),
+                    id,
+                    queue,
+                )
+                .await
+            }
+        };
+        self.replication.lock().unwrap().remove_replica(id);
+        result
+    }
+
+    /// Syncs a replica by streaming a snapshot into its socket, as it is
+    /// generated. Replicas asking for a sync within the configured delay share
+    /// the same transfer.
+    async fn diskless_resync(
+        self: &Arc<Self>,
+        frame_stream: &mut FrameStream,
+        client: &Client,
+    ) -> anyhow::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut waiting = self.diskless_waiting.lock().unwrap();
+            if waiting.is_empty() {
+                tokio::spawn(self.clone().start_diskless_transfer());
+            }
+            waiting.push(DisklessWaiting {
+                addr: client.addr,
+                listening_port: client.listening_port,
+                tx,
+            });
+        }
+        let DisklessTransfer {
+            replid,
+            offset,
+            id,
+            queue,
+            mut chunks,
+        } = rx.await.context("diskless transfer not started")?;
+
+        let result = async {
+            frame_stream
+                .write_frame(Frame::Simple(format!("FULLRESYNC {replid} {offset}")))
+                .await?;
+            let mark = replication::random_replid();
+            frame_stream
+                .write_raw(format!("$EOF:{mark}\r\n").as_bytes())
+                .await?;
+            let mut len = 0;
+            while let Some(chunk) = chunks.recv().await {
+                let chunk = chunk?;
+                len += chunk.len();
+                frame_stream.write_raw(&chunk).await?;
+            }
+            frame_stream.write_raw(mark.as_bytes()).await?;
+            println!("diskless resync: sent {len} bytes of rdb at offset {offset}");
+
+            // The replica can only tell the end of the payload by the mark at
+            // the end of what it read, so the stream waits for its first ACK.
+            loop {
+                let frame = frame_stream
+                    .read_frame()
+                    .await?
+                    .ok_or(anyhow!("replica closed the connection"))?;
+                if let Ok(Command::Replconf(Replconf::Ack(offset))) = Command::parse(frame) {
+                    self.replication.lock().unwrap().ack(id, offset);
+                    break;
+                }
+            }
+            self.replication
+                .lock()
+                .unwrap()
+                .set_replica_state(id, ReplicaState::Online);
+            // The first ACK is only counted by WAIT once the replica is online.
+            self.replica_acks.notify_waiters();
+
+            self.stream_to_replica(frame_stream, id, queue).await
+        }
+        .await;
+        self.replication.lock().unwrap().remove_replica(id);
+        result
+    }
+
+    /// Starts a diskless transfer to every replica waiting for one, once the
+    /// configured delay passed.
+    async fn start_diskless_transfer(self: Arc<Self>) {
+        tokio::time::sleep(Duration::from_secs(self.config.repl_diskless_sync_delay)).await;
+
+        let waiting = std::mem::take(&mut *self.diskless_waiting.lock().unwrap());
+        let mut senders = Vec::new();
+        let (snapshot, stream_db) = {
+            let db = self.db.lock_all();
+            let mut replication = self.replication.lock().unwrap();
+            for waiting in waiting {
+                let offset = replication.offset;
+                let (id, queue) =
+                    replication.add_replica(waiting.addr, waiting.listening_port, offset);
+                let (chunk_tx, chunks) = mpsc::channel(DISKLESS_CHUNKS_IN_FLIGHT);
+                let transfer = DisklessTransfer {
+                    replid: replication.replid.clone(),
+                    offset,
+                    id,
+                    queue,
+                    chunks,
+                };
+                match waiting.tx.send(transfer) {
+                    Ok(()) => senders.push(chunk_tx),
+                    Err(_) => replication.remove_replica(id),
+                }
+            }
+            (db.snapshot(), replication.stream_db)
+        };
+        if senders.is_empty() {
+            return;
+        }
+
+        println!("starting diskless transfer to {} replicas", senders.len());
+        tokio::task::spawn_blocking(move || {
+            let mut writer = ChunkWriter {
+                senders,
+                buf: Vec::with_capacity(DISKLESS_CHUNK_SIZE),
+            };
+            let stream_db = stream_db.to_string();
+            let result = rdb::write(
+                &mut writer,
+                &snapshot.databases(),
+                &[("repl-stream-db", &stream_db)],
+            )
+            .and_then(|()| Ok(writer.flush()?));
+            if let Err(err) = result {
+                println!("diskless transfer failed: {:#}", err);
+                for sender in &writer.senders {
+                    let _ = sender.blocking_send(Err(anyhow!("diskless transfer failed")));
+                }
+            }
+        });
+    }
+
+    /// Appends a write command to the replication stream and returns the
+    /// resulting offset. Callers hold the shard locks of the keys written, so
+    /// the stream has the same order as the writes to each key.
+    ///
+    /// Replicas forward the exact bytes received from their master instead, so
+    /// that sub-replicas share its replication ID and offsets.
+    ///
+    /// Writes are logged to the append-only file here too, in the same order.
+    /// Both select the client's database first when it changed, and get
+    /// expiry times made absolute, as replicas may apply writes long after.
+    fn propagate(&self, client: &mut Client, command: &Command) -> u64 {
+        let master_frame = client.master_frame.take();
+        self.propagate_to(client.db, command, master_frame)
+    }
+
+    /// Propagates a write to database `db`, as [`Self::propagate`] does.
+    fn propagate_to(&self, db: usize, command: &Command, master_frame: Option<Bytes>) -> u64 {
+        let encoded = aof::encode_command(command);
+        if let Some(aof) = self.aof.lock().unwrap().as_mut() {
+            if let Err(err) = aof.append(db, &encoded) {
+                println!("failed to write to the append only file: {:#}", err);
+            }
+        }
+
+        let role = self.role();
+        let mut replication = self.replication.lock().unwrap();
+        match role {
+            Role::Master => {
+                if replication.stream_db != db {
+                    replication.feed(Command::Select(db).to_frame().to_bytes());
+                    replication.stream_db = db;
+                }
+                replication.feed(encoded);
+            }
+            Role::Slave { .. } => {
+                if let Some(raw) = master_frame {
+                    replication.feed(raw);
+                }
+            }
+        }
+
+        replication.offset
+    }
+
+    /// Waits until `numreplicas` replicas acknowledged the client's last write,
+    /// or until `timeout` milliseconds passed (0 waits forever). Returns the
+    /// number of replicas that acknowledged it.
+    async fn wait(&self, client: &Client, numreplicas: u64, timeout: u64) -> usize {
+        if let Role::Slave { .. } = self.role() {
+            return 0;
+        }
+        let deadline = (timeout > 0).then(|| Instant::now() + Duration::from_millis(timeout));
+        let target = client.last_write_offset;
+
+        let mut getack_sent = false;
+        loop {
+            let notified = self.replica_acks.notified();
+            let acked = {
+                let mut replication = self.replication.lock().unwrap();
+                let acked = replication.acked_replicas(target);
+                if acked as u64 >= numreplicas {
+                    return acked;
+                }
+                if !getack_sent {
+                    replication.feed(Command::Replconf(Replconf::GetAck).to_frame().to_bytes());
+                    getack_sent = true;
+                }
+                acked
+            };
+
+            match deadline {
+                Some(deadline) => tokio::select! {
+                    _ = notified => (),
+                    _ = tokio::time::sleep_until(deadline) => return acked,
+                },
+                None => notified.await,
+            }
+        }
+    }
+
+    fn write_replication_info(&self, buf: &mut BytesMut) {
+        buf.write_str("# Replication\n").unwrap();
+        let role = self.role();
+        let replication = self.replication.lock().unwrap();
+        match role {
+            Role::Master => buf.write_str("role:master\n").unwrap(),
+            Role::Slave {
+                master_host,
+                master_port,
+            } => {
+                let link_up = self.master_link_up.load(Ordering::SeqCst);
+                let now = rdb::unix_time_ms();
+                buf.write_str("role:slave\n").unwrap();
+                writeln!(buf, "master_host:{}", master_host).unwrap();
+                writeln!(buf, "master_port:{}", master_port).unwrap();
+                writeln!(
+                    buf,
+                    "master_link_status:{}",
+                    if link_up { "up" } else { "down" }
+                )
+                .unwrap();
+                let last_io = if link_up {
+                    (now.saturating_sub(self.master_last_io.load(Ordering::SeqCst)) / 1000) as i64
+                } else {
+                    -1
+                };
+                writeln!(buf, "master_last_io_seconds_ago:{}", last_io).unwrap();
+                writeln!(
+                    buf,
+                    "master_sync_in_progress:{}",
+                    self.master_sync_in_progress.load(Ordering::SeqCst) as u8
+                )
+                .unwrap();
+                if !link_up {
+                    let down_since = self.master_link_down_since.load(Ordering::SeqCst);
+                    let down_for = if down_since == 0 {
+                        -1
+                    } else {
+                        (now.saturating_sub(down_since) / 1000) as i64
+                    };
+                    writeln!(buf, "master_link_down_since_seconds:{}", down_for).unwrap();
+                }
+                writeln!(buf, "slave_repl_offset:{}", replication.offset).unwrap();
+            }
+        };
+        writeln!(buf, "connected_slaves:{}", replication.replicas().len()).unwrap();
+        for (i, replica) in replication.replicas().iter().enumerate() {
+            writeln!(
+                buf,
+                "slave{}:ip={},port={},state={},offset={},lag={}",
+                i,
+                replica.addr.ip(),
+                replica.listening_port.unwrap_or(replica.addr.port()),
+                replica.state.as_str(),
+                replica.ack_offset,
+                replica.last_ack.elapsed().as_secs(),
+            )
+            .unwrap();
+        }
+        writeln!(buf, "master_replid:{}", replication.replid).unwrap();
+        writeln!(buf, "master_replid2:{}", replication.replid2).unwrap();
+        writeln!(buf, "master_repl_offset:{}", replication.offset).unwrap();
+        writeln!(
+            buf,
+            "second_repl_offset:{}",
+            replication.second_replid_offset
+        )
+        .unwrap();
+        writeln!(
+            buf,
+            "repl_backlog_active:{}",
+            replication.has_backlog() as u8
+        )
+        .unwrap();
+        writeln!(buf, "repl_backlog_size:{}", replication.backlog_size()).unwrap();
+        writeln!(
+            buf,
+            "repl_backlog_first_byte_offset:{}",
+            replication.offset - replication.backlog_histlen() as u64 + 1
+        )
+        .unwrap();
+        writeln!(
+            buf,
+            "repl_backlog_histlen:{}",
+            replication.backlog_histlen()
+        )
+        .unwrap();
+    }
+
+    fn write_memory_info(&self, buf: &mut BytesMut) {
+        buf.write_str("# Memory\n").unwrap();
+        let used = self.db.used_memory();
+        writeln!(buf, "used_memory:{}", used).unwrap();
+        writeln!(buf, "maxmemory:{}", self.config.maxmemory).unwrap();
+        writeln!(
+            buf,
+            "maxmemory_policy:{}",
+            self.config.maxmemory_policy.as_str()
+        )
+        .unwrap();
+    }
+
+    fn write_persistence_info(&self, buf: &mut BytesMut) {
+        buf.write_str("# Persistence\n").unwrap();
+        writeln!(
+            buf,
+            "rdb_changes_since_last_save:{}",
+            self.dirty.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        writeln!(
+            buf,
+            "rdb_bgsave_in_progress:{}",
+            self.save_in_progress.load(Ordering::SeqCst) as u8
+        )
+        .unwrap();
+        let aof = self.aof.lock().unwrap();
+        writeln!(buf, "aof_enabled:{}", aof.is_some() as u8).unwrap();
+        writeln!(
+            buf,
+            "aof_rewrite_in_progress:{}",
+            self.aof_rewrite_in_progress.load(Ordering::SeqCst) as u8
+        )
+        .unwrap();
+        let error = aof.as_ref().and_then(|aof| aof.error());
+        let status = if error.is_some() { "err" } else { "ok" };
+        writeln!(buf, "aof_last_write_status:{}", status).unwrap();
+        if let Some(error) = error {
+            writeln!(buf, "aof_last_write_error:{}", error).unwrap();
+        }
+    }
+
+    fn write_stats_info(&self, buf: &mut BytesMut) {
+        buf.write_str("# Stats\n").unwrap();
+        writeln!(
+            buf,
+            "evicted_keys:{}",
+            self.evicted_keys.load(Ordering::SeqCst)
+        )
+        .unwrap();
+        writeln!(
+            buf,
+            "expired_keys:{}",
+            self.expired_keys.load(Ordering::SeqCst)
+        )
+        .unwrap();
+    }
+
+    fn write_cluster_info(&self, buf: &mut BytesMut) {
+        buf.write_str("# Cluster\n").unwrap();
+        let enabled = self.cluster.is_some() as u8;
+        writeln!(buf, "cluster_enabled:{}", enabled).unwrap();
+    }
+
+    fn write_keyspace_info(&self, buf: &mut BytesMut) {
+        buf.write_str("# Keyspace\n").unwrap();
+        for (index, (keys, expires)) in self.db.key_counts().into_iter().enumerate() {
+            if keys == 0 {
+                continue;
+            }
+            writeln!(buf, "db{}:keys={},expires={}", index, keys, expires).unwrap();
+        }
+    }
+
+    /// Continues the replication stream of a replica from the backlog.
+    async fn partial_resync(
+        &self,
+        frame_stream: &mut FrameStream,
+        replication_id: &str,
+        backlog: Bytes,
+        id: u64,
+        queue: mpsc::UnboundedReceiver<Bytes>,
+    ) -> anyhow::Result<()> {
+        frame_stream
+            .write_frame(Frame::Simple(format!("CONTINUE {replication_id}")))
+            .await?;
+        frame_stream.write_raw(&backlog).await?;
+        println!("partial resync: sent {} bytes of backlog", backlog.len());
+        self.replication
+            .lock()
+            .unwrap()
+            .set_replica_state(id, ReplicaState::Online);
+
+        self.stream_to_replica(frame_stream, id, queue).await
+    }
+
+    async fn full_resync(
+        &self,
+        frame_stream: &mut FrameStream,
+        replication_id: &str,
+        offset: u64,
+        (snapshot, stream_db): (Snapshot, usize),
+        id: u64,
+        queue: mpsc::UnboundedReceiver<Bytes>,
+    ) -> anyhow::Result<()> {
+        frame_stream
+            .write_frame(Frame::Simple(format!(
+                "FULLRESYNC {replication_id} {offset}"
+            )))
+            .await?;
+
+        let rdb = tokio::task::spawn_blocking(move || {
+            let mut rdb = Vec::new();
+            let stream_db = stream_db.to_string();
+            rdb::write(
+                &mut rdb,
+                &snapshot.databases(),
+                &[("repl-stream-db", &stream_db)],
+            )
+            .map(|()| rdb)
+        })
+        .await??;
+        frame_stream.write_rdb(&rdb).await?;
+        println!(
+            "full resync: sent {} bytes of rdb at offset {offset}",
+            rdb.len()
+        );
+        self.replication
+            .lock()
+            .unwrap()
+            .set_replica_state(id, ReplicaState::Online);
+
+        self.stream_to_replica(frame_stream, id, queue).await
+    }
+
+    /// Sends the replication stream to an online replica, and processes its
+    /// acknowledgements.
+    async fn stream_to_replica(
+        &self,
+        frame_stream: &mut FrameStream,
+        id: u64,
+        mut queue: mpsc::UnboundedReceiver<Bytes>,
+    ) -> anyhow::Result<()> {
+        loop {
+            tokio::select! {
+                bytes = queue.recv() => match bytes {
+                    Some(bytes) => frame_stream.write_raw(&bytes).await?,
+                    None => break,
+                },
+                frame = frame_stream.read_frame() => {
+                    let Some(frame) = frame? else {
+                        break;
+                    };
+                    if let Ok(Command::Replconf(Replconf::Ack(offset))) = Command::parse(frame) {
+                        self.replication.lock().unwrap().ack(id, offset);
+                        self.replica_acks.notify_waiters();
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct Client {
+    addr: SocketAddr,
+    // Port the client listens on, as announced by replicas with REPLCONF.
+    listening_port: Option<u16>,
+    // Replication offset right after the client's last write, for WAIT.
+    last_write_offset: u64,
+    // Capabilities announced by replicas with REPLCONF.
+    capabilities: Vec<String>,
+    // Bytes of the command being applied from the master's stream.
+    master_frame: Option<Bytes>,
+    // Index of the selected database.
+    db: usize,
+    // Whether the client sent ASKING, which lets its next command access a
+    // slot being imported.
+    asking: bool,
+    id: u64,
+    // Connection the messages published to the client's channels are pushed
+    // to, `None` for clients without one.
+    subscriber: Option<Subscriber>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    // Whether the client switched to RESP3 with HELLO.
+    resp3: bool,
+    // Options of CLIENT TRACKING, `None` while tracking is off.
+    tracking: Option<TrackingOptions>,
+    // Whether the next command's reads are tracked, as set by CLIENT
+    // CACHING in the OPTIN and OPTOUT modes.
+    caching: Option<bool>,
+}
+
+impl Client {
+    fn new(addr: SocketAddr) -> Self {
+        Client {
+            addr,
+            listening_port: None,
+            last_write_offset: 0,
+            capabilities: Vec::new(),
+            master_frame: None,
+            db: 0,
+            asking: false,
+            id: 0,
+            subscriber: None,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            resp3: false,
+            tracking: None,
+            caching: None,
+        }
+    }
+
+    /// Whether the client subscribed to channels or patterns, which limits
+    /// the commands it can send.
+    fn is_subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty()
+    }
+
+    /// Adapts a push to the client's protocol. RESP2 clients get pushes as
+    /// arrays, and invalidations as messages of the `__redis__:invalidate`
+    /// channel only while subscribed to it.
+    fn push(&self, frame: Frame) -> Option<Frame> {
+        let Frame::Push(frames) = frame else {
+            return Some(frame);
+        };
+        if self.resp3 {
+            return Some(Frame::Push(frames));
+        }
+        match frames.first() {
+            Some(Frame::Bulk(kind)) if &kind[..] == b"invalidate" => {
+                if !self.channels.contains(INVALIDATE_CHANNEL) {
+                    return None;
+                }
+                let keys = frames.into_iter().nth(1).unwrap_or(Frame::Null);
+                Some(Frame::Array(vec![
+                    Frame::Bulk(Bytes::from_static(b"message")),
+                    Frame::Bulk(Bytes::from_static(INVALIDATE_CHANNEL.as_bytes())),
+                    keys,
+                ]))
+            }
+            Some(Frame::Bulk(kind)) if &kind[..] == b"tracking-redir-broken" => None,
+            _ => Some(Frame::Array(frames)),
+        }
+    }
+}
+
+/// The instance MIGRATE moves keys to.
+struct Target {
+    host: String,
+    port: u16,
+    db: usize,
+}
+
+/// A replica waiting for the next diskless transfer.
+struct DisklessWaiting {
+    addr: SocketAddr,
+    listening_port: Option<u16>,
+    tx: oneshot::Sender<DisklessTransfer>,
+}
+
+/// A diskless transfer as seen by one of its replicas: the position of the
+/// snapshot in the replication stream and the snapshot itself, in chunks.
+struct DisklessTransfer {
+    replid: String,
+    offset: u64,
+    id: u64,
+    queue: mpsc::UnboundedReceiver<Bytes>,
+    chunks: mpsc::Receiver<anyhow::Result<Bytes>>,
+}
+
+/// Sends what the RDB writer produces to every replica of a diskless
+/// transfer, dropping replicas that went away.
+struct ChunkWriter {
+    senders: Vec<mpsc::Sender<anyhow::Result<Bytes>>>,
+    buf: Vec<u8>,
+}
+
+impl std::io::Write for ChunkWriter {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(bytes);
+        if self.buf.len() >= DISKLESS_CHUNK_SIZE {
+            self.flush()?;
+        }
+
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buf));
+        self.senders
+            .retain(|sender| sender.blocking_send(Ok(chunk.clone())).is_ok());
+        if self.senders.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "no replica left",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// How a replica gets in sync: from the backlog, or from a snapshot.
+enum Sync {
+    Partial(Bytes),
+    /// The databases, along with the database selected in the stream.
+    Full(Snapshot, usize),
+}
+
+// Period of the pings sent by masters to their replicas.
+const REPL_PING_PERIOD: Duration = Duration::from_secs(10);
+// Time without data after which the master link is considered dead.
+const REPL_TIMEOUT: Duration = Duration::from_secs(60);
+const DISKLESS_CHUNK_SIZE: usize = 16 * 1024;
+const DISKLESS_CHUNKS_IN_FLIGHT: usize = 16;
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+const ACTIVE_EXPIRE_PERIOD: Duration = Duration::from_millis(100);
+// Keys with an expiry sampled per database and round of active expiry.
+const ACTIVE_EXPIRE_SAMPLES: usize = 20;
+// Time after which active expiry stops until its next period.
+const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
+
+// Offset of the cluster bus port from the client port.
+const CLUSTER_BUS_PORT_OFFSET: u16 = 10000;
+const CLUSTER_CRON_PERIOD: Duration = Duration::from_millis(100);
+const CLUSTER_BUS_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const SENTINEL_CRON_PERIOD: Duration = Duration::from_millis(100);
+const SENTINEL_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+const SENTINEL_REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+// Pause before a failed hello subscription is opened again.
+const SENTINEL_RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+// Version reported by HELLO, which clients check for the features they use.
+const REDIS_VERSION: &str = "7.2.0";
+// Channel RESP2 clients subscribe to for the invalidations redirected to them.
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+const DB_INDEX_ERROR: &[u8] = b"ERR DB index is out of range";
+const SAME_OBJECT_ERROR: &[u8] = b"ERR source and destination objects are the same";
+const OOM_ERROR: &[u8] = b"OOM command not allowed when used memory > 'maxmemory'.";
+const FREQ_ERROR: &[u8] =
+    b"ERR An LFU maxmemory policy is not selected, access frequency not tracked.";
+const IDLETIME_ERROR: &[u8] = b"ERR An LFU maxmemory policy is selected, idle time not tracked.";
+const CLUSTER_DISABLED_ERROR: &[u8] = b"ERR This instance has cluster support disabled";
+const SENTINEL_DISABLED_ERROR: &[u8] = b"ERR unknown command 'sentinel'";
+const CROSSSLOT_ERROR: &[u8] = b"CROSSSLOT Keys in request don't hash to the same slot";
+const CLUSTERDOWN_UNBOUND_ERROR: &[u8] = b"CLUSTERDOWN Hash slot not served";
+const CLUSTERDOWN_ERROR: &[u8] = b"CLUSTERDOWN The cluster is down";
+const TRYAGAIN_ERROR: &[u8] = b"TRYAGAIN Multiple keys request during rehashing of slot";
+const CLUSTER_REPLICAOF_ERROR: &[u8] = b"ERR REPLICAOF not allowed in cluster mode.";
+const CLUSTER_REPLICATE_ERROR: &[u8] =
+    b"ERR To set a master the node must be empty and without assigned slots.";
+const CLUSTER_RESET_ERROR: &[u8] =
+    b"ERR CLUSTER RESET can't be called with master nodes containing keys";
+const CLUSTER_SELECT_ERROR: &[u8] = b"ERR SELECT is not allowed in cluster mode";
+const CLUSTER_MOVE_ERROR: &[u8] = b"ERR MOVE is not allowed in cluster mode";
+const CLUSTER_SWAPDB_ERROR: &[u8] = b"ERR SWAPDB is not allowed in cluster mode";
+const CLUSTER_COPY_ERROR: &[u8] = b"ERR Copying to another database is not allowed in cluster mode";
+const BUSYKEY_ERROR: &[u8] = b"BUSYKEY Target key name already exists.";
+const WRONGTYPE_ERROR: &[u8] = b"WRONGTYPE Operation against a key holding the wrong kind of value";
+const NOMASTERLINK_ERROR: &[u8] = b"NOMASTERLINK Can't SYNC while not connected with my master";
+const READONLY_ERROR: &[u8] = b"READONLY You can't write against a read only replica.";
+const MASTERDOWN_ERROR: &[u8] =
+    b"MASTERDOWN Link with MASTER is down and replica-serve-stale-data is set to 'no'.";
+const NOPROTO_ERROR: &[u8] = b"NOPROTO unsupported protocol version";
+const TRACKING_REDIRECT_ERROR: &[u8] = b"ERR The client ID you want redirect to does not exist";
+const TRACKING_BCAST_ERROR: &[u8] = b"ERR You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.";
+const TRACKING_OPTIN_ERROR: &[u8] = b"ERR You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.";
+const CACHING_ERROR: &[u8] = b"ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled";
+const CACHING_YES_ERROR: &[u8] =
+    b"ERR CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.";
+const CACHING_NO_ERROR: &[u8] =
+    b"ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.";
+
+/// Reads a reply of the master during the handshake.
+async fn read_reply(frame_stream: &mut FrameStream) -> anyhow::Result<Frame> {
+    tokio::time::timeout(REPL_TIMEOUT, frame_stream.read_frame())
+        .await
+        .map_err(|_| anyhow!("timeout waiting for master reply"))??
+        .ok_or(anyhow!("master closed the connection"))
+}
+
+/// Reads a reply of the target of MIGRATE.
+async fn read_target_reply(frame_stream: &mut FrameStream) -> anyhow::Result<Frame> {
+    frame_stream
+        .read_frame()
+        .await?
+        .ok_or(anyhow!("target closed the connection"))
+}
+
+fn key_count(db: &Db) -> usize {
+    db.key_counts().into_iter().map(|(keys, _)| keys).sum()
+}
+
+/// Splits a bind address into its host, without brackets, and whether it is
+/// optional.
+fn parse_bind(bind: &str) -> (&str, bool) {
+    let (host, optional) = match bind.strip_prefix('-') {
+        Some(host) => (host, true),
+        None => (bind, false),
+    };
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+
+    (host, optional)
+}
+
+/// The command carrying out a request of the sentinel, on a connection going
+/// out from `local_ip`.
+fn sentinel_request_frame(request: &Request, local_ip: &str) -> Frame {
+    let command = match request {
+        Request::Ping => Command::Ping,
+        Request::Info => Command::Info(None),
+        Request::Hello(hello) => Command::Publish {
+            channel: sentinel::HELLO_CHANNEL.to_owned(),
+            message: Bytes::from(format!("{},{}", local_ip, hello)),
+        },
+        Request::IsMasterDownByAddr {
+            addr,
+            epoch,
+            run_id,
+        } => Command::Sentinel(Sentinel::IsMasterDownByAddr {
+            host: addr.0.clone(),
+            port: addr.1,
+            epoch: *epoch,
+            run_id: run_id.clone(),
+        }),
+        Request::Replicaof(master) => Command::Replicaof(master.clone()),
+    };
+
+    command.to_frame()
+}
+
+/// Formats an address for display, with brackets around IPv6 addresses.
+fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Role {
+    Master,
     Slave {
-        master_host: Ipv4Addr,
+        master_host: String,
         master_port: u16,
     },
 }
@@ -0,0 +1,245 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use bytes::Bytes;
+
+use crate::{command::TrackingOptions, frame::Frame, pubsub::Subscriber};
+
+/// The keys read by clients with tracking enabled, and the prefixes of the
+/// clients in broadcasting mode, so that writes are sent to them as
+/// invalidations.
+#[derive(Default)]
+pub struct Tracking {
+    inner: Mutex<Inner>,
+    // Most keys remembered, 0 for no limit.
+    max_keys: usize,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Every connected client, as the target of redirections.
+    connections: HashMap<u64, Subscriber>,
+    // Options of the clients with tracking enabled.
+    trackers: HashMap<u64, TrackingOptions>,
+    // Keys read by clients in the default mode, with the IDs of these clients.
+    // Entries of clients that disabled tracking since are skipped.
+    keys: HashMap<String, HashSet<u64>>,
+    // Prefixes of the clients in broadcasting mode, the empty one matching
+    // every key.
+    prefixes: HashMap<String, HashSet<u64>>,
+}
+
+impl Tracking {
+    pub fn new(max_keys: usize) -> Self {
+        Tracking {
+            inner: Mutex::default(),
+            max_keys,
+        }
+    }
+
+    pub fn connect(&self, id: u64, subscriber: &Subscriber) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.connections.insert(id, subscriber.clone());
+    }
+
+    pub fn disconnect(&self, id: u64) {
+        self.disable(id);
+        self.inner.lock().unwrap().connections.remove(&id);
+    }
+
+    pub fn is_connected(&self, id: u64) -> bool {
+        self.inner.lock().unwrap().connections.contains_key(&id)
+    }
+
+    pub fn enable(&self, id: u64, options: TrackingOptions) {
+        self.disable(id);
+        let mut inner = self.inner.lock().unwrap();
+        for prefix in broadcast_prefixes(&options) {
+            inner.prefixes.entry(prefix).or_default().insert(id);
+        }
+        inner.trackers.insert(id, options);
+    }
+
+    pub fn disable(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(options) = inner.trackers.remove(&id) else {
+            return;
+        };
+        for prefix in broadcast_prefixes(&options) {
+            if let Some(ids) = inner.prefixes.get_mut(&prefix) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    inner.prefixes.remove(&prefix);
+                }
+            }
+        }
+    }
+
+    /// Records that a client read keys, which it is notified of once they
+    /// change. Beyond the most keys remembered, arbitrary ones are forgotten,
+    /// and invalidated as if they changed.
+    pub fn remember(&self, id: u64, keys: &[&str]) {
+        let mut inner = self.inner.lock().unwrap();
+        for key in keys {
+            inner.keys.entry((*key).to_owned()).or_default().insert(id);
+        }
+        while self.max_keys > 0 && inner.keys.len() > self.max_keys {
+            let key = inner.keys.keys().next().unwrap().clone();
+            let ids = inner.keys.remove(&key).unwrap();
+            for id in ids {
+                inner.send(id, Frame::bulk_array([&key]), None);
+            }
+        }
+    }
+
+    /// Notifies the clients which read a key, or which broadcast a prefix of
+    /// it, that it changed. `writer` is the client which modified it, if
+    /// any.
+    pub fn invalidate(&self, key: &str, writer: Option<u64>) {
+        let mut inner = self.inner.lock().unwrap();
+        let mut ids = inner.keys.remove(key).unwrap_or_default();
+        ids.extend(
+            (0..=key.len())
+                .filter_map(|end| key.get(..end))
+                .filter_map(|prefix| inner.prefixes.get(prefix))
+                .flatten(),
+        );
+        for id in ids {
+            let keys = Frame::bulk_array([key]);
+            inner.send(id, keys, writer);
+        }
+    }
+
+    /// Notifies every tracking client that all keys changed, as when the
+    /// dataset is flushed.
+    pub fn invalidate_all(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.keys.clear();
+        let ids = inner.trackers.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            inner.send(id, Frame::Null, None);
+        }
+    }
+}
+
+impl Inner {
+    fn send(&self, id: u64, keys: Frame, writer: Option<u64>) {
+        let Some(options) = self.trackers.get(&id) else {
+            return;
+        };
+        if options.noloop && writer == Some(id) {
+            return;
+        }
+        let invalidate = Frame::Push(vec![Frame::Bulk(Bytes::from_static(b"invalidate")), keys]);
+        let target = options.redirect.unwrap_or(id);
+        match self.connections.get(&target) {
+            Some(connection) => {
+                let _ = connection.send(invalidate);
+            }
+            // The client is told that the invalidations it redirected to
+            // another one are lost.
+            None => {
+                if let Some(connection) = self.connections.get(&id) {
+                    let _ = connection.send(Frame::Push(vec![
+                        Frame::Bulk(Bytes::from_static(b"tracking-redir-broken")),
+                        Frame::Integer(target as i64),
+                    ]));
+                }
+            }
+        }
+    }
+}
+
+fn broadcast_prefixes(options: &TrackingOptions) -> Vec<String> {
+    match options.prefixes.is_empty() {
+        true if options.bcast => vec![String::new()],
+        _ => options.prefixes.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn invalidated(rx: &mut mpsc::UnboundedReceiver<Frame>) -> Option<Bytes> {
+        rx.try_recv().ok().map(|frame| frame.to_bytes())
+    }
+
+    #[test]
+    fn test_default_mode() {
+        let tracking = Tracking::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tracking.connect(1, &tx);
+        tracking.enable(1, TrackingOptions::default());
+        tracking.remember(1, &["a"]);
+
+        tracking.invalidate("b", None);
+        assert_eq!(None, invalidated(&mut rx));
+        tracking.invalidate("a", Some(2));
+        let expected = b">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\na\r\n";
+        assert_eq!(Some(Bytes::from_static(expected)), invalidated(&mut rx));
+        // Keys are invalidated once, until read again.
+        tracking.invalidate("a", Some(2));
+        assert_eq!(None, invalidated(&mut rx));
+    }
+
+    #[test]
+    fn test_max_keys() {
+        let tracking = Tracking::new(2);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tracking.connect(1, &tx);
+        tracking.enable(1, TrackingOptions::default());
+
+        tracking.remember(1, &["a", "b"]);
+        assert_eq!(None, invalidated(&mut rx));
+        tracking.remember(1, &["c"]);
+        assert!(invalidated(&mut rx).is_some());
+        assert_eq!(None, invalidated(&mut rx));
+        assert_eq!(2, tracking.inner.lock().unwrap().keys.len());
+    }
+
+    #[test]
+    fn test_bcast_noloop_and_redirect() {
+        let tracking = Tracking::default();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let (redirect_tx, mut redirect_rx) = mpsc::unbounded_channel();
+        tracking.connect(1, &tx);
+        tracking.connect(2, &redirect_tx);
+        let options = TrackingOptions {
+            bcast: true,
+            prefixes: vec!["user:".to_owned()],
+            noloop: true,
+            ..TrackingOptions::default()
+        };
+        tracking.enable(1, options.clone());
+
+        tracking.invalidate("user:1", Some(1));
+        tracking.invalidate("order:1", Some(3));
+        assert_eq!(None, invalidated(&mut rx));
+        tracking.invalidate("user:1", Some(3));
+        assert!(invalidated(&mut rx).is_some());
+
+        tracking.enable(
+            1,
+            TrackingOptions {
+                redirect: Some(2),
+                ..options
+            },
+        );
+        tracking.invalidate("user:2", None);
+        assert_eq!(None, invalidated(&mut rx));
+        assert!(invalidated(&mut redirect_rx).is_some());
+        tracking.disconnect(2);
+        tracking.invalidate("user:2", None);
+        let expected = b">2\r\n$21\r\ntracking-redir-broken\r\n:2\r\n";
+        assert_eq!(Some(Bytes::from_static(expected)), invalidated(&mut rx));
+
+        tracking.disable(1);
+        tracking.invalidate("user:3", None);
+        assert_eq!(None, invalidated(&mut rx));
+    }
+}
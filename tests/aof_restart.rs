@@ -0,0 +1,105 @@
+//! Rebuilds the dataset of a restarted server from its append-only file: the
+//! base snapshot of the last rewrite, the incremental files written since,
+//! and a last command cut short by a crash.
+
+use std::{fs, io::Write, time::Duration};
+
+use redis::{
+    aof::Manifest,
+    config::Config,
+    frame::Frame,
+    server::{Role, Server},
+};
+
+mod common;
+
+const KEYS: usize = 100;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_restart_from_aof() {
+    const PORT: u16 = 7531;
+    const RESTARTED_PORT: u16 = 7532;
+    let config = Config {
+        appendonly: true,
+        ..common::config("aof-restart", PORT)
+    };
+    let aof_dir = config.aof_dir();
+    tokio::spawn(Server::new(Role::Master, config).start());
+    let mut server = common::connect(PORT).await;
+
+    // Keys written before the rewrite end up in the base snapshot.
+    for i in 0..KEYS {
+        common::call(&mut server, &["SET", &format!("key:{i}"), "base"]).await;
+    }
+    common::call(&mut server, &["BGREWRITEAOF"]).await;
+    wait_for_rewrite(&mut server).await;
+
+    // And the writes since in an incremental file, replayed over the base.
+    common::call(&mut server, &["SET", "key:0", "incr"]).await;
+    common::call(&mut server, &["DEL", "key:1"]).await;
+    common::call(&mut server, &["SELECT", "1"]).await;
+    common::call(&mut server, &["SET", "other", "incr"]).await;
+
+    // The server is restarted from a copy of its files, as after a crash in
+    // the middle of a write.
+    let config = Config {
+        appendonly: true,
+        ..common::config("aof-restart", RESTARTED_PORT)
+    };
+    let restarted_dir = config.aof_dir();
+    fs::create_dir_all(&restarted_dir).unwrap();
+    for entry in fs::read_dir(&aof_dir).unwrap() {
+        let entry = entry.unwrap();
+        fs::copy(entry.path(), restarted_dir.join(entry.file_name())).unwrap();
+    }
+    let manifest = Manifest::load(&restarted_dir, &config.appendfilename)
+        .unwrap()
+        .unwrap();
+    assert!(manifest.base.is_some());
+    let incr = restarted_dir.join(&manifest.incrs.last().unwrap().name);
+    let len = fs::metadata(&incr).unwrap().len();
+    fs::OpenOptions::new()
+        .append(true)
+        .open(&incr)
+        .unwrap()
+        .write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nkey")
+        .unwrap();
+    tokio::spawn(Server::new(Role::Master, config).start());
+    let mut restarted = common::connect(RESTARTED_PORT).await;
+
+    let reply = common::call(&mut restarted, &["DBSIZE"]).await;
+    assert!(
+        matches!(reply, Frame::Integer(n) if n as usize == KEYS - 1),
+        "{:?}",
+        reply
+    );
+    let reply = common::call(&mut restarted, &["GET", "key:0"]).await;
+    assert!(matches!(reply, Frame::Bulk(value) if value == "incr"));
+    let reply = common::call(&mut restarted, &["GET", "key:1"]).await;
+    assert!(matches!(reply, Frame::Null), "{:?}", reply);
+    let reply = common::call(&mut restarted, &["GET", "key:2"]).await;
+    assert!(matches!(reply, Frame::Bulk(value) if value == "base"));
+    common::call(&mut restarted, &["SELECT", "1"]).await;
+    let reply = common::call(&mut restarted, &["GET", "other"]).await;
+    assert!(matches!(reply, Frame::Bulk(value) if value == "incr"));
+
+    // The incomplete command is cut from the file.
+    assert_eq!(len, fs::metadata(&incr).unwrap().len());
+}
+
+async fn wait_for_rewrite(server: &mut redis::net::FrameStream) {
+    for _ in 0..200 {
+        let reply = common::call(server, &["INFO", "persistence"]).await;
+        let Frame::Bulk(info) = reply else {
+            panic!("unexpected reply to INFO: {:?}", reply);
+        };
+        if info
+            .windows(25)
+            .any(|line| line == b"aof_rewrite_in_progress:0")
+        {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the rewrite did not finish");
+}
@@ -0,0 +1,41 @@
+//! Binds the IPv4 and IPv6 wildcards on the same port, and checks that the
+//! server is reachable over both.
+
+use redis::{
+    config::Config,
+    frame::Frame,
+    net::FrameStream,
+    server::{Role, Server},
+};
+use tokio::net::TcpStream;
+
+mod common;
+
+const PORT: u16 = 7481;
+
+#[tokio::test]
+async fn test_bind_both_wildcards() {
+    let config = Config {
+        bind: vec!["0.0.0.0".to_owned(), "::".to_owned()],
+        ..common::config("bind", PORT)
+    };
+    let server = tokio::spawn(Server::new(Role::Master, config).start());
+
+    let mut ipv4 = tokio::select! {
+        connection = common::connect(PORT) => connection,
+        result = server => panic!("server stopped: {:?}", result),
+    };
+    let reply = common::call(&mut ipv4, &["PING"]).await;
+    assert!(
+        matches!(&reply, Frame::Bulk(pong) if pong == "PONG"),
+        "{:?}",
+        reply
+    );
+    let mut ipv6 = FrameStream::new(TcpStream::connect(("::1", PORT)).await.unwrap());
+    let reply = common::call(&mut ipv6, &["PING"]).await;
+    assert!(
+        matches!(&reply, Frame::Bulk(pong) if pong == "PONG"),
+        "{:?}",
+        reply
+    );
+}
@@ -0,0 +1,197 @@
+//! Moves slots between two cluster nodes while a client keeps writing to
+//! them, and checks that no write is lost.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use redis::{
+    cluster,
+    config::Config,
+    frame::Frame,
+    net::FrameStream,
+    server::{Role, Server},
+};
+
+mod common;
+
+const PORTS: [u16; 2] = [7461, 7462];
+// Hash tags of the keys written, the first half of which are migrated.
+const TAGS: usize = 20;
+// Distinct keys per hash tag, overwritten over and over.
+const KEYS_PER_TAG: usize = 20;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_migrate_slots_under_write_load() {
+    for port in PORTS {
+        start_node(port);
+    }
+    let mut client = Client::default();
+    let ids = [
+        bulk_string(client.call(PORTS[0], &["CLUSTER", "MYID"]).await),
+        bulk_string(client.call(PORTS[1], &["CLUSTER", "MYID"]).await),
+    ];
+    client
+        .call(PORTS[0], &["CLUSTER", "ADDSLOTSRANGE", "0", "16383"])
+        .await;
+    let port = PORTS[1].to_string();
+    client
+        .call(PORTS[0], &["CLUSTER", "MEET", "127.0.0.1", &port])
+        .await;
+    // Until both nodes agree that the first one serves every slot.
+    for port in PORTS {
+        loop {
+            let info = bulk_string(client.call(port, &["CLUSTER", "INFO"]).await);
+            let nodes = bulk_string(client.call(port, &["CLUSTER", "NODES"]).await);
+            if info.contains("cluster_state:ok") && !nodes.contains("handshake") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = tokio::spawn(write_keys(done.clone()));
+    for tag in 0..TAGS / 2 {
+        let slot = cluster::key_slot(format!("tag{tag}").as_bytes()).to_string();
+        migrate_slot(&mut client, &slot, &ids).await;
+    }
+    done.store(true, Ordering::SeqCst);
+    let written = writer.await.unwrap();
+
+    for (key, value) in &written {
+        let reply = client.call_cluster(&["GET", key]).await;
+        assert_eq!(value, &bulk_string(reply), "value of {key}");
+    }
+    for tag in 0..TAGS {
+        let slot = cluster::key_slot(format!("tag{tag}").as_bytes()).to_string();
+        let reply = client
+            .call(PORTS[0], &["CLUSTER", "COUNTKEYSINSLOT", &slot])
+            .await;
+        let expected = if tag < TAGS / 2 { 0 } else { KEYS_PER_TAG };
+        assert!(matches!(reply, Frame::Integer(n) if n as usize == expected));
+    }
+}
+
+fn start_node(port: u16) {
+    let config = Config {
+        cluster_enabled: true,
+        cluster_node_timeout: 2000,
+        ..common::config("cluster-migration", port)
+    };
+    tokio::spawn(Server::new(Role::Master, config).start());
+}
+
+/// Moves a slot from the first node to the second one, the way
+/// `redis-cli --cluster reshard` does.
+async fn migrate_slot(client: &mut Client, slot: &str, ids: &[String; 2]) {
+    let importing = ["CLUSTER", "SETSLOT", slot, "IMPORTING", &ids[0]];
+    assert_ok(client.call(PORTS[1], &importing).await);
+    let migrating = ["CLUSTER", "SETSLOT", slot, "MIGRATING", &ids[1]];
+    assert_ok(client.call(PORTS[0], &migrating).await);
+    loop {
+        let Frame::Array(keys) = client
+            .call(PORTS[0], &["CLUSTER", "GETKEYSINSLOT", slot, "5"])
+            .await
+        else {
+            panic!("GETKEYSINSLOT did not reply with an array");
+        };
+        if keys.is_empty() {
+            break;
+        }
+        let port = PORTS[1].to_string();
+        let mut migrate = vec!["MIGRATE", "127.0.0.1", &port, "", "0", "5000", "KEYS"];
+        let keys = keys.into_iter().map(bulk_string).collect::<Vec<_>>();
+        migrate.extend(keys.iter().map(String::as_str));
+        assert_ok(client.call(PORTS[0], &migrate).await);
+    }
+    let node = ["CLUSTER", "SETSLOT", slot, "NODE", &ids[1]];
+    assert_ok(client.call(PORTS[1], &node).await);
+    assert_ok(client.call(PORTS[0], &node).await);
+}
+
+/// Overwrites keys of every hash tag until `done` is set, and returns the
+/// last value written to each key.
+async fn write_keys(done: Arc<AtomicBool>) -> HashMap<String, String> {
+    let mut client = Client::default();
+    let mut written = HashMap::new();
+    let mut i = 0;
+    while !done.load(Ordering::SeqCst) || i < TAGS * KEYS_PER_TAG * 2 {
+        let key = format!("{{tag{}}}:{}", i % TAGS, i / TAGS % KEYS_PER_TAG);
+        let value = i.to_string();
+        assert_ok(client.call_cluster(&["SET", &key, &value]).await);
+        written.insert(key, value);
+        i += 1;
+    }
+
+    written
+}
+
+/// A client following the redirections of the cluster.
+#[derive(Default)]
+struct Client {
+    connections: HashMap<u16, FrameStream>,
+}
+
+impl Client {
+    async fn call(&mut self, port: u16, args: &[&str]) -> Frame {
+        let connection = match self.connections.entry(port) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(common::connect(port).await),
+        };
+        common::call(connection, args).await
+    }
+
+    /// Sends a command to the node serving its key, starting with the first
+    /// node.
+    async fn call_cluster(&mut self, args: &[&str]) -> Frame {
+        let mut port = PORTS[0];
+        loop {
+            let reply = self.call(port, args).await;
+            let Frame::Error(error) = &reply else {
+                return reply;
+            };
+            let error = String::from_utf8(error.to_vec()).unwrap();
+            let fields = error.split(' ').collect::<Vec<_>>();
+            match fields[0] {
+                "MOVED" => port = redirect_port(fields[2]),
+                "ASK" => {
+                    let target = redirect_port(fields[2]);
+                    assert_ok(self.call(target, &["ASKING"]).await);
+                    let reply = self.call(target, args).await;
+                    if !matches!(&reply, Frame::Error(error) if error.starts_with(b"MOVED")) {
+                        return reply;
+                    }
+                }
+                "TRYAGAIN" => tokio::time::sleep(Duration::from_millis(10)).await,
+                _ => return reply,
+            }
+        }
+    }
+}
+
+fn redirect_port(address: &str) -> u16 {
+    address.rsplit_once(':').unwrap().1.parse().unwrap()
+}
+
+fn bulk_string(frame: Frame) -> String {
+    match frame {
+        Frame::Bulk(bytes) => String::from_utf8(bytes.to_vec()).unwrap(),
+        frame => panic!("expected a bulk string, got {:?}", frame),
+    }
+}
+
+fn assert_ok(frame: Frame) {
+    // SET replies with a bulk string.
+    let ok = match &frame {
+        Frame::Simple(s) => s == "OK",
+        Frame::Bulk(bytes) => bytes == "OK",
+        _ => false,
+    };
+    assert!(ok, "expected OK, got {:?}", frame);
+}
@@ -0,0 +1,67 @@
+//! Helpers shared by the integration tests, which each start servers on
+//! ports of their own.
+
+// Each test uses some of the helpers only.
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use redis::{
+    config::Config,
+    frame::Frame,
+    net::{FrameStream, RdbReader},
+};
+use tokio::{net::TcpStream, runtime::Handle};
+
+/// The config of a server listening on `port`, with its files in a
+/// directory of its own and without snapshots.
+pub fn config(name: &str, port: u16) -> Config {
+    let dir = std::env::temp_dir().join(format!("{name}-{}-{port}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    Config {
+        port,
+        dir,
+        save: Vec::new(),
+        ..Config::default()
+    }
+}
+
+pub async fn connect(port: u16) -> FrameStream {
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(stream) => return FrameStream::new(stream),
+            // The server may still be starting.
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+}
+
+pub async fn call(connection: &mut FrameStream, args: &[&str]) -> Frame {
+    connection
+        .write_frame(Frame::bulk_array(args))
+        .await
+        .unwrap();
+    connection.read_frame().await.unwrap().unwrap()
+}
+
+/// Connects to `port` as a replica, and returns the connection at the start of
+/// the command stream, along with the reply to PSYNC. The snapshot of a full
+/// resync is skipped.
+pub async fn psync(port: u16, replid: &str, offset: i64) -> (FrameStream, String) {
+    let mut connection = connect(port).await;
+    let offset = offset.to_string();
+    let reply = match call(&mut connection, &["PSYNC", replid, &offset]).await {
+        Frame::Simple(reply) => reply,
+        reply => panic!("unexpected reply to PSYNC: {:?}", reply),
+    };
+    if reply.starts_with("FULLRESYNC") {
+        let transfer = connection.read_rdb_header().await.unwrap();
+        let reader = RdbReader::new(connection, transfer, Handle::current());
+        connection = tokio::task::spawn_blocking(move || reader.finish())
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    (connection, reply)
+}
@@ -0,0 +1,86 @@
+//! Replicates a dataset to replicas loading the snapshot of their master
+//! from disk and from the socket, with the snapshot sent as a bulk string or
+//! ended by a mark.
+
+use std::time::Duration;
+
+use redis::{
+    config::{Config, DisklessLoad},
+    frame::Frame,
+    server::{Role, Server},
+};
+
+mod common;
+
+const KEYS: usize = 1000;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_load_from_disk_sync() {
+    replicate(
+        7511,
+        false,
+        &[(7512, DisklessLoad::Disabled), (7513, DisklessLoad::Swapdb)],
+    )
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_load_from_diskless_sync() {
+    replicate(
+        7514,
+        true,
+        &[(7515, DisklessLoad::Disabled), (7516, DisklessLoad::Swapdb)],
+    )
+    .await;
+}
+
+async fn replicate(port: u16, diskless_sync: bool, replicas: &[(u16, DisklessLoad)]) {
+    let config = Config {
+        repl_diskless_sync: diskless_sync,
+        repl_diskless_sync_delay: 0,
+        ..common::config("diskless-load", port)
+    };
+    tokio::spawn(Server::new(Role::Master, config).start());
+    let mut master = common::connect(port).await;
+    for i in 0..KEYS {
+        let key = format!("key:{i}");
+        common::call(&mut master, &["SET", &key, &"x".repeat(i)]).await;
+    }
+
+    for (started, &(replica_port, diskless_load)) in replicas.iter().enumerate() {
+        let config = Config {
+            repl_diskless_load: diskless_load,
+            ..common::config("diskless-load", replica_port)
+        };
+        let role = Role::Slave {
+            master_host: "127.0.0.1".to_owned(),
+            master_port: port,
+        };
+        tokio::spawn(Server::new(role, config).start());
+        let mut replica = common::connect(replica_port).await;
+        wait_for(&mut replica, "key:999", &"x".repeat(999)).await;
+        // Along with a key written after each replica started before.
+        let reply = common::call(&mut replica, &["DBSIZE"]).await;
+        assert!(
+            matches!(reply, Frame::Integer(n) if n as usize == KEYS + started),
+            "{:?}",
+            reply
+        );
+
+        // The command stream goes on after the snapshot.
+        let key = format!("after:{replica_port}");
+        common::call(&mut master, &["SET", &key, "1"]).await;
+        wait_for(&mut replica, &key, "1").await;
+    }
+}
+
+async fn wait_for(replica: &mut redis::net::FrameStream, key: &str, value: &str) {
+    for _ in 0..200 {
+        let reply = common::call(replica, &["GET", key]).await;
+        if matches!(reply, Frame::Bulk(bytes) if bytes == value) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} was not replicated", key);
+}
@@ -0,0 +1,42 @@
+//! Subscribes to the expired keyspace events, and checks that they are
+//! published for keys that expire without being accessed again.
+
+use std::time::Duration;
+
+use common::{call, connect};
+use redis::{
+    config::{Config, KeyspaceEvents},
+    frame::Frame,
+    server::{Role, Server},
+};
+
+mod common;
+
+const PORT: u16 = 7471;
+
+#[tokio::test]
+async fn test_expired_events() {
+    let config = Config {
+        notify_keyspace_events: KeyspaceEvents::parse("Ex").unwrap(),
+        ..common::config("keyspace-notifications", PORT)
+    };
+    tokio::spawn(Server::new(Role::Master, config).start());
+
+    let mut subscriber = connect(PORT).await;
+    call(&mut subscriber, &["SUBSCRIBE", "__keyevent@0__:expired"]).await;
+    let mut client = connect(PORT).await;
+    call(&mut client, &["SET", "a", "1", "PX", "50"]).await;
+    call(&mut client, &["SET", "b", "1"]).await;
+
+    let message = tokio::time::timeout(Duration::from_secs(5), subscriber.read_frame())
+        .await
+        .expect("no expired event")
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        b"*3\r\n$7\r\nmessage\r\n$22\r\n__keyevent@0__:expired\r\n$1\r\na\r\n",
+        &message.to_bytes()[..]
+    );
+    let reply = call(&mut client, &["DBSIZE"]).await;
+    assert!(matches!(reply, Frame::Integer(1)), "{:?}", reply);
+}
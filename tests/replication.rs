@@ -0,0 +1,221 @@
+//! Propagates the writes of a master to its replicas.
+
+use std::time::Duration;
+
+use redis::{
+    aof,
+    command::Command,
+    config::Config,
+    frame::Frame,
+    net::FrameStream,
+    server::{Role, Server},
+};
+
+mod common;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_propagate_absolute_ttl() {
+    const PORT: u16 = 7521;
+    tokio::spawn(Server::new(Role::Master, common::config("replication", PORT)).start());
+    let (mut stream, _) = common::psync(PORT, "?", -1).await;
+    let mut master = common::connect(PORT).await;
+
+    common::call(&mut master, &["SET", "key", "value", "PX", "100000"]).await;
+
+    let set = loop {
+        match Command::parse(stream.read_frame().await.unwrap().unwrap()).unwrap() {
+            Command::Set { key, px, pxat, .. } => break (key, px, pxat),
+            _ => continue,
+        }
+    };
+    assert_eq!("key", set.0);
+    assert_eq!(None, set.1);
+    assert!(set.2.is_some_and(|at| at > redis::rdb::unix_time_ms()));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_master_forwards_writes() {
+    const PORT: u16 = 7522;
+    const REPLICA_PORT: u16 = 7523;
+    tokio::spawn(Server::new(Role::Master, common::config("replication", PORT)).start());
+    let role = Role::Slave {
+        master_host: "127.0.0.1".to_owned(),
+        master_port: PORT,
+    };
+    tokio::spawn(Server::new(role, common::config("replication", REPLICA_PORT)).start());
+    let mut master = common::connect(PORT).await;
+
+    // The master lists the replica once it is online.
+    let listed = format!("ip=127.0.0.1,port={REPLICA_PORT},state=online");
+    wait_until(|| async {
+        info(&mut common::connect(PORT).await, "replication")
+            .await
+            .contains(&listed)
+    })
+    .await;
+    let before = info_field(&mut master, "master_repl_offset").await;
+
+    let set = ["SET", "key", "value"];
+    common::call(&mut master, &set).await;
+
+    // The offset advances by the bytes of the write.
+    let after = info_field(&mut master, "master_repl_offset").await;
+    assert_eq!(
+        Frame::bulk_array(set).to_bytes().len() as u64,
+        after - before
+    );
+    wait_until(|| async {
+        let reply = common::call(&mut common::connect(REPLICA_PORT).await, &["GET", "key"]).await;
+        matches!(reply, Frame::Bulk(value) if value == "value")
+    })
+    .await;
+    wait_until(|| async {
+        let mut replica = common::connect(REPLICA_PORT).await;
+        info_field(&mut replica, "slave_repl_offset").await == after
+    })
+    .await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_partial_resync() {
+    const PORT: u16 = 7524;
+    tokio::spawn(Server::new(Role::Master, common::config("replication", PORT)).start());
+    let (mut stream, reply) = common::psync(PORT, "?", -1).await;
+    let mut parts = reply.split(' ').skip(1);
+    let replid = parts.next().unwrap().to_owned();
+    let mut offset: i64 = parts.next().unwrap().parse().unwrap();
+    let mut master = common::connect(PORT).await;
+
+    common::call(&mut master, &["SET", "a", "1"]).await;
+    loop {
+        let (frame, raw) = stream.read_frame_raw().await.unwrap().unwrap();
+        offset += raw.len() as i64;
+        if matches!(Command::parse(frame).unwrap(), Command::Set { .. }) {
+            break;
+        }
+    }
+    drop(stream);
+    common::call(&mut master, &["SET", "b", "2"]).await;
+
+    // Only the write missed while disconnected follows.
+    let (mut stream, reply) = common::psync(PORT, &replid, offset + 1).await;
+    assert_eq!(format!("CONTINUE {replid}"), reply);
+    let key = loop {
+        let frame = stream.read_frame().await.unwrap().unwrap();
+        if let Command::Set { key, .. } = Command::parse(frame).unwrap() {
+            break key;
+        }
+    };
+    assert_eq!("b", key);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_select_before_writes() {
+    const PORT: u16 = 7527;
+    let config = Config {
+        appendonly: true,
+        ..common::config("replication", PORT)
+    };
+    let aof_file = config.aof_dir().join("appendonly.aof.1.incr.aof");
+    tokio::spawn(Server::new(Role::Master, config).start());
+    let (mut stream, _) = common::psync(PORT, "?", -1).await;
+    let mut master = common::connect(PORT).await;
+
+    common::call(&mut master, &["SELECT", "1"]).await;
+    common::call(&mut master, &["SET", "a", "1"]).await;
+    common::call(&mut master, &["SELECT", "0"]).await;
+    common::call(&mut master, &["SET", "b", "2"]).await;
+
+    let expected = ["SELECT 1", "SET a", "SELECT 0", "SET b"];
+    let mut written = Vec::new();
+    while written.len() < expected.len() {
+        let command = Command::parse(stream.read_frame().await.unwrap().unwrap()).unwrap();
+        // Pings are sent to replicas in between.
+        written.extend(describe(command));
+    }
+    assert_eq!(expected, written.as_slice());
+
+    let (frames, _) = aof::parse_commands(&std::fs::read(aof_file).unwrap()).unwrap();
+    let logged: Vec<_> = frames
+        .into_iter()
+        .filter_map(|frame| describe(Command::parse(frame).unwrap()))
+        .collect();
+    assert_eq!(expected, logged.as_slice());
+}
+
+fn describe(command: Command) -> Option<String> {
+    match command {
+        Command::Select(index) => Some(format!("SELECT {index}")),
+        Command::Set { key, .. } => Some(format!("SET {key}")),
+        _ => None,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_replicaof() {
+    const PORT: u16 = 7525;
+    const OTHER_PORT: u16 = 7526;
+    tokio::spawn(Server::new(Role::Master, common::config("replication", PORT)).start());
+    tokio::spawn(Server::new(Role::Master, common::config("replication", OTHER_PORT)).start());
+    let mut master = common::connect(PORT).await;
+    common::call(&mut master, &["SET", "key", "value"]).await;
+    let mut other = common::connect(OTHER_PORT).await;
+    common::call(&mut other, &["SET", "other", "value"]).await;
+
+    // A master is demoted, and takes over the dataset of its new master.
+    let port = PORT.to_string();
+    let reply = common::call(&mut other, &["REPLICAOF", "127.0.0.1", &port]).await;
+    assert!(matches!(reply, Frame::Simple(reply) if reply == "OK"));
+    assert_eq!("slave", info_value(&mut other, "role").await);
+    wait_until(|| async {
+        let mut other = common::connect(OTHER_PORT).await;
+        info_value(&mut other, "master_link_status").await == "up"
+    })
+    .await;
+    let reply = common::call(&mut other, &["GET", "key"]).await;
+    assert!(matches!(reply, Frame::Bulk(value) if value == "value"));
+    let reply = common::call(&mut other, &["GET", "other"]).await;
+    assert!(matches!(reply, Frame::Null), "{:?}", reply);
+
+    // Once promoted, it keeps the dataset and the history it followed.
+    let replid = info_value(&mut other, "master_replid").await;
+    let offset = info_field(&mut other, "master_repl_offset").await;
+    common::call(&mut other, &["REPLICAOF", "NO", "ONE"]).await;
+    assert_eq!("master", info_value(&mut other, "role").await);
+    assert_eq!(replid, info_value(&mut other, "master_replid2").await);
+    assert_ne!(replid, info_value(&mut other, "master_replid").await);
+    let second_offset = info_field(&mut other, "second_repl_offset").await;
+    assert_eq!(offset + 1, second_offset);
+    let reply = common::call(&mut other, &["GET", "key"]).await;
+    assert!(matches!(reply, Frame::Bulk(value) if value == "value"));
+}
+
+async fn info(connection: &mut FrameStream, section: &str) -> String {
+    match common::call(connection, &["INFO", section]).await {
+        Frame::Bulk(info) => String::from_utf8(info.to_vec()).unwrap(),
+        reply => panic!("unexpected reply to INFO: {:?}", reply),
+    }
+}
+
+async fn info_value(connection: &mut FrameStream, field: &str) -> String {
+    let info = info(connection, "replication").await;
+    info.lines()
+        .find_map(|line| line.strip_prefix(field)?.strip_prefix(':'))
+        .unwrap_or_else(|| panic!("no {} in {}", field, info))
+        .to_owned()
+}
+
+async fn info_field(connection: &mut FrameStream, field: &str) -> u64 {
+    info_value(connection, field).await.parse().unwrap()
+}
+
+/// Polls `condition` for up to 10 seconds.
+async fn wait_until<F: std::future::Future<Output = bool>>(mut condition: impl FnMut() -> F) {
+    for _ in 0..200 {
+        if condition().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("timed out");
+}
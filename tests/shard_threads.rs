@@ -0,0 +1,69 @@
+//! Runs commands from concurrent clients on a server in thread-per-core
+//! mode, on keys of every shard and across shards.
+
+use redis::{
+    config::Config,
+    frame::Frame,
+    server::{Role, Server},
+};
+
+mod common;
+
+const PORT: u16 = 7491;
+const CLIENTS: usize = 4;
+const KEYS_PER_CLIENT: usize = 100;
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_commands_routed_to_shards() {
+    let config = Config {
+        shard_threads: 4,
+        ..common::config("shard-threads", PORT)
+    };
+    tokio::spawn(Server::new(Role::Master, config).start());
+
+    let clients = (0..CLIENTS)
+        .map(|client| tokio::spawn(write_and_read(client)))
+        .collect::<Vec<_>>();
+    for client in clients {
+        client.await.unwrap();
+    }
+
+    let mut connection = common::connect(PORT).await;
+    let reply = common::call(&mut connection, &["DBSIZE"]).await;
+    let expected = CLIENTS * KEYS_PER_CLIENT / 2;
+    assert!(
+        matches!(reply, Frame::Integer(n) if n as usize == expected),
+        "{:?}",
+        reply
+    );
+}
+
+/// Sets keys, reads them back, and deletes half of them with a single DEL,
+/// which spans shards.
+async fn write_and_read(client: usize) {
+    let mut connection = common::connect(PORT).await;
+    let keys = (0..KEYS_PER_CLIENT)
+        .map(|i| format!("key:{client}:{i}"))
+        .collect::<Vec<_>>();
+    for key in &keys {
+        common::call(&mut connection, &["SET", key, key]).await;
+    }
+    for key in &keys {
+        let reply = common::call(&mut connection, &["GET", key]).await;
+        assert!(
+            matches!(&reply, Frame::Bulk(value) if value == key),
+            "{:?}",
+            reply
+        );
+    }
+
+    let mut del = vec!["DEL"];
+    del.extend(keys[..KEYS_PER_CLIENT / 2].iter().map(String::as_str));
+    let reply = common::call(&mut connection, &del).await;
+    let expected = KEYS_PER_CLIENT / 2;
+    assert!(
+        matches!(reply, Frame::Integer(n) if n as usize == expected),
+        "{:?}",
+        reply
+    );
+}